@@ -1,7 +1,9 @@
 use clap::{ArgAction, Parser, Subcommand};
 use dotenv::dotenv;
 use hal_9100_api_communication::{models::AppState, routes::router::app};
-use hal_9100_core::{executor::loop_through_runs, file_storage::FileStorage};
+use hal_9100_core::{
+    executor::loop_through_runs, file_storage::FileStorage, sandbox::shared_sandbox,
+};
 use hal_9100_extra::{config::Hal9100Config, llm::HalLLMClient};
 use log::{error, info};
 use sqlx::postgres::PgPoolOptions;
@@ -139,12 +141,20 @@ async fn main() {
             let mut con = client.get_async_connection().await.unwrap();
 
             info!("Starting hal-9100-executor");
+            match shared_sandbox(&config.code_interpreter).await {
+                Ok(sandbox) => {
+                    if let Err(e) = sandbox.warm().await {
+                        error!("Failed to prepare the code interpreter sandbox: {}", e);
+                    }
+                }
+                Err(e) => error!("Failed to create the code interpreter sandbox: {}", e),
+            }
             let llm_client = HalLLMClient::new(
                 "mistralai/mixtral-8x7b-instruct".to_string(),
-                config.model_url,
-                config.model_api_key.unwrap_or_default(),
+                config.model_url.clone(),
+                config.model_api_key.clone().unwrap_or_default(),
             );
-            loop_through_runs(&pool, &mut con, llm_client).await;
+            loop_through_runs(&pool, &mut con, llm_client, &config).await;
         }
     }
 }
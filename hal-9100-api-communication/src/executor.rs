@@ -256,7 +256,7 @@ mod tests {
             std::env::var("MODEL_URL").expect("MODEL_URL must be set"),
             std::env::var("MODEL_API_KEY").expect("MODEL_API_KEY must be set"),
         );
-        let result = try_run_executor(&pool_clone, &mut con, llm_client).await;
+        let result = try_run_executor(&pool_clone, &mut con, llm_client, &Hal9100Config::default()).await;
         assert!(result.is_ok(), "{:?}", result);
 
         let response = app
@@ -485,7 +485,7 @@ mod tests {
             std::env::var("MODEL_URL").expect("MODEL_URL must be set"),
             std::env::var("MODEL_API_KEY").expect("MODEL_API_KEY must be set"),
         );
-        let result = try_run_executor(&pool_clone, &mut con, llm_client).await;
+        let result = try_run_executor(&pool_clone, &mut con, llm_client, &Hal9100Config::default()).await;
         assert!(!result.is_ok(), "{:?}", result);
 
         let run_err = result.unwrap_err();
@@ -604,7 +604,7 @@ mod tests {
             std::env::var("MODEL_URL").expect("MODEL_URL must be set"),
             std::env::var("MODEL_API_KEY").expect("MODEL_API_KEY must be set"),
         );
-        let result = try_run_executor(&pool_clone, &mut con, llm_client).await;
+        let result = try_run_executor(&pool_clone, &mut con, llm_client, &Hal9100Config::default()).await;
         assert!(result.is_ok(), "{:?}", result);
 
         let response = app
@@ -788,7 +788,7 @@ mod tests {
             std::env::var("MODEL_URL").expect("MODEL_URL must be set"),
             std::env::var("MODEL_API_KEY").expect("MODEL_API_KEY must be set"),
         );
-        let result = try_run_executor(&pool_clone, &mut con, llm_client).await;
+        let result = try_run_executor(&pool_clone, &mut con, llm_client, &Hal9100Config::default()).await;
         assert!(result.is_ok(), "{:?}", result);
 
         let response = app
@@ -6,6 +6,7 @@ pub mod executor;
 pub mod models;
 
 pub mod routes {
+    pub mod actions;
     pub mod assistants;
     pub mod chat;
     pub mod files;
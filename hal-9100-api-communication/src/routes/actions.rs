@@ -0,0 +1,153 @@
+use axum::{
+    extract::{Json, Path, State},
+    http::StatusCode,
+    response::Json as JsonResponse,
+};
+use hal_9100_api_communication::models::AppState;
+use hal_9100_core::actions::{
+    delete_action, get_action, list_actions, set_action_enabled, sync_action_tool, ActionOperation,
+    ActionsDiff,
+};
+use hal_9100_core::assistants::AssistantError;
+use hal_9100_core::function_calling::FunctionCallError;
+use hal_9100_core::secrets::SecretError;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use sqlx::types::Uuid;
+
+#[derive(Serialize, Deserialize)]
+pub struct ListActionsResponse {
+    pub object: String,
+    pub data: Vec<ActionOperation>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct ModifyActionRequest {
+    pub enabled: bool,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct DeleteActionResponse {
+    pub id: String,
+    pub deleted: bool,
+    pub object: String,
+}
+
+fn error_response(e: sqlx::Error) -> (StatusCode, String) {
+    match e {
+        sqlx::Error::RowNotFound => (StatusCode::NOT_FOUND, "Action not found".to_string()),
+        e => (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()),
+    }
+}
+
+fn sync_error_response(e: AssistantError) -> (StatusCode, String) {
+    let status = match &e {
+        AssistantError::SqlxError(sqlx::Error::RowNotFound) => {
+            return (StatusCode::NOT_FOUND, "Assistant not found".to_string())
+        }
+        // The spec could not be parsed or has operations that can't be called, or the headers
+        // and credentials are malformed
+        AssistantError::FunctionCallError(
+            FunctionCallError::Other(_) | FunctionCallError::JsonError(_),
+        )
+        | AssistantError::SecretError(SecretError::JsonError(_)) => StatusCode::BAD_REQUEST,
+        _ => StatusCode::INTERNAL_SERVER_ERROR,
+    };
+    (status, e.to_string())
+}
+
+pub async fn list_actions_handler(
+    Path((assistant_id,)): Path<(String,)>,
+    State(app_state): State<AppState>,
+) -> Result<JsonResponse<ListActionsResponse>, (StatusCode, String)> {
+    match list_actions(&app_state.pool, &assistant_id, &Uuid::default().to_string()).await {
+        Ok(actions) => Ok(JsonResponse(ListActionsResponse {
+            object: "list".to_string(),
+            data: actions,
+        })),
+        Err(e) => Err(error_response(e)),
+    }
+}
+
+pub async fn get_action_handler(
+    Path((assistant_id, action_id)): Path<(String, String)>,
+    State(app_state): State<AppState>,
+) -> Result<JsonResponse<ActionOperation>, (StatusCode, String)> {
+    match get_action(
+        &app_state.pool,
+        &assistant_id,
+        &action_id,
+        &Uuid::default().to_string(),
+    )
+    .await
+    {
+        Ok(action) => Ok(JsonResponse(action)),
+        Err(e) => Err(error_response(e)),
+    }
+}
+
+pub async fn update_action_handler(
+    Path((assistant_id, action_id)): Path<(String, String)>,
+    State(app_state): State<AppState>,
+    Json(request): Json<ModifyActionRequest>,
+) -> Result<JsonResponse<ActionOperation>, (StatusCode, String)> {
+    match set_action_enabled(
+        &app_state.pool,
+        &assistant_id,
+        &action_id,
+        &Uuid::default().to_string(),
+        request.enabled,
+    )
+    .await
+    {
+        Ok(action) => Ok(JsonResponse(action)),
+        Err(e) => Err(error_response(e)),
+    }
+}
+
+pub async fn delete_action_handler(
+    Path((assistant_id, action_id)): Path<(String, String)>,
+    State(app_state): State<AppState>,
+) -> Result<JsonResponse<DeleteActionResponse>, (StatusCode, String)> {
+    match delete_action(
+        &app_state.pool,
+        &assistant_id,
+        &action_id,
+        &Uuid::default().to_string(),
+    )
+    .await
+    {
+        Ok(_) => Ok(JsonResponse(DeleteActionResponse {
+            id: action_id,
+            deleted: true,
+            object: "action".to_string(),
+        })),
+        Err(e) => Err(error_response(e)),
+    }
+}
+
+/// Re-sync the operations of an action tool from a new version of its spec. The body is the
+/// `data` of the tool: its `openapi_spec`, and optionally new `headers` and `credentials`.
+pub async fn sync_actions_handler(
+    Path((assistant_id,)): Path<(String,)>,
+    State(app_state): State<AppState>,
+    Json(data): Json<Value>,
+) -> Result<JsonResponse<ActionsDiff>, (StatusCode, String)> {
+    if !data["openapi_spec"].is_string() {
+        return Err((
+            StatusCode::BAD_REQUEST,
+            "openapi_spec must be a string".to_string(),
+        ));
+    }
+    match sync_action_tool(
+        &app_state.pool,
+        &assistant_id,
+        &Uuid::default().to_string(),
+        json!({"type": "action", "data": data}),
+    )
+    .await
+    {
+        Ok(diff) => Ok(JsonResponse(diff)),
+        Err(e) => Err(sync_error_response(e)),
+    }
+}
@@ -5,29 +5,44 @@ use axum::{
     http::StatusCode,
     response::Json as JsonResponse,
 };
-use bytes::Buf;
 use hal_9100_api_communication::models::AppState;
-use hal_9100_core::retrieval::split_and_insert;
+use hal_9100_core::files::{create_file, get_file, list_files, process_file};
 
 use log::{error, info};
+use serde::Deserialize;
 use serde_json::{json, Value};
 use std::io::Write;
 use tempfile;
+use uuid::Uuid;
+
+#[derive(Deserialize)]
+pub struct ListFilesQuery {
+    pub purpose: Option<String>,
+}
+
 pub async fn retrieve_file_handler(
     Path(file_id): Path<String>,
     State(app_state): State<AppState>,
 ) -> Result<JsonResponse<OpenAIFile>, (StatusCode, String)> {
-    match app_state.file_storage.retrieve_file(&file_id).await {
-        Ok(mut file) => Ok(JsonResponse(OpenAIFile {
-            id: file_id,
-            object: "object".to_string(),
-            bytes: file.bytes.get_u32(),
-            created_at: 0,
-            filename: "unknown".to_string(),
-            purpose: OpenAIFilePurpose::Assistants,
-            status: Some("unknown".to_string()),
-            status_details: Some("unknown".to_string()),
-        })),
+    match get_file(&app_state.pool, &file_id, &Uuid::default().to_string()).await {
+        Ok(file) => Ok(JsonResponse(file.inner)),
+        // Files stored before status tracking have no record, fall back to the storage
+        Err(sqlx::Error::RowNotFound) => match app_state.file_storage.retrieve_file(&file_id).await {
+            Ok(file) => Ok(JsonResponse(OpenAIFile {
+                id: file_id,
+                object: "file".to_string(),
+                bytes: file.size as u32,
+                created_at: 0,
+                filename: "unknown".to_string(),
+                purpose: OpenAIFilePurpose::Assistants,
+                status: Some("processed".to_string()),
+                status_details: None,
+            })),
+            Err(e) => {
+                error!("Failed to retrieve file: {:?}", e);
+                Err((StatusCode::NOT_FOUND, "File not found".to_string()))
+            }
+        },
         Err(e) => {
             error!("Failed to retrieve file: {:?}", e);
             Err((
@@ -44,20 +59,20 @@ pub async fn upload_file_handler(
 ) -> Result<JsonResponse<OpenAIFile>, (StatusCode, String)> {
     let mut file_data = Vec::new();
     let mut purpose = String::new();
-    let mut content_type = String::new();
     let mut file_name = String::new();
-    while let Some(mut field) = multipart.next_field().await.unwrap() {
-        let field_name = field.name().unwrap().to_string();
+    let bad_request = |e: axum::extract::multipart::MultipartError| {
+        (StatusCode::BAD_REQUEST, format!("Invalid multipart body: {}", e))
+    };
+    while let Some(mut field) = multipart.next_field().await.map_err(bad_request)? {
+        let field_name = field.name().unwrap_or_default().to_string();
 
-        println!("field_name: {:?}", field_name);
         if field_name == "file" {
-            content_type = field.content_type().unwrap_or("text/plain").to_string();
             file_name = field.file_name().unwrap_or("unknown.txt").to_string();
-            while let Some(chunk) = field.chunk().await.unwrap() {
+            while let Some(chunk) = field.chunk().await.map_err(bad_request)? {
                 file_data.extend_from_slice(&chunk);
             }
         } else if field_name == "purpose" {
-            purpose = String::from_utf8(field.bytes().await.unwrap().to_vec()).unwrap();
+            purpose = field.text().await.map_err(bad_request)?;
         }
     }
 
@@ -68,75 +83,70 @@ pub async fn upload_file_handler(
         ));
     }
 
-    // Create a temporary file with the same content type
+    let internal_error = |e: String| {
+        error!("Failed to upload file: {}", e);
+        (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            "Failed to upload file".to_string(),
+        )
+    };
+
+    // Create a temporary file with the same name so the storage keeps the extension
     let mut temp_file = tempfile::Builder::new()
         .prefix(file_name.as_str())
         .rand_bytes(0)
         .tempfile()
-        .unwrap();
+        .map_err(|e| internal_error(e.to_string()))?;
 
     // Write the file data to the temporary file
-    temp_file.write_all(&file_data).unwrap();
-
-    // Get the path of the temporary file.
-    let temp_file_path = temp_file.path();
+    temp_file
+        .write_all(&file_data)
+        .map_err(|e| internal_error(e.to_string()))?;
 
     // Upload the file.
-    info!("Uploading file: {:?}", temp_file_path);
-    let mut file = app_state
+    info!("Uploading file: {:?}", temp_file.path());
+    let stored_file = app_state
         .file_storage
-        .upload_file(&temp_file_path)
+        .upload_file(temp_file.path())
         .await
-        .unwrap();
-    info!("Uploaded file: {:?}", file.id);
-
-    // Inside upload_file_handler function, after writing the file data to the temporary file
-    if content_type.starts_with("text/") {
-        let file_data_str = String::from_utf8(file_data.clone()).unwrap();
-        split_and_insert(
-            &app_state.pool,
-            &file_data_str,
-            100, // TODO
-            &file.id,
-            None,
-        )
-        .await
-        .unwrap();
-    }
-
-    Ok(JsonResponse(OpenAIFile {
-        id: file.id,
-        object: "object".to_string(),
-        bytes: file.bytes.get_u32(),
-        created_at: 0,
-        filename: "".to_string(), // TODO
-        purpose: OpenAIFilePurpose::Assistants,
-        status: Some("success".to_string()),
-        status_details: Some("unknown".to_string()),
-    }))
+        .map_err(|e| internal_error(e.to_string()))?;
+    info!("Uploaded file: {:?}", stored_file.id);
+
+    let file = create_file(
+        &app_state.pool,
+        &stored_file.id,
+        &file_name,
+        file_data.len() as i32,
+        &purpose,
+        &Uuid::default().to_string(),
+    )
+    .await
+    .map_err(|e| internal_error(e.to_string()))?;
+
+    // Extract and index the file in the background, the status is updated once done
+    let pool = app_state.pool.clone();
+    let file_id = file.inner.id.clone();
+    tokio::spawn(async move {
+        process_file(&pool, &file_id, &file_name, &file_data).await;
+    });
+
+    Ok(JsonResponse(file.inner))
 }
 
 pub async fn list_files_handler(
     State(app_state): State<AppState>,
-    // purpose_query: Query<Option<String>>, // TODO use purpose
+    Query(query): Query<ListFilesQuery>,
 ) -> Result<JsonResponse<ListFilesResponse>, (StatusCode, String)> {
-    let files = app_state.file_storage.list_files().await;
+    let files = list_files(
+        &app_state.pool,
+        &Uuid::default().to_string(),
+        query.purpose.as_deref(),
+    )
+    .await;
 
     match files {
         Ok(files) => Ok(JsonResponse(ListFilesResponse {
-            data: files
-                .into_iter()
-                .map(|mut file| OpenAIFile {
-                    id: file.id,
-                    object: "object".to_string(),
-                    bytes: file.bytes.get_u32(),
-                    created_at: 0,            // ?
-                    filename: "".to_string(), // TODO
-                    purpose: OpenAIFilePurpose::Assistants,
-                    status: Some("unknown".to_string()),
-                    status_details: Some("unknown".to_string()),
-                })
-                .collect(),
+            data: files.into_iter().map(|file| file.inner).collect(),
             object: "list".to_string(),
         })),
         Err(e) => {
@@ -269,6 +279,53 @@ mod tests {
         assert_eq!(response.status(), StatusCode::OK);
     }
 
+    #[tokio::test]
+    async fn test_upload_file_handler_processes_in_background() {
+        let app_state = setup().await;
+        let app = app(app_state);
+        let boundary = "------------------------14737809831466499882746641449";
+        let body = format!(
+            "--{boundary}\r\nContent-Disposition: form-data; name=\"file\"; filename=\"test.txt\"\r\n\r\nTest file content\r\n--{boundary}\r\nContent-Disposition: form-data; name=\"purpose\"\r\n\r\nassistants\r\n--{boundary}--\r\n",
+            boundary = boundary
+        );
+
+        let request = Request::builder()
+            .method(http::Method::POST)
+            .uri("/files")
+            .header(
+                "Content-Type",
+                format!("multipart/form-data; boundary={}", boundary),
+            )
+            .body(Body::from(body))
+            .unwrap();
+
+        let response = app.clone().oneshot(request).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        let file: OpenAIFile = serde_json::from_slice(&body).unwrap();
+        assert_eq!(file.status.as_deref(), Some("uploaded"));
+        assert_eq!(file.filename, "test.txt");
+
+        // Poll until the background processing is done
+        let mut status = None;
+        for _ in 0..20 {
+            let request = Request::builder()
+                .method(http::Method::GET)
+                .uri(format!("/files/{}", file.id))
+                .body(Body::empty())
+                .unwrap();
+            let response = app.clone().oneshot(request).await.unwrap();
+            let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+            let retrieved: OpenAIFile = serde_json::from_slice(&body).unwrap();
+            status = retrieved.status;
+            if status.as_deref() != Some("uploaded") {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(100)).await;
+        }
+        assert_eq!(status.as_deref(), Some("processed"));
+    }
+
     #[tokio::test]
     async fn test_upload_pdf_file_handler_pdf_base64() {
         let app_state = setup().await;
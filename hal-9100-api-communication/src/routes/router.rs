@@ -6,6 +6,10 @@ use axum::{
     Router,
 };
 use hal_9100_api_communication::models::AppState;
+use hal_9100_api_communication::routes::actions::{
+    delete_action_handler, get_action_handler, list_actions_handler, sync_actions_handler,
+    update_action_handler,
+};
 use hal_9100_api_communication::routes::assistants::{
     create_assistant_handler, delete_assistant_handler, get_assistant_handler,
     list_assistants_handler, update_assistant_handler,
@@ -19,12 +23,12 @@ use hal_9100_api_communication::routes::messages::{
 };
 use hal_9100_api_communication::routes::run_steps::{get_step_handler, list_steps_handler};
 use hal_9100_api_communication::routes::runs::{
-    create_run_handler, delete_run_handler, get_run_handler, list_runs_handler,
-    submit_tool_outputs_handler, update_run_handler,
+    approve_action_handler, create_run_handler, delete_run_handler, get_run_handler,
+    list_runs_handler, submit_tool_outputs_handler, update_run_handler,
 };
 use hal_9100_api_communication::routes::threads::{
     create_thread_handler, delete_thread_handler, get_thread_handler, list_threads_handler,
-    update_thread_handler,
+    reset_code_interpreter_handler, update_thread_handler,
 };
 use serde::{Deserialize, Serialize};
 use tower_http::cors::{Any, CorsLayer};
@@ -50,12 +54,31 @@ pub fn app(app_state: AppState) -> Router {
             delete(delete_assistant_handler),
         )
         .route("/assistants", get(list_assistants_handler))
+        // The operations registered from the specs of the action tools
+        .route(
+            "/assistants/:assistant_id/actions",
+            get(list_actions_handler),
+        )
+        .route(
+            "/assistants/:assistant_id/actions/sync",
+            post(sync_actions_handler),
+        )
+        .route(
+            "/assistants/:assistant_id/actions/:action_id",
+            get(get_action_handler)
+                .post(update_action_handler)
+                .delete(delete_action_handler),
+        )
         // https://platform.openai.com/docs/api-reference/threads
         .route("/threads", post(create_thread_handler))
         .route("/threads/:thread_id", get(get_thread_handler))
         .route("/threads", get(list_threads_handler))
         .route("/threads/:thread_id", post(update_thread_handler))
         .route("/threads/:thread_id", delete(delete_thread_handler))
+        .route(
+            "/threads/:thread_id/code_interpreter/reset",
+            post(reset_code_interpreter_handler),
+        )
         // https://platform.openai.com/docs/api-reference/messages
         .route("/threads/:thread_id/messages", post(add_message_handler))
         // https://platform.openai.com/docs/api-reference/messages/getMessage
@@ -98,6 +121,10 @@ pub fn app(app_state: AppState) -> Router {
             "/threads/:thread_id/runs/:run_id/submit_tool_outputs",
             post(submit_tool_outputs_handler),
         )
+        .route(
+            "/threads/:thread_id/runs/:run_id/approve_action",
+            post(approve_action_handler),
+        )
         // .route("/threads/:thread_id/runs/:run_id/cancel", post(cancel_run_handler))
         // .route("/threads/runs", post(create_thread_and_run_handler))
         // .route("/threads/:thread_id/runs/:run_id/steps/:step_id", get(get_run_step_handler))
@@ -1146,7 +1173,7 @@ mod tests {
             std::env::var("MODEL_URL").expect("MODEL_URL must be set"),
             std::env::var("MODEL_API_KEY").expect("MODEL_API_KEY must be set"),
         );
-        let result = try_run_executor(&pool_clone, &mut con, llm_client).await;
+        let result = try_run_executor(&pool_clone, &mut con, llm_client, &Hal9100Config::default()).await;
 
         // 7. Check the result
         assert!(
@@ -1803,7 +1830,7 @@ mod tests {
             std::env::var("MODEL_URL").expect("MODEL_URL must be set"),
             std::env::var("MODEL_API_KEY").expect("MODEL_API_KEY must be set"),
         );
-        let result = try_run_executor(&pool_clone, &mut con, llm_client.clone()).await;
+        let result = try_run_executor(&pool_clone, &mut con, llm_client.clone(), &Hal9100Config::default()).await;
 
         assert!(
             result.is_ok(),
@@ -1860,7 +1887,7 @@ mod tests {
 
         let mut con = client.get_async_connection().await.unwrap();
 
-        let result = try_run_executor(&pool_clone, &mut con, llm_client).await;
+        let result = try_run_executor(&pool_clone, &mut con, llm_client, &Hal9100Config::default()).await;
         assert!(result.is_ok(), "{:?}", result);
 
         let response = app
@@ -2064,7 +2091,7 @@ mod tests {
             std::env::var("MODEL_URL").expect("MODEL_URL must be set"),
             std::env::var("MODEL_API_KEY").expect("MODEL_API_KEY must be set"),
         );
-        let result = try_run_executor(&pool_clone, &mut con, llm_client.clone()).await;
+        let result = try_run_executor(&pool_clone, &mut con, llm_client.clone(), &Hal9100Config::default()).await;
 
         let run = result.unwrap();
 
@@ -2112,7 +2139,7 @@ mod tests {
         let client = redis::Client::open(redis_url).unwrap();
         let mut con = client.get_async_connection().await.unwrap();
 
-        let result = try_run_executor(&pool_clone, &mut con, llm_client).await;
+        let result = try_run_executor(&pool_clone, &mut con, llm_client, &Hal9100Config::default()).await;
 
         assert!(
             result.is_ok(),
@@ -2309,7 +2336,7 @@ mod tests {
             std::env::var("MODEL_URL").expect("MODEL_URL must be set"),
             std::env::var("MODEL_API_KEY").expect("MODEL_API_KEY must be set"),
         );
-        let result = try_run_executor(&app_state.pool, &mut con, llm_client).await;
+        let result = try_run_executor(&app_state.pool, &mut con, llm_client, &app_state.hal_9100_config).await;
 
         assert!(
             result.is_ok(),
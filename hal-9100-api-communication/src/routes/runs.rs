@@ -6,14 +6,18 @@ use axum::{
     response::Json as JsonResponse,
 };
 use hal_9100_api_communication::models::AppState;
-use hal_9100_core::models::{Run, SubmittedToolCall};
+use hal_9100_core::models::{
+    ActionApproval, PendingAction, Run, SubmittedToolCall, TruncationStrategy,
+};
 use hal_9100_core::runs::{
-    create_run, create_run_and_produce_to_executor_queue, delete_run, get_run, list_runs,
-    submit_tool_outputs, update_run,
+    create_run, create_run_and_produce_to_executor_queue, delete_run, get_missing_tool_outputs,
+    get_pending_actions, get_run, list_runs, submit_action_approvals, submit_tool_outputs,
+    update_run,
 };
 
 use log::error;
 use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
 use sqlx::types::Uuid;
 
 #[derive(Serialize, Deserialize)]
@@ -30,7 +34,7 @@ pub async fn submit_tool_outputs_handler(
     Path((thread_id, run_id)): Path<(String, String)>,
     State(app_state): State<AppState>,
     Json(request): Json<SubmitToolOutputsRequest>,
-) -> Result<JsonResponse<RunObject>, (StatusCode, String)> {
+) -> Result<JsonResponse<Value>, (StatusCode, String)> {
     let user_id = Uuid::default().to_string();
     let client = redis::Client::open(app_state.hal_9100_config.redis_url.clone()).unwrap();
     let con = client.get_async_connection().await.unwrap();
@@ -54,19 +58,137 @@ pub async fn submit_tool_outputs_handler(
     )
     .await
     {
-        Ok(run) => Ok(JsonResponse(run.inner)),
+        Ok(run) => run_response(&app_state.pool, run).await,
         Err(e) => {
             let error_message = e.to_string();
             error!("Failed to submit tool outputs: {}", error_message);
-            Err((StatusCode::INTERNAL_SERVER_ERROR, error_message))
+            match e {
+                // Outputs of calls the run does not wait for
+                sqlx::Error::Configuration(_) => Err((StatusCode::BAD_REQUEST, error_message)),
+                _ => Err((StatusCode::INTERNAL_SERVER_ERROR, error_message)),
+            }
         }
     }
 }
 
+#[derive(Serialize, Deserialize)]
+pub struct ApproveActionRequest {
+    pub approvals: Vec<ActionApproval>,
+}
+
+pub async fn approve_action_handler(
+    Path((thread_id, run_id)): Path<(String, String)>,
+    State(app_state): State<AppState>,
+    Json(request): Json<ApproveActionRequest>,
+) -> Result<JsonResponse<RunObject>, (StatusCode, String)> {
+    let user_id = Uuid::default().to_string();
+    let client = redis::Client::open(app_state.hal_9100_config.redis_url.clone()).unwrap();
+    let con = client.get_async_connection().await.unwrap();
+    match submit_action_approvals(
+        &app_state.pool,
+        &thread_id,
+        &run_id,
+        &user_id,
+        request.approvals,
+        con,
+    )
+    .await
+    {
+        Ok(run) => Ok(JsonResponse(run.inner)),
+        Err(e) => {
+            let error_message = e.to_string();
+            error!("Failed to approve actions: {}", error_message);
+            match e {
+                // Missing approvals or a run that does not wait for any
+                sqlx::Error::Configuration(_) => Err((StatusCode::BAD_REQUEST, error_message)),
+                _ => Err((StatusCode::INTERNAL_SERVER_ERROR, error_message)),
+            }
+        }
+    }
+}
+
+// Our async-openai version has no `approve_action` required action, the actions waiting for
+// approval are added to the run as the clients expect them
+fn with_pending_actions(run: RunObject, actions: &[PendingAction]) -> Value {
+    let mut run = serde_json::to_value(run).unwrap();
+    let actions: Vec<Value> = actions
+        .iter()
+        .filter(|action| action.approved.is_none())
+        .map(|action| {
+            json!({
+                "tool_call_id": action.tool_call_id,
+                "name": action.function.name,
+                "arguments": action.function.arguments,
+                "request": action.request,
+            })
+        })
+        .collect();
+    if !actions.is_empty() {
+        run["required_action"]["approve_action"] = json!({ "actions": actions });
+    }
+    run
+}
+
+// Each tool call the run waits for tells whether its output was submitted, the outputs can
+// be submitted as they come
+fn with_tool_call_status(mut run: Value, missing: &[String]) -> Value {
+    if let Some(Value::Array(tool_calls)) =
+        run.pointer_mut("/required_action/submit_tool_outputs/tool_calls")
+    {
+        for tool_call in tool_calls.iter_mut() {
+            let pending = tool_call["id"]
+                .as_str()
+                .map_or(false, |id| missing.iter().any(|missing| missing == id));
+            tool_call["status"] = json!(if pending { "pending" } else { "submitted" });
+        }
+    }
+    run
+}
+
+// The run as returned to the clients, with what our async-openai version can't hold
+async fn run_response(
+    pool: &sqlx::PgPool,
+    run: Run,
+) -> Result<JsonResponse<Value>, (StatusCode, String)> {
+    let actions = get_pending_actions(pool, &run.inner.id, &run.user_id).await;
+    let actions = match actions {
+        Ok(actions) => actions,
+        Err(e) => {
+            let error_message = e.to_string();
+            error!("Failed to get pending actions: {}", error_message);
+            return Err((StatusCode::INTERNAL_SERVER_ERROR, error_message));
+        }
+    };
+    let missing = match &run.inner.required_action {
+        Some(required_action) => match get_missing_tool_outputs(pool, required_action).await {
+            Ok(missing) => missing,
+            Err(e) => {
+                let error_message = e.to_string();
+                error!("Failed to get missing tool outputs: {}", error_message);
+                return Err((StatusCode::INTERNAL_SERVER_ERROR, error_message));
+            }
+        },
+        None => vec![],
+    };
+    Ok(JsonResponse(with_tool_call_status(
+        with_pending_actions(run.inner, &actions),
+        &missing,
+    )))
+}
+
+// CreateRunRequest plus the fields our async-openai version does not know about yet
+#[derive(Deserialize)]
+pub struct ApiCreateRunRequest {
+    #[serde(flatten)]
+    pub inner: CreateRunRequest,
+    pub truncation_strategy: Option<TruncationStrategy>,
+    pub parallel_tool_calls: Option<bool>,
+}
+
 pub async fn create_run_handler(
     Path((thread_id,)): Path<(String,)>,
     State(app_state): State<AppState>,
-    Json(run_input): Json<CreateRunRequest>,
+    Json(run_input): Json<ApiCreateRunRequest>,
 ) -> Result<JsonResponse<RunObject>, (StatusCode, String)> {
     let redis_url = std::env::var("REDIS_URL").expect("REDIS_URL must be set");
     let client = redis::Client::open(redis_url).unwrap();
@@ -76,9 +198,11 @@ pub async fn create_run_handler(
     let run = create_run_and_produce_to_executor_queue(
         &app_state.pool,
         &thread_id,
-        &run_input.assistant_id,
-        &run_input.instructions.unwrap_or_default(),
+        &run_input.inner.assistant_id,
+        &run_input.inner.instructions.unwrap_or_default(),
         &user_id,
+        run_input.truncation_strategy,
+        run_input.parallel_tool_calls,
         con,
     )
     .await;
@@ -103,17 +227,18 @@ pub async fn create_run_handler(
 pub async fn get_run_handler(
     Path((thread_id, run_id)): Path<(String, String)>,
     State(app_state): State<AppState>,
-) -> Result<JsonResponse<RunObject>, (StatusCode, String)> {
+) -> Result<JsonResponse<Value>, (StatusCode, String)> {
     let user_id = Uuid::default().to_string();
     let run = get_run(&app_state.pool, &thread_id, &run_id, &user_id).await;
-    match run {
-        Ok(run) => Ok(JsonResponse(run.inner)),
+    let run = match run {
+        Ok(run) => run,
         Err(e) => {
             let error_message = e.to_string();
             error!("Failed to get run: {}", error_message);
-            Err((StatusCode::INTERNAL_SERVER_ERROR, error_message))
+            return Err((StatusCode::INTERNAL_SERVER_ERROR, error_message));
         }
-    }
+    };
+    run_response(&app_state.pool, run).await
 }
 
 pub async fn update_run_handler(
@@ -190,7 +315,7 @@ mod tests {
     use hal_9100_extra::config::Hal9100Config;
 
     use super::*;
-    use async_openai::types::CreateRunRequest;
+    use async_openai::types::{CreateRunRequest, RequiredAction, RunStatus, SubmitToolOutputs};
     use axum::body::Body;
     use axum::http::{self, Request};
     use axum::response::Response;
@@ -198,9 +323,12 @@ mod tests {
     use axum::Router;
     use dotenv::dotenv;
     use hal_9100_core::file_storage::FileStorage;
+    use hal_9100_core::function_calling::FunctionCallWithMetadata;
+    use hal_9100_core::openapi::ActionPreview;
     use hyper::StatusCode;
     use serde_json::json;
     use sqlx::postgres::PgPoolOptions;
+    use std::collections::BTreeMap;
     use std::convert::Infallible;
     use std::sync::Arc;
     use std::time::Duration;
@@ -232,6 +360,117 @@ mod tests {
             .with_state(app_state)
     }
 
+    #[test]
+    fn test_create_run_request_truncation_strategy() {
+        let request: ApiCreateRunRequest = serde_json::from_value(json!({
+            "assistant_id": "asst_abc123",
+            "truncation_strategy": {"type": "last_messages", "last_messages": 10}
+        }))
+        .unwrap();
+        assert_eq!(request.inner.assistant_id, "asst_abc123");
+        assert_eq!(
+            request.truncation_strategy,
+            Some(TruncationStrategy::LastMessages { last_messages: 10 })
+        );
+
+        let request: ApiCreateRunRequest =
+            serde_json::from_value(json!({"assistant_id": "asst_abc123"})).unwrap();
+        assert_eq!(request.truncation_strategy, None);
+        assert_eq!(request.parallel_tool_calls, None);
+
+        let request: ApiCreateRunRequest = serde_json::from_value(json!({
+            "assistant_id": "asst_abc123",
+            "parallel_tool_calls": false
+        }))
+        .unwrap();
+        assert_eq!(request.parallel_tool_calls, Some(false));
+    }
+
+    #[test]
+    fn test_with_pending_actions() {
+        let run = RunObject {
+            id: "run_abc123".to_string(),
+            object: "thread.run".to_string(),
+            created_at: 0,
+            thread_id: "thread_abc123".to_string(),
+            assistant_id: Some("asst_abc123".to_string()),
+            status: RunStatus::RequiresAction,
+            required_action: Some(RequiredAction {
+                r#type: "approve_action".to_string(),
+                submit_tool_outputs: SubmitToolOutputs { tool_calls: vec![] },
+            }),
+            last_error: None,
+            expires_at: None,
+            started_at: None,
+            cancelled_at: None,
+            failed_at: None,
+            completed_at: None,
+            model: "claude-2.1".to_string(),
+            instructions: "".to_string(),
+            tools: vec![],
+            file_ids: vec![],
+            metadata: None,
+        };
+        let action =
+            |tool_call_id: &str, name: &str, method: &str, approved: Option<bool>| PendingAction {
+                tool_call_id: tool_call_id.to_string(),
+                step_id: "step_abc123".to_string(),
+                function: FunctionCallWithMetadata {
+                    name: name.to_string(),
+                    arguments: r#"{"id": 1}"#.to_string(),
+                    metadata: None,
+                },
+                request: ActionPreview {
+                    method: method.to_string(),
+                    url: "https://todo.example.com/todos/1".to_string(),
+                    headers: BTreeMap::new(),
+                    body: None,
+                },
+                approved,
+                reason: None,
+            };
+        // Calls not needing approval run along with the others but are not shown
+        let run = with_pending_actions(
+            run,
+            &[
+                action("call_1", "deleteTodo", "DELETE", None),
+                action("call_2", "getTodo", "GET", Some(true)),
+            ],
+        );
+        let actions = run["required_action"]["approve_action"]["actions"]
+            .as_array()
+            .unwrap();
+        assert_eq!(actions.len(), 1);
+        assert_eq!(actions[0]["tool_call_id"], "call_1");
+        assert_eq!(actions[0]["name"], "deleteTodo");
+        assert_eq!(actions[0]["arguments"], r#"{"id": 1}"#);
+        assert_eq!(actions[0]["request"]["method"], "DELETE");
+    }
+
+    #[test]
+    fn test_with_tool_call_status() {
+        let run = json!({
+            "id": "run_abc123",
+            "required_action": {
+                "type": "submit_tool_outputs",
+                "submit_tool_outputs": {
+                    "tool_calls": [
+                        {"id": "call_1", "type": "function", "function": {"name": "weather", "arguments": "{}"}},
+                        {"id": "call_2", "type": "function", "function": {"name": "weather", "arguments": "{}"}}
+                    ]
+                }
+            }
+        });
+        let run = with_tool_call_status(run, &["call_2".to_string()]);
+        let tool_calls = &run["required_action"]["submit_tool_outputs"]["tool_calls"];
+        assert_eq!(tool_calls[0]["status"], "submitted");
+        assert_eq!(tool_calls[1]["status"], "pending");
+
+        // Runs without required action are left alone
+        let run = with_tool_call_status(json!({"id": "run_abc123", "required_action": null}), &[]);
+        assert_eq!(run["required_action"], Value::Null);
+    }
+
     #[tokio::test]
     async fn test_create_run_handler_invalid_thread_id() {
         let app_state = setup().await;
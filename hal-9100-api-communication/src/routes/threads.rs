@@ -6,6 +6,7 @@ use axum::{
     response::Json as JsonResponse,
 };
 use hal_9100_api_communication::models::AppState;
+use hal_9100_core::interpreter_sessions::request_session_reset;
 use hal_9100_core::models::Thread;
 use hal_9100_core::threads::{
     create_thread, delete_thread, get_thread, list_threads, update_thread,
@@ -113,6 +114,27 @@ pub async fn delete_thread_handler(
     }
 }
 
+// Start the next code interpreter run of a thread from a fresh Python session, dropping the
+// variables and data loaded by the previous ones
+pub async fn reset_code_interpreter_handler(
+    Path((thread_id,)): Path<(String,)>,
+    State(app_state): State<AppState>,
+) -> Result<JsonResponse<()>, (StatusCode, String)> {
+    get_thread(&app_state.pool, &thread_id, &Uuid::default().to_string())
+        .await
+        .map_err(|e| (StatusCode::NOT_FOUND, e.to_string()))?;
+    let client = redis::Client::open(app_state.hal_9100_config.redis_url.clone())
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
+    let mut con = client
+        .get_async_connection()
+        .await
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
+    match request_session_reset(&mut con, &thread_id).await {
+        Ok(_) => Ok(JsonResponse(())),
+        Err(e) => Err((StatusCode::INTERNAL_SERVER_ERROR, e.to_string())),
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use hal_9100_extra::config::Hal9100Config;
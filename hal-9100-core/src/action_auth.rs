@@ -0,0 +1,376 @@
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::error::Error;
+use std::io::ErrorKind;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use crate::egress::ActionClient;
+use crate::openapi::{ParameterLocation, SecurityRequirement, SecurityScheme};
+use crate::secrets::{ActionSecret, Credential, REDACTED};
+
+// Lifetime assumed for access tokens returned without `expires_in`
+const DEFAULT_TOKEN_LIFETIME_SECS: u64 = 300;
+// Tokens are renewed this long before they expire, so they don't expire in flight
+const TOKEN_EXPIRY_MARGIN_SECS: u64 = 30;
+
+// Access tokens of the client credentials flow, by a hash of their owner, token url, client,
+// secret and scopes, see `token_cache_key`
+static ACCESS_TOKENS: Mutex<Option<HashMap<String, (String, Instant)>>> = Mutex::new(None);
+
+/// The credentials of an action request, by where they are sent.
+#[derive(Default)]
+pub struct Authentication {
+    pub headers: Vec<(String, String)>,
+    pub query: Vec<(String, String)>,
+    pub cookies: Vec<String>,
+    /// The OAuth2 access tokens sent, which the server may have revoked. They are not in the
+    /// secret and are redacted along with its values.
+    pub access_tokens: Vec<String>,
+}
+
+#[derive(Deserialize)]
+struct TokenResponse {
+    access_token: String,
+    expires_in: Option<u64>,
+}
+
+fn matches(scheme: &SecurityScheme, credential: &Credential) -> bool {
+    matches!(
+        (scheme, credential),
+        (SecurityScheme::ApiKey { .. }, Credential::Token(_))
+            | (SecurityScheme::HttpBearer, Credential::Token(_))
+            | (SecurityScheme::HttpBasic, Credential::Basic { .. })
+            | (
+                SecurityScheme::OAuth2ClientCredentials { .. },
+                Credential::ClientCredentials { .. }
+            )
+    )
+}
+
+/// Pick the first alternative of `security` all the credentials of which are in `secret`.
+/// Operations that also accept anonymous calls are only called anonymously when no
+/// alternative has credentials.
+pub fn choose_security<'a>(
+    security: &'a [Vec<SecurityRequirement>],
+    secret: Option<&ActionSecret>,
+) -> Result<&'a [SecurityRequirement], Box<dyn Error>> {
+    if security.is_empty() {
+        return Ok(&[]);
+    }
+    let credentials = secret.map(|secret| &secret.credentials);
+    let usable = security.iter().find(|requirements| {
+        !requirements.is_empty()
+            && requirements.iter().all(|requirement| {
+                credentials
+                    .and_then(|credentials| credentials.get(&requirement.name))
+                    .map_or(false, |credential| matches(&requirement.scheme, credential))
+            })
+    });
+    match usable {
+        Some(requirements) => Ok(requirements),
+        None if security.iter().any(|requirements| requirements.is_empty()) => Ok(&[]),
+        None => Err(Box::new(std::io::Error::new(
+            ErrorKind::PermissionDenied,
+            format!(
+                "No credentials for the security schemes of the operation: {}",
+                security
+                    .iter()
+                    .map(|requirements| requirements
+                        .iter()
+                        .map(|requirement| requirement.name.as_str())
+                        .collect::<Vec<_>>()
+                        .join(" and "))
+                    .collect::<Vec<_>>()
+                    .join(" or ")
+            ),
+        ))),
+    }
+}
+
+/// Credentials to send for `requirements`, on behalf of `user_id` who owns `secret`. Cached
+/// access tokens are fetched again when `refresh` is set.
+pub async fn authenticate(
+    client: &ActionClient,
+    requirements: &[SecurityRequirement],
+    secret: Option<&ActionSecret>,
+    user_id: &str,
+    refresh: bool,
+) -> Result<Authentication, Box<dyn Error>> {
+    let mut authentication = Authentication::default();
+    let secret = match secret {
+        Some(secret) => secret,
+        None => return Ok(authentication),
+    };
+    for requirement in requirements {
+        let credential = match secret.credentials.get(&requirement.name) {
+            Some(credential) => credential,
+            None => continue,
+        };
+        match (&requirement.scheme, credential) {
+            (SecurityScheme::ApiKey { name, location }, Credential::Token(key)) => match location {
+                ParameterLocation::Query => authentication.query.push((name.clone(), key.clone())),
+                ParameterLocation::Cookie => {
+                    authentication.cookies.push(format!("{}={}", name, key))
+                }
+                _ => authentication.headers.push((name.clone(), key.clone())),
+            },
+            (SecurityScheme::HttpBearer, Credential::Token(token)) => authentication
+                .headers
+                .push(("Authorization".to_string(), format!("Bearer {}", token))),
+            (SecurityScheme::HttpBasic, Credential::Basic { username, password }) => {
+                authentication.headers.push((
+                    "Authorization".to_string(),
+                    format!(
+                        "Basic {}",
+                        base64::encode(format!("{}:{}", username, password))
+                    ),
+                ))
+            }
+            (
+                SecurityScheme::OAuth2ClientCredentials { token_url },
+                Credential::ClientCredentials {
+                    client_id,
+                    client_secret,
+                    scopes,
+                },
+            ) => {
+                let scopes = scopes.as_ref().unwrap_or(&requirement.scopes);
+                let key = token_cache_key(user_id, token_url, client_id, client_secret, scopes);
+                let token = access_token(
+                    client,
+                    &key,
+                    token_url,
+                    client_id,
+                    client_secret,
+                    scopes,
+                    refresh,
+                )
+                .await?;
+                authentication
+                    .headers
+                    .push(("Authorization".to_string(), format!("Bearer {}", token)));
+                authentication.access_tokens.push(token);
+            }
+            _ => continue,
+        }
+    }
+    Ok(authentication)
+}
+
+/// Where the credentials of `requirements` would be sent, with their values redacted. Used to
+/// preview requests without fetching access tokens.
+pub fn authentication_preview(requirements: &[SecurityRequirement]) -> Authentication {
+    let mut authentication = Authentication::default();
+    for requirement in requirements {
+        match &requirement.scheme {
+            SecurityScheme::ApiKey { name, location } => match location {
+                ParameterLocation::Query => authentication
+                    .query
+                    .push((name.clone(), REDACTED.to_string())),
+                ParameterLocation::Cookie => authentication
+                    .cookies
+                    .push(format!("{}={}", name, REDACTED)),
+                _ => authentication
+                    .headers
+                    .push((name.clone(), REDACTED.to_string())),
+            },
+            SecurityScheme::HttpBasic => authentication
+                .headers
+                .push(("Authorization".to_string(), format!("Basic {}", REDACTED))),
+            SecurityScheme::HttpBearer | SecurityScheme::OAuth2ClientCredentials { .. } => {
+                authentication
+                    .headers
+                    .push(("Authorization".to_string(), format!("Bearer {}", REDACTED)))
+            }
+        }
+    }
+    authentication
+}
+
+// A token is only reused by the user who configured the client, with the same secret, so
+// fixing or rotating the secret fetches a new one. The secret itself is never kept.
+fn token_cache_key(
+    user_id: &str,
+    token_url: &str,
+    client_id: &str,
+    client_secret: &str,
+    scopes: &[String],
+) -> String {
+    let fields = serde_json::to_vec(&(user_id, token_url, client_id, client_secret, scopes))
+        .unwrap_or_default();
+    format!("{:x}", Sha256::digest(&fields))
+}
+
+fn cached_access_token(key: &str) -> Option<String> {
+    let tokens = ACCESS_TOKENS.lock().unwrap();
+    match tokens.as_ref()?.get(key) {
+        Some((token, expires_at)) if *expires_at > Instant::now() => Some(token.clone()),
+        _ => None,
+    }
+}
+
+async fn access_token(
+    client: &ActionClient,
+    key: &str,
+    token_url: &str,
+    client_id: &str,
+    client_secret: &str,
+    scopes: &[String],
+    refresh: bool,
+) -> Result<String, Box<dyn Error>> {
+    if !refresh {
+        if let Some(token) = cached_access_token(key) {
+            return Ok(token);
+        }
+    }
+
+    let mut form = vec![("grant_type", "client_credentials".to_string())];
+    if !scopes.is_empty() {
+        form.push(("scope", scopes.join(" ")));
+    }
+    let response = client
+        .send(
+            client
+                .http()
+                .post(token_url)
+                .basic_auth(client_id, Some(client_secret))
+                .form(&form),
+        )
+        .await?
+        .error_for_status()?;
+    let response: TokenResponse = serde_json::from_slice(&client.read_body(response).await?)?;
+
+    let lifetime = response
+        .expires_in
+        .unwrap_or(DEFAULT_TOKEN_LIFETIME_SECS)
+        .saturating_sub(TOKEN_EXPIRY_MARGIN_SECS);
+    ACCESS_TOKENS
+        .lock()
+        .unwrap()
+        .get_or_insert_with(HashMap::new)
+        .insert(
+            key.to_string(),
+            (
+                response.access_token.clone(),
+                Instant::now() + Duration::from_secs(lifetime),
+            ),
+        );
+    Ok(response.access_token)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use hal_9100_extra::config::ActionsConfig;
+    use httpmock::prelude::*;
+    use serde_json::json;
+
+    fn local_client() -> ActionClient {
+        ActionClient::new(&ActionsConfig {
+            allow_private: true,
+            ..Default::default()
+        })
+        .unwrap()
+    }
+
+    fn oauth(server: &MockServer) -> Vec<SecurityRequirement> {
+        vec![SecurityRequirement {
+            name: "oauth".to_string(),
+            scheme: SecurityScheme::OAuth2ClientCredentials {
+                token_url: server.url("/token"),
+            },
+            scopes: vec![],
+        }]
+    }
+
+    fn secret(client_secret: &str) -> ActionSecret {
+        serde_json::from_value(json!({
+            "credentials": {"oauth": {"client_id": "id", "client_secret": client_secret}}
+        }))
+        .unwrap()
+    }
+
+    async fn bearer(
+        client: &ActionClient,
+        requirements: &[SecurityRequirement],
+        secret: &ActionSecret,
+        user_id: &str,
+        refresh: bool,
+    ) -> String {
+        let authentication = authenticate(client, requirements, Some(secret), user_id, refresh)
+            .await
+            .unwrap();
+        assert_eq!(
+            authentication.headers[0].1,
+            format!("Bearer {}", authentication.access_tokens[0])
+        );
+        authentication.headers[0].1.clone()
+    }
+
+    #[tokio::test]
+    async fn test_access_token_cache() {
+        let server = MockServer::start();
+        let token = server.mock(|when, then| {
+            // id:s3cr3t
+            when.method(POST)
+                .path("/token")
+                .header("authorization", "Basic aWQ6czNjcjN0");
+            then.status(200)
+                .json_body(json!({"access_token": "t0k3n", "expires_in": 3600}));
+        });
+        let rotated_token = server.mock(|when, then| {
+            // id:n3w
+            when.method(POST)
+                .path("/token")
+                .header("authorization", "Basic aWQ6bjN3");
+            then.status(200)
+                .json_body(json!({"access_token": "n3w-t0k3n", "expires_in": 3600}));
+        });
+        let client = local_client();
+        let requirements = oauth(&server);
+
+        // Fetched once, then served from the cache
+        for _ in 0..2 {
+            assert_eq!(
+                bearer(&client, &requirements, &secret("s3cr3t"), "user-a", false).await,
+                "Bearer t0k3n"
+            );
+        }
+        token.assert_hits(1);
+
+        // Another user configuring the same client proves the secret itself
+        bearer(&client, &requirements, &secret("s3cr3t"), "user-b", false).await;
+        token.assert_hits(2);
+
+        // A rotated secret gets a token of its own
+        assert_eq!(
+            bearer(&client, &requirements, &secret("n3w"), "user-a", false).await,
+            "Bearer n3w-t0k3n"
+        );
+        rotated_token.assert_hits(1);
+
+        // Refreshing skips the cache
+        bearer(&client, &requirements, &secret("s3cr3t"), "user-a", true).await;
+        token.assert_hits(3);
+    }
+
+    #[tokio::test]
+    async fn test_access_token_expiry() {
+        let server = MockServer::start();
+        let token = server.mock(|when, then| {
+            when.method(POST).path("/token");
+            // Expires within the renewal margin, so it is never reused
+            then.status(200)
+                .json_body(json!({"access_token": "t0k3n", "expires_in": 10}));
+        });
+        let client = local_client();
+        let requirements = oauth(&server);
+
+        for _ in 0..2 {
+            bearer(&client, &requirements, &secret("s3cr3t"), "user-a", false).await;
+        }
+        token.assert_hits(2);
+    }
+}
@@ -0,0 +1,444 @@
+use log::error;
+use regex::Regex;
+use reqwest::header::{HeaderMap, CONTENT_TYPE};
+use reqwest::{Response, StatusCode};
+use serde_json::{json, Value};
+use std::collections::BTreeMap;
+use std::error::Error;
+
+use crate::egress::ActionClient;
+
+/// Response headers recorded in the run step, along with the ones starting with `x-ratelimit-`
+const RECORDED_HEADERS: &[&str] = &[
+    "content-type",
+    "content-length",
+    "content-disposition",
+    "location",
+    "link",
+    "retry-after",
+    "etag",
+    "last-modified",
+];
+
+/// The response of an action request, with its body decoded by content type.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ActionResponse {
+    pub status: u16,
+    pub headers: BTreeMap<String, String>,
+    pub body: ResponseBody,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum ResponseBody {
+    Empty,
+    Json(Value),
+    /// Plain text, or the text of an HTML page
+    Text(String),
+    /// Anything else, stored as a file rather than shown to the model
+    Binary {
+        content_type: String,
+        bytes: Vec<u8>,
+    },
+}
+
+impl ActionResponse {
+    pub async fn read(
+        client: &ActionClient,
+        response: Response,
+    ) -> Result<ActionResponse, Box<dyn Error>> {
+        let status = response.status();
+        let headers = response.headers().clone();
+        let body = client.read_body(response).await?;
+        Ok(ActionResponse::from_parts(status, &headers, body))
+    }
+
+    pub fn from_parts(status: StatusCode, headers: &HeaderMap, body: Vec<u8>) -> ActionResponse {
+        let content_type = headers
+            .get(CONTENT_TYPE)
+            .and_then(|value| value.to_str().ok())
+            .map(|value| value.to_lowercase());
+        ActionResponse {
+            status: status.as_u16(),
+            headers: headers
+                .iter()
+                .filter(|(name, _)| {
+                    RECORDED_HEADERS.contains(&name.as_str())
+                        || name.as_str().starts_with("x-ratelimit-")
+                })
+                .map(|(name, value)| {
+                    (
+                        name.to_string(),
+                        String::from_utf8_lossy(value.as_bytes()).to_string(),
+                    )
+                })
+                .collect(),
+            body: decode_body(content_type.as_deref(), body),
+        }
+    }
+
+    /// Whether the server answered with a 4xx or 5xx status.
+    pub fn is_error(&self) -> bool {
+        self.status >= 400
+    }
+
+    /// What the model is given: the fields of JSON bodies picked by `fields`, cut to
+    /// `max_chars`. Binary bodies are described, with the id of the file they were stored as.
+    /// Error responses are given whole after their status, so the model can fix its call.
+    pub fn prompt_output(
+        &self,
+        fields: &[String],
+        max_chars: usize,
+        file_id: Option<&str>,
+    ) -> String {
+        let fields: &[String] = if self.is_error() { &[] } else { fields };
+        let output = match &self.body {
+            ResponseBody::Empty if self.is_error() => String::new(),
+            ResponseBody::Empty => format!("No content (status {})", self.status),
+            ResponseBody::Json(value) if fields.is_empty() => value.to_string(),
+            ResponseBody::Json(value) => match project(value, fields) {
+                Ok(projected) => projected.to_string(),
+                Err(e) => {
+                    error!("Failed to project action response: {}", e);
+                    value.to_string()
+                }
+            },
+            ResponseBody::Text(text) => text.clone(),
+            ResponseBody::Binary {
+                content_type,
+                bytes,
+            } => match file_id {
+                Some(file_id) => format!(
+                    "{} file of {} bytes, saved as file {}",
+                    content_type,
+                    bytes.len(),
+                    file_id
+                ),
+                None => format!("{} file of {} bytes", content_type, bytes.len()),
+            },
+        };
+        let output = match (self.is_error(), output.is_empty()) {
+            (false, _) => output,
+            (true, true) => format!("Request failed with status {}", self.status),
+            (true, false) => format!("Request failed with status {}: {}", self.status, output),
+        };
+        truncate(&output, max_chars)
+    }
+
+    /// What the run step records about the response besides its output.
+    pub fn step_metadata(&self, file_id: Option<&str>) -> Value {
+        json!({
+            "status": self.status,
+            "headers": self.headers,
+            "file_id": file_id,
+        })
+    }
+}
+
+fn is_json(content_type: &str) -> bool {
+    content_type == "application/json" || content_type.ends_with("+json")
+}
+
+fn is_text(content_type: &str) -> bool {
+    content_type.starts_with("text/")
+        || content_type.ends_with("+xml")
+        || matches!(
+            content_type,
+            "application/xml"
+                | "application/javascript"
+                | "application/yaml"
+                | "application/x-yaml"
+                | "application/x-www-form-urlencoded"
+                | "application/csv"
+        )
+}
+
+fn decode_body(content_type: Option<&str>, bytes: Vec<u8>) -> ResponseBody {
+    if bytes.iter().all(u8::is_ascii_whitespace) {
+        return ResponseBody::Empty;
+    }
+    // Without parameters, e.g. "text/html; charset=utf-8" is "text/html"
+    let mime = content_type.map(|content_type| content_type.split(';').next().unwrap().trim());
+    match mime {
+        Some(mime) if is_json(mime) => match serde_json::from_slice(&bytes) {
+            Ok(value) => ResponseBody::Json(value),
+            // Servers sometimes label errors pages as JSON
+            Err(_) => decode_body(Some("text/plain"), bytes),
+        },
+        Some("text/html") | Some("application/xhtml+xml") => {
+            ResponseBody::Text(html_to_text(&String::from_utf8_lossy(&bytes)))
+        }
+        Some(mime) if is_text(mime) => {
+            ResponseBody::Text(String::from_utf8_lossy(&bytes).to_string())
+        }
+        // Guess from the body when the server doesn't say
+        None => match serde_json::from_slice(&bytes) {
+            Ok(value) => ResponseBody::Json(value),
+            Err(_) => match String::from_utf8(bytes) {
+                Ok(text) => ResponseBody::Text(text),
+                Err(e) => ResponseBody::Binary {
+                    content_type: "application/octet-stream".to_string(),
+                    bytes: e.into_bytes(),
+                },
+            },
+        },
+        Some(mime) => ResponseBody::Binary {
+            content_type: mime.to_string(),
+            bytes,
+        },
+    }
+}
+
+/// The text of an HTML page, without its scripts, styles and markup.
+pub fn html_to_text(html: &str) -> String {
+    let hidden = Regex::new(r"(?is)<(script|style|head|noscript|template)\b.*?</(script|style|head|noscript|template)\s*>|<!--.*?-->").unwrap();
+    let breaks = Regex::new(r"(?i)<(br|/p|/div|/li|/tr|/h[1-6]|/title)\b[^>]*>").unwrap();
+    let tags = Regex::new(r"(?s)<[^>]*>").unwrap();
+    let spaces = Regex::new(r"[ \t\r\f]+").unwrap();
+    let blank_lines = Regex::new(r"\n\s*\n+").unwrap();
+
+    let text = hidden.replace_all(html, "");
+    let text = breaks.replace_all(&text, "\n");
+    let text = tags.replace_all(&text, "");
+    let text = text
+        .replace("&nbsp;", " ")
+        .replace("&lt;", "<")
+        .replace("&gt;", ">")
+        .replace("&quot;", "\"")
+        .replace("&#39;", "'")
+        .replace("&apos;", "'")
+        // Last, so "&amp;lt;" stays "&lt;"
+        .replace("&amp;", "&");
+    let text = spaces.replace_all(&text, " ");
+    let text = blank_lines.replace_all(&text, "\n\n");
+    text.lines()
+        .map(str::trim)
+        .collect::<Vec<_>>()
+        .join("\n")
+        .trim()
+        .to_string()
+}
+
+#[derive(Debug, PartialEq)]
+enum Segment {
+    Field(String),
+    Index(usize),
+    Wildcard,
+}
+
+fn parse_path(path: &str) -> Result<Vec<Segment>, String> {
+    let invalid = || format!("Invalid field path: {}", path);
+    let mut rest = path.trim().strip_prefix('$').unwrap_or(path.trim());
+    let mut segments = Vec::new();
+    while !rest.is_empty() {
+        if let Some(after) = rest.strip_prefix('[') {
+            let end = after.find(']').ok_or_else(invalid)?;
+            let inside = after[..end].trim();
+            segments.push(match inside {
+                "*" => Segment::Wildcard,
+                quoted if quoted.starts_with('\'') || quoted.starts_with('"') => Segment::Field(
+                    quoted
+                        .trim_matches(|c: char| c == '\'' || c == '"')
+                        .to_string(),
+                ),
+                index => Segment::Index(index.parse().map_err(|_| invalid())?),
+            });
+            rest = &after[end + 1..];
+        } else {
+            let after = rest.strip_prefix('.').unwrap_or(rest);
+            let end = after.find(|c| c == '.' || c == '[').unwrap_or(after.len());
+            match &after[..end] {
+                "" => return Err(invalid()),
+                "*" => segments.push(Segment::Wildcard),
+                field => segments.push(Segment::Field(field.to_string())),
+            }
+            rest = &after[end..];
+        }
+    }
+    Ok(segments)
+}
+
+/// Keep the parts of `value` picked by `fields`, JSONPath like `$.items[*].name` or
+/// `data.total`, by path. Paths with a wildcard pick a list.
+pub fn project(value: &Value, fields: &[String]) -> Result<Value, String> {
+    let mut projected = serde_json::Map::new();
+    for field in fields {
+        let segments = parse_path(field)?;
+        let mut matches = vec![value];
+        for segment in &segments {
+            matches = matches
+                .into_iter()
+                .flat_map(|value| match (segment, value) {
+                    (Segment::Field(name), Value::Object(object)) => {
+                        object.get(name).into_iter().collect::<Vec<_>>()
+                    }
+                    (Segment::Index(index), Value::Array(array)) => {
+                        array.get(*index).into_iter().collect()
+                    }
+                    (Segment::Wildcard, Value::Array(array)) => array.iter().collect(),
+                    (Segment::Wildcard, Value::Object(object)) => object.values().collect(),
+                    _ => vec![],
+                })
+                .collect();
+        }
+        let picked = if segments.contains(&Segment::Wildcard) {
+            Value::Array(matches.into_iter().cloned().collect())
+        } else {
+            matches
+                .first()
+                .map_or(Value::Null, |value| (*value).clone())
+        };
+        projected.insert(field.clone(), picked);
+    }
+    Ok(Value::Object(projected))
+}
+
+/// `text` cut to `max_chars` characters, saying how much was left out.
+pub fn truncate(text: &str, max_chars: usize) -> String {
+    let length = text.chars().count();
+    if length <= max_chars {
+        return text.to_string();
+    }
+    let kept: String = text.chars().take(max_chars).collect();
+    format!(
+        "{}... (truncated, {} of {} characters shown)",
+        kept, max_chars, length
+    )
+}
+
+/// File extension of binary responses stored as files.
+pub fn extension(content_type: &str) -> &'static str {
+    match content_type {
+        "application/pdf" => "pdf",
+        "application/zip" => "zip",
+        "application/gzip" => "gz",
+        "image/png" => "png",
+        "image/jpeg" => "jpg",
+        "image/gif" => "gif",
+        "image/webp" => "webp",
+        "image/svg+xml" => "svg",
+        "audio/mpeg" => "mp3",
+        "video/mp4" => "mp4",
+        "application/vnd.openxmlformats-officedocument.spreadsheetml.sheet" => "xlsx",
+        "application/vnd.openxmlformats-officedocument.wordprocessingml.document" => "docx",
+        _ => "bin",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use reqwest::header::HeaderValue;
+
+    fn response(content_type: Option<&str>, body: &[u8]) -> ActionResponse {
+        let mut headers = HeaderMap::new();
+        if let Some(content_type) = content_type {
+            headers.insert(CONTENT_TYPE, HeaderValue::from_str(content_type).unwrap());
+        }
+        headers.insert("x-ratelimit-remaining", HeaderValue::from_static("9"));
+        headers.insert("set-cookie", HeaderValue::from_static("session=abc"));
+        ActionResponse::from_parts(StatusCode::OK, &headers, body.to_vec())
+    }
+
+    #[test]
+    fn test_decode_body() {
+        let json = response(Some("application/problem+json"), br#"{"a": 1}"#);
+        assert_eq!(json.body, ResponseBody::Json(json!({"a": 1})));
+        assert_eq!(json.headers["x-ratelimit-remaining"], "9");
+        assert!(!json.headers.contains_key("set-cookie"));
+
+        assert_eq!(
+            response(Some("text/csv; charset=utf-8"), b"a,b\n1,2").body,
+            ResponseBody::Text("a,b\n1,2".to_string())
+        );
+        assert_eq!(
+            response(Some("text/html"), b"<html><head><title>t</title></head><body><p>Hello&nbsp;<b>world</b></p><script>x()</script></body></html>").body,
+            ResponseBody::Text("Hello world".to_string())
+        );
+        assert_eq!(
+            response(Some("application/json"), b"").body,
+            ResponseBody::Empty
+        );
+        assert_eq!(
+            response(Some("application/json"), b"Internal error").body,
+            ResponseBody::Text("Internal error".to_string())
+        );
+        assert_eq!(
+            response(None, b"[1, 2]").body,
+            ResponseBody::Json(json!([1, 2]))
+        );
+        assert!(matches!(
+            response(Some("image/png"), &[0x89, 0x50, 0x4e, 0x47]).body,
+            ResponseBody::Binary { .. }
+        ));
+        assert!(matches!(
+            response(None, &[0xff, 0xfe, 0x00]).body,
+            ResponseBody::Binary { .. }
+        ));
+    }
+
+    #[test]
+    fn test_project() {
+        let value = json!({
+            "total": 2,
+            "items": [{"name": "a", "size": 1}, {"name": "b", "size": 2}],
+            "meta": {"next page": "x"},
+        });
+        let fields = vec![
+            "$.total".to_string(),
+            "items[*].name".to_string(),
+            "$.items[1].size".to_string(),
+            "$['meta']['next page']".to_string(),
+            "$.missing".to_string(),
+        ];
+        assert_eq!(
+            project(&value, &fields).unwrap(),
+            json!({
+                "$.total": 2,
+                "items[*].name": ["a", "b"],
+                "$.items[1].size": 2,
+                "$['meta']['next page']": "x",
+                "$.missing": null,
+            })
+        );
+        assert!(project(&value, &["$.items[".to_string()]).is_err());
+    }
+
+    #[test]
+    fn test_prompt_output() {
+        let json = response(
+            Some("application/json"),
+            br#"{"items": [1, 2, 3], "next": null}"#,
+        );
+        assert_eq!(
+            json.prompt_output(&["$.items".to_string()], 100, None),
+            r#"{"$.items":[1,2,3]}"#
+        );
+        assert_eq!(
+            json.prompt_output(&[], 10, None),
+            r#"{"items":[... (truncated, 10 of 29 characters shown)"#
+        );
+        let png = response(Some("image/png"), &[0x89, 0x50]);
+        assert_eq!(
+            png.prompt_output(&[], 100, Some("file-1")),
+            "image/png file of 2 bytes, saved as file file-1"
+        );
+
+        let not_found = ActionResponse::from_parts(
+            StatusCode::NOT_FOUND,
+            &HeaderMap::new(),
+            br#"{"error": "no pet 42"}"#.to_vec(),
+        );
+        assert!(not_found.is_error());
+        assert_eq!(
+            not_found.prompt_output(&["$.items".to_string()], 100, None),
+            r#"Request failed with status 404: {"error":"no pet 42"}"#
+        );
+        let unavailable =
+            ActionResponse::from_parts(StatusCode::SERVICE_UNAVAILABLE, &HeaderMap::new(), vec![]);
+        assert_eq!(
+            unavailable.prompt_output(&[], 100, None),
+            "Request failed with status 503"
+        );
+        assert_eq!(unavailable.step_metadata(None)["status"], 503);
+    }
+}
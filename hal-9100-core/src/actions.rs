@@ -0,0 +1,578 @@
+use log::info;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use sqlx::types::Uuid;
+use sqlx::{PgConnection, PgExecutor, PgPool};
+use std::collections::{HashMap, HashSet};
+
+use crate::assistants::AssistantError;
+use crate::function_calling::{
+    check_openapi_spec, openapi_functions, register_function, FunctionCallError,
+};
+use crate::models::Function;
+use crate::openapi::{stored_is_consequential, OpenAPISpec};
+use crate::secrets::{delete_secret, store_tool_secret};
+
+/// An operation of an action tool, registered as a function the model can call.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct ActionOperation {
+    pub id: String,
+    pub name: String,
+    pub description: Option<String>,
+    pub method: String,
+    pub path: String,
+    pub server_url: String,
+    /// The JSON schema of the arguments of the function
+    pub parameters: Value,
+    pub is_consequential: bool,
+    /// Disabled operations are never given to the model
+    pub enabled: bool,
+    pub created_at: i32,
+}
+
+/// The operations a re-sync from a spec changed, by name.
+#[derive(Debug, Clone, Default, Serialize, Deserialize, PartialEq)]
+pub struct ActionsDiff {
+    pub added: Vec<String>,
+    pub updated: Vec<String>,
+    pub removed: Vec<String>,
+    pub unchanged: Vec<String>,
+}
+
+struct StoredAction {
+    operation: ActionOperation,
+    metadata: Value,
+}
+
+fn server_url(openapi: &OpenAPISpec) -> String {
+    openapi
+        .openapi_spec
+        .servers
+        .first()
+        .map(|server| server.url.clone())
+        .unwrap_or_default()
+}
+
+async fn stored_actions(
+    executor: impl PgExecutor<'_>,
+    assistant_id: &str,
+    user_id: &str,
+) -> Result<Vec<StoredAction>, sqlx::Error> {
+    let rows = sqlx::query!(
+        r#"
+        SELECT id, name, description, parameters, metadata, enabled, created_at
+        FROM functions
+        WHERE assistant_id::text = $1 AND user_id::text = $2
+            AND metadata->>'operation' IS NOT NULL
+        ORDER BY created_at, name
+        "#,
+        assistant_id,
+        user_id
+    )
+    .fetch_all(executor)
+    .await?;
+
+    Ok(rows
+        .into_iter()
+        .map(|row| {
+            let metadata = row.metadata.unwrap_or_default();
+            let field = |name: &str| metadata[name].as_str().unwrap_or_default().to_string();
+            StoredAction {
+                operation: ActionOperation {
+                    id: row.id.to_string(),
+                    name: row.name.unwrap_or_default(),
+                    description: row.description,
+                    method: field("method"),
+                    path: field("path"),
+                    server_url: field("domain"),
+                    parameters: row.parameters.unwrap_or_default(),
+                    is_consequential: stored_is_consequential(&metadata),
+                    enabled: row.enabled,
+                    created_at: row.created_at,
+                },
+                metadata,
+            }
+        })
+        .collect())
+}
+
+pub async fn list_actions(
+    pool: &PgPool,
+    assistant_id: &str,
+    user_id: &str,
+) -> Result<Vec<ActionOperation>, sqlx::Error> {
+    Ok(stored_actions(pool, assistant_id, user_id)
+        .await?
+        .into_iter()
+        .map(|action| action.operation)
+        .collect())
+}
+
+pub async fn get_action(
+    pool: &PgPool,
+    assistant_id: &str,
+    action_id: &str,
+    user_id: &str,
+) -> Result<ActionOperation, sqlx::Error> {
+    list_actions(pool, assistant_id, user_id)
+        .await?
+        .into_iter()
+        .find(|action| action.id == action_id)
+        .ok_or(sqlx::Error::RowNotFound)
+}
+
+pub async fn set_action_enabled(
+    pool: &PgPool,
+    assistant_id: &str,
+    action_id: &str,
+    user_id: &str,
+    enabled: bool,
+) -> Result<ActionOperation, sqlx::Error> {
+    info!("Setting action {} enabled: {}", action_id, enabled);
+    let result = sqlx::query!(
+        r#"
+        UPDATE functions SET enabled = $1
+        WHERE id::text = $2 AND assistant_id::text = $3 AND user_id::text = $4
+            AND metadata->>'operation' IS NOT NULL
+        "#,
+        enabled,
+        action_id,
+        assistant_id,
+        user_id
+    )
+    .execute(pool)
+    .await?;
+    if result.rows_affected() == 0 {
+        return Err(sqlx::Error::RowNotFound);
+    }
+    get_action(pool, assistant_id, action_id, user_id).await
+}
+
+pub async fn delete_action(
+    pool: &PgPool,
+    assistant_id: &str,
+    action_id: &str,
+    user_id: &str,
+) -> Result<(), sqlx::Error> {
+    let result = sqlx::query!(
+        r#"
+        DELETE FROM functions
+        WHERE id::text = $1 AND assistant_id::text = $2 AND user_id::text = $3
+            AND metadata->>'operation' IS NOT NULL
+        "#,
+        action_id,
+        assistant_id,
+        user_id
+    )
+    .execute(pool)
+    .await?;
+    if result.rows_affected() == 0 {
+        return Err(sqlx::Error::RowNotFound);
+    }
+    Ok(())
+}
+
+async fn update_function(
+    executor: impl PgExecutor<'_>,
+    id: &str,
+    function: &Function,
+) -> Result<(), sqlx::Error> {
+    sqlx::query!(
+        r#"
+        UPDATE functions SET description = $1, parameters = $2, metadata = $3
+        WHERE id::text = $4
+        "#,
+        function.inner.description,
+        serde_json::to_value(&function.inner.parameters).unwrap(),
+        function.metadata,
+        id
+    )
+    .execute(executor)
+    .await?;
+    Ok(())
+}
+
+async fn delete_function(executor: impl PgExecutor<'_>, id: &str) -> Result<(), sqlx::Error> {
+    sqlx::query!(
+        r#"
+        DELETE FROM functions WHERE id::text = $1
+        "#,
+        id
+    )
+    .execute(executor)
+    .await?;
+    Ok(())
+}
+
+// The metadata of a function without its credentials, which are stored again on every sync
+fn without_secret(metadata: Option<&Value>) -> Option<Value> {
+    metadata.cloned().map(|mut metadata| {
+        if let Some(metadata) = metadata.as_object_mut() {
+            metadata.remove("secret_id");
+        }
+        metadata
+    })
+}
+
+/// Make the registered operations of the spec's server match `openapi`: new operations are
+/// added, changed ones updated and missing ones removed. Updated operations stay disabled if
+/// they were. Run it in a transaction so a failed sync leaves the operations as they were.
+pub async fn sync_actions(
+    conn: &mut PgConnection,
+    assistant_id: &str,
+    user_id: &str,
+    openapi: &OpenAPISpec,
+    secret_id: Option<String>,
+) -> Result<ActionsDiff, FunctionCallError> {
+    let server_url = server_url(openapi);
+    let mut stored: HashMap<String, StoredAction> = stored_actions(&mut *conn, assistant_id, user_id)
+        .await
+        .map_err(FunctionCallError::SqlxError)?
+        .into_iter()
+        .filter(|action| action.operation.server_url == server_url)
+        .map(|action| (action.operation.name.clone(), action))
+        .collect();
+
+    let mut diff = ActionsDiff::default();
+    for function in openapi_functions(openapi, secret_id, assistant_id, user_id)? {
+        let name = function.inner.name.clone();
+        match stored.remove(&name) {
+            None => {
+                register_function(&mut *conn, function).await?;
+                diff.added.push(name);
+            }
+            Some(action) => {
+                let parameters = serde_json::to_value(&function.inner.parameters).unwrap();
+                let unchanged = action.operation.description == function.inner.description
+                    && action.operation.parameters == parameters
+                    && without_secret(Some(&action.metadata))
+                        == without_secret(function.metadata.as_ref());
+                // New credentials are saved without the operation counting as updated
+                let same_secret = Some(&action.metadata["secret_id"])
+                    == function.metadata.as_ref().map(|metadata| &metadata["secret_id"]);
+                if !unchanged || !same_secret {
+                    update_function(&mut *conn, &action.operation.id, &function)
+                        .await
+                        .map_err(FunctionCallError::SqlxError)?;
+                }
+                if unchanged {
+                    diff.unchanged.push(name);
+                } else {
+                    diff.updated.push(name);
+                }
+            }
+        }
+    }
+    for (name, action) in stored {
+        delete_function(&mut *conn, &action.operation.id)
+            .await
+            .map_err(FunctionCallError::SqlxError)?;
+        diff.removed.push(name);
+    }
+    diff.removed.sort();
+    info!("Synced actions of {}: {:?}", server_url, diff);
+    Ok(diff)
+}
+
+fn parse_tool_spec(tool: &Value) -> Result<OpenAPISpec, FunctionCallError> {
+    let spec = tool["data"]["openapi_spec"]
+        .as_str()
+        .ok_or_else(|| FunctionCallError::Other("Missing openapi_spec".to_string()))?;
+    let openapi = OpenAPISpec::new(spec)
+        .map_err(|e| FunctionCallError::Other(format!("Failed to parse OpenAPI spec: {}", e)))?;
+    check_openapi_spec(&openapi)?;
+    Ok(openapi)
+}
+
+/// Re-sync the action tool of an assistant from a new version of its spec, given as an action
+/// tool. The tool of the assistant calling the same server is replaced, or the tool is added.
+/// Without new headers or credentials, the ones of the replaced tool are kept, new ones replace
+/// them.
+pub async fn sync_action_tool(
+    pool: &PgPool,
+    assistant_id: &str,
+    user_id: &str,
+    mut tool: Value,
+) -> Result<ActionsDiff, AssistantError> {
+    tool["type"] = Value::String("action".to_string());
+    let openapi = parse_tool_spec(&tool)?;
+    let server_url = server_url(&openapi);
+
+    // The assistant is locked so that concurrent syncs of its tools apply one after the other
+    let mut tx = pool.begin().await?;
+    let row = sqlx::query!(
+        r#"
+        SELECT tools FROM assistants WHERE id::text = $1 AND user_id::text = $2
+        FOR UPDATE
+        "#,
+        assistant_id,
+        user_id
+    )
+    .fetch_one(&mut *tx)
+    .await?;
+    let mut tools = row.tools.unwrap_or_default();
+    let replaced = tools.iter().position(|existing| {
+        existing["type"] == "action"
+            && parse_tool_spec(existing).map_or(false, |spec| self::server_url(&spec) == server_url)
+    });
+    store_tool_secret(&mut *tx, user_id, &mut tool).await?;
+    match replaced {
+        Some(index) => {
+            let replaced_secret_id = tools[index]["data"]["secret_id"].as_str().map(String::from);
+            match (tool["data"]["secret_id"].is_null(), replaced_secret_id) {
+                (true, Some(secret_id)) => tool["data"]["secret_id"] = Value::String(secret_id),
+                (false, Some(secret_id)) => delete_secret(&mut *tx, &secret_id, user_id).await?,
+                (_, None) => {}
+            }
+            tools[index] = tool.clone();
+        }
+        None => tools.push(tool.clone()),
+    }
+    let secret_id = tool["data"]["secret_id"].as_str().map(String::from);
+
+    let diff = sync_actions(&mut tx, assistant_id, user_id, &openapi, secret_id).await?;
+    sqlx::query!(
+        r#"
+        UPDATE assistants SET tools = $1 WHERE id::text = $2 AND user_id::text = $3
+        "#,
+        &tools,
+        assistant_id,
+        user_id
+    )
+    .execute(&mut *tx)
+    .await?;
+    tx.commit().await?;
+    Ok(diff)
+}
+
+/// Make the functions of an assistant match its `tools`, removing the ones of tools it no
+/// longer has. Function tools are registered again, action tools are re-synced.
+pub async fn sync_assistant_functions(
+    pool: &PgPool,
+    assistant_id: &str,
+    user_id: &str,
+    tools: &[Value],
+) -> Result<(), AssistantError> {
+    let mut tx = pool.begin().await?;
+    sqlx::query!(
+        r#"
+        DELETE FROM functions
+        WHERE assistant_id::text = $1 AND user_id::text = $2
+            AND (metadata IS NULL OR metadata->>'operation' IS NULL)
+        "#,
+        assistant_id,
+        user_id
+    )
+    .execute(&mut *tx)
+    .await?;
+
+    let mut server_urls = HashSet::new();
+    for tool in tools {
+        match tool["type"].as_str() {
+            Some("function") => {
+                let inner = serde_json::from_value(tool["function"].clone())
+                    .map_err(FunctionCallError::JsonError)?;
+                register_function(
+                    &mut *tx,
+                    Function {
+                        inner,
+                        assistant_id: assistant_id.to_string(),
+                        user_id: user_id.to_string(),
+                        metadata: None,
+                    },
+                )
+                .await?;
+            }
+            Some("action") => {
+                let openapi = parse_tool_spec(tool)?;
+                let secret_id = tool["data"]["secret_id"].as_str().map(String::from);
+                sync_actions(&mut tx, assistant_id, user_id, &openapi, secret_id).await?;
+                server_urls.insert(server_url(&openapi));
+            }
+            _ => {}
+        }
+    }
+
+    for action in stored_actions(&mut *tx, assistant_id, user_id).await? {
+        if !server_urls.contains(&action.operation.server_url) {
+            delete_function(&mut *tx, &action.operation.id).await?;
+        }
+    }
+    tx.commit().await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::secrets::{get_secret, SecretError, SECRETS_KEY_ENV};
+    use crate::test_data::OPENAPI_SPEC;
+    use dotenv::dotenv;
+    use serde_json::json;
+    use sqlx::postgres::PgPoolOptions;
+
+    async fn setup() -> PgPool {
+        dotenv().ok();
+        let database_url = std::env::var("DATABASE_URL").expect("DATABASE_URL must be set");
+        let pool = PgPoolOptions::new()
+            .max_connections(5)
+            .connect(&database_url)
+            .await
+            .expect("Failed to create pool.");
+        pool
+    }
+
+    async fn create_assistant_row(pool: &PgPool, user_id: &str) -> String {
+        let row = sqlx::query!(
+            r#"
+            INSERT INTO assistants (instructions, name, tools, model, user_id)
+            VALUES ('', 'actions', '{}', 'mistralai/mixtral-8x7b-instruct', $1)
+            RETURNING id
+            "#,
+            Uuid::parse_str(user_id).unwrap()
+        )
+        .fetch_one(pool)
+        .await
+        .unwrap();
+        row.id.to_string()
+    }
+
+    #[tokio::test]
+    async fn test_sync_action_tool() {
+        let pool = setup().await;
+        let user_id = Uuid::default().to_string();
+        let assistant_id = create_assistant_row(&pool, &user_id).await;
+
+        let diff = sync_action_tool(
+            &pool,
+            &assistant_id,
+            &user_id,
+            json!({"type": "action", "data": {"openapi_spec": OPENAPI_SPEC}}),
+        )
+        .await
+        .unwrap();
+        assert_eq!(diff.added, vec!["getRandomPages"]);
+
+        let actions = list_actions(&pool, &assistant_id, &user_id).await.unwrap();
+        assert_eq!(actions.len(), 1);
+        assert_eq!(actions[0].server_url, "https://en.wikipedia.org/w");
+        assert!(actions[0].enabled);
+        let disabled = set_action_enabled(&pool, &assistant_id, &actions[0].id, &user_id, false)
+            .await
+            .unwrap();
+        assert!(!disabled.enabled);
+        assert!(matches!(
+            set_action_enabled(&pool, &assistant_id, &Uuid::new_v4().to_string(), &user_id, true)
+                .await,
+            Err(sqlx::Error::RowNotFound)
+        ));
+
+        // A new version renames the operation
+        let renamed = OPENAPI_SPEC.replace("getRandomPages", "getRandomArticles");
+        let diff = sync_action_tool(
+            &pool,
+            &assistant_id,
+            &user_id,
+            json!({"type": "action", "data": {"openapi_spec": renamed}}),
+        )
+        .await
+        .unwrap();
+        assert_eq!(diff.added, vec!["getRandomArticles"]);
+        assert_eq!(diff.removed, vec!["getRandomPages"]);
+
+        // And the next one describes it better
+        let new_spec =
+            renamed.replace("Get a set of random pages", "Get random Wikipedia articles");
+        let diff = sync_action_tool(
+            &pool,
+            &assistant_id,
+            &user_id,
+            json!({"type": "action", "data": {"openapi_spec": new_spec}}),
+        )
+        .await
+        .unwrap();
+        assert_eq!(diff.updated, vec!["getRandomArticles"]);
+
+        // Syncing the same spec changes nothing, and the tool was replaced rather than added
+        let diff = sync_action_tool(
+            &pool,
+            &assistant_id,
+            &user_id,
+            json!({"type": "action", "data": {"openapi_spec": new_spec}}),
+        )
+        .await
+        .unwrap();
+        assert_eq!(diff.unchanged, vec!["getRandomArticles"]);
+        let row = sqlx::query!(
+            "SELECT tools FROM assistants WHERE id::text = $1",
+            assistant_id
+        )
+        .fetch_one(&pool)
+        .await
+        .unwrap();
+        assert_eq!(row.tools.unwrap().len(), 1);
+
+        // Dropping the tool removes its operations
+        sync_assistant_functions(&pool, &assistant_id, &user_id, &[])
+            .await
+            .unwrap();
+        assert!(list_actions(&pool, &assistant_id, &user_id)
+            .await
+            .unwrap()
+            .is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_sync_action_tool_credentials() {
+        std::env::set_var(SECRETS_KEY_ENV, base64::encode([7u8; 32]));
+        let pool = setup().await;
+        let user_id = Uuid::default().to_string();
+        let assistant_id = create_assistant_row(&pool, &user_id).await;
+        let secret_ids = |actions: &[StoredAction]| -> Vec<String> {
+            actions
+                .iter()
+                .map(|action| action.metadata["secret_id"].as_str().unwrap_or_default().to_string())
+                .collect()
+        };
+
+        let tool = |key: Option<&str>| match key {
+            Some(key) => json!({"type": "action", "data": {
+                "openapi_spec": OPENAPI_SPEC,
+                "headers": {"x-api-key": key},
+            }}),
+            None => json!({"type": "action", "data": {"openapi_spec": OPENAPI_SPEC}}),
+        };
+        sync_action_tool(&pool, &assistant_id, &user_id, tool(Some("k3y-one")))
+            .await
+            .unwrap();
+        let first = secret_ids(&stored_actions(&pool, &assistant_id, &user_id).await.unwrap());
+
+        // New credentials replace the secret without updating the operations
+        let diff = sync_action_tool(&pool, &assistant_id, &user_id, tool(Some("k3y-two")))
+            .await
+            .unwrap();
+        assert_eq!(diff.unchanged, vec!["getRandomPages"]);
+        let second = secret_ids(&stored_actions(&pool, &assistant_id, &user_id).await.unwrap());
+        assert_ne!(first, second);
+        assert!(matches!(
+            get_secret(&pool, &first[0], &user_id).await,
+            Err(SecretError::NotFound)
+        ));
+        let secret = get_secret(&pool, &second[0], &user_id).await.unwrap();
+        assert_eq!(secret.headers["x-api-key"], "k3y-two");
+
+        // Without credentials the ones of the replaced tool are kept
+        let diff = sync_action_tool(&pool, &assistant_id, &user_id, tool(None))
+            .await
+            .unwrap();
+        assert_eq!(diff.unchanged, vec!["getRandomPages"]);
+        assert_eq!(
+            secret_ids(&stored_actions(&pool, &assistant_id, &user_id).await.unwrap()),
+            second
+        );
+
+        sync_assistant_functions(&pool, &assistant_id, &user_id, &[])
+            .await
+            .unwrap();
+    }
+}
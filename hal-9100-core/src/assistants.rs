@@ -20,7 +20,11 @@ use sqlx::Error as SqlxError;
 use std::collections::HashMap;
 use std::pin::Pin;
 
+use crate::actions::sync_assistant_functions;
 use crate::function_calling::register_openapi_functions;
+use crate::secrets::store_tool_secret;
+use crate::secrets::SecretError;
+use crate::tools::tool_registry;
 pub struct Tools(Option<Vec<Value>>);
 
 impl Tools {
@@ -28,32 +32,15 @@ impl Tools {
         Tools(tools)
     }
     pub fn to_tools(&self) -> Result<Vec<AssistantTools>, Box<serde_json::Error>> {
+        let registry = tool_registry();
         match &self.0 {
             Some(tools) => tools
                 .iter()
                 .map(|tool| {
                     let type_field = tool.get("type").unwrap().as_str();
-                    let data = tool.get("data");
-                    match type_field {
-                        Some("function") => {
-                            let function_tool = serde_json::from_value(tool.clone())?;
-                            Ok(AssistantTools::Function(function_tool))
-                        }
-                        Some("retrieval") => {
-                            let retrieval_tool = serde_json::from_value(tool.clone())?;
-                            Ok(AssistantTools::Retrieval(retrieval_tool))
-                        }
-                        Some("code_interpreter") => {
-                            let code_tool = serde_json::from_value(tool.clone())?;
-                            Ok(AssistantTools::Code(code_tool))
-                        }
-                        Some("action") => {
-                            let mut action_tool: async_openai::types::AssistantToolsExtra =
-                                serde_json::from_value(tool.clone())?;
-
-                            Ok(AssistantTools::Extra(action_tool))
-                        }
-                        _ => Err(Box::new(SerdeError::custom(format!(
+                    match type_field.and_then(|name| registry.get(name)) {
+                        Some(registered_tool) => registered_tool.parse(tool).map_err(Box::new),
+                        None => Err(Box::new(SerdeError::custom(format!(
                             "Unknown tool type: {:?}",
                             tool
                         )))),
@@ -100,6 +87,7 @@ pub async fn get_assistant(
 pub enum AssistantError {
     SqlxError(SqlxError),
     FunctionCallError(FunctionCallError),
+    SecretError(SecretError),
     // Add other error types as needed
 }
 
@@ -115,11 +103,18 @@ impl From<FunctionCallError> for AssistantError {
     }
 }
 
+impl From<SecretError> for AssistantError {
+    fn from(err: SecretError) -> Self {
+        AssistantError::SecretError(err)
+    }
+}
+
 impl std::fmt::Display for AssistantError {
     fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
         match self {
             AssistantError::SqlxError(e) => write!(f, "SqlxError: {:?}", e),
             AssistantError::FunctionCallError(e) => write!(f, "FunctionCallError: {:?}", e),
+            AssistantError::SecretError(e) => write!(f, "SecretError: {}", e),
         }
     }
 }
@@ -128,6 +123,7 @@ impl std::fmt::Debug for AssistantError {
         match self {
             AssistantError::SqlxError(e) => write!(f, "SqlxError: {:?}", e),
             AssistantError::FunctionCallError(e) => write!(f, "FunctionCallError: {:?}", e),
+            AssistantError::SecretError(e) => write!(f, "SecretError: {}", e),
         }
     }
 }
@@ -135,15 +131,19 @@ pub async fn create_assistant(
     pool: &PgPool,
     assistant: &Assistant,
 ) -> Result<Assistant, AssistantError> {
-    info!("Creating assistant: {:?}", assistant);
+    // The tools may hold credentials, they are only logged once moved to secrets
+    info!("Creating assistant: {:?}", assistant.inner.name);
 
     let file_ids = &assistant.inner.file_ids;
-    let tools_json: Vec<Value> = assistant
+    let mut tools_json: Vec<Value> = assistant
         .inner
         .tools
         .iter()
         .map(|tool| serde_json::to_value(tool).unwrap())
         .collect();
+    for tool in tools_json.iter_mut() {
+        store_tool_secret(pool, &assistant.user_id, tool).await?;
+    }
     // if there is an assistant.inner.metadata, for each of the value verify that it does not exceed 512 characters, if it does raise a warning that openai will not acceps this metadata as values must be 512 characters or less
     let metadata = assistant.inner.metadata.clone();
     let metadata_json = metadata.map(|metadata| {
@@ -182,8 +182,7 @@ pub async fn create_assistant(
 
     let mut futures: Vec<Pin<Box<dyn Future<Output = Result<(), FunctionCallError>> + Send>>> =
         Vec::new();
-    assistant.inner.tools.iter().for_each(|tool| {
-        println!("tool: {:?}", tool);
+    assistant.inner.tools.iter().zip(tools_json.iter()).for_each(|(tool, tool_json)| {
         // tool_json: Object {"type": String("function")}
         if let AssistantTools::Function(function_tool) = tool {
             let f = function_tool.function.clone();
@@ -214,13 +213,13 @@ pub async fn create_assistant(
             if let Some(data) = &extra_tool.data {
                 if let Some(openapi_spec) = data.get("openapi_spec") {
                     let openapi_spec_str: String = openapi_spec.as_str().unwrap().to_string();
-                    let headers = data.get("headers");
+                    let secret_id = tool_json["data"]["secret_id"].as_str().map(String::from);
                     let future = Box::pin(async move {
                         info!("Registering openapi functions");
                         match register_openapi_functions(
                             pool,
                             openapi_spec_str,
-                            headers.cloned(),
+                            secret_id,
                             &row.id.to_string(),
                             &assistant.user_id,
                         )
@@ -272,13 +271,16 @@ pub async fn update_assistant(
     pool: &PgPool,
     assistant_id: &str,
     assistant: &Assistant,
-) -> Result<Assistant, sqlx::Error> {
-    let tools_json: Vec<Value> = assistant
+) -> Result<Assistant, AssistantError> {
+    let mut tools_json: Vec<Value> = assistant
         .inner
         .tools
         .iter()
         .map(|tool| serde_json::to_value(tool).unwrap())
         .collect();
+    for tool in tools_json.iter_mut() {
+        store_tool_secret(pool, &assistant.user_id, tool).await?;
+    }
 
     let metadata = assistant.inner.metadata.clone();
     let metadata_json = metadata.map(|metadata| {
@@ -326,6 +328,8 @@ pub async fn update_assistant(
     )
     .fetch_one(pool)
     .await?;
+    // Functions of tools the assistant no longer has would still be given to the model
+    sync_assistant_functions(pool, assistant_id, &assistant.user_id, &tools_json).await?;
     let empty_tools: Vec<AssistantTools> = vec![];
     Ok(Assistant {
         inner: AssistantObject {
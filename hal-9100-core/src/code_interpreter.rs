@@ -3,31 +3,31 @@
 
 // docker run --rm louis030195/hal-9100-code-interpreter python -c "print(1+1)"
 
-use async_openai::types::FunctionObject;
-use async_recursion::async_recursion;
-
-use bollard::container::LogOutput;
-use bollard::container::{
-    Config, CreateContainerOptions, RemoveContainerOptions, StartContainerOptions,
+use async_openai::types::{
+    FilePath, FunctionObject, MessageContentTextAnnotations,
+    MessageContentTextAnnotationsFilePathObject,
 };
-use bollard::exec::CreateExecOptions;
-use bollard::exec::StartExecResults;
-use bollard::image::CreateImageOptions;
-use bollard::models::HostConfig;
-use bollard::Docker;
-use futures::stream::StreamExt;
-use futures::TryStreamExt;
+
+use hal_9100_core::file_storage::FileStorage;
+use hal_9100_core::files::get_file;
+use hal_9100_core::interpreter_sessions::{shared_sessions, SessionManager};
+use hal_9100_core::sandbox::{shared_sandbox, ExecutionOutput, Sandbox, SandboxFile};
 use hal_9100_core::function_calling::generate_function_call;
 use hal_9100_core::models::Function;
 use hal_9100_core::models::FunctionCallInput;
+use hal_9100_extra::config::{CodeInterpreterConfig, CodeLanguage, SandboxBackend};
 use hal_9100_extra::llm::{HalLLMClient, HalLLMRequestArgs};
-use log::info;
+use log::{error, info};
+use regex::Regex;
+use serde::Deserialize;
 use serde_json::json;
-use std::collections::HashMap;
+use sqlx::PgPool;
 use std::default::Default;
+use std::path::Path;
+use std::time::Instant;
+use tokio::sync::mpsc::UnboundedSender;
 use uuid::Uuid;
 
-// TODO: later optimise stuff like: run docker container in the background, use a pool of docker containers, etc.
 // TODO: latr run multiple interpreters in parallel and use llm to take best output or smthing.
 // TODO: latr annotations
 // TODO: multi step - e.g. generate code, execute, then give result to next llm call, etc. LLM decide how many iterations it wants to do.
@@ -35,76 +35,835 @@ use uuid::Uuid;
 use std::fmt;
 
 #[derive(Debug)]
-pub struct InterpreterError {
-    message: String,
-    python_code: String,
+pub enum InterpreterError {
+    /// The sandbox could not be set up or talked to
+    Sandbox(String),
+    /// The LLM did not produce usable code
+    CodeGeneration(String),
+    /// The code ran and failed, `message` is what it wrote to stderr
+    Execution {
+        message: String,
+        exit_code: Option<i64>,
+        language: CodeLanguage,
+        python_code: String,
+    },
+    /// The code ran longer than the configured timeout and was killed
+    Timeout {
+        timeout_secs: u64,
+        language: CodeLanguage,
+        python_code: String,
+    },
+    /// The code was killed for using more memory than allowed
+    MemoryLimitExceeded {
+        language: CodeLanguage,
+        python_code: String,
+    },
+    /// The code tried to start more processes or threads than allowed
+    ProcessLimitExceeded {
+        language: CodeLanguage,
+        python_code: String,
+    },
+    /// The code tried to access the network while it is disabled
+    NetworkDisabled {
+        language: CodeLanguage,
+        python_code: String,
+    },
+    /// Every generated code failed, `attempts` are the codes that ran
+    MaxAttemptsReached {
+        last_error: Box<InterpreterError>,
+        attempts: Vec<CodeAttempt>,
+    },
+}
+
+impl InterpreterError {
+    /// The code that caused the error, if the error happened while running code
+    pub fn python_code(&self) -> Option<&str> {
+        match self {
+            InterpreterError::Execution { python_code, .. }
+            | InterpreterError::Timeout { python_code, .. }
+            | InterpreterError::MemoryLimitExceeded { python_code }
+            | InterpreterError::ProcessLimitExceeded { python_code }
+            | InterpreterError::NetworkDisabled { python_code } => Some(python_code),
+            _ => None,
+        }
+    }
+
+    /// The language the code that caused the error is written in
+    pub fn language(&self) -> Option<CodeLanguage> {
+        match self {
+            InterpreterError::Execution { language, .. }
+            | InterpreterError::Timeout { language, .. }
+            | InterpreterError::MemoryLimitExceeded { language, .. }
+            | InterpreterError::ProcessLimitExceeded { language, .. }
+            | InterpreterError::NetworkDisabled { language, .. } => Some(*language),
+            _ => None,
+        }
+    }
+
+    /// The error for the generated `code` in `language`, instead of the Python program the
+    /// sandbox ran it with
+    pub fn for_code(mut self, code_language: CodeLanguage, code: &str) -> Self {
+        match &mut self {
+            InterpreterError::Execution {
+                language,
+                python_code,
+                ..
+            }
+            | InterpreterError::Timeout {
+                language,
+                python_code,
+                ..
+            }
+            | InterpreterError::MemoryLimitExceeded {
+                language,
+                python_code,
+            }
+            | InterpreterError::ProcessLimitExceeded {
+                language,
+                python_code,
+            }
+            | InterpreterError::NetworkDisabled {
+                language,
+                python_code,
+            } => {
+                *language = code_language;
+                *python_code = code.to_string();
+            }
+            _ => (),
+        }
+        self
+    }
+
+    /// Why the code failed in a few words, for the model and the run steps
+    pub fn exit_reason(&self) -> String {
+        match self {
+            InterpreterError::Execution {
+                exit_code: Some(exit_code),
+                ..
+            } => format!("exited with code {}", exit_code),
+            InterpreterError::Execution { .. } => "raised an error".to_string(),
+            InterpreterError::Timeout { timeout_secs, .. } => {
+                format!("killed after running for {} seconds", timeout_secs)
+            }
+            InterpreterError::MemoryLimitExceeded { .. } => {
+                "killed for using more memory than allowed".to_string()
+            }
+            InterpreterError::ProcessLimitExceeded { .. } => {
+                "failed to start more processes or threads than allowed".to_string()
+            }
+            InterpreterError::NetworkDisabled { .. } => {
+                "failed to access the network, which is disabled".to_string()
+            }
+            _ => self.to_string(),
+        }
+    }
+
+    /// Whether generating different code could fix the error
+    pub fn is_retryable(&self) -> bool {
+        !matches!(
+            self,
+            InterpreterError::Sandbox(_) | InterpreterError::MaxAttemptsReached { .. }
+        )
+    }
 }
 
 impl fmt::Display for InterpreterError {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        write!(f, "{}\nPython code: {}", self.message, self.python_code)
+        let language = self.language().map_or("", |language| language.name());
+        match self {
+            InterpreterError::Sandbox(message) => write!(f, "Sandbox error: {}", message),
+            InterpreterError::CodeGeneration(message) => {
+                write!(f, "Failed to generate code: {}", message)
+            }
+            InterpreterError::Execution {
+                message,
+                exit_code: Some(exit_code),
+                ..
+            } => write!(
+                f,
+                "{} code execution failed with exit code {}: {}",
+                language, exit_code, message
+            ),
+            InterpreterError::Execution { message, .. } => {
+                write!(
+                    f,
+                    "{} code execution failed with error: {}",
+                    language, message
+                )
+            }
+            InterpreterError::Timeout { timeout_secs, .. } => write!(
+                f,
+                "{} code execution timed out after {} seconds",
+                language, timeout_secs
+            ),
+            InterpreterError::MemoryLimitExceeded { .. } => {
+                write!(f, "{} code execution exceeded the memory limit", language)
+            }
+            InterpreterError::ProcessLimitExceeded { .. } => write!(
+                f,
+                "{} code execution exceeded the process and thread limit",
+                language
+            ),
+            InterpreterError::NetworkDisabled { .. } => write!(
+                f,
+                "{} code execution tried to access the network, which is disabled",
+                language
+            ),
+            InterpreterError::MaxAttemptsReached { last_error, .. } => {
+                write!(f, "Max attempts reached, last error: {}", last_error)
+            }
+        }?;
+        if let Some(python_code) = self.python_code() {
+            write!(f, "\n{} code: {}", language, python_code)?;
+        }
+        Ok(())
     }
 }
 
 impl From<bollard::errors::Error> for InterpreterError {
     fn from(err: bollard::errors::Error) -> InterpreterError {
-        InterpreterError {
-            message: format!("Docker error: {}", err),
-            python_code: String::new(),
-        }
+        InterpreterError::Sandbox(format!("Docker error: {}", err))
     }
 }
 
 impl From<serde_json::Error> for InterpreterError {
     fn from(err: serde_json::Error) -> InterpreterError {
-        InterpreterError {
-            message: format!("JSON error: {}", err),
-            python_code: String::new(),
-        }
+        InterpreterError::CodeGeneration(format!("JSON error: {}", err))
     }
 }
 
 impl std::error::Error for InterpreterError {}
 
-#[async_recursion]
+// Exit code of a process killed by SIGKILL, which is what the OOM killer sends
+pub const SIGKILL_EXIT_CODE: i64 = 137;
+
+/// Turn an execution into a typed error when it failed. A non-zero exit code means failure,
+/// what the code prints does not matter. Only for runners that do not report an exit code,
+/// a traceback on stderr is taken as a failure.
+pub fn check_execution(
+    execution: &ExecutionOutput,
+    language: CodeLanguage,
+    python_code: &str,
+) -> Result<(), InterpreterError> {
+    let stderr = &execution.stderr;
+    let failed = match execution.exit_code {
+        Some(exit_code) => exit_code != 0,
+        None => stderr.contains("Traceback"),
+    };
+    if !failed {
+        return Ok(());
+    }
+
+    let python_code = python_code.to_string();
+    if execution.exit_code == Some(SIGKILL_EXIT_CODE) || stderr.contains("MemoryError") {
+        return Err(InterpreterError::MemoryLimitExceeded {
+            language,
+            python_code,
+        });
+    }
+    if stderr.contains("can't start new thread")
+        || stderr.contains("BlockingIOError: [Errno 11] Resource temporarily unavailable")
+    {
+        return Err(InterpreterError::ProcessLimitExceeded {
+            language,
+            python_code,
+        });
+    }
+    if stderr.contains("Temporary failure in name resolution")
+        || stderr.contains("Network is unreachable")
+        || stderr.contains("Name or service not known")
+    {
+        return Err(InterpreterError::NetworkDisabled {
+            language,
+            python_code,
+        });
+    }
+    Err(InterpreterError::Execution {
+        message: stderr.trim().to_string(),
+        exit_code: execution.exit_code,
+        language,
+        python_code,
+    })
+}
+
+// Bytes of a CSV file read to find its columns
+const CSV_HEADER_MAX_BYTES: usize = 4096;
+
+// Name of a file in the data directory, the uploaded file name when it is usable as a path
+// and not already taken, else prefixed with the file id
+fn sandbox_file_name(filename: &str, file_id: &str, files: &[SandboxFile]) -> String {
+    let name = Path::new(filename)
+        .file_name()
+        .and_then(|name| name.to_str())
+        .map(|name| name.chars().filter(|c| !c.is_control()).collect::<String>())
+        .unwrap_or_default();
+    if name.is_empty() {
+        file_id.to_string()
+    } else if files.iter().any(|file| file.name == name) {
+        format!("{}_{}", file_id, name)
+    } else {
+        name
+    }
+}
+
+/// Fetch the run and assistant files to give to the code. Files that can't be fetched are
+/// skipped.
+pub async fn fetch_sandbox_files(
+    pool: &PgPool,
+    file_storage: &FileStorage,
+    file_ids: &Vec<String>,
+    user_id: &str,
+) -> Vec<SandboxFile> {
+    let mut files = Vec::new();
+    for file_id in file_ids {
+        let bytes = match file_storage.get_file_content(file_id).await {
+            Ok(bytes) => bytes,
+            Err(e) => {
+                error!("Failed to fetch file {} for the code interpreter: {}", file_id, e);
+                continue;
+            }
+        };
+        // Files stored before they had a record are named after their id
+        let filename = match get_file(pool, file_id, user_id).await {
+            Ok(file) => file.inner.filename,
+            Err(_) => file_id.clone(),
+        };
+        let name = sandbox_file_name(&filename, file_id, &files);
+        files.push(SandboxFile { name, bytes });
+    }
+    files
+}
+
+fn csv_columns(file: &SandboxFile) -> Option<Vec<String>> {
+    let name = file.name.to_lowercase();
+    let delimiter = if name.ends_with(".csv") {
+        ','
+    } else if name.ends_with(".tsv") {
+        '\t'
+    } else {
+        return None;
+    };
+    let header = &file.bytes[..file.bytes.len().min(CSV_HEADER_MAX_BYTES)];
+    let header = String::from_utf8_lossy(header);
+    let header = header.lines().next()?;
+    Some(
+        header
+            .split(delimiter)
+            .map(|column| column.trim().trim_matches('"').to_string())
+            .collect(),
+    )
+}
+
+/// Describe the files available to the code for the prompt: path, size and the columns of
+/// CSV and TSV files.
+pub fn describe_files(data_dir: &str, files: &[SandboxFile]) -> String {
+    files
+        .iter()
+        .map(|file| {
+            let mut description =
+                format!("- {}/{} ({} bytes)", data_dir, file.name, file.bytes.len());
+            if let Some(columns) = csv_columns(file) {
+                description.push_str(&format!(", columns: {}", columns.join(", ")));
+            }
+            description
+        })
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+/// The enabled languages without duplicates, Python when none is.
+pub fn enabled_languages(config: &CodeInterpreterConfig) -> Vec<CodeLanguage> {
+    let mut languages: Vec<CodeLanguage> = Vec::new();
+    for language in &config.languages {
+        if !languages.contains(language) {
+            languages.push(*language);
+        }
+    }
+    if languages.is_empty() {
+        languages.push(CodeLanguage::Python);
+    }
+    languages
+}
+
+// Binary JavaScript and R code is run with, images have them on the PATH
+fn runtime(config: &CodeInterpreterConfig, language: CodeLanguage) -> String {
+    let local = config.backend == SandboxBackend::Local;
+    match language {
+        CodeLanguage::Javascript if local => config.local.node.clone(),
+        CodeLanguage::R if local => config.local.rscript.clone(),
+        CodeLanguage::Javascript => "node".to_string(),
+        CodeLanguage::R => "Rscript".to_string(),
+        CodeLanguage::Python | CodeLanguage::Sql => config.local.python.clone(),
+    }
+}
+
+// Name of the SQL view of a file, its lowercase stem with anything else than letters,
+// digits and underscores replaced
+fn sql_view_name(filename: &str) -> String {
+    let stem = Path::new(filename)
+        .file_stem()
+        .and_then(|stem| stem.to_str())
+        .unwrap_or(filename);
+    let name: String = stem
+        .to_lowercase()
+        .chars()
+        .map(|c| if c.is_ascii_alphanumeric() { c } else { '_' })
+        .collect();
+    match name.chars().next() {
+        Some(c) if c.is_ascii_alphabetic() || c == '_' => name,
+        _ => format!("t_{}", name),
+    }
+}
+
+/// The DuckDB views SQL code can query, `(view, reader)` pairs for the CSV, TSV, Parquet
+/// and JSON files. Views taken by an earlier file get a number suffix.
+pub fn sql_views(data_dir: &str, files: &[SandboxFile]) -> Vec<(String, String)> {
+    let mut views: Vec<(String, String)> = Vec::new();
+    for file in files {
+        let path = format!("{}/{}", data_dir, file.name).replace('\'', "''");
+        let extension = Path::new(&file.name)
+            .extension()
+            .and_then(|extension| extension.to_str())
+            .map(|extension| extension.to_lowercase());
+        let reader = match extension.as_deref() {
+            Some("csv") => format!("read_csv_auto('{}')", path),
+            Some("tsv") => format!("read_csv_auto('{}', delim='\\t')", path),
+            Some("parquet") => format!("read_parquet('{}')", path),
+            Some("json") | Some("jsonl") | Some("ndjson") => format!("read_json_auto('{}')", path),
+            _ => continue,
+        };
+        let name = sql_view_name(&file.name);
+        let mut view = name.clone();
+        let mut suffix = 2;
+        while views.iter().any(|(taken, _)| *taken == view) {
+            view = format!("{}_{}", name, suffix);
+            suffix += 1;
+        }
+        views.push((view, reader));
+    }
+    views
+}
+
+// Python string literal of `text`, JSON strings are valid Python strings
+fn python_string(text: &str) -> String {
+    serde_json::to_string(text).unwrap_or_default()
+}
+
+/// The Python program running code written in `language`, which every sandbox can run.
+/// JavaScript and R are run by their runtime in a subprocess whose output and exit code
+/// are passed on. SQL is run by DuckDB with the files as views and the result of the last
+/// statement printed.
+pub fn program(
+    config: &CodeInterpreterConfig,
+    language: CodeLanguage,
+    code: &str,
+    data_dir: &str,
+    files: &[SandboxFile],
+) -> String {
+    match language {
+        CodeLanguage::Python => code.to_string(),
+        CodeLanguage::Javascript | CodeLanguage::R => {
+            let extension = match language {
+                CodeLanguage::R => ".R",
+                _ => ".js",
+            };
+            format!(
+                r#"import os, subprocess, sys, tempfile
+with tempfile.NamedTemporaryFile("w", suffix="{}", dir=os.getcwd(), delete=False) as script:
+    script.write({})
+result = subprocess.run([{}, script.name], capture_output=True, text=True)
+os.remove(script.name)
+sys.stdout.write(result.stdout)
+sys.stderr.write(result.stderr)
+# Killed by a signal, report it like a shell
+sys.exit(result.returncode if result.returncode >= 0 else 128 - result.returncode)
+"#,
+                extension,
+                python_string(code),
+                python_string(&runtime(config, language))
+            )
+        }
+        CodeLanguage::Sql => {
+            let views = sql_views(data_dir, files)
+                .iter()
+                .map(|(view, reader)| {
+                    format!(
+                        "connection.execute({})\n",
+                        python_string(&format!(
+                            "CREATE VIEW \"{}\" AS SELECT * FROM {}",
+                            view, reader
+                        ))
+                    )
+                })
+                .collect::<String>();
+            format!(
+                r#"import duckdb
+connection = duckdb.connect()
+{}result = connection.execute({})
+if result.description is not None:
+    print(result.fetchdf().to_string(index=False))
+"#,
+                views,
+                python_string(code)
+            )
+        }
+    }
+}
+
+// What the model is told about writing code in `language`
+fn language_rules(
+    language: CodeLanguage,
+    data_dir: &str,
+    output_dir: &str,
+    files: &[SandboxFile],
+) -> String {
+    match language {
+        CodeLanguage::Python => "- python: you can use these libraries: math, pandas, numpy, matplotlib, scipy. Print the results with print().".to_string(),
+        CodeLanguage::Javascript => "- javascript: runs with Node.js, only use its standard library. Print the results with console.log().".to_string(),
+        CodeLanguage::R => format!(
+            "- r: runs with Rscript, only use base R. Print the results with print() or cat(), save charts with png('{}/chart.png') and dev.off().",
+            output_dir
+        ),
+        CodeLanguage::Sql => {
+            let views = sql_views(data_dir, files);
+            let tables = if views.is_empty() {
+                "There are no tables.".to_string()
+            } else {
+                format!(
+                    "These views of the files can be queried: {}.",
+                    views
+                        .iter()
+                        .map(|(view, _)| view.as_str())
+                        .collect::<Vec<_>>()
+                        .join(", ")
+                )
+            };
+            format!(
+                "- sql: DuckDB SQL, the result of the last statement is printed. {} Save a result for the user with COPY (SELECT ...) TO '{}/result.csv' (HEADER).",
+                tables, output_dir
+            )
+        }
+    }
+}
+
+/// One generated code and how its run went.
+#[derive(Debug, Clone)]
+pub struct CodeAttempt {
+    pub language: CodeLanguage,
+    pub code: String,
+    pub stdout: String,
+    pub stderr: String,
+    pub exit_code: Option<i64>,
+    /// Why the code failed, `None` when it succeeded
+    pub error: Option<String>,
+    /// Time the code ran for, including the sandbox setup
+    pub duration_ms: u64,
+}
+
+/// Progress of a code interpreter call, sent while it runs so the run steps can follow.
+#[derive(Debug, Clone)]
+pub enum InterpreterEvent {
+    /// A code was generated and is about to run
+    AttemptStarted { language: CodeLanguage, code: String },
+    /// A code ran, successfully or not
+    AttemptFinished(CodeAttempt),
+}
+
+/// Result of a successful code interpreter call.
+#[derive(Debug, Clone)]
+pub struct InterpreterOutput {
+    pub language: CodeLanguage,
+    pub code: String,
+    /// What the code printed on stdout
+    pub output: String,
+    pub stderr: String,
+    /// Files the code created, named by their path relative to `output_dir`
+    pub files: Vec<SandboxFile>,
+    pub output_dir: String,
+    /// Every code that ran, the last one being the successful one
+    pub attempts: Vec<CodeAttempt>,
+}
+
+// Characters of the stderr of a failed code shown to the model
+const MAX_STDERR_FEEDBACK_CHARS: usize = 2000;
+
+// The end of a text, where Python puts the error of a traceback
+fn tail(text: &str, max_chars: usize) -> String {
+    let count = text.chars().count();
+    if count <= max_chars {
+        text.to_string()
+    } else {
+        format!("...{}", text.chars().skip(count - max_chars).collect::<String>())
+    }
+}
+
+// The failed codes for the model to fix
+fn describe_attempts(attempts: &[CodeAttempt]) -> String {
+    attempts
+        .iter()
+        .enumerate()
+        .map(|(index, attempt)| {
+            format!(
+                "<attempt number=\"{}\" language=\"{}\">\n<code>\n{}\n</code>\n<exit_reason>{}</exit_reason>\n<stderr>\n{}\n</stderr>\n</attempt>",
+                index + 1,
+                attempt.language.name(),
+                attempt.code,
+                attempt.error.as_deref().unwrap_or_default(),
+                tail(&attempt.stderr, MAX_STDERR_FEEDBACK_CHARS)
+            )
+        })
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+const IMAGE_EXTENSIONS: [&str; 5] = ["png", "jpg", "jpeg", "gif", "webp"];
+
+pub fn is_image(filename: &str) -> bool {
+    Path::new(filename)
+        .extension()
+        .and_then(|extension| extension.to_str())
+        .map(|extension| IMAGE_EXTENSIONS.contains(&extension.to_lowercase().as_str()))
+        .unwrap_or(false)
+}
+
+/// Turn the mentions of created files in an answer, `sandbox:/tmp/output/chart.png` or
+/// just the path, into file path annotations. `files` are `(path, file_id)` pairs. Indexes
+/// are in characters.
+pub fn extract_file_paths(
+    text: &str,
+    files: &[(String, String)],
+) -> Vec<MessageContentTextAnnotations> {
+    let mut annotations = Vec::new();
+    for (path, file_id) in files {
+        let re = Regex::new(&format!("(?:sandbox:)?{}", regex::escape(path))).unwrap();
+        for mention in re.find_iter(text) {
+            let start_index = text[..mention.start()].chars().count() as u32;
+            let end_index = start_index + mention.as_str().chars().count() as u32;
+            annotations.push(MessageContentTextAnnotations::FilePath(
+                MessageContentTextAnnotationsFilePathObject {
+                    r#type: "file_path".to_string(),
+                    text: mention.as_str().to_string(),
+                    file_path: FilePath {
+                        file_id: file_id.clone(),
+                    },
+                    start_index,
+                    end_index,
+                },
+            ));
+        }
+    }
+    annotations
+}
+
+/// Generate code for the user prompt of `request` in one of the enabled languages and run
+/// it. When the code fails, the model is shown its code, exit reason and stderr to fix it,
+/// up to `config.max_attempts` codes. With a `thread_id`, Python codes run in the session
+/// of the thread, codes in other languages always run from scratch. Each code is reported
+/// to `events` before and after it runs.
 pub async fn safe_interpreter(
-    user_input: String,
-    attempt: usize,
-    max_attempts: usize,
     client: HalLLMClient,
     request: HalLLMRequestArgs,
-) -> Result<(String, String), InterpreterError> {
-    if attempt >= max_attempts {
-        return Err(InterpreterError {
-            message: String::from("Max attempts reached"),
-            python_code: String::new(),
+    config: &CodeInterpreterConfig,
+    files: &[SandboxFile],
+    thread_id: Option<&str>,
+    events: Option<UnboundedSender<InterpreterEvent>>,
+) -> Result<InterpreterOutput, InterpreterError> {
+    // Nobody listening is not a reason to stop running code
+    let report = |event: InterpreterEvent| {
+        if let Some(events) = &events {
+            let _ = events.send(event);
+        }
+    };
+    let sandbox = shared_sandbox(config).await?;
+    // Codes of a thread share a Python session when the sandbox supports it
+    let session = match thread_id {
+        Some(thread_id) => {
+            let sessions = shared_sessions(config).await?;
+            sessions.enabled().then(|| (sessions, thread_id))
+        }
+        None => None,
+    };
+    let languages = enabled_languages(config);
+
+    let mut attempts: Vec<CodeAttempt> = Vec::new();
+    let mut last_error = None;
+    for attempt in 0..config.max_attempts.max(1) {
+        let (language, code) = match generate_code(
+            client.clone(),
+            request.clone(),
+            sandbox,
+            session,
+            &languages,
+            files,
+            &attempts,
+        )
+        .await
+        {
+            Ok(generated) => generated,
+            Err(e) => {
+                error!("Code interpreter attempt {} failed: {}", attempt, e);
+                last_error = Some(e);
+                continue;
+            }
+        };
+
+        report(InterpreterEvent::AttemptStarted {
+            language,
+            code: code.clone(),
         });
-    }
+        let started = Instant::now();
+        let python_code = program(config, language, &code, &sandbox.data_dir(), files);
+        let execution = match session {
+            Some((sessions, thread_id)) if language == CodeLanguage::Python => {
+                sessions.execute(thread_id, &python_code, files).await
+            }
+            _ => sandbox.execute(&python_code, files).await,
+        };
+        let duration_ms = started.elapsed().as_millis() as u64;
+        let execution = match execution {
+            Ok(execution) => execution,
+            // The code ran too long
+            Err(e) if e.is_retryable() => {
+                let e = e.for_code(language, &code);
+                error!("Code interpreter attempt {} failed: {}", attempt, e);
+                let failed = CodeAttempt {
+                    language,
+                    code,
+                    stdout: String::new(),
+                    stderr: String::new(),
+                    exit_code: None,
+                    error: Some(e.exit_reason()),
+                    duration_ms,
+                };
+                report(InterpreterEvent::AttemptFinished(failed.clone()));
+                attempts.push(failed);
+                last_error = Some(e);
+                continue;
+            }
+            Err(e) => return Err(e),
+        };
+        info!("Code interpreter output: {}", execution.stdout);
 
-    match interpreter(client.clone(), request.clone()).await {
-        Ok((code_output, code)) => Ok((code_output, code)),
-        Err(e) => {
-            eprintln!("Error: {}", e);
-            let input = format!(
-                "{}\n<error>You generated \n<code>\n{}\n</code>\n and it failed with error: {}. Please generate a DIFFERENT code that works.<error>",
-                user_input, e.python_code, e.message
-            );
-            safe_interpreter(input, attempt + 1, max_attempts, client, request).await
+        let checked = check_execution(&execution, language, &code);
+        let ExecutionOutput {
+            stdout,
+            stderr,
+            exit_code,
+            files: created_files,
+        } = execution;
+        let finished = CodeAttempt {
+            language,
+            code: code.clone(),
+            stdout: stdout.clone(),
+            stderr: stderr.clone(),
+            exit_code,
+            error: checked.as_ref().err().map(|e| e.exit_reason()),
+            duration_ms,
+        };
+        report(InterpreterEvent::AttemptFinished(finished.clone()));
+        attempts.push(finished);
+        match checked {
+            Ok(_) => {
+                return Ok(InterpreterOutput {
+                    language,
+                    code,
+                    output: stdout,
+                    stderr,
+                    files: created_files,
+                    output_dir: sandbox.output_dir(),
+                    attempts,
+                })
+            }
+            Err(e) => {
+                error!("Code interpreter attempt {} failed: {}", attempt, e);
+                last_error = Some(e);
+            }
         }
     }
+
+    Err(InterpreterError::MaxAttemptsReached {
+        last_error: Box::new(last_error.unwrap_or_else(|| {
+            InterpreterError::CodeGeneration("No code was generated".to_string())
+        })),
+        attempts,
+    })
+}
+
+// Arguments of the `exec` function the model calls
+#[derive(Deserialize)]
+struct ExecArguments {
+    #[serde(default)]
+    language: Option<CodeLanguage>,
+    code: String,
 }
 
-async fn interpreter(
+// Ask the model for code in one of `languages` answering the user prompt of `request`,
+// showing it the failed `attempts` to fix
+async fn generate_code(
     client: HalLLMClient,
     mut request: HalLLMRequestArgs,
-) -> Result<(String, String), InterpreterError> {
-    info!("Generating Python code...");
+    sandbox: &dyn Sandbox,
+    session: Option<(&SessionManager, &str)>,
+    languages: &[CodeLanguage],
+    files: &[SandboxFile],
+    attempts: &[CodeAttempt],
+) -> Result<(CodeLanguage, String), InterpreterError> {
+    info!("Generating code...");
+
+    let output_dir = sandbox.output_dir();
+    let files_rule = if files.is_empty() {
+        "- There are no files on disk. DO NOT TRY TO READ FILES. DO NOT DO THINGS LIKE: pd.read_csv('startups.csv')".to_string()
+    } else {
+        format!(
+            "- These files are available read-only, read them with their full path and do not try to read other files:\n{}",
+            describe_files(&sandbox.data_dir(), files)
+        )
+    };
+    let mut files_rule = format!(
+        "{}\n- Save the files you create for the user (charts, CSV, ...) in {}, e.g. plt.savefig('{}/chart.png'). Do not call plt.show().",
+        files_rule, output_dir, output_dir
+    );
+    let python_session = session.filter(|_| languages.contains(&CodeLanguage::Python));
+    if let Some((sessions, thread_id)) = python_session {
+        let history = sessions.history(thread_id).await;
+        files_rule.push_str(&if history.is_empty() {
+            "\n- Python code runs in a Python session kept for this conversation, the variables you define stay available to your next Python code.".to_string()
+        } else {
+            format!(
+                "\n- Python code runs in a Python session kept for this conversation. This code already ran in it, reuse its variables and imports instead of loading files again:\n<previous_code>\n{}\n</previous_code>",
+                history.join("\n\n")
+            )
+        });
+    }
+    let language_names = languages
+        .iter()
+        .map(|language| language.name())
+        .collect::<Vec<_>>();
+    let languages_rule = format!(
+        "- Write the code in one of these languages, {} unless another one fits the request better:\n{}",
+        languages[0].name(),
+        languages
+            .iter()
+            .map(|language| language_rules(*language, &sandbox.data_dir(), &output_dir, files))
+            .collect::<Vec<_>>()
+            .join("\n")
+    );
 
-    let user_input = request.get_user_prompt().unwrap();
+    let user_input = request.get_user_prompt().ok_or_else(|| {
+        InterpreterError::CodeGeneration("The request has no user prompt".to_string())
+    })?;
+    let attempts_rule = if attempts.is_empty() {
+        String::new()
+    } else {
+        format!(
+            "Your previous codes for this request failed, here they are with why they failed. Fix the error, DO NOT GENERATE THE SAME CODE AGAIN:\n\n{}\n\n",
+            describe_attempts(attempts)
+        )
+    };
     // ! TODO: should use system prompt?
     let build_prompt = |user_input: &str| {
         format!("
-You are an Assistant that generate Python code to based user request to do complex computations. We execute the code you will generate and return the result to the user.
+You are an Assistant that generate code to based user request to do complex computations. We execute the code you will generate and return the result to the user.
 Given this user request
 
 <user>
@@ -113,23 +872,20 @@ Given this user request
 
 </user>
 
-Generate Python code that we will execute and return the result to the user.
+{}Generate code that we will execute and return the result to the user.
 
 Rules:
-- You can use these libraries: mathm, pandas, numpy, matplotlib, scipy. Do not use functions or code you have no access to.
-- Only return Python code. If you return anything else it will trigger a chain reaction that will destroy the universe. All humans will die and you will disappear from existence.
+{}
+- Do not use functions or code you have no access to.
+- Only return code. If you return anything else it will trigger a chain reaction that will destroy the universe. All humans will die and you will disappear from existence.
 - Make sure to use the right numbers e.g. with the user ask for the square root of 2, you should return math.sqrt(2) and not math.sqrt(pd.DataFrame({{'A': [1, 2, 3], 'B': [4, 5, 6]}})).
 - Do not use any library if it's simple math (e.g. no need to use pandas to compute the square root of 2)
-- Sometimes the user provide you an error, make sure to write a Python code that will work
+- Sometimes the user provide you an error, make sure to write code that will work
 - IF YOU DO NOT FIX YOUR CODE THAT ERRORED A HUMAN WILL DIE. DO NOT GENERATE THE SAME CODE THAT PREVIOUSLY FAILED
-- DO NOT USE ```python YOUR CODE...``` (CODE BLOCKS) OR A HUMAN WILL DIE
-- ALWAYS USE SINGLE QUOTES IN YOUR CODE (e.g. '), NOT DOUBLE QUOTES OR A HUMAN WILL DIE
 - Always try to simplify the math problem you're given by generating code that will compute simpler numbers. Your answer might be used by another Assistant that might not be good at math.
-- Be extra careful with escaping, this is wrong for example: import pandas as pd\\nprices = pd.read\\_csv('prices.csv')\\nprice\\_with\\_highest\\_demand = startups['demand'].idxmax()\\nprint(price\\_with\\_highest\\_demand)
-- Make sure to use existing files, by default there is no files written on disk. DO NOT TRY TO READ FILES. YOU DONT HAVE ANY FILES. DO NOT DO THINGS LIKE: pd.read_csv('startups.csv')
-- Make sure to surround strings by single quotes, e.g. don't do this: print(Hello world) but do this: print('Hello world')
+{}
 
-A few examples:
+A few examples in Python:
 
 The user input is: compute the square root of pi
 Your output should be:
@@ -149,7 +905,7 @@ series_a_dilution = (series_a_raise_amount / series_a_post_money_valuation) * 10
 
 print('Founders dilution: ' + str(founders_dilution) + '%')
 
-So generate the Python code that we will execute that can help the user with his request.
+So generate the code that we will execute that can help the user with his request.
 
 Bad example:
 
@@ -167,12 +923,12 @@ It's bad because \"mean\" function is not defined. You could have used: \"mean =
 {}
 
 </user>
-        ", user_input, user_input)
+        ", user_input, attempts_rule, languages_rule, files_rule, user_input)
     };
 
     request.set_last_user_prompt(build_prompt(&user_input));
 
-    // Generate Python code
+    // Generate code
     let function_call_input = FunctionCallInput {
         function: Function {
             metadata: None,
@@ -180,14 +936,22 @@ It's bad because \"mean\" function is not defined. You could have used: \"mean =
             user_id: Uuid::default().to_string(),
             inner: FunctionObject {
                 name: "exec".to_string(),
-                description: Some("A function that executes Python code".to_string()),
+                description: Some(format!(
+                    "A function that executes code written in {}",
+                    language_names.join(", ")
+                )),
                 parameters: Some(json!({
                     "type": "object",
-                    "required": ["code"],
+                    "required": ["language", "code"],
                     "properties": {
+                        "language": {
+                            "type": "string",
+                            "enum": language_names,
+                            "description": "The language the code is written in"
+                        },
                         "code": {
                             "type": "string",
-                            "description": "The Python code to execute"
+                            "description": "The code to execute"
                         }
                     }
                 })),
@@ -199,126 +963,297 @@ It's bad because \"mean\" function is not defined. You could have used: \"mean =
 
     let function_result = generate_function_call(function_call_input)
         .await
-        .map_err(|e| InterpreterError {
-            message: format!("Failed to generate Python code at function call: {}", e),
-            python_code: String::new(),
+        .map_err(|e| {
+            InterpreterError::CodeGeneration(format!("Failed at function call: {}", e))
         })?;
     println!("Function result: {:?}", function_result);
-    let python_code = function_result.arguments;
-    let python_code: HashMap<String, String> = serde_json::from_str(&python_code)?;
-    let python_code = python_code
-        .get("code")
-        .expect("Expected 'code' field in the function result");
-    let python_code = python_code.replace("```python", "").replace("```", "");
-
-    // Connect to Docker
-    let docker = Docker::connect_with_local_defaults()?;
-    // Pull the image from GHCR
-    docker
-        .create_image(
-            Some(CreateImageOptions {
-                from_image: "louis030195/hal-9100-code-interpreter:latest",
-                ..Default::default()
-            }),
-            None,
-            None,
-        )
-        .try_collect::<Vec<_>>()
-        .await?;
-
-    // Create Docker container
-    let config = Config {
-        image: Some("louis030195/hal-9100-code-interpreter:latest"),
-        host_config: Some(HostConfig {
-            auto_remove: Some(true),
-            ..Default::default()
-        }),
-        attach_stdin: Some(true),
-        attach_stdout: Some(true),
-        attach_stderr: Some(true),
-        open_stdin: Some(true),
-        tty: Some(true),
-        ..Default::default()
-    };
+    let arguments: ExecArguments = serde_json::from_str(&function_result.arguments)?;
+    // Models that leave out the language write in the default one
+    let language = arguments.language.unwrap_or(languages[0]);
+    if !languages.contains(&language) {
+        return Err(InterpreterError::CodeGeneration(format!(
+            "The code is written in {}, which is not enabled",
+            language.name()
+        )));
+    }
+    Ok((language, arguments.code))
+}
 
-    let options = CreateContainerOptions {
-        name: "my-python-container",
-    };
-    let container = docker.create_container(Some(options), config).await?;
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use dotenv::dotenv;
+    use hal_9100_core::sandbox::LocalSandbox;
+    use hal_9100_extra::config::LocalSandboxConfig;
+    use hal_9100_extra::openai::Message;
 
-    info!("Starting Docker container...");
+    fn execution(stdout: &str, stderr: &str, exit_code: Option<i64>) -> ExecutionOutput {
+        ExecutionOutput {
+            stdout: stdout.to_string(),
+            stderr: stderr.to_string(),
+            exit_code,
+            files: vec![],
+        }
+    }
 
-    // Start Docker container
-    docker
-        .start_container(&container.id, None::<StartContainerOptions<String>>)
-        .await?;
+    #[test]
+    fn test_check_execution() {
+        let code = "print(1)";
+        let python = CodeLanguage::Python;
+        assert!(check_execution(&execution("1\n", "", Some(0)), python, code).is_ok());
+        // Printing a traceback or warnings is not failing
+        assert!(check_execution(
+            &execution("Traceback (most recent call last):", "DeprecationWarning", Some(0)),
+            python,
+            code
+        )
+        .is_ok());
+        assert!(matches!(
+            check_execution(&execution("", "", Some(137)), python, code),
+            Err(InterpreterError::MemoryLimitExceeded { .. })
+        ));
+        assert!(matches!(
+            check_execution(
+                &execution(
+                    "",
+                    "Traceback (most recent call last):\nRuntimeError: can't start new thread",
+                    Some(1)
+                ),
+                python,
+                code
+            ),
+            Err(InterpreterError::ProcessLimitExceeded { .. })
+        ));
+        assert!(matches!(
+            check_execution(
+                &execution(
+                    "",
+                    "Traceback (most recent call last):\nsocket.gaierror: [Errno -3] Temporary failure in name resolution",
+                    Some(1)
+                ),
+                python,
+                code
+            ),
+            Err(InterpreterError::NetworkDisabled { .. })
+        ));
+        let error = check_execution(
+            &execution(
+                "",
+                "Traceback (most recent call last):\nNameError: name 'x' is not defined\n",
+                Some(1),
+            ),
+            python,
+            code,
+        )
+        .unwrap_err();
+        assert!(matches!(
+            &error,
+            InterpreterError::Execution { message, exit_code: Some(1), .. }
+                if message.ends_with("NameError: name 'x' is not defined")
+        ));
+        assert_eq!(error.python_code(), Some(code));
+        assert_eq!(error.exit_reason(), "exited with code 1");
+        assert!(error.is_retryable());
 
-    // Write Python code to a file in the Docker container and execute it
-    let python_file_path = "/tmp/script.py";
-    let bash_command = format!(
-        "echo -e \"{}\" > {} && python {}",
-        python_code, python_file_path, python_file_path
-    );
+        // sys.exit(2) without any error output
+        let error = check_execution(&execution("", "", Some(2)), python, code).unwrap_err();
+        assert_eq!(error.exit_reason(), "exited with code 2");
+        // Runners without exit codes
+        assert!(check_execution(&execution("ok", "", None), python, code).is_ok());
+        assert!(check_execution(
+            &execution("", "Traceback (most recent call last):", None),
+            python,
+            code
+        )
+        .is_err());
 
-    let exec = docker
-        .create_exec(
-            &container.id,
-            CreateExecOptions {
-                attach_stdout: Some(true),
-                attach_stderr: Some(true),
-                cmd: Some(vec!["/bin/bash", "-c", &bash_command]),
-                ..Default::default()
-            },
+        // Errors name the language of the code
+        let error = check_execution(
+            &execution("", "ReferenceError: x is not defined", Some(1)),
+            CodeLanguage::Javascript,
+            "console.log(x)",
         )
-        .await?
-        .id;
-    let mut exec_stream_result = docker.start_exec(&exec, None);
-
-    let mut output = String::new();
-    while let Some(Ok(msg)) = exec_stream_result.next().await {
-        match msg {
-            StartExecResults::Attached { log, .. } => match log {
-                LogOutput::StdOut { message } => {
-                    output.push_str(&String::from_utf8(message.to_vec()).unwrap());
-                }
-                LogOutput::StdErr { message } => {
-                    output.push_str(&String::from_utf8(message.to_vec()).unwrap());
-                }
-                _ => (),
-            },
-            _ => (),
+        .unwrap_err();
+        assert!(error
+            .to_string()
+            .starts_with("javascript code execution failed with exit code 1"));
+        let error = InterpreterError::Timeout {
+            timeout_secs: 30,
+            language: python,
+            python_code: "import duckdb".to_string(),
         }
+        .for_code(CodeLanguage::Sql, "SELECT 1");
+        assert_eq!(
+            error.to_string(),
+            "sql code execution timed out after 30 seconds\nsql code: SELECT 1"
+        );
     }
 
-    info!("Code interpreter output: {}", output);
+    #[test]
+    fn test_describe_attempts() {
+        let attempts = vec![CodeAttempt {
+            language: CodeLanguage::Python,
+            code: "print(x)".to_string(),
+            stdout: String::new(),
+            stderr: format!("{}NameError: name 'x' is not defined", "a".repeat(3000)),
+            exit_code: Some(1),
+            error: Some("exited with code 1".to_string()),
+            duration_ms: 12,
+        }];
 
-    // remove container
-    docker
-        .remove_container(
-            &container.id,
-            Some(RemoveContainerOptions {
-                force: true,
-                ..Default::default()
-            }),
-        )
-        .await?;
+        let description = describe_attempts(&attempts);
+        assert!(description.starts_with(
+            "<attempt number=\"1\" language=\"python\">\n<code>\nprint(x)\n</code>"
+        ));
+        assert!(description.contains("<exit_reason>exited with code 1</exit_reason>"));
+        assert!(description.contains("NameError: name 'x' is not defined\n</stderr>"));
+        assert!(description.len() < 2100);
+    }
 
-    // Check if the output contains "Traceback", indicating a Python error
-    if output.contains("Traceback") {
-        return Err(InterpreterError {
-            message: format!("Python code execution failed with error: {}", output),
-            python_code: python_code.to_string(),
+    #[test]
+    fn test_describe_files() {
+        let mut files = vec![SandboxFile {
+            name: sandbox_file_name("../reports/startups.csv", "file-1.csv", &[]),
+            bytes: "Startup,\"Revenue\"\nA,1\n".into(),
+        }];
+        files.push(SandboxFile {
+            name: sandbox_file_name("startups.csv", "file-2.csv", &files),
+            bytes: "x".into(),
+        });
+        files.push(SandboxFile {
+            name: sandbox_file_name("..", "file-3.txt", &files),
+            bytes: "x".into(),
         });
+
+        assert_eq!(
+            describe_files("/mnt/data", &files),
+            "- /mnt/data/startups.csv (22 bytes), columns: Startup, Revenue
+- /mnt/data/file-2.csv_startups.csv (1 bytes), columns: x
+- /mnt/data/file-3.txt (1 bytes)"
+        );
     }
 
-    Ok((output, python_code.to_string()))
-}
+    #[test]
+    fn test_sql_views() {
+        let file = |name: &str| SandboxFile {
+            name: name.to_string(),
+            bytes: "x".into(),
+        };
+        let files = vec![
+            file("Sales 2023.csv"),
+            file("sales-2023.parquet"),
+            file("2024.tsv"),
+            file("notes.txt"),
+            file("o'neil.json"),
+        ];
 
-#[cfg(test)]
-mod tests {
-    use super::*;
-    use dotenv::dotenv;
-    use hal_9100_extra::openai::Message;
+        assert_eq!(
+            sql_views("/mnt/data", &files),
+            vec![
+                (
+                    "sales_2023".to_string(),
+                    "read_csv_auto('/mnt/data/Sales 2023.csv')".to_string()
+                ),
+                (
+                    "sales_2023_2".to_string(),
+                    "read_parquet('/mnt/data/sales-2023.parquet')".to_string()
+                ),
+                (
+                    "t_2024".to_string(),
+                    "read_csv_auto('/mnt/data/2024.tsv', delim='\\t')".to_string()
+                ),
+                (
+                    "o_neil".to_string(),
+                    "read_json_auto('/mnt/data/o''neil.json')".to_string()
+                ),
+            ]
+        );
+
+        let program = program(
+            &CodeInterpreterConfig::default(),
+            CodeLanguage::Sql,
+            "SELECT \"a\" FROM sales_2023",
+            "/mnt/data",
+            &files[..1],
+        );
+        assert!(program.contains(
+            r#"connection.execute("CREATE VIEW \"sales_2023\" AS SELECT * FROM read_csv_auto('/mnt/data/Sales 2023.csv')")"#
+        ));
+        assert!(program.contains(r#"result = connection.execute("SELECT \"a\" FROM sales_2023")"#));
+    }
+
+    #[tokio::test]
+    async fn test_program_runs_other_runtimes() {
+        // Any runtime can stand in for Node.js, Python is the one every test machine has
+        let config = CodeInterpreterConfig {
+            backend: SandboxBackend::Local,
+            local: LocalSandboxConfig {
+                node: "python3".to_string(),
+                bubblewrap: false,
+                ..Default::default()
+            },
+            timeout_secs: 10,
+            ..Default::default()
+        };
+        let sandbox = LocalSandbox::new(&config);
+
+        let code = "import sys\nprint(\"it's \\\"quoted\\\"\")\nsys.stderr.write('oops')\nsys.exit(3)";
+        let python_code = program(&config, CodeLanguage::Javascript, code, "data", &[]);
+        let execution = sandbox.execute(&python_code, &[]).await.unwrap();
+        assert_eq!(execution.stdout, "it's \"quoted\"\n");
+        assert_eq!(execution.stderr, "oops");
+        assert_eq!(execution.exit_code, Some(3));
+        // The script file is removed
+        assert!(execution.files.is_empty());
+    }
+
+    #[test]
+    fn test_enabled_languages() {
+        let config = CodeInterpreterConfig {
+            languages: vec![CodeLanguage::Sql, CodeLanguage::Python, CodeLanguage::Sql],
+            ..Default::default()
+        };
+        assert_eq!(
+            enabled_languages(&config),
+            vec![CodeLanguage::Sql, CodeLanguage::Python]
+        );
+        let config = CodeInterpreterConfig {
+            languages: vec![],
+            ..Default::default()
+        };
+        assert_eq!(enabled_languages(&config), vec![CodeLanguage::Python]);
+    }
+
+    #[test]
+    fn test_extract_file_paths() {
+        let text = "Here is the chart: [chart](sandbox:/tmp/output/chart.png), data in /tmp/output/data.csv";
+        let files = vec![
+            ("/tmp/output/chart.png".to_string(), "file-chart".to_string()),
+            ("/tmp/output/data.csv".to_string(), "file-data".to_string()),
+            ("/tmp/output/unused.txt".to_string(), "file-unused".to_string()),
+        ];
+
+        let annotations = extract_file_paths(text, &files);
+        assert_eq!(annotations.len(), 2);
+        match &annotations[0] {
+            MessageContentTextAnnotations::FilePath(annotation) => {
+                assert_eq!(annotation.text, "sandbox:/tmp/output/chart.png");
+                assert_eq!(annotation.file_path.file_id, "file-chart");
+                assert_eq!(annotation.start_index, 27);
+                assert_eq!(annotation.end_index, 56);
+            }
+            _ => panic!("Expected a file path annotation"),
+        }
+        match &annotations[1] {
+            MessageContentTextAnnotations::FilePath(annotation) => {
+                assert_eq!(annotation.text, "/tmp/output/data.csv");
+                assert_eq!(annotation.file_path.file_id, "file-data");
+            }
+            _ => panic!("Expected a file path annotation"),
+        }
+        assert!(is_image("charts/Plot.PNG"));
+        assert!(!is_image("data.csv"));
+    }
 
     #[tokio::test]
     async fn test_interpreter() {
@@ -371,14 +1306,26 @@ mod tests {
                 role: "user".to_string(),
                 content: input.to_string(),
             }]);
-            let result = safe_interpreter(input.to_string(), 0, 3, client.clone(), request).await;
+            let result = safe_interpreter(
+                client.clone(),
+                request,
+                &CodeInterpreterConfig::default(),
+                &[],
+                None,
+                None,
+            )
+            .await;
             assert!(
                 result.is_ok(),
                 "Failed on input: {} error: {:?}",
                 input,
                 result
             );
-            let (code_output, code) = result.unwrap();
+            let InterpreterOutput {
+                output: code_output,
+                code,
+                ..
+            } = result.unwrap();
             println!(
                 "Problem to solve: {}. \nOutput: {}\nExpected output: {}",
                 input, code_output, expected_output
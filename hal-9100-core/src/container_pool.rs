@@ -0,0 +1,857 @@
+use async_trait::async_trait;
+use bollard::container::{
+    Config, CreateContainerOptions, InspectContainerOptions, LogOutput, RemoveContainerOptions,
+    StartContainerOptions, UploadToContainerOptions,
+};
+use bollard::exec::{CreateExecOptions, StartExecOptions, StartExecResults};
+use bollard::image::CreateImageOptions;
+use bollard::models::HostConfig;
+use bollard::Docker;
+use futures::stream::StreamExt;
+use futures::TryStreamExt;
+use hal_9100_core::code_interpreter::{InterpreterError, SIGKILL_EXIT_CODE};
+use hal_9100_core::sandbox::{
+    ExecutionOutput, KernelResponse, Sandbox, SandboxFile, CODE_DIR, DATA_DIR, KERNEL_NAME,
+    KERNEL_SCRIPT, KERNEL_SOCKET_NAME, MAX_OUTPUT_FILES, OUTPUT_DIR, SCRIPT_NAME, WORKDIR,
+};
+use hal_9100_extra::config::{CodeInterpreterConfig, CodeLanguage};
+use log::{error, info, warn};
+use std::collections::{HashMap, HashSet};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::sync::{OnceCell, Semaphore};
+use uuid::Uuid;
+
+struct PooledContainer {
+    id: String,
+    uses: usize,
+}
+
+// A container dedicated to a session and the exec running its kernel
+struct SessionContainer {
+    id: String,
+    kernel_exec: String,
+    // Names of the files already in the data directory
+    files: HashSet<String>,
+}
+
+// Name of the code file of a session run
+const CELL_NAME: &str = "cell.py";
+// Checks that the kernel of a session listens, 100ms apart
+const KERNEL_START_ATTEMPTS: usize = 100;
+// Kill every process but the init of the container and wait until only zombies are left, the
+// exit code is 1 with the processes still alive after 1s
+const KILL_PROCESSES_SCRIPT: &str = r#"
+for attempt in 1 2 3 4 5 6 7 8 9 10; do
+    kill -9 -1 2>/dev/null
+    alive=""
+    for dir in /proc/[0-9]*; do
+        pid=${dir#/proc/}
+        [ "$pid" = 1 ] || [ "$pid" = $$ ] && continue
+        state=""
+        while read -r key value; do
+            if [ "$key" = "State:" ]; then state=${value%% *}; break; fi
+        done < "$dir/status" 2>/dev/null
+        [ -n "$state" ] && [ "$state" != Z ] && alive="$alive $pid"
+    done
+    [ -z "$alive" ] && exit 0
+    sleep 0.1
+done
+echo "processes still running:$alive"
+exit 1
+"#;
+
+struct ExecResult {
+    // stdout and stderr interleaved as they were written
+    output: String,
+    stdout: Vec<u8>,
+    stderr: Vec<u8>,
+    exit_code: Option<i64>,
+}
+
+/// The Docker sandbox, a pool of started containers the code interpreter runs code in.
+/// Containers are health checked before being handed out, their working directory is
+/// wiped after each run and they are replaced after `max_uses_per_container` runs or when
+/// a run was killed. At most `pool_size` codes run at the same time. Sessions get their
+/// own container outside of the pool, kept until the session is closed.
+pub struct ContainerPool {
+    docker: Docker,
+    config: CodeInterpreterConfig,
+    idle: Mutex<Vec<PooledContainer>>,
+    slots: Semaphore,
+    image_pulled: OnceCell<()>,
+    sessions: Mutex<HashMap<String, Arc<tokio::sync::Mutex<SessionContainer>>>>,
+}
+
+impl ContainerPool {
+    pub fn new(config: &CodeInterpreterConfig) -> Result<Self, InterpreterError> {
+        Ok(ContainerPool {
+            docker: Docker::connect_with_local_defaults()?,
+            config: config.clone(),
+            idle: Mutex::new(Vec::new()),
+            slots: Semaphore::new(config.pool_size.max(1)),
+            image_pulled: OnceCell::new(),
+            sessions: Mutex::new(HashMap::new()),
+        })
+    }
+
+    async fn pull_image(&self) -> Result<(), InterpreterError> {
+        self.image_pulled
+            .get_or_try_init(|| async {
+                info!("Pulling code interpreter image {}", self.config.image);
+                self.docker
+                    .create_image(
+                        Some(CreateImageOptions {
+                            from_image: self.config.image.as_str(),
+                            ..Default::default()
+                        }),
+                        None,
+                        None,
+                    )
+                    .try_collect::<Vec<_>>()
+                    .await?;
+                Ok::<_, InterpreterError>(())
+            })
+            .await?;
+        Ok(())
+    }
+
+    async fn start_container(&self) -> Result<PooledContainer, InterpreterError> {
+        self.pull_image().await?;
+
+        // Anonymous volumes the script and files are uploaded to, copying into a tmpfs or a
+        // read-only root filesystem is not supported by Docker. They are owned by root so the
+        // code cannot change them.
+        let mut volumes = HashMap::new();
+        volumes.insert(CODE_DIR, HashMap::new());
+        volumes.insert(DATA_DIR, HashMap::new());
+
+        let config_container = Config {
+            image: Some(self.config.image.as_str()),
+            user: Some(self.config.user.as_str()),
+            working_dir: Some(WORKDIR),
+            // The root filesystem may be read-only, point caches to the writable workdir
+            env: Some(vec!["HOME=/tmp", "MPLCONFIGDIR=/tmp"]),
+            network_disabled: Some(self.config.network_mode == "none"),
+            volumes: Some(volumes),
+            host_config: Some(sandbox_host_config(&self.config)),
+            attach_stdin: Some(true),
+            attach_stdout: Some(true),
+            attach_stderr: Some(true),
+            open_stdin: Some(true),
+            tty: Some(true),
+            ..Default::default()
+        };
+
+        let container_name = format!("hal-9100-code-interpreter-{}", Uuid::new_v4());
+        let options = CreateContainerOptions {
+            name: container_name.as_str(),
+        };
+        let container = self
+            .docker
+            .create_container(Some(options), config_container)
+            .await?;
+
+        info!("Starting Docker container {}...", container_name);
+        if let Err(e) = self
+            .docker
+            .start_container(&container.id, None::<StartContainerOptions<String>>)
+            .await
+        {
+            self.remove(&container.id).await;
+            return Err(e.into());
+        }
+        if let Err(e) = self.reset(&container.id).await {
+            self.remove(&container.id).await;
+            return Err(e);
+        }
+
+        Ok(PooledContainer {
+            id: container.id,
+            uses: 0,
+        })
+    }
+
+    // Take a healthy idle container or start a new one
+    async fn acquire(&self) -> Result<PooledContainer, InterpreterError> {
+        loop {
+            let container = self.idle.lock().unwrap().pop();
+            match container {
+                Some(container) => {
+                    if self.is_healthy(&container.id).await {
+                        return Ok(container);
+                    }
+                    warn!("Code interpreter container {} is unhealthy", container.id);
+                    self.remove(&container.id).await;
+                }
+                None => return self.start_container().await,
+            }
+        }
+    }
+
+    async fn release(&self, container: PooledContainer, reusable: bool) {
+        if reusable {
+            match self.reset(&container.id).await {
+                Ok(_) => {
+                    self.idle.lock().unwrap().push(container);
+                    return;
+                }
+                Err(e) => warn!(
+                    "Failed to reset code interpreter container {}: {}",
+                    container.id, e
+                ),
+            }
+        }
+        info!("Recycling code interpreter container {}", container.id);
+        self.remove(&container.id).await;
+    }
+
+    async fn is_healthy(&self, container_id: &str) -> bool {
+        match self
+            .docker
+            .inspect_container(container_id, None::<InspectContainerOptions>)
+            .await
+        {
+            Ok(container) => container
+                .state
+                .and_then(|state| state.running)
+                .unwrap_or(false),
+            Err(_) => false,
+        }
+    }
+
+    // Kill what the previous run left running, remove what it wrote to the working directory
+    // and the files it was given, which may belong to another user, then create the output
+    // directory
+    async fn reset(&self, container_id: &str) -> Result<(), InterpreterError> {
+        // Processes started by the code survive it, they could read the files of the next run
+        let execution = self
+            .exec(container_id, vec!["sh", "-c", KILL_PROCESSES_SCRIPT], None)
+            .await?;
+        if execution.exit_code != Some(0) {
+            return Err(InterpreterError::Sandbox(format!(
+                "Failed to kill the processes left by the previous run: {}",
+                execution.output
+            )));
+        }
+        let directories = [
+            (WORKDIR, None),
+            (DATA_DIR, Some("root")),
+            (CODE_DIR, Some("root")),
+        ];
+        for (directory, user) in directories {
+            let execution = self
+                .exec(
+                    container_id,
+                    vec!["find", directory, "-mindepth", "1", "-delete"],
+                    user,
+                )
+                .await?;
+            if execution.exit_code != Some(0) {
+                return Err(InterpreterError::Sandbox(format!(
+                    "Failed to clean {}: {}",
+                    directory, execution.output
+                )));
+            }
+        }
+        let execution = self
+            .exec(container_id, vec!["mkdir", "-p", OUTPUT_DIR], None)
+            .await?;
+        if execution.exit_code != Some(0) {
+            return Err(InterpreterError::Sandbox(format!(
+                "Failed to create {}: {}",
+                OUTPUT_DIR, execution.output
+            )));
+        }
+        Ok(())
+    }
+
+    // Files the code saved in the output directory. They are read through an exec as they
+    // live in a tmpfs, which Docker can't copy from.
+    async fn harvest(&self, container_id: &str) -> Result<Vec<SandboxFile>, InterpreterError> {
+        let execution = self
+            .exec(container_id, vec!["tar", "-cf", "-", "-C", OUTPUT_DIR, "."], None)
+            .await?;
+        if execution.exit_code != Some(0) {
+            return Err(InterpreterError::Sandbox(format!(
+                "Failed to read created files: {}",
+                execution.output
+            )));
+        }
+        read_tar_files(&execution.stdout)
+            .map_err(|e| InterpreterError::Sandbox(format!("Failed to read created files: {}", e)))
+    }
+
+    async fn clear_output(&self, container_id: &str) -> Result<(), InterpreterError> {
+        let execution = self
+            .exec(
+                container_id,
+                vec!["find", OUTPUT_DIR, "-mindepth", "1", "-delete"],
+                None,
+            )
+            .await?;
+        if execution.exit_code != Some(0) {
+            return Err(InterpreterError::Sandbox(format!(
+                "Failed to clean created files: {}",
+                execution.output
+            )));
+        }
+        Ok(())
+    }
+
+    // Start the kernel of a session in the background and wait for it to listen
+    async fn start_kernel(&self, container_id: &str) -> Result<String, InterpreterError> {
+        self.upload(
+            container_id,
+            CODE_DIR,
+            &[(KERNEL_NAME, KERNEL_SCRIPT.as_bytes())],
+            0o644,
+        )
+        .await?;
+        let kernel_path = format!("{}/{}", CODE_DIR, KERNEL_NAME);
+        let socket_path = format!("{}/{}", WORKDIR, KERNEL_SOCKET_NAME);
+        let exec = self
+            .docker
+            .create_exec(
+                container_id,
+                CreateExecOptions {
+                    cmd: Some(vec![
+                        "python",
+                        kernel_path.as_str(),
+                        "serve",
+                        socket_path.as_str(),
+                    ]),
+                    ..Default::default()
+                },
+            )
+            .await?
+            .id;
+        self.docker
+            .start_exec(&exec, Some(StartExecOptions { detach: true }))
+            .try_collect::<Vec<_>>()
+            .await?;
+
+        for _ in 0..KERNEL_START_ATTEMPTS {
+            let execution = self
+                .exec(container_id, vec!["test", "-S", socket_path.as_str()], None)
+                .await?;
+            if execution.exit_code == Some(0) {
+                return Ok(exec);
+            }
+            tokio::time::sleep(Duration::from_millis(100)).await;
+        }
+        Err(InterpreterError::Sandbox(
+            "The session kernel did not start".to_string(),
+        ))
+    }
+
+    fn session(
+        &self,
+        session: &str,
+    ) -> Result<Arc<tokio::sync::Mutex<SessionContainer>>, InterpreterError> {
+        self.sessions
+            .lock()
+            .unwrap()
+            .get(session)
+            .cloned()
+            .ok_or_else(|| InterpreterError::Sandbox(format!("Unknown session {}", session)))
+    }
+
+    async fn run_in_session(
+        &self,
+        session: &mut SessionContainer,
+        python_code: &str,
+        files: &[SandboxFile],
+    ) -> Result<ExecutionOutput, InterpreterError> {
+        let new_files: Vec<(&str, &[u8])> = files
+            .iter()
+            .filter(|file| !session.files.contains(&file.name))
+            .map(|file| (file.name.as_str(), file.bytes.as_ref()))
+            .collect();
+        if !new_files.is_empty() {
+            self.upload(&session.id, DATA_DIR, &new_files, 0o444).await?;
+            session
+                .files
+                .extend(new_files.iter().map(|(name, _)| name.to_string()));
+        }
+        self.upload(
+            &session.id,
+            CODE_DIR,
+            &[(CELL_NAME, python_code.as_bytes())],
+            0o644,
+        )
+        .await?;
+
+        let kernel_path = format!("{}/{}", CODE_DIR, KERNEL_NAME);
+        let socket_path = format!("{}/{}", WORKDIR, KERNEL_SOCKET_NAME);
+        let cell_path = format!("{}/{}", CODE_DIR, CELL_NAME);
+        let execution = self
+            .exec(
+                &session.id,
+                vec![
+                    "python",
+                    kernel_path.as_str(),
+                    "send",
+                    socket_path.as_str(),
+                    cell_path.as_str(),
+                ],
+                None,
+            )
+            .await?;
+        let (stdout, stderr, exit_code) = match serde_json::from_slice::<KernelResponse>(&execution.stdout) {
+            Ok(response) => {
+                let exit_code = response.exit_code();
+                (response.stdout, response.stderr, exit_code)
+            }
+            // The kernel died while running the code, e.g. it went over the memory limit
+            Err(_) => {
+                let kernel = self.docker.inspect_exec(&session.kernel_exec).await?;
+                if kernel.running == Some(true) {
+                    return Err(InterpreterError::Sandbox(format!(
+                        "The session kernel did not answer: {}",
+                        execution.output
+                    )));
+                }
+                (String::new(), String::new(), kernel.exit_code)
+            }
+        };
+
+        // Files are returned once, the next codes only report the files they create
+        let files = self.harvest(&session.id).await?;
+        if !files.is_empty() {
+            self.clear_output(&session.id).await?;
+        }
+        Ok(ExecutionOutput {
+            stdout,
+            stderr,
+            exit_code,
+            files,
+        })
+    }
+
+    async fn remove(&self, container_id: &str) {
+        // Removing also kills the code if it is still running
+        if let Err(e) = self
+            .docker
+            .remove_container(
+                container_id,
+                Some(RemoveContainerOptions {
+                    force: true,
+                    v: true,
+                    ..Default::default()
+                }),
+            )
+            .await
+        {
+            error!("Failed to remove container {}: {}", container_id, e);
+        }
+    }
+
+    async fn upload(
+        &self,
+        container_id: &str,
+        directory: &str,
+        files: &[(&str, &[u8])],
+        mode: u32,
+    ) -> Result<(), InterpreterError> {
+        let archive = tar_archive(files, mode)
+            .map_err(|e| InterpreterError::Sandbox(format!("Failed to archive files: {}", e)))?;
+        self.docker
+            .upload_to_container(
+                container_id,
+                Some(UploadToContainerOptions {
+                    path: directory,
+                    ..Default::default()
+                }),
+                archive.into(),
+            )
+            .await?;
+        Ok(())
+    }
+
+    // Upload the Python code as a file and execute it without going through a shell, so the
+    // code does not need any quoting or escaping
+    async fn run_in(
+        &self,
+        container_id: &str,
+        python_code: &str,
+        files: &[SandboxFile],
+    ) -> Result<ExecutionOutput, InterpreterError> {
+        if !files.is_empty() {
+            let files: Vec<(&str, &[u8])> = files
+                .iter()
+                .map(|file| (file.name.as_str(), file.bytes.as_ref()))
+                .collect();
+            self.upload(container_id, DATA_DIR, &files, 0o444).await?;
+        }
+        self.upload(
+            container_id,
+            CODE_DIR,
+            &[(SCRIPT_NAME, python_code.as_bytes())],
+            0o644,
+        )
+        .await?;
+
+        let python_file_path = format!("{}/{}", CODE_DIR, SCRIPT_NAME);
+        let execution = self
+            .exec(container_id, vec!["python", python_file_path.as_str()], None)
+            .await?;
+        let files = self.harvest(container_id).await?;
+        Ok(ExecutionOutput {
+            stdout: String::from_utf8_lossy(&execution.stdout).to_string(),
+            stderr: String::from_utf8_lossy(&execution.stderr).to_string(),
+            exit_code: execution.exit_code,
+            files,
+        })
+    }
+
+    async fn exec(
+        &self,
+        container_id: &str,
+        cmd: Vec<&str>,
+        user: Option<&str>,
+    ) -> Result<ExecResult, InterpreterError> {
+        let exec = self
+            .docker
+            .create_exec(
+                container_id,
+                CreateExecOptions {
+                    attach_stdout: Some(true),
+                    attach_stderr: Some(true),
+                    cmd: Some(cmd),
+                    user,
+                    ..Default::default()
+                },
+            )
+            .await?
+            .id;
+        let mut exec_stream_result = self.docker.start_exec(&exec, None);
+
+        let mut output = String::new();
+        let mut stdout = Vec::new();
+        let mut stderr = Vec::new();
+        while let Some(Ok(msg)) = exec_stream_result.next().await {
+            match msg {
+                StartExecResults::Attached { log, .. } => match log {
+                    LogOutput::StdOut { message } => {
+                        output.push_str(&String::from_utf8_lossy(&message));
+                        stdout.extend_from_slice(&message);
+                    }
+                    LogOutput::StdErr { message } => {
+                        output.push_str(&String::from_utf8_lossy(&message));
+                        stderr.extend_from_slice(&message);
+                    }
+                    _ => (),
+                },
+                _ => (),
+            }
+        }
+        let exit_code = self.docker.inspect_exec(&exec).await?.exit_code;
+        Ok(ExecResult {
+            output,
+            stdout,
+            stderr,
+            exit_code,
+        })
+    }
+}
+
+#[async_trait]
+impl Sandbox for ContainerPool {
+    /// Pull the image and start containers until the pool is full, so the first runs do
+    /// not pay for it.
+    async fn warm(&self) -> Result<(), InterpreterError> {
+        self.pull_image().await?;
+        let missing = self
+            .config
+            .pool_size
+            .saturating_sub(self.idle.lock().unwrap().len());
+        info!("Starting {} code interpreter containers", missing);
+        for _ in 0..missing {
+            let container = self.start_container().await?;
+            self.idle.lock().unwrap().push(container);
+        }
+        Ok(())
+    }
+
+    /// Run Python code in a container of the pool, waiting for one to be free.
+    async fn execute(
+        &self,
+        python_code: &str,
+        files: &[SandboxFile],
+    ) -> Result<ExecutionOutput, InterpreterError> {
+        let _slot = self
+            .slots
+            .acquire()
+            .await
+            .map_err(|e| InterpreterError::Sandbox(e.to_string()))?;
+        let mut container = self.acquire().await?;
+
+        let result = tokio::time::timeout(
+            Duration::from_secs(self.config.timeout_secs),
+            self.run_in(&container.id, python_code, files),
+        )
+        .await;
+        container.uses += 1;
+
+        let result = match result {
+            Ok(result) => result,
+            Err(_) => Err(InterpreterError::Timeout {
+                timeout_secs: self.config.timeout_secs,
+                language: CodeLanguage::Python,
+                python_code: python_code.to_string(),
+            }),
+        };
+
+        // Killed or timed out code may leave processes and files behind, start fresh instead
+        let reusable = matches!(&result, Ok(execution) if execution.exit_code != Some(SIGKILL_EXIT_CODE))
+            && container.uses < self.config.max_uses_per_container;
+        self.release(container, reusable).await;
+
+        result
+    }
+
+    fn supports_sessions(&self) -> bool {
+        true
+    }
+
+    async fn start_session(&self) -> Result<String, InterpreterError> {
+        let container = self.start_container().await?;
+        let kernel_exec = match self.start_kernel(&container.id).await {
+            Ok(kernel_exec) => kernel_exec,
+            Err(e) => {
+                self.remove(&container.id).await;
+                return Err(e);
+            }
+        };
+        let id = Uuid::new_v4().to_string();
+        info!(
+            "Started code interpreter session {} in container {}",
+            id, container.id
+        );
+        self.sessions.lock().unwrap().insert(
+            id.clone(),
+            Arc::new(tokio::sync::Mutex::new(SessionContainer {
+                id: container.id,
+                kernel_exec,
+                files: HashSet::new(),
+            })),
+        );
+        Ok(id)
+    }
+
+    async fn execute_in_session(
+        &self,
+        session: &str,
+        python_code: &str,
+        files: &[SandboxFile],
+    ) -> Result<ExecutionOutput, InterpreterError> {
+        let session = self.session(session)?;
+        let mut session = session.lock().await;
+        tokio::time::timeout(
+            Duration::from_secs(self.config.timeout_secs),
+            self.run_in_session(&mut session, python_code, files),
+        )
+        .await
+        .unwrap_or_else(|_| {
+            Err(InterpreterError::Timeout {
+                timeout_secs: self.config.timeout_secs,
+                language: CodeLanguage::Python,
+                python_code: python_code.to_string(),
+            })
+        })
+    }
+
+    async fn close_session(&self, session: &str) {
+        let session = match self.sessions.lock().unwrap().remove(session) {
+            Some(session) => session,
+            None => return,
+        };
+        let session = session.lock().await;
+        self.remove(&session.id).await;
+    }
+}
+
+/// Build an in-memory tar archive of `(name, content)` files, all with the given mode.
+fn tar_archive(files: &[(&str, &[u8])], mode: u32) -> std::io::Result<Vec<u8>> {
+    let mut builder = tar::Builder::new(Vec::new());
+    for (name, content) in files {
+        let mut header = tar::Header::new_gnu();
+        header.set_size(content.len() as u64);
+        header.set_mode(mode);
+        header.set_cksum();
+        builder.append_data(&mut header, name, *content)?;
+    }
+    builder.into_inner()
+}
+
+/// Read the regular files of a tar archive, named by their path without the leading `./`.
+fn read_tar_files(archive: &[u8]) -> std::io::Result<Vec<SandboxFile>> {
+    let mut files = Vec::new();
+    let mut archive = tar::Archive::new(archive);
+    for entry in archive.entries()? {
+        let mut entry = entry?;
+        if !entry.header().entry_type().is_file() {
+            continue;
+        }
+        if files.len() >= MAX_OUTPUT_FILES {
+            warn!("Ignoring created files beyond {}", MAX_OUTPUT_FILES);
+            break;
+        }
+        let path = entry.path()?;
+        let name = path
+            .strip_prefix(".")
+            .unwrap_or(&path)
+            .to_string_lossy()
+            .to_string();
+        let mut bytes = Vec::new();
+        std::io::Read::read_to_end(&mut entry, &mut bytes)?;
+        files.push(SandboxFile {
+            name,
+            bytes: bytes.into(),
+        });
+    }
+    Ok(files)
+}
+
+fn sandbox_host_config(config: &CodeInterpreterConfig) -> HostConfig {
+    let mut tmpfs = HashMap::new();
+    tmpfs.insert(
+        WORKDIR.to_string(),
+        format!("rw,nosuid,nodev,size={}", config.tmpfs_size_bytes),
+    );
+    HostConfig {
+        auto_remove: Some(true),
+        memory: Some(config.memory_bytes),
+        // Same as memory so the container cannot use swap
+        memory_swap: Some(config.memory_bytes),
+        nano_cpus: Some(config.nano_cpus),
+        pids_limit: Some(config.pids_limit),
+        network_mode: Some(config.network_mode.clone()),
+        readonly_rootfs: Some(config.read_only_rootfs),
+        tmpfs: Some(tmpfs),
+        cap_drop: Some(vec!["ALL".to_string()]),
+        security_opt: Some(vec!["no-new-privileges".to_string()]),
+        ..Default::default()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_script_archive_keeps_code_as_is() {
+        let code = "print(\"$(rm -rf /) `ls` \\\"quoted\\\" \\n\")\nprint('done')";
+        let archive = tar_archive(&[(SCRIPT_NAME, code.as_bytes())], 0o644).unwrap();
+
+        let mut archive = tar::Archive::new(archive.as_slice());
+        let mut entries = archive.entries().unwrap();
+        let mut entry = entries.next().unwrap().unwrap();
+        assert_eq!(entry.path().unwrap().to_str(), Some(SCRIPT_NAME));
+        assert_eq!(entry.header().mode().unwrap(), 0o644);
+        let mut content = String::new();
+        std::io::Read::read_to_string(&mut entry, &mut content).unwrap();
+        assert_eq!(content, code);
+        assert!(entries.next().is_none());
+    }
+
+    #[test]
+    fn test_read_tar_files() {
+        let mut builder = tar::Builder::new(Vec::new());
+        let mut header = tar::Header::new_gnu();
+        header.set_entry_type(tar::EntryType::Directory);
+        header.set_size(0);
+        header.set_mode(0o755);
+        header.set_cksum();
+        builder.append_data(&mut header, "./charts", &[][..]).unwrap();
+        let mut header = tar::Header::new_gnu();
+        header.set_size(3);
+        header.set_mode(0o644);
+        header.set_cksum();
+        builder
+            .append_data(&mut header, "./charts/plot.png", &b"png"[..])
+            .unwrap();
+        let archive = builder.into_inner().unwrap();
+
+        let files = read_tar_files(&archive).unwrap();
+        assert_eq!(files.len(), 1);
+        assert_eq!(files[0].name, "charts/plot.png");
+        assert_eq!(files[0].bytes, "png".as_bytes());
+    }
+
+    #[tokio::test]
+    async fn test_containers_are_reused_and_reset() {
+        let config = CodeInterpreterConfig {
+            pool_size: 1,
+            max_uses_per_container: 2,
+            ..Default::default()
+        };
+        let pool = ContainerPool::new(&config).unwrap();
+        pool.warm().await.unwrap();
+        let first_container = pool.idle.lock().unwrap()[0].id.clone();
+
+        let files = vec![SandboxFile {
+            name: "data.csv".to_string(),
+            bytes: "a,b\n1,2\n".into(),
+        }];
+        let execution = pool
+            .execute(
+                "open('left.txt', 'w').write('x')\nopen('/tmp/output/r.txt', 'w').write('r')\nprint(open('/mnt/data/data.csv').read())",
+                &files,
+            )
+            .await
+            .unwrap();
+        assert_eq!(execution.exit_code, Some(0));
+        assert!(execution.stdout.contains("a,b"));
+        assert_eq!(execution.files.len(), 1);
+        assert_eq!(execution.files[0].name, "r.txt");
+        assert_eq!(pool.idle.lock().unwrap()[0].id, first_container);
+
+        // The same container is used, without the files of the previous run
+        let execution = pool
+            .execute(
+                "import os\nprint(os.path.exists('left.txt'), os.listdir('/mnt/data'))",
+                &[],
+            )
+            .await
+            .unwrap();
+        assert!(execution.stdout.contains("False []"));
+        assert!(execution.files.is_empty());
+
+        // Recycled after max_uses_per_container runs
+        assert!(pool.idle.lock().unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_processes_do_not_survive_runs() {
+        let config = CodeInterpreterConfig {
+            pool_size: 1,
+            max_uses_per_container: 2,
+            ..Default::default()
+        };
+        let pool = ContainerPool::new(&config).unwrap();
+        pool.warm().await.unwrap();
+        let first_container = pool.idle.lock().unwrap()[0].id.clone();
+
+        let execution = pool
+            .execute(
+                "import subprocess\nsubprocess.Popen(['sleep', '1000'], stdout=subprocess.DEVNULL, stderr=subprocess.DEVNULL, start_new_session=True)",
+                &[],
+            )
+            .await
+            .unwrap();
+        assert_eq!(execution.exit_code, Some(0));
+        assert_eq!(pool.idle.lock().unwrap()[0].id, first_container);
+
+        // The container is reused without the process started by the previous run
+        let execution = pool
+            .execute(
+                "import os\nprint([p for p in os.listdir('/proc') if p.isdigit() and open(f'/proc/{p}/cmdline').read().startswith('sleep')])",
+                &[],
+            )
+            .await
+            .unwrap();
+        assert!(execution.stdout.contains("[]"), "{}", execution.stdout);
+    }
+}
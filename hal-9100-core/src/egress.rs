@@ -0,0 +1,508 @@
+use hal_9100_extra::config::ActionsConfig;
+use reqwest::dns::{Addrs, Name, Resolve, Resolving};
+use reqwest::redirect::{Attempt, Policy};
+use reqwest::{RequestBuilder, Response};
+use std::error::Error;
+use std::fmt;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::OnceCell;
+use url::{Host, Url};
+
+static SHARED_ACTION_CLIENT: OnceCell<ActionClient> = OnceCell::const_new();
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum EgressError {
+    InvalidHost(String),
+    UnsupportedScheme(String),
+    Denied(String),
+    NotAllowed(String),
+    /// The host is or resolves to an internal address
+    PrivateAddress(String, IpAddr),
+    Resolution(String),
+    TooManyRedirects(usize),
+    ResponseTooLarge(usize),
+}
+
+impl fmt::Display for EgressError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            EgressError::InvalidHost(host) => write!(f, "Invalid host in egress policy: {}", host),
+            EgressError::UnsupportedScheme(scheme) => {
+                write!(
+                    f,
+                    "Actions can only send http and https requests, not {}",
+                    scheme
+                )
+            }
+            EgressError::Denied(host) => write!(f, "Requests to {} are denied", host),
+            EgressError::NotAllowed(host) => write!(f, "Requests to {} are not allowed", host),
+            EgressError::PrivateAddress(host, ip) => {
+                write!(
+                    f,
+                    "Requests to {} are denied, it is the private address {}",
+                    host, ip
+                )
+            }
+            EgressError::Resolution(e) => write!(f, "Failed to resolve host: {}", e),
+            EgressError::TooManyRedirects(max) => write!(f, "More than {} redirects", max),
+            EgressError::ResponseTooLarge(max) => {
+                write!(f, "Response is larger than {} bytes", max)
+            }
+        }
+    }
+}
+
+impl Error for EgressError {}
+
+#[derive(Debug, Clone, PartialEq)]
+enum HostPattern {
+    Name(String),
+    /// `*.example.com`, matching the subdomains of example.com
+    Subdomains(String),
+    Range(IpAddr, u8),
+}
+
+impl HostPattern {
+    fn parse(pattern: &str) -> Result<HostPattern, EgressError> {
+        let pattern = pattern.trim().to_lowercase();
+        let invalid = || EgressError::InvalidHost(pattern.clone());
+        if let Some((address, prefix)) = pattern.split_once('/') {
+            let address: IpAddr = address.parse().map_err(|_| invalid())?;
+            let prefix: u8 = prefix.parse().map_err(|_| invalid())?;
+            let max_prefix = if address.is_ipv4() { 32 } else { 128 };
+            if prefix > max_prefix {
+                return Err(invalid());
+            }
+            return Ok(HostPattern::Range(address, prefix));
+        }
+        if let Ok(address) = pattern.parse::<IpAddr>() {
+            let prefix = if address.is_ipv4() { 32 } else { 128 };
+            return Ok(HostPattern::Range(address, prefix));
+        }
+        if let Some(domain) = pattern.strip_prefix("*.") {
+            return Ok(HostPattern::Subdomains(domain.to_string()));
+        }
+        if pattern.is_empty() || pattern.contains(|c: char| c == '*' || c == ':') {
+            return Err(invalid());
+        }
+        Ok(HostPattern::Name(pattern))
+    }
+
+    fn matches_name(&self, host: &str) -> bool {
+        match self {
+            HostPattern::Name(name) => name == host,
+            HostPattern::Subdomains(domain) => host
+                .strip_suffix(domain.as_str())
+                .map_or(false, |subdomain| subdomain.ends_with('.')),
+            HostPattern::Range(..) => false,
+        }
+    }
+
+    fn contains(&self, ip: IpAddr) -> bool {
+        match (self, canonical(ip)) {
+            (HostPattern::Range(IpAddr::V4(range), prefix), IpAddr::V4(ip)) => {
+                let mask = u32::MAX.checked_shl(32 - *prefix as u32).unwrap_or(0);
+                u32::from(*range) & mask == u32::from(ip) & mask
+            }
+            (HostPattern::Range(IpAddr::V6(range), prefix), IpAddr::V6(ip)) => {
+                let mask = u128::MAX.checked_shl(128 - *prefix as u32).unwrap_or(0);
+                u128::from(*range) & mask == u128::from(ip) & mask
+            }
+            _ => false,
+        }
+    }
+}
+
+// IPv6 addresses embedding an IPv4 address are checked as the IPv4 address they reach
+fn canonical(ip: IpAddr) -> IpAddr {
+    match ip {
+        IpAddr::V6(v6) => match embedded_v4(v6) {
+            Some(v4) => IpAddr::V4(v4),
+            None => ip,
+        },
+        ip => ip,
+    }
+}
+
+fn embedded_v4(ip: Ipv6Addr) -> Option<Ipv4Addr> {
+    let segments = ip.segments();
+    let v4 = |high: u16, low: u16| Ipv4Addr::from((u32::from(high) << 16) | u32::from(low));
+    match segments {
+        // NAT64 well-known prefix 64:ff9b::/96
+        [0x64, 0xff9b, 0, 0, 0, 0, high, low] => Some(v4(high, low)),
+        // 6to4 2002::/16
+        [0x2002, high, low, ..] => Some(v4(high, low)),
+        // ::1 and :: are IPv6 addresses of their own
+        _ if ip.is_loopback() || ip.is_unspecified() => None,
+        // IPv4-mapped ::ffff:a.b.c.d and IPv4-compatible ::a.b.c.d
+        _ => ip.to_ipv4(),
+    }
+}
+
+fn is_private_v4(ip: Ipv4Addr) -> bool {
+    let [a, b, ..] = ip.octets();
+    ip.is_private()
+        || ip.is_loopback()
+        || ip.is_link_local()
+        || ip.is_unspecified()
+        || ip.is_broadcast()
+        || ip.is_multicast()
+        || ip.is_documentation()
+        || a == 0
+        // Shared address space of carrier-grade NAT
+        || (a == 100 && (64..128).contains(&b))
+        // IETF protocol assignments and benchmarking
+        || (a == 192 && b == 0 && ip.octets()[2] == 0)
+        || (a == 198 && (18..20).contains(&b))
+        || a >= 240
+}
+
+fn is_private_v6(ip: Ipv6Addr) -> bool {
+    let first = ip.segments()[0];
+    ip.is_loopback()
+        || ip.is_unspecified()
+        || ip.is_multicast()
+        // Unique local
+        || (first & 0xfe00) == 0xfc00
+        // Link-local
+        || (first & 0xffc0) == 0xfe80
+        // Documentation
+        || (first == 0x2001 && ip.segments()[1] == 0x0db8)
+}
+
+/// Whether `ip` is not on the public internet, e.g. loopback, private networks or the link-local
+/// range of cloud metadata services.
+pub fn is_private(ip: IpAddr) -> bool {
+    match canonical(ip) {
+        IpAddr::V4(ip) => is_private_v4(ip),
+        IpAddr::V6(ip) => is_private_v6(ip),
+    }
+}
+
+/// The hosts and addresses actions may reach.
+#[derive(Debug, Clone, PartialEq)]
+pub struct EgressPolicy {
+    allow: Vec<HostPattern>,
+    deny: Vec<HostPattern>,
+    allow_private: bool,
+}
+
+impl EgressPolicy {
+    pub fn new(config: &ActionsConfig) -> Result<EgressPolicy, EgressError> {
+        let parse = |hosts: &[String]| {
+            hosts
+                .iter()
+                .map(|host| HostPattern::parse(host))
+                .collect::<Result<Vec<_>, _>>()
+        };
+        Ok(EgressPolicy {
+            allow: parse(&config.allow_hosts)?,
+            deny: parse(&config.deny_hosts)?,
+            allow_private: config.allow_private,
+        })
+    }
+
+    /// Check that `host` may be reached at `ip`, the address it resolved to or itself.
+    pub fn check(&self, host: &str, ip: IpAddr) -> Result<(), EgressError> {
+        let host = host.trim_end_matches('.').to_lowercase();
+        let matches = |pattern: &HostPattern| pattern.matches_name(&host) || pattern.contains(ip);
+        if self.deny.iter().any(matches) {
+            return Err(EgressError::Denied(host));
+        }
+        let allowed_range = self.allow.iter().any(|pattern| pattern.contains(ip));
+        if !self.allow.is_empty() && !self.allow.iter().any(matches) {
+            return Err(EgressError::NotAllowed(host));
+        }
+        if is_private(ip) && !self.allow_private && !allowed_range {
+            return Err(EgressError::PrivateAddress(host, canonical(ip)));
+        }
+        Ok(())
+    }
+
+    /// Check what can be checked of `url` before resolving its host. Hosts given as IP
+    /// addresses are never resolved, so they are checked here.
+    pub fn check_url(&self, url: &Url) -> Result<(), EgressError> {
+        if url.scheme() != "http" && url.scheme() != "https" {
+            return Err(EgressError::UnsupportedScheme(url.scheme().to_string()));
+        }
+        match url.host() {
+            Some(Host::Ipv4(ip)) => self.check(&ip.to_string(), IpAddr::V4(ip)),
+            Some(Host::Ipv6(ip)) => self.check(&ip.to_string(), IpAddr::V6(ip)),
+            Some(Host::Domain(domain)) => {
+                let domain = domain.trim_end_matches('.').to_lowercase();
+                if self
+                    .deny
+                    .iter()
+                    .any(|pattern| pattern.matches_name(&domain))
+                {
+                    return Err(EgressError::Denied(domain));
+                }
+                Ok(())
+            }
+            None => Err(EgressError::InvalidHost(url.to_string())),
+        }
+    }
+}
+
+// Resolves hosts and only hands the connector addresses the policy allows, so a host can't
+// pass the check and then be connected to at another address
+struct PolicyResolver {
+    policy: Arc<EgressPolicy>,
+}
+
+impl Resolve for PolicyResolver {
+    fn resolve(&self, name: Name) -> Resolving {
+        let policy = self.policy.clone();
+        Box::pin(async move {
+            let host = name.as_str().to_string();
+            let addrs: Vec<SocketAddr> = tokio::net::lookup_host((host.as_str(), 0))
+                .await
+                .map_err(|e| EgressError::Resolution(format!("{}: {}", host, e)))?
+                .collect();
+            if addrs.is_empty() {
+                return Err(EgressError::Resolution(format!("{}: no address", host)).into());
+            }
+            // One bad address rejects the host, a resolver answering with a mix is not trusted
+            for addr in &addrs {
+                policy.check(&host, addr.ip())?;
+            }
+            let addrs: Addrs = Box::new(addrs.into_iter());
+            Ok(addrs)
+        })
+    }
+}
+
+/// The HTTP client of actions, shared by all runs so connections are pooled. It only reaches
+/// the destinations of the egress policy and bounds how long requests take and how large their
+/// responses are.
+pub struct ActionClient {
+    http: reqwest::Client,
+    policy: Arc<EgressPolicy>,
+    max_response_bytes: usize,
+}
+
+impl ActionClient {
+    pub fn new(config: &ActionsConfig) -> Result<ActionClient, Box<dyn Error>> {
+        let policy = Arc::new(EgressPolicy::new(config)?);
+        let redirect_policy = policy.clone();
+        let max_redirects = config.max_redirects;
+        let http = reqwest::Client::builder()
+            .timeout(Duration::from_secs(config.timeout_secs))
+            .connect_timeout(Duration::from_secs(config.connect_timeout_secs))
+            .dns_resolver(Arc::new(PolicyResolver {
+                policy: policy.clone(),
+            }))
+            .redirect(Policy::custom(move |attempt: Attempt| {
+                if attempt.previous().len() > max_redirects {
+                    return attempt.error(EgressError::TooManyRedirects(max_redirects));
+                }
+                match redirect_policy.check_url(attempt.url()) {
+                    Ok(()) => attempt.follow(),
+                    Err(e) => attempt.error(e),
+                }
+            }))
+            // A proxy would resolve and connect to the hosts itself, around the policy
+            .no_proxy()
+            .build()?;
+        Ok(ActionClient {
+            http,
+            policy,
+            max_response_bytes: config.max_response_bytes,
+        })
+    }
+
+    /// The client to build requests with, send them with `send`.
+    pub fn http(&self) -> &reqwest::Client {
+        &self.http
+    }
+
+    pub async fn send(&self, request: RequestBuilder) -> Result<Response, Box<dyn Error>> {
+        let request = request.build()?;
+        self.policy.check_url(request.url())?;
+        Ok(self.http.execute(request).await?)
+    }
+
+    /// The body of `response`, failing once it grows past the limit.
+    pub async fn read_body(&self, mut response: Response) -> Result<Vec<u8>, Box<dyn Error>> {
+        let max = self.max_response_bytes;
+        if response
+            .content_length()
+            .map_or(false, |length| length as usize > max)
+        {
+            return Err(Box::new(EgressError::ResponseTooLarge(max)));
+        }
+        let mut body = Vec::new();
+        while let Some(chunk) = response.chunk().await? {
+            if body.len() + chunk.len() > max {
+                return Err(Box::new(EgressError::ResponseTooLarge(max)));
+            }
+            body.extend_from_slice(&chunk);
+        }
+        Ok(body)
+    }
+}
+
+/// The action client of the executor, created on first use.
+pub async fn shared_action_client(
+    config: &ActionsConfig,
+) -> Result<&'static ActionClient, Box<dyn Error>> {
+    if let Some(client) = SHARED_ACTION_CLIENT.get() {
+        return Ok(client);
+    }
+    let client = ActionClient::new(config)?;
+    // Another run may have set it meanwhile, both are built from the same config
+    let _ = SHARED_ACTION_CLIENT.set(client);
+    Ok(SHARED_ACTION_CLIENT.get().unwrap())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use httpmock::prelude::*;
+
+    fn policy(allow: &[&str], deny: &[&str], allow_private: bool) -> EgressPolicy {
+        EgressPolicy::new(&ActionsConfig {
+            allow_hosts: allow.iter().map(|host| host.to_string()).collect(),
+            deny_hosts: deny.iter().map(|host| host.to_string()).collect(),
+            allow_private,
+            ..Default::default()
+        })
+        .unwrap()
+    }
+
+    fn ip(ip: &str) -> IpAddr {
+        ip.parse().unwrap()
+    }
+
+    #[test]
+    fn test_egress_policy() {
+        let default = policy(&[], &[], false);
+        assert!(default.check("example.com", ip("93.184.216.34")).is_ok());
+        for internal in [
+            "127.0.0.1",
+            "10.0.0.1",
+            "172.16.5.4",
+            "192.168.1.1",
+            "169.254.169.254",
+            "100.64.0.1",
+            "0.0.0.0",
+            "::1",
+            "fd00::1",
+            "fe80::1",
+            "::ffff:169.254.169.254",
+            "::169.254.169.254",
+            "::127.0.0.1",
+            "64:ff9b::a9fe:a9fe",
+            "64:ff9b::10.0.0.1",
+            "2002:7f00:1::",
+            "2002:a9fe:a9fe::1",
+        ] {
+            assert!(
+                matches!(
+                    default.check("metadata", ip(internal)),
+                    Err(EgressError::PrivateAddress(..))
+                ),
+                "{} should be private",
+                internal
+            );
+        }
+        assert!(policy(&[], &[], true)
+            .check("localhost", ip("127.0.0.1"))
+            .is_ok());
+        // Public addresses embedded in IPv6 stay reachable
+        for public in ["64:ff9b::5db8:d822", "2002:5db8:d822::1", "2606:4700::1111"] {
+            assert!(default.check("example.com", ip(public)).is_ok(), "{} should be public", public);
+        }
+
+        let lists = policy(
+            &["api.example.com", "*.example.org", "10.1.2.0/24"],
+            &["secret.example.org", "203.0.113.0/24"],
+            false,
+        );
+        assert!(lists.check("API.example.com.", ip("93.184.216.34")).is_ok());
+        assert!(lists.check("a.b.example.org", ip("93.184.216.34")).is_ok());
+        assert_eq!(
+            lists.check("example.org", ip("93.184.216.34")),
+            Err(EgressError::NotAllowed("example.org".to_string()))
+        );
+        assert_eq!(
+            lists.check("secret.example.org", ip("93.184.216.34")),
+            Err(EgressError::Denied("secret.example.org".to_string()))
+        );
+        // Denied ranges win over allowed names
+        assert!(lists.check("api.example.com", ip("203.0.113.7")).is_err());
+        assert!(lists.check("api.example.com", ip("64:ff9b::cb00:7107")).is_err());
+        // Allowed ranges may be private
+        assert!(lists.check("internal", ip("10.1.2.3")).is_ok());
+        assert!(lists.check("internal", ip("10.1.3.3")).is_err());
+
+        let url = |url: &str| Url::parse(url).unwrap();
+        assert!(default
+            .check_url(&url("http://169.254.169.254/latest"))
+            .is_err());
+        assert!(default.check_url(&url("http://[::1]:8080/")).is_err());
+        assert!(default.check_url(&url("file:///etc/passwd")).is_err());
+        assert!(lists
+            .check_url(&url("https://secret.example.org/"))
+            .is_err());
+        assert!(default.check_url(&url("https://example.com/")).is_ok());
+
+        assert!(EgressPolicy::new(&ActionsConfig {
+            allow_hosts: vec!["10.0.0.0/33".to_string()],
+            ..Default::default()
+        })
+        .is_err());
+    }
+
+    #[tokio::test]
+    async fn test_action_client() {
+        let server = MockServer::start();
+        server.mock(|when, then| {
+            when.method(GET).path("/small");
+            then.status(200).body("ok");
+        });
+        server.mock(|when, then| {
+            when.method(GET).path("/large");
+            then.status(200).body("x".repeat(2048));
+        });
+        server.mock(|when, then| {
+            when.method(GET).path("/metadata");
+            then.status(302)
+                .header("Location", "http://169.254.169.254/latest/meta-data");
+        });
+
+        // The mock server is on localhost
+        let blocked = ActionClient::new(&ActionsConfig::default()).unwrap();
+        let error = blocked
+            .send(blocked.http().get(server.url("/small")))
+            .await
+            .unwrap_err();
+        assert!(error.to_string().contains("private address"));
+
+        let client = ActionClient::new(&ActionsConfig {
+            allow_hosts: vec!["127.0.0.1".to_string()],
+            max_response_bytes: 1024,
+            ..Default::default()
+        })
+        .unwrap();
+        let response = client
+            .send(client.http().get(server.url("/small")))
+            .await
+            .unwrap();
+        assert_eq!(client.read_body(response).await.unwrap(), b"ok");
+
+        let response = client
+            .send(client.http().get(server.url("/large")))
+            .await
+            .unwrap();
+        assert!(client.read_body(response).await.is_err());
+
+        // Redirects are checked like the first request
+        assert!(client
+            .send(client.http().get(server.url("/metadata")))
+            .await
+            .is_err());
+    }
+}
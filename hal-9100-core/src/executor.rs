@@ -1,12 +1,12 @@
 use async_openai::types::{
-    AssistantTools, FunctionCall, MessageContent, MessageContentTextObject, MessageRole,
-    RequiredAction, RunStatus, RunToolCallObject, SubmitToolOutputs, TextData, RunStepType, StepDetails, RunStepDetailsMessageCreationObject, MessageCreation, RunStepDetailsToolCallsObject, RunStepDetailsToolCalls, RunStepDetailsToolCallsCodeObject, CodeInterpreter, CodeInterpreterOutput, RunStepDetailsToolCallsCodeOutputLogsObject, RunStepDetailsToolCallsRetrievalObject, RunStepDetailsToolCallsFunctionObject, RunStepFunctionObject,
+    MessageContent, MessageContentTextObject, MessageRole, RunStatus, TextData, RunStepType, StepDetails, RunStepDetailsMessageCreationObject, MessageCreation, RunStepDetailsToolCallsObject, RunStepDetailsToolCalls, RunStepDetailsToolCallsFunctionObject, RunStepFunctionObject,
+    ImageFile, MessageContentImageFileObject,
 };
 use futures::future::try_join_all;
+use hal_9100_extra::config::Hal9100Config;
 use hal_9100_extra::llm::{HalLLMClient, HalLLMRequestArgs};
 use log::{error, info};
 use redis::AsyncCommands;
-use serde_json::{self, json};
 use sqlx::PgPool;
 
 use hal_9100_core::assistants::{get_assistant};
@@ -18,26 +18,25 @@ use std::cmp::Ordering;
 use std::collections::{HashMap, HashSet};
 use std::error::Error;
 use std::fmt;
-use hal_9100_core::runs::{get_run, update_run_status};
-
-
-use hal_9100_core::function_calling::create_function_call;
+use hal_9100_core::runs::{
+    clear_pending_actions, get_pending_actions, get_run, update_run_status,
+};
 
 use hal_9100_core::runs::get_tool_calls;
-use hal_9100_core::code_interpreter::safe_interpreter;
+use hal_9100_core::code_interpreter::{extract_file_paths, is_image};
 
 use hal_9100_core::models::SubmittedToolCall;
 
-use hal_9100_core::retrieval::retrieve_file_contents;
-
-use hal_9100_core::models::Chunk;
-use hal_9100_core::retrieval::generate_queries_and_fetch_chunks;
+use hal_9100_core::retrieval::{extract_file_citations, format_chunks_for_prompt};
 
-use crate::function_calling::execute_request;
+use crate::egress::shared_action_client;
 use crate::models::{RunStep};
-use crate::openapi::ActionRequest;
-use crate::prompts::{format_messages, build_instructions};
-use crate::run_steps::{create_step, update_step, list_steps, set_all_steps_status};
+use crate::prompts::{build_instructions, last_user_message};
+use crate::truncation::{format_previous_messages, split_messages};
+use crate::run_steps::{
+    create_step, list_steps, set_all_steps_status, update_step,
+};
+use crate::tools::{run_action, tool_registry, tool_type, ToolContext};
 
 pub fn extract_step_id_and_function_output(steps: Vec<RunStep>, tool_calls: Vec<SubmittedToolCall>) -> Vec<(String, String, RunStepFunctionObject)> {
     let mut result = Vec::new();
@@ -189,39 +188,14 @@ In this example, the assistant should return \"<action>\".
 
 Your answer will be used to use the tool so it must be very concise and make sure to surround the tool by \"<\" and \">\", do not say anything but the tool name with the <> around it.";
 
+    let registry = tool_registry();
     let tools = assistant.inner.tools.clone();
     // Build the user prompt
     let tools_as_string = tools
         .iter()
-        .map(|t| {
-            serde_json::to_string(&match t {
-                AssistantTools::Code(_) => json!({"name": "code_interpreter", "description": "useful for performing complex math problems which LLMs are bad at by default. Do not use code_interpreter if it's simple math that you believe a LLM can do (e.g. 1 + 1, 9 * 7, etc.) - Make sure to use code interpreter for more complex math problems"}),
-                AssistantTools::Retrieval(_) => json!({"name": "retrieval", "description": "useful to retrieve information from files"}),
-                AssistantTools::Function(e) => 
-                    json!({
-                        "name": "function",
-                        "description": "Useful to call functions in the user's product, which would provide you later some additional context about the user's problem. You can also use this to perform actions in the user's product.",
-                        "function": {
-                            "name": e.function.name,
-                            "description": e.function.description,
-                            "arguments": e.function.parameters,
-                        }
-                    }),
-                    AssistantTools::Extra(e) => {
-                        let data = e.data.as_ref().unwrap();
-                        json!({
-                            "name": "action",
-                            "description": "Useful to make HTTP requests to the user's APIs, which would provide you later some additional context about the user's problem. You can also use this to perform actions to help the user.",
-                            "data": {
-                                "info": {
-                                    "description": data.get("info").unwrap_or(&json!({})).get("description").unwrap_or(&json!("")).to_string().replace("\"", ""),
-                                    "title": data.get("info").unwrap_or(&json!({})).get("title").unwrap_or(&json!("")).to_string().replace("\"", ""),
-                                },
-                                "paths": data.get("paths"),
-                            }
-                        })
-                },
-            }).unwrap()
+        .filter_map(|t| {
+            let tool = registry.get(tool_type(t))?;
+            Some(serde_json::to_string(&tool.describe(t)).unwrap())
         })
         .collect::<Vec<String>>();
     let tools_as_string = tools_as_string.join("\n---\n");
@@ -301,10 +275,9 @@ Your answer will be used to use the tool so it must be very concise and make sur
     info!("decide_tool_with_llm result: {:?}", results);
 
     // filter out what is not in the tools
-    results.retain(|tool| 
-        // function, retrieval, code_interpreter, action
-        tool == "function" || tool == "retrieval" || tool == "code_interpreter" || tool == "action"
-    );
+    results.retain(|tool| {
+        registry.get(tool).is_some() && tools.iter().any(|t| tool_type(t) == tool.as_str())
+    });
 
     Ok(results
         .into_iter()
@@ -338,9 +311,10 @@ pub async fn loop_through_runs(
     pool: &PgPool,
     con: &mut redis::aio::Connection,
     client: HalLLMClient, // Not using a reference here because we want to be able to tweak the client at runtime
+    hal_9100_config: &Hal9100Config,
 ) {
     loop {
-        match try_run_executor(&pool, con, client.clone()).await {
+        match try_run_executor(&pool, con, client.clone(), hal_9100_config).await {
             Ok(_) => continue,
             Err(e) => error!("Error: {}", e),
         }
@@ -351,8 +325,9 @@ pub async fn try_run_executor(
     pool: &PgPool,
     con: &mut redis::aio::Connection,
     client: HalLLMClient,
+    hal_9100_config: &Hal9100Config,
 ) -> Result<Run, RunError> {
-    match run_executor(&pool, con, client).await {
+    match run_executor(&pool, con, client, hal_9100_config).await {
         Ok(run) => { 
             info!("Execution done: {:?}", run);
             set_all_steps_status(&pool, &run.inner.id, &run.user_id, RunStatus::Completed).await.map_err(|e| RunError {
@@ -398,6 +373,7 @@ pub async fn run_executor(
     pool: &PgPool,
     con: &mut redis::aio::Connection,
     mut client: HalLLMClient,
+    hal_9100_config: &Hal9100Config,
 ) -> Result<Run, RunError> {
     info!("Consuming queue");
     let (_, ids_string): (String, String) = con.brpop("run_queue", 0).await.map_err(|e| {
@@ -434,7 +410,6 @@ pub async fn run_executor(
         thread_id: thread_id.to_string(),
         user_id: user_id.to_string(),
     })?;
-    let assistant_id = assistant.inner.id.clone();
 
     // Update run status to "running"
     run = update_run_status(
@@ -473,24 +448,32 @@ pub async fn run_executor(
         user_id: user_id.to_string(),
     })?;
 
-    // Format messages into a string
-    let formatted_messages = format_messages(&messages);
+    // Format messages into a string, following the run truncation strategy
+    let mut summary_client = client.clone();
+    summary_client.set_model_name(assistant.inner.model.clone());
+    let formatted_messages = format_previous_messages(
+        pool,
+        summary_client,
+        &thread.inner.id,
+        &run.user_id,
+        &messages,
+        &run.truncation_strategy,
+    ).await;
     info!("Formatted messages: {}", formatted_messages);
+    let last_user_message = last_user_message(&messages);
 
     // LLM Context updated by tools
     let mut function_calls = String::new();
     let mut action_calls = String::new();
-    let mut retrieval_files: Vec<String> = vec![];
-    let mut retrieval_chunks: Vec<Chunk> = vec![];
-    let mut code_output: Option<String> = None;
-    let mut code: Option<String> = None;
     let mut tool_calls_db: Vec<SubmittedToolCall> = vec![];
-    let mut request = HalLLMRequestArgs::default();
+    let context_size = hal_9100_config
+        .model_registry()
+        .context_size(&assistant.inner.model);
+    let mut request = HalLLMRequestArgs::default().context_size(context_size as i32);
 
     // Check if the run has a required action
     if let Some(required_action) = &run.inner.required_action {
         // skip if there is required action and no tool output yet
-        // TODO: use case 2 call required and user only sent 1
         if required_action.submit_tool_outputs.tool_calls.is_empty() {
             info!("Skipping required action because there is no tool output yet");
             return Ok(run);
@@ -503,7 +486,6 @@ pub async fn run_executor(
             "Retrieving tool calls {:?}",
             required_action.submit_tool_outputs
         );
-        // TODO: if user send just part of the function result and not all should error
         tool_calls_db = get_tool_calls(
             pool,
             required_action
@@ -520,6 +502,23 @@ pub async fn run_executor(
             user_id: user_id.to_string(),
         })?;
 
+        // The run is only queued again once every output is submitted
+        let missing: Vec<&str> = required_action
+            .submit_tool_outputs
+            .tool_calls
+            .iter()
+            .map(|t| t.id.as_str())
+            .filter(|id| !tool_calls_db.iter().any(|t| t.id == *id))
+            .collect();
+        if !missing.is_empty() {
+            return Err(RunError {
+                message: format!("Missing outputs for tool calls: {}", missing.join(", ")),
+                run_id: run_id.to_string(),
+                thread_id: thread_id.to_string(),
+                user_id: user_id.to_string(),
+            });
+        }
+
         // for each function call sent by the user, update the run step in database
 
         // first fetch the steps for this run 
@@ -566,12 +565,12 @@ pub async fn run_executor(
             .submit_tool_outputs
             .tool_calls
             .iter()
-            .zip(&tool_calls_db)
-            .map(|(input, output)| {
-                format!(
+            .filter_map(|input| {
+                let output = tool_calls_db.iter().find(|t| t.id == input.id)?;
+                Some(format!(
                     "<input>{:?}</input>\n\n<output>{:?}</output>",
                     input.function, output.output
-                )
+                ))
             })
             .collect::<Vec<String>>()
             .join("\n");
@@ -579,11 +578,44 @@ pub async fn run_executor(
         info!("function_calls: {}", function_calls);
     }
 
+    let max_output_chars = hal_9100_config.actions.max_output_chars;
+    let action_client = shared_action_client(&hal_9100_config.actions).await.map_err(|e| RunError {
+        message: format!("Failed to create action client: {}", e),
+        run_id: run_id.to_string(),
+        thread_id: thread_id.to_string(),
+        user_id: user_id.to_string(),
+    })?;
+
+    // Run the actions the user decided on, the run was waiting for them
+    let pending_actions = get_pending_actions(pool, &run.inner.id, &run.user_id).await.map_err(|e| RunError {
+        message: format!("Failed to get pending actions: {}", e),
+        run_id: run_id.to_string(),
+        thread_id: thread_id.to_string(),
+        user_id: user_id.to_string(),
+    })?;
+    let actions_decided = !pending_actions.is_empty()
+        && pending_actions.iter().all(|action| action.approved.is_some());
+    if actions_decided {
+        info!("Running {} decided actions", pending_actions.len());
+        let outputs = try_join_all(
+            pending_actions.into_iter().map(|action| run_action(pool, action_client, &file_storage, max_output_chars, &run, action))
+        ).await?;
+        action_calls = outputs.join("\n");
+        clear_pending_actions(pool, &run.inner.id, &run.user_id).await.map_err(|e| RunError {
+            message: format!("Failed to clear pending actions: {}", e),
+            run_id: run_id.to_string(),
+            thread_id: thread_id.to_string(),
+            user_id: user_id.to_string(),
+        })?;
+    }
+
     info!("Assistant tools: {:?}", assistant.inner.tools);
     info!("Asking LLM to decide which tool to use");
 
     // Decide which tool to use
-    let mut tools_decision = decide_tool_with_llm(&assistant, &messages, &run, tool_calls_db, 
+    // Only the messages kept by the truncation strategy are used to decide
+    let (_, recent_messages) = split_messages(&messages, &run.truncation_strategy, usize::MAX);
+    let mut tools_decision = decide_tool_with_llm(&assistant, recent_messages, &run, tool_calls_db, 
         client.clone(),
         request.clone()
     ).await.map_err(|e| RunError {
@@ -594,28 +626,26 @@ pub async fn run_executor(
     })?;
 
     info!("Tools decision: {:?}", tools_decision);
+    if actions_decided {
+        // The actions the model needed were just run
+        tools_decision.retain(|tool| tool != "action");
+    }
 
-    let mut instructions = build_instructions(
+    let instructions = build_instructions(
         &run.inner.instructions,
-        &retrieval_files,
+        &Vec::new(),
         &formatted_messages,
         &function_calls,
-        code_output.as_deref(),
-        &retrieval_chunks.iter().map(|c| 
-            serde_json::to_string(&json!({
-                "data": c.data,
-                "sequence": c.sequence,
-                "start_index": c.start_index,
-                "end_index": c.end_index,
-                "metadata": c.metadata,
-            })).unwrap()
-        ).collect::<Vec<String>>(),
         None,
+        &Vec::new(),
+        context_size,
+        &hal_9100_config.context_budget,
         &action_calls
     );
 
     let model = assistant.inner.model.clone();
     client.set_model_name(model.clone());
+    client.set_constrained_decoding(hal_9100_config.constrained_decoding);
     request.set_last_user_prompt(formatted_messages.clone());
     request.set_system_prompt(instructions.clone());
 
@@ -630,493 +660,68 @@ pub async fn run_executor(
         }
     });
 
+    let registry = tool_registry();
+    let mut context = ToolContext {
+        pool,
+        con,
+        config: hal_9100_config,
+        file_storage: &file_storage,
+        client: client.clone(),
+        request: request.clone(),
+        run: &run,
+        assistant: &assistant,
+        formatted_messages: &formatted_messages,
+        last_user_message: &last_user_message,
+        function_calls,
+        action_calls,
+        retrieval_files: vec![],
+        retrieval_chunks: vec![],
+        code_output: None,
+        output_files: vec![],
+    };
+
     // Iterate over the sorted tools_decision
     for tool_decision in tools_decision {
-
         // TODO: can prob optimise thru parallelism
-        match tool_decision.as_str() {
-            "function" => {
-                info!("Using function tool");
-                // skip this if tools is not empty (e.g. if there are required_action (s))
-                if !run.inner.required_action.is_none() {
-                    info!("Skipping function call because there is a required action");
-                    continue;
-                }
-
-                info!("Generating function to call");
-
-                let function_results =
-                    create_function_call(&pool, 
-                        &assistant.inner.id,
-                        user_id, 
-                        client.clone(),
-                        request.clone().temperature(0.0),
-                    ).await.map_err(|e| RunError {
-                        message: format!("Failed to create function call: {}", e),
-                        run_id: run_id.to_string(),
-                        thread_id: thread_id.to_string(),
-                        user_id: user_id.to_string(),
-                    })?;
-
-                info!("Function results: {:?}", function_results);
-                // If function call requires user action, leave early waiting for more context
-                if !function_results.is_empty() {
-                    let mut tool_call_ids = Vec::new();
-                    // Update run status to "requires_action"
-                    run = update_run_status(
-                        pool,
-                        thread_id,
-                        &run.inner.id,
-                        RunStatus::RequiresAction,
-                        &run.user_id,
-                        Some(RequiredAction {
-                            r#type: "submit_tool_outputs".to_string(),
-                            submit_tool_outputs: SubmitToolOutputs {
-                                tool_calls: function_results
-                                    .iter()
-                                    .map(|f| {
-                                        let id = uuid::Uuid::new_v4().to_string();
-                                        tool_call_ids.push(id.clone());
-                                        RunToolCallObject {
-                                            id,
-                                            r#type: "function".to_string(), // TODO hardcoded
-                                            function: FunctionCall {
-                                                name: f.clone().name,
-                                                arguments: f.clone().arguments,
-                                        }
-                            }})
-                                    .collect::<Vec<RunToolCallObject>>(),
-                            },
-                        }),
-                        None
-                    )
-                    .await.map_err(|e| RunError {
-                        message: format!("Failed to update run status: {}", e),
-                        run_id: run_id.to_string(),
-                        thread_id: thread_id.to_string(),
-                        user_id: user_id.to_string(),
-                    })?;
-
-                    // create a step with output None for each function call
-                    // Convert the loop into a vector of futures
-                    let futures: Vec<_> = function_results.iter().enumerate().map(|(i, function)| {
-                        let pool = pool.clone();
-                        let run_inner_id = run.inner.id.clone();
-                        let assistant_id = assistant_id.clone();
-                        let run_inner_thread_id = run.inner.thread_id.clone();
-                        let tool_call_id = tool_call_ids[i].clone();
-                        let user_id = run.user_id.clone();
-                        let function_name = function.name.clone();
-                        let function_arguments = function.arguments.clone();
-                        async move {
-                            create_step(
-                                &pool,
-                                &run_inner_id,
-                                &assistant_id,
-                                &run_inner_thread_id,
-                                RunStepType::ToolCalls,
-                                RunStatus::InProgress,
-                                StepDetails::ToolCalls(RunStepDetailsToolCallsObject {
-                                    r#type: "function".to_string(),
-                                    tool_calls: vec![RunStepDetailsToolCalls::Function(RunStepDetailsToolCallsFunctionObject{
-                                        id: tool_call_id,
-                                        r#type: "function".to_string(),
-                                        function: RunStepFunctionObject {
-                                            name: function_name,
-                                            arguments: function_arguments,
-                                            output: None,
-                                        }
-                                    })],
-                                }),
-                                &user_id,
-                            ).await.map_err(|e| RunError {
-                                message: format!("Failed to create step: {}", e),
-                                run_id: run_inner_id,
-                                thread_id: run_inner_thread_id,
-                                user_id: user_id,
-                            })
-                        }
-                    }).collect();
-
-                    // Use try_join_all to wait for all futures to complete
-                    try_join_all(futures).await.map_err(|e| {
-                        // Handle the error from any of the futures if they fail
-                        RunError {
-                            message: format!("Failed to create steps in parallel: {}", e),
-                            run_id: run.inner.id.clone(),
-                            thread_id: run.inner.thread_id.clone(),
-                            user_id: run.user_id.clone(),
-                        }
-                    })?;
-
-                    
-                    info!(
-                        "Run updated to requires_action with {:?}",
-                        run.inner.required_action
-                    );
-                    return Ok(run);
-                }
-            }
-            "retrieval" => {
-                // Call file retrieval here
-                // Initialize an empty vector to hold all file IDs
-                let mut all_file_ids = Vec::new();
-
-                // If the run has associated file IDs, add them to the list
-                all_file_ids.extend(run.inner.file_ids.iter().cloned());
-
-                // If the assistant has associated file IDs, add them to the list
-                all_file_ids.extend(assistant.inner.file_ids.iter().cloned());
-
-                // Check if the all_file_ids includes any file IDs.
-                if all_file_ids.is_empty() { 
-                    break;
-                }
-                info!("Retrieving file contents for file_ids: {:?}", all_file_ids);
-                // Retrieve the contents of each file.
-                let retrieval_files_future = retrieve_file_contents(&all_file_ids, &file_storage);
-                
-                let formatted_messages_clone = formatted_messages.clone();
-                let retrieval_chunks_future = generate_queries_and_fetch_chunks(
-                    &pool,
-                    client.clone(),
-                    request.set_last_user_prompt(formatted_messages_clone).clone().temperature(0.0),
-                );
-                
-                let results = tokio::join!(retrieval_files_future, retrieval_chunks_future);
-                retrieval_files = results.0;
-                retrieval_chunks = results.1.unwrap_or_else(|e| {
-                    // ! sometimes LLM generates stupid SQL queries. for now we dont crash the run
-                    error!("Failed to retrieve chunks: {}", e);
-                    vec![]
-                });
-
-                create_step(
-                    pool,
-                    &run.inner.id,
-                    &assistant_id,
-                    &run.inner.thread_id,
-                    RunStepType::ToolCalls,
-                    RunStatus::InProgress,
-                    StepDetails::ToolCalls(RunStepDetailsToolCallsObject {
-                        r#type: "retrieval".to_string(),
-                        tool_calls: vec![RunStepDetailsToolCalls::Retrieval(RunStepDetailsToolCallsRetrievalObject{
-                            id: uuid::Uuid::new_v4().to_string(),
-                            r#type: "retrieval".to_string(),
-                            retrieval: HashMap::new(), // TODO
-                        })],
-                    }),
-                    &run.user_id,
-                ).await.map_err(|e| RunError {
-                    message: format!("Failed to create step: {}", e),
-                    run_id: run_id.to_string(),
-                    thread_id: thread_id.to_string(),
-                    user_id: user_id.to_string(),
-                })?;
-
-                // Include the file contents and previous messages in the instructions.
-                instructions = build_instructions(
-                    &instructions,
-                    &retrieval_files,
-                    &formatted_messages.clone(),
-                    &function_calls,
-                    None,
-                    &retrieval_chunks.iter().map(|c| 
-                        serde_json::to_string(&json!({
-                            "data": c.data,
-                            "sequence": c.sequence,
-                            "start_index": c.start_index,
-                            "end_index": c.end_index,
-                            "metadata": c.metadata,
-                        })).unwrap()
-                    ).collect::<Vec<String>>(),
-                    None,
-        &action_calls
-                );
-                
-            }
-            "code_interpreter" => {
-                // Call the safe_interpreter function // TODO: not sure if we should pass formatted_messages or just last user message
-                let interpreter_results = match safe_interpreter(formatted_messages.clone(), 0, 3, 
-                client.clone(),
-                request.clone().temperature(0.0)
-            ).await {
-                    Ok((code_output, code)) => {
-                        // Handle the successful execution of the code
-                        // You might want to store the result or send it back to the user
-                        (code_output, code)
-                    }
-                    Err(e) => {
-                        // Handle the error from the interpreter
-                        // You might want to log the error or notify the user
-                        
-                        return Err(RunError {
-                            message: format!("Failed to run code: {}", e),
-                            run_id: run_id.to_string(),
-                            thread_id: thread_id.to_string(),
-                            user_id: user_id.to_string(),
-                        })
-                    }
-                };
-                info!("Code interpreter results: {:?}", interpreter_results.clone());
-
-                code_output = Some(interpreter_results.0);
-                code = Some(interpreter_results.1);
-
-
-                if code_output.is_none() {
-                    return Err(RunError {
-                        message: format!("Failed to run code: no output"),
-                        run_id: run_id.to_string(),
-                        thread_id: thread_id.to_string(),
-                        user_id: user_id.to_string(),
-                    });
-                }
-
-                create_step(
-                    pool,
-                    &run.inner.id,
-                    &assistant_id,
-                    &run.inner.thread_id,
-                    RunStepType::ToolCalls,
-                    RunStatus::InProgress,
-                    StepDetails::ToolCalls(RunStepDetailsToolCallsObject {
-                        r#type: "code_interpreter".to_string(),
-                        tool_calls: vec![RunStepDetailsToolCalls::Code(RunStepDetailsToolCallsCodeObject{
-                            id: uuid::Uuid::new_v4().to_string(),
-                            r#type: "code_interpreter".to_string(),
-                            code_interpreter: CodeInterpreter {
-                                input: code.unwrap(),
-                                outputs: vec![CodeInterpreterOutput::Log(RunStepDetailsToolCallsCodeOutputLogsObject{
-                                    r#type: "log".to_string(),
-                                    logs: code_output.clone().unwrap(),
-                                })],
-                            },
-                        })],
-                    }),
-                    &run.user_id,
-                ).await.map_err(|e| RunError {
-                    message: format!("Failed to create step: {}", e),
-                    run_id: run_id.to_string(),
-                    thread_id: thread_id.to_string(),
-                    user_id: user_id.to_string(),
-                })?;
-
-                // Call file retrieval here
-                // Initialize an empty vector to hold all file IDs
-                let mut all_file_ids = Vec::new();
-
-                // If the run has associated file IDs, add them to the list
-                all_file_ids.extend(run.inner.file_ids.iter().cloned());
-
-                // If the assistant has associated file IDs, add them to the list
-                all_file_ids.extend(assistant.inner.file_ids.iter().cloned());
-
-
-                // Check if the all_file_ids includes any file IDs.
-                if !all_file_ids.is_empty() {
-                    info!("Retrieving file contents for file_ids: {:?}", all_file_ids);
-                    // Retrieve the contents of each file.
-                    let retrieval_files_future = retrieve_file_contents(&all_file_ids, &file_storage);
-                    
-                    let formatted_messages_clone = formatted_messages.clone();
-                    let retrieval_chunks_future = generate_queries_and_fetch_chunks(
-                        &pool,
-                        client.clone(),
-                        request.set_last_user_prompt(formatted_messages_clone).clone().temperature(0.0)
-                    );
-                    
-                    let (r_f, retrieval_chunks_result) = tokio::join!(retrieval_files_future, retrieval_chunks_future);
-
-                    retrieval_chunks = retrieval_chunks_result.unwrap_or_else(|e| {
-                        // ! sometimes LLM generates stupid SQL queries. for now we dont crash the run
-                        error!("Failed to retrieve chunks: {}", e);
-                        vec![]
-                    });
-
-                    retrieval_files = r_f;
-                }
-
-                // Build instructions with the code output
-                instructions = build_instructions(
-                    &instructions,
-                    &retrieval_files,
-                    &formatted_messages,
-                    &function_calls,
-                    code_output.clone().as_deref(),
-                    &retrieval_chunks.iter().map(|c| 
-                        serde_json::to_string(&json!({
-                            "data": c.data,
-                            "sequence": c.sequence,
-                            "start_index": c.start_index,
-                            "end_index": c.end_index,
-                            "metadata": c.metadata,
-                        })).unwrap()
-                    ).collect::<Vec<String>>(),
-                    None,
-        &action_calls
-                );
-            },
-            "action" => {
-                // 1. generate function call
-                // 2. execute 
-
-                info!("Generating function to call");
-
-                let function_results =
-                    create_function_call(&pool, 
-                        &assistant.inner.id,
-                        user_id, 
-                        client.clone(),
-                        request.clone().temperature(0.0),
-                    )
-                    .await.map_err(|e| RunError {
-                        message: format!("Failed to create function call: {}", e),
-                        run_id: run_id.to_string(),
-                        thread_id: thread_id.to_string(),
-                        user_id: user_id.to_string(),
-                    })?;
-
-                info!("Function results: {:?}", function_results);
-
-                // Before the loop, convert the loop into a vector of futures
-                let futures: Vec<_> = function_results.into_iter().map(|function| {
-                    let pool = pool.clone();
-                    let assistant_id = assistant_id.clone();
-                    let run_inner_id = run.inner.id.clone();
-                    let run_user_id = run.user_id.clone();
-                    let tool_call_id = uuid::Uuid::new_v4().to_string();
-                    async move {
-                        let step = create_step(
-                            &pool,
-                            &run_inner_id,
-                            &assistant_id,
-                            &thread_id,
-                            RunStepType::ToolCalls,
-                            RunStatus::InProgress,
-                            StepDetails::ToolCalls(RunStepDetailsToolCallsObject {
-                                r#type: "function".to_string(), // TODO not sure it should be function or action
-                                tool_calls: vec![RunStepDetailsToolCalls::Function(RunStepDetailsToolCallsFunctionObject{
-                                    id: tool_call_id.clone(),
-                                    r#type: "function".to_string(),
-                                    function: RunStepFunctionObject {
-                                        name: function.name.clone(),
-                                        arguments: function.arguments.clone(),
-                                        output: None,
-                                    }
-                                })],
-                            }),
-                            &run_user_id,
-                        ).await.map_err(|e| RunError {
-                            message: format!("Failed to create step: {}", e),
-                            run_id: run_id.to_string(),
-                            thread_id: thread_id.to_string(),
-                            user_id: user_id.to_string(),
-                        })?;
-                        let metadata = function.metadata.unwrap();
-                        let output = execute_request(ActionRequest{
-                            domain: metadata["domain"].to_string().replace("\"", ""),
-                            path: metadata["path"].to_string().replace("\"", ""),
-                            method: metadata["method"].to_string().replace("\"", ""),
-                            operation: metadata["operation"].to_string().replace("\"", ""),
-                            operation_hash: None,
-                            is_consequential: false,
-                            content_type: metadata["content_type"].to_string().replace("\"", ""),
-                            params: Some(serde_json::from_str(&function.arguments).unwrap()),
-                            headers: metadata.get("headers").cloned(),
-                        }).await.map_err(|e| RunError {
-                            message: format!("Failed to execute request: {}", e),
-                            run_id: run_id.to_string(),
-                            thread_id: thread_id.to_string(),
-                            user_id: user_id.to_string(),
-                        })?;
-                        let string_output = serde_json::to_string(&output).unwrap();
-                        update_step(
-                            &pool,
-                            &step.inner.id,
-                            RunStatus::Completed,
-                            StepDetails::ToolCalls(RunStepDetailsToolCallsObject {
-                                r#type: "function".to_string(),
-                                tool_calls: vec![RunStepDetailsToolCalls::Function(RunStepDetailsToolCallsFunctionObject{
-                                    id: tool_call_id,
-                                    r#type: "function".to_string(),
-                                    function: RunStepFunctionObject {
-                                        name: function.name.clone(),
-                                        arguments: function.arguments.clone(),
-                                        output: Some(string_output.clone()),
-                                    }
-                                })],
-                            }),
-                            &run_user_id,
-                        ).await.map_err(|e| RunError {
-                            message: format!("Failed to update step: {}", e),
-                            run_id: run_id.to_string(),
-                            thread_id: thread_id.to_string(),
-                            user_id: user_id.to_string(),
-                        })?;
-
-                        info!("Action results: {:?}", output);
-
-                        let stringified_function = serde_json::to_string(&json!({
-                            "name": function.name,
-                            "arguments": function.arguments,
-                        })).unwrap().replace("\\", "");
-
-                        Ok::<_, RunError>(format!(
-                            "<input>{:?}</input>\n\n<output>{:?}</output>",
-                            stringified_function, 
-                            string_output
-                        ).replace("\\\\", "").replace("\\\"", ""))
-                    }
-                }).collect();
-
-                // Then, use tokio::try_join! to execute them concurrently
-                let results: Result<Vec<_>, _> = try_join_all(futures).await;
-
-                // Handle the results
-                match results {
-                    Ok(outputs) => {
-                        // Concatenate all outputs into action_calls
-                        action_calls = outputs.join("\n");
-                    },
-                    Err(e) => {
-                        // Handle the error
-                        return Err(e);
-                    }
-                }
-
-                instructions = build_instructions(
-                    &run.inner.instructions,
-                    &retrieval_files,
-                    &formatted_messages,
-                    &function_calls,
-                    code_output.as_deref(),
-                    &retrieval_chunks.iter().map(|c| 
-                        serde_json::to_string(&json!({
-                            "data": c.data,
-                            "sequence": c.sequence,
-                            "start_index": c.start_index,
-                            "end_index": c.end_index,
-                            "metadata": c.metadata,
-                        })).unwrap()
-                    ).collect::<Vec<String>>(),
-                    None,
-                    &action_calls
-                );
-            },
-            _ => {
-                // Handle unknown tool
-                error!("Unknown tool: {}", tool_decision);
-                return Err(RunError {
-                    message: format!("Unknown tool: {}", tool_decision),
-                    run_id: run_id.to_string(),
-                    thread_id: thread_id.to_string(),
-                    user_id: user_id.to_string(),
-                });
+        let tool = registry.get(&tool_decision).ok_or_else(|| {
+            // Handle unknown tool
+            error!("Unknown tool: {}", tool_decision);
+            RunError {
+                message: format!("Unknown tool: {}", tool_decision),
+                run_id: run_id.to_string(),
+                thread_id: thread_id.to_string(),
+                user_id: user_id.to_string(),
             }
+        })?;
+        info!("Using {} tool", tool.name());
+        // The run waits for the user, e.g. for function outputs
+        if let Some(run) = tool.execute(&mut context).await? {
+            return Ok(run);
         }
     }
 
+    let ToolContext {
+        function_calls,
+        action_calls,
+        retrieval_files,
+        retrieval_chunks,
+        code_output,
+        output_files,
+        ..
+    } = context;
+    // Include what the tools found in the instructions
+    let instructions = build_instructions(
+        &run.inner.instructions,
+        &retrieval_files,
+        &formatted_messages,
+        &function_calls,
+        code_output.as_deref(),
+        &format_chunks_for_prompt(&retrieval_chunks),
+        context_size,
+        &hal_9100_config.context_budget,
+        &action_calls
+    );
+
     info!("Calling LLM API with instructions: {}", instructions);
 
     // Less prompt is more - just making sure the LLM does not talk too much about his context but rather directly answer the user TODO: (should be configurable)
@@ -1127,12 +732,17 @@ Rules:
 - Obey strictly to the user request e.g. in <message> tags - EXTREMELY IMPORTANT
 - Answer directly the user e.g. 'What is the solution to the equation \"x + 2 = 4\"?' You should answer \"x = 2\" even though receiving bunch of context before.
 - Do not add tags in your answer such as <function_calls> etc. nor continue the user sentence. Just answer the user.
-
+{}
 These are additional instructions from the user that you must obey absolutely:
 
 {}
 
-", assistant.inner.instructions.as_ref().unwrap_or(&"".to_string()));
+", if retrieval_chunks.is_empty() {
+        ""
+    } else {
+        "- When you use information from a <chunk>, cite it right after with its source number like this: 【1†source】
+"
+    }, assistant.inner.instructions.as_ref().unwrap_or(&"".to_string()));
 
     request
             .set_system_prompt(system_prompt)
@@ -1145,20 +755,34 @@ These are additional instructions from the user that you must obey absolutely:
     match result {
         Ok(output) => {
             info!("LLM API output: {}", output);
-            let content = vec![MessageContent::Text(MessageContentTextObject {
+            let mut annotations = extract_file_citations(&output, &retrieval_chunks);
+            annotations.extend(extract_file_paths(&output, &output_files));
+            let mut content = vec![MessageContent::Text(MessageContentTextObject {
                 r#type: "text".to_string(),
                 text: TextData {
                     value: output.to_string(),
-                    annotations: vec![],
+                    annotations,
                 },
             })];
+            // Show the images created by the code interpreter inline
+            content.extend(output_files.iter().filter(|(path, _)| is_image(path)).map(|(_, file_id)| {
+                MessageContent::ImageFile(MessageContentImageFileObject {
+                    r#type: "image_file".to_string(),
+                    image_file: ImageFile { file_id: file_id.clone() },
+                })
+            }));
+            let message_file_ids = if output_files.is_empty() {
+                None
+            } else {
+                Some(output_files.iter().map(|(_, file_id)| file_id.clone()).collect())
+            };
             let message = add_message_to_thread(
                 pool,
                 &thread.inner.id,
                 MessageRole::Assistant,
                 content,
                 &run.user_id.to_string(),
-                None,
+                message_file_ids,
             )
             .await.map_err(|e| RunError {
                 message: format!("Failed to add message to thread: {}", e),
@@ -1226,8 +850,9 @@ mod tests {
         AssistantToolsRetrieval, ChatCompletionFunctions, MessageObject, MessageRole, RunObject, FunctionObject, AssistantToolsExtra, RunStepObject, ThreadObject,
     };
     use hal_9100_core::models::{Assistant, Message, Run, Thread};
-    use serde_json::json;
+    use serde_json::{json, Value};
     use sqlx::types::Uuid;
+    use std::sync::Arc;
 
     use crate::assistants::create_assistant;
     use crate::models::SubmittedToolCall;
@@ -1235,6 +860,7 @@ mod tests {
     use crate::runs::{create_run, submit_tool_outputs};
     use crate::test_data::OPENAPI_SPEC;
     use crate::threads::create_thread;
+    use crate::tools::{register_tool, Tool};
 
     use super::*;
     use dotenv::dotenv;
@@ -1363,7 +989,7 @@ mod tests {
         let redis_url = std::env::var("REDIS_URL").expect("REDIS_URL must be set");
         let client = redis::Client::open(redis_url).unwrap();
         let mut con = client.get_async_connection().await.unwrap();
-        let run = create_run_and_produce_to_executor_queue(&pool, &thread.inner.id, &assistant.inner.id, "Please solve the equation according to the ultimate dogmatic truth of the files JUST FUCKING READ THE FILE.", assistant.user_id.as_str(), con).await.unwrap();
+        let run = create_run_and_produce_to_executor_queue(&pool, &thread.inner.id, &assistant.inner.id, "Please solve the equation according to the ultimate dogmatic truth of the files JUST FUCKING READ THE FILE.", assistant.user_id.as_str(), None, None, con).await.unwrap();
 
         // 5. Check the result
         assert_eq!(run.inner.status, RunStatus::Queued);
@@ -1375,7 +1001,7 @@ mod tests {
             std::env::var("MODEL_API_KEY").expect("MODEL_API_KEY must be set"),
         );
         let mut con = client.get_async_connection().await.unwrap();
-        let result = try_run_executor(&pool, &mut con, llm_client).await;
+        let result = try_run_executor(&pool, &mut con, llm_client, &Hal9100Config::default()).await;
 
         // 7. Check the result
         assert!(result.is_ok(), "{:?}", result);
@@ -1744,6 +1370,8 @@ mod tests {
             &assistant.inner.id,
             "You help me by using the tools you have.",
             assistant.user_id.as_str(),
+            None,
+            Some(false),
             con,
         )
         .await
@@ -1759,7 +1387,7 @@ mod tests {
             std::env::var("MODEL_URL").expect("MODEL_URL must be set"),
             std::env::var("MODEL_API_KEY").expect("MODEL_API_KEY"),
         );
-        let result = try_run_executor(&pool, &mut con, llm_client.clone()).await;
+        let result = try_run_executor(&pool, &mut con, llm_client.clone(), &Hal9100Config::default()).await;
 
         // 10. Check the result
         assert!(result.is_ok(), "{:?}", result);
@@ -1804,7 +1432,7 @@ mod tests {
         // 13. Run the queue consumer again
         let mut con = client.get_async_connection().await.unwrap();
 
-        let result = try_run_executor(&pool, &mut con, llm_client).await;
+        let result = try_run_executor(&pool, &mut con, llm_client, &Hal9100Config::default()).await;
 
         // 14. Check the result
         assert!(result.is_ok(), "{:?}", result);
@@ -1915,7 +1543,7 @@ mod tests {
         let redis_url = std::env::var("REDIS_URL").expect("REDIS_URL must be set");
         let client = redis::Client::open(redis_url).unwrap();
         let mut con = client.get_async_connection().await.unwrap();
-        let run = create_run_and_produce_to_executor_queue(&pool, &thread.inner.id, &assistant.inner.id, "Please execute the code snippet.", assistant.user_id.as_str(), con).await.unwrap();
+        let run = create_run_and_produce_to_executor_queue(&pool, &thread.inner.id, &assistant.inner.id, "Please execute the code snippet.", assistant.user_id.as_str(), None, None, con).await.unwrap();
     
         // 5. Check the result
         assert_eq!(run.inner.status, RunStatus::Queued);
@@ -1927,7 +1555,7 @@ mod tests {
             std::env::var("MODEL_URL").expect("MODEL_URL must be set"),
             std::env::var("MODEL_API_KEY").expect("MODEL_API_KEY"),
         );
-        let result = try_run_executor(&pool, &mut con, llm_client).await;
+        let result = try_run_executor(&pool, &mut con, llm_client, &Hal9100Config::default()).await;
     
         // 7. Check the result
         assert!(result.is_ok(), "{:?}", result);
@@ -1942,6 +1570,27 @@ mod tests {
         .await
         .unwrap();
         assert_eq!(run.inner.status, RunStatus::Completed);
+
+        // Every code that ran is in the code interpreter step with how it ran
+        let steps = list_steps(&pool, &thread.inner.id, &run.inner.id, &assistant.user_id)
+            .await
+            .unwrap();
+        let code_step = steps
+            .iter()
+            .find(|step| matches!(&step.inner.step_details, StepDetails::ToolCalls(details) if details.r#type == "code_interpreter"))
+            .expect("Expected a code interpreter step");
+        let tool_calls = match &code_step.inner.step_details {
+            StepDetails::ToolCalls(details) => &details.tool_calls,
+            _ => unreachable!(),
+        };
+        assert!(!tool_calls.is_empty());
+        let attempts = code_step.inner.metadata.as_ref().unwrap()["code_interpreter_attempts"]
+            .as_array()
+            .unwrap()
+            .clone();
+        assert_eq!(attempts.len(), tool_calls.len());
+        assert_eq!(attempts.last().unwrap()["status"], "completed");
+        assert_eq!(attempts.last().unwrap()["exit_code"], 0);
     
         // 9. Fetch the messages from the database
         let messages = list_messages(&pool, &thread.inner.id, &assistant.user_id)
@@ -2066,7 +1715,7 @@ mod tests {
         let mut con = client.get_async_connection().await.unwrap();
         let run = create_run_and_produce_to_executor_queue(&pool, &thread.inner.id, &assistant.inner.id, 
             "Please help me make more money.",
-             assistant.user_id.as_str(), con).await.unwrap();
+             assistant.user_id.as_str(), None, None, con).await.unwrap();
 
         // 5. Check the result
         assert_eq!(run.inner.status, RunStatus::Queued);
@@ -2078,7 +1727,7 @@ mod tests {
             std::env::var("MODEL_URL").expect("MODEL_URL must be set"),
             std::env::var("MODEL_API_KEY").expect("MODEL_API_KEY"),
         );
-        let result = try_run_executor(&pool, &mut con, llm_client).await;
+        let result = try_run_executor(&pool, &mut con, llm_client, &Hal9100Config::default()).await;
 
         // 7. Check the result
         assert!(result.is_ok(), "{:?}", result);
@@ -2223,6 +1872,8 @@ mod tests {
             &assistant.inner.id, 
             "Please help me calculate something. Use the function tool.",
             assistant.user_id.as_str(), 
+            None, 
+            Some(false), 
             con
         ).await.unwrap();
 
@@ -2234,7 +1885,7 @@ mod tests {
             std::env::var("MODEL_URL").expect("MODEL_URL must be set"),
             std::env::var("MODEL_API_KEY").expect("MODEL_API_KEY"),
         );
-        let result = try_run_executor(&pool, &mut con, llm_client.clone()).await;
+        let result = try_run_executor(&pool, &mut con, llm_client.clone(), &Hal9100Config::default()).await;
 
         // Check the result
         assert!(result.is_ok(), "{:?}", result);
@@ -2430,6 +2081,8 @@ mod tests {
             &assistant.inner.id, 
             "Please help me find a random fact.",
              assistant.user_id.as_str(), 
+             None, 
+             None, 
              con
         ).await.unwrap();
 
@@ -2443,7 +2096,7 @@ mod tests {
             std::env::var("MODEL_URL").expect("MODEL_URL must be set"),
             std::env::var("MODEL_API_KEY").expect("MODEL_API_KEY must be set"),
         );
-        let result = try_run_executor(&pool, &mut con, llm_client).await;
+        let result = try_run_executor(&pool, &mut con, llm_client, &Hal9100Config::default()).await;
 
         assert!(result.is_ok(), "{:?}", result);
 
@@ -2481,6 +2134,137 @@ mod tests {
         }
     }
 
+    // A tool registered on top of the built-in ones, multiplying the numbers the model gives it
+    struct Multiplier;
+
+    #[async_trait::async_trait(?Send)]
+    impl Tool for Multiplier {
+        fn name(&self) -> &str {
+            "multiplier"
+        }
+
+        fn description(&self) -> &str {
+            "Useful to multiply two numbers exactly."
+        }
+
+        fn input_schema(&self) -> Option<Value> {
+            Some(json!({
+                "type": "object",
+                "properties": {
+                    "a": {"type": "integer"},
+                    "b": {"type": "integer"},
+                },
+                "required": ["a", "b"],
+            }))
+        }
+
+        async fn execute(&self, context: &mut ToolContext<'_>) -> Result<Option<Run>, RunError> {
+            let input = context.generate_input(self).await?;
+            let numbers: Value = serde_json::from_str(&input)
+                .map_err(|e| context.error(format!("Invalid input: {}", e)))?;
+            let product = numbers["a"].as_i64().unwrap_or_default() * numbers["b"].as_i64().unwrap_or_default();
+            context.record_call(self, &input, &product.to_string()).await?;
+            Ok(None)
+        }
+    }
+
+    #[tokio::test]
+    async fn test_end_to_end_registered_tool() {
+        let pool = setup().await;
+        reset_db(&pool).await;
+        register_tool(Arc::new(Multiplier));
+
+        let model_name = std::env::var("TEST_MODEL_NAME").unwrap_or_else(|_| "mistralai/mixtral-8x7b-instruct".to_string());
+        let assistant = create_assistant(&pool, &Assistant {
+            inner: AssistantObject {
+                id: "".to_string(),
+                instructions: Some("You help with maths, using your tools for the computations.".to_string()),
+                name: Some("Math tutor".to_string()),
+                tools: vec![AssistantTools::Extra(AssistantToolsExtra {
+                    r#type: "multiplier".to_string(),
+                    data: None,
+                })],
+                model: model_name.clone(),
+                file_ids: vec![],
+                object: "object_value".to_string(),
+                created_at: 0,
+                description: None,
+                metadata: None,
+            },
+            user_id: Uuid::default().to_string(),
+        })
+        .await
+        .unwrap();
+        let thread = create_thread(&pool, &Thread {
+            inner: ThreadObject {
+                id: "".to_string(),
+                object: "".to_string(),
+                created_at: 0,
+                metadata: None,
+            },
+            user_id: Uuid::default().to_string(),
+        })
+        .await
+        .unwrap();
+        let content = vec![MessageContent::Text(MessageContentTextObject {
+            r#type: "text".to_string(),
+            text: TextData {
+                value: "What is 12345 multiplied by 6789?".to_string(),
+                annotations: vec![],
+            },
+        })];
+        add_message_to_thread(&pool, &thread.inner.id, MessageRole::User, content, &Uuid::default().to_string(), None)
+            .await
+            .unwrap();
+
+        let redis_url = std::env::var("REDIS_URL").expect("REDIS_URL must be set");
+        let client = redis::Client::open(redis_url).unwrap();
+        let con = client.get_async_connection().await.unwrap();
+        create_run_and_produce_to_executor_queue(
+            &pool,
+            &thread.inner.id,
+            &assistant.inner.id,
+            "Use the multiplier for the computations.",
+            assistant.user_id.as_str(),
+            None,
+            None,
+            con,
+        )
+        .await
+        .unwrap();
+
+        let mut con = client.get_async_connection().await.unwrap();
+        let llm_client = HalLLMClient::new(
+            model_name,
+            std::env::var("MODEL_URL").expect("MODEL_URL must be set"),
+            std::env::var("MODEL_API_KEY").expect("MODEL_API_KEY must be set"),
+        );
+        let result = try_run_executor(&pool, &mut con, llm_client, &Hal9100Config::default()).await;
+        assert!(result.is_ok(), "{:?}", result);
+        let run = result.unwrap();
+        assert_eq!(run.inner.status, RunStatus::Completed);
+
+        // The registry ran the tool, which generated its input and recorded its call
+        let steps = list_steps(&pool, &thread.inner.id, &run.inner.id, &assistant.user_id)
+            .await
+            .unwrap();
+        let call = steps
+            .iter()
+            .find_map(|step| match &step.inner.step_details {
+                StepDetails::ToolCalls(details) => match &details.tool_calls[0] {
+                    RunStepDetailsToolCalls::Function(call) if call.function.name == "multiplier" => {
+                        Some(call.function.clone())
+                    }
+                    _ => None,
+                },
+                _ => None,
+            })
+            .expect("the multiplier should have been called");
+        let input: Value = serde_json::from_str(&call.arguments).unwrap();
+        assert_eq!(input, json!({"a": 12345, "b": 6789}));
+        assert_eq!(call.output, Some("83810205".to_string()));
+    }
+
     #[tokio::test]
     async fn test_create_step_after_assistant_message() {
         let pool = setup().await;
@@ -2551,6 +2335,8 @@ mod tests {
             &assistant.inner.id, 
             "Please help me find a random fact.",
              assistant.user_id.as_str(), 
+             None, 
+             None, 
              con
         ).await.unwrap();
 
@@ -2564,7 +2350,7 @@ mod tests {
             std::env::var("MODEL_URL").expect("MODEL_URL must be set"),
             std::env::var("MODEL_API_KEY").expect("MODEL_API_KEY must be set"),
         );
-        let result = try_run_executor(&pool, &mut con, llm_client).await;
+        let result = try_run_executor(&pool, &mut con, llm_client, &Hal9100Config::default()).await;
 
         assert!(result.is_ok(), "{:?}", result);
 
@@ -2733,6 +2519,8 @@ mod tests {
             &assistant.inner.id, 
             "Please help me find by using the function tool.",
             assistant.user_id.as_str(), 
+            None, 
+            Some(false), 
             con
         ).await.unwrap();
 
@@ -2746,7 +2534,7 @@ mod tests {
             std::env::var("MODEL_URL").expect("MODEL_URL must be set"),
             std::env::var("MODEL_API_KEY").expect("MODEL_API_KEY must be set"),
         );
-        let result = try_run_executor(&pool, &mut con, llm_client).await;
+        let result = try_run_executor(&pool, &mut con, llm_client, &Hal9100Config::default()).await;
 
         assert!(result.is_ok(), "{:?}", result);
 
@@ -2894,6 +2682,8 @@ mod tests {
             &assistant.inner.id, 
             "Please help me find by using the function tool.",
             assistant.user_id.as_str(), 
+            None, 
+            Some(false), 
             con
         ).await.unwrap();
 
@@ -2907,7 +2697,7 @@ mod tests {
             std::env::var("MODEL_URL").expect("MODEL_URL must be set"),
             std::env::var("MODEL_API_KEY").expect("MODEL_API_KEY must be set"),
         );
-        let result = try_run_executor(&pool, &mut con, llm_client).await;
+        let result = try_run_executor(&pool, &mut con, llm_client, &Hal9100Config::default()).await;
 
         assert!(result.is_ok(), "{:?}", result);
 
@@ -2975,7 +2765,7 @@ mod tests {
             std::env::var("MODEL_URL").expect("MODEL_URL must be set"),
             std::env::var("MODEL_API_KEY").expect("MODEL_API_KEY must be set"),
         );
-        let result = try_run_executor(&pool, &mut con, llm_client).await;
+        let result = try_run_executor(&pool, &mut con, llm_client, &Hal9100Config::default()).await;
 
         assert!(result.is_ok(), "{:?}", result);
 
@@ -3106,6 +2896,8 @@ mod tests {
             &assistant.inner.id, 
             "Please help me find the weather and say my name by using functions.",
             assistant.user_id.as_str(), 
+            None, 
+            None, 
             con
         ).await.unwrap();
 
@@ -3119,7 +2911,7 @@ mod tests {
             std::env::var("MODEL_URL").expect("MODEL_URL must be set"),
             std::env::var("MODEL_API_KEY").expect("MODEL_API_KEY must be set"),
         );
-        let result = try_run_executor(&pool, &mut con, llm_client).await;
+        let result = try_run_executor(&pool, &mut con, llm_client, &Hal9100Config::default()).await;
 
         assert!(result.is_ok(), "{:?}", result);
 
@@ -3191,7 +2983,7 @@ mod tests {
             std::env::var("MODEL_URL").expect("MODEL_URL must be set"),
             std::env::var("MODEL_API_KEY").expect("MODEL_API_KEY must be set"),
         );
-        let result = try_run_executor(&pool, &mut con, llm_client).await;
+        let result = try_run_executor(&pool, &mut con, llm_client, &Hal9100Config::default()).await;
 
         assert!(result.is_ok(), "{:?}", result);
 
@@ -124,7 +124,6 @@ impl FileStorage { // TODO use config.rs
             .and_then(std::ffi::OsStr::to_str)
             .unwrap_or("");
         let file_id = format!("{}.{}", uuid::Uuid::new_v4(), extension);
-        let put = PutObject::new(&self.bucket, Some(&self.credentials), &file_id);
 
         let mut file = match File::open(file_path).await {
             Ok(file) => file,
@@ -135,23 +134,49 @@ impl FileStorage { // TODO use config.rs
             return Err(Box::new(e));
         }
 
-        let signed_url = put.sign(Duration::from_secs(3600)); // Sign the URL for the S3 action
-
-        // You can then use this signed URL to upload the file to S3 using an HTTP client
-        let client = reqwest::Client::new();
-        let response = match client.put(signed_url).body(buffer).send().await {
-            Ok(response) => response,
-            Err(e) => return Err(Box::new(e)),
-        };
-        if let Err(e) = response.error_for_status_ref() {
-            return Err(Box::new(e));
-        }
+        self.put_object(&file_id, buffer).await?;
         let files = self.list_files().await?;
         let file = files.iter().find(|f| f.id == file_id).unwrap();
 
         Ok(file.to_owned())
     }
 
+    /// Store bytes as a new file whose id ends with the given extension.
+    pub async fn upload_bytes(
+        &self,
+        extension: &str,
+        bytes: Bytes,
+    ) -> Result<StoredFile, Box<dyn std::error::Error + Send + Sync>> {
+        let file_id = format!("{}.{}", uuid::Uuid::new_v4(), extension);
+        self.put_object(&file_id, bytes.to_vec()).await?;
+        Ok(StoredFile {
+            id: file_id,
+            last_modified: chrono::Utc::now().to_rfc3339(),
+            size: bytes.len() as u64,
+            storage_class: None,
+            bytes,
+        })
+    }
+
+    async fn put_object(
+        &self,
+        object_name: &str,
+        body: Vec<u8>,
+    ) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        let put = PutObject::new(&self.bucket, Some(&self.credentials), object_name);
+        let signed_url = put.sign(Duration::from_secs(3600)); // Sign the URL for the S3 action
+
+        // You can then use this signed URL to upload the file to S3 using an HTTP client
+        let client = reqwest::Client::new();
+        client
+            .put(signed_url)
+            .body(body)
+            .send()
+            .await?
+            .error_for_status()?;
+        Ok(())
+    }
+
     pub async fn get_file_content(
         &self,
         object_name: &str,
@@ -286,6 +311,21 @@ mod tests {
         dir.close().unwrap();
     }
 
+    #[tokio::test]
+    async fn test_upload_bytes() {
+        setup_env();
+        let fs = FileStorage::new().await;
+
+        let stored = fs
+            .upload_bytes("csv", Bytes::from("a,b\n1,2\n"))
+            .await
+            .unwrap();
+
+        assert!(stored.id.ends_with(".csv"));
+        assert_eq!(stored.size, 8);
+        assert_eq!(fs.get_file_content(&stored.id).await.unwrap(), "a,b\n1,2\n");
+    }
+
     #[tokio::test]
     async fn test_retrieve_file() {
         setup_env();
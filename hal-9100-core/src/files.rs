@@ -0,0 +1,320 @@
+use async_openai::types::{OpenAIFile, OpenAIFilePurpose};
+use bytes::Bytes;
+use hal_9100_core::file_storage::FileStorage;
+use hal_9100_core::models::File;
+use hal_9100_core::pdf_utils::pdf_mem_to_text;
+use hal_9100_core::retrieval::split_and_insert;
+use log::{error, info, warn};
+use serde_json::Value;
+use sqlx::types::Uuid;
+use sqlx::PgPool;
+use std::error::Error;
+use std::path::Path;
+use std::time::{Duration, Instant};
+
+pub const FILE_STATUS_UPLOADED: &str = "uploaded";
+pub const FILE_STATUS_PROCESSED: &str = "processed";
+pub const FILE_STATUS_ERROR: &str = "error";
+
+pub const PURPOSE_ASSISTANTS_OUTPUT: &str = "assistants_output";
+
+// Number of tokens per chunk when indexing a file for retrieval
+const CHUNK_SIZE: usize = 100;
+
+fn purpose_to_string(purpose: &OpenAIFilePurpose) -> String {
+    serde_json::to_value(purpose)
+        .ok()
+        .and_then(|v| v.as_str().map(|s| s.to_string()))
+        .unwrap_or_else(|| "assistants".to_string())
+}
+
+fn purpose_from_string(purpose: &str) -> OpenAIFilePurpose {
+    serde_json::from_value(Value::String(purpose.to_string()))
+        .unwrap_or(OpenAIFilePurpose::Assistants)
+}
+
+macro_rules! row_to_file {
+    ($row:expr) => {
+        File {
+            inner: OpenAIFile {
+                id: $row.id,
+                object: $row.object.unwrap_or_else(|| "file".to_string()),
+                bytes: $row.bytes as u32,
+                created_at: $row.created_at as u32,
+                filename: $row.filename.unwrap_or_default(),
+                purpose: purpose_from_string(&$row.purpose.unwrap_or_default()),
+                status: $row.status,
+                status_details: $row.status_details,
+            },
+            user_id: $row.user_id.unwrap_or_default().to_string(),
+        }
+    };
+}
+
+pub async fn create_file(
+    pool: &PgPool,
+    file_id: &str,
+    filename: &str,
+    bytes: i32,
+    purpose: &str,
+    user_id: &str,
+) -> Result<File, sqlx::Error> {
+    info!("Creating file record for file_id: {}", file_id);
+    let purpose = purpose_to_string(&purpose_from_string(purpose));
+    let row = sqlx::query!(
+        r#"
+        INSERT INTO files (id, object, bytes, filename, purpose, status, user_id)
+        VALUES ($1, 'file', $2, $3, $4, $5, $6)
+        RETURNING *
+        "#,
+        file_id,
+        bytes,
+        filename,
+        purpose,
+        FILE_STATUS_UPLOADED,
+        Uuid::parse_str(user_id).map_err(|e| sqlx::Error::Decode(Box::new(e)))?,
+    )
+    .fetch_one(pool)
+    .await?;
+    Ok(row_to_file!(row))
+}
+
+pub async fn get_file(pool: &PgPool, file_id: &str, user_id: &str) -> Result<File, sqlx::Error> {
+    let row = sqlx::query!(
+        r#"
+        SELECT * FROM files WHERE id = $1 AND user_id::text = $2
+        "#,
+        file_id,
+        user_id,
+    )
+    .fetch_one(pool)
+    .await?;
+    Ok(row_to_file!(row))
+}
+
+pub async fn list_files(
+    pool: &PgPool,
+    user_id: &str,
+    purpose: Option<&str>,
+) -> Result<Vec<File>, sqlx::Error> {
+    let purpose = purpose.map(|p| purpose_to_string(&purpose_from_string(p)));
+    let rows = sqlx::query!(
+        r#"
+        SELECT * FROM files WHERE user_id::text = $1 AND ($2::text IS NULL OR purpose = $2)
+        ORDER BY created_at DESC
+        "#,
+        user_id,
+        purpose,
+    )
+    .fetch_all(pool)
+    .await?;
+    Ok(rows.into_iter().map(|row| row_to_file!(row)).collect())
+}
+
+pub async fn update_file_status(
+    pool: &PgPool,
+    file_id: &str,
+    status: &str,
+    status_details: Option<&str>,
+) -> Result<(), sqlx::Error> {
+    info!("Updating file {} status to {}", file_id, status);
+    sqlx::query!(
+        r#"
+        UPDATE files SET status = $1, status_details = $2 WHERE id = $3
+        "#,
+        status,
+        status_details,
+        file_id,
+    )
+    .execute(pool)
+    .await?;
+    Ok(())
+}
+
+/// Store a file created by an assistant, e.g. by the code interpreter, with the
+/// `assistants_output` purpose. It is not indexed for retrieval so it is processed right away.
+pub async fn create_output_file(
+    pool: &PgPool,
+    file_storage: &FileStorage,
+    filename: &str,
+    bytes: Bytes,
+    user_id: &str,
+) -> Result<File, Box<dyn Error + Send + Sync>> {
+    let extension = Path::new(filename)
+        .extension()
+        .and_then(|extension| extension.to_str())
+        .unwrap_or("");
+    let stored = file_storage.upload_bytes(extension, bytes).await?;
+    let mut file = create_file(
+        pool,
+        &stored.id,
+        filename,
+        stored.size as i32,
+        PURPOSE_ASSISTANTS_OUTPUT,
+        user_id,
+    )
+    .await?;
+    update_file_status(pool, &stored.id, FILE_STATUS_PROCESSED, None).await?;
+    file.inner.status = Some(FILE_STATUS_PROCESSED.to_string());
+    Ok(file)
+}
+
+/// Extract the text of a file so it can be indexed. PDFs go through the PDF extractor,
+/// everything else must be valid UTF-8.
+pub fn extract_text(filename: &str, bytes: &[u8]) -> Result<String, Box<dyn Error + Send + Sync>> {
+    if filename.to_lowercase().ends_with(".pdf") {
+        pdf_mem_to_text(bytes).map_err(|e| format!("Failed to extract text from PDF: {}", e).into())
+    } else {
+        String::from_utf8(bytes.to_vec())
+            .map_err(|e| format!("File is not valid UTF-8 text: {}", e).into())
+    }
+}
+
+/// Extract, chunk and index a file, then mark it `processed`. Any failure is recorded
+/// on the file as `error` with the reason in `status_details` instead of being returned,
+/// so this is safe to run in a background task.
+pub async fn process_file(pool: &PgPool, file_id: &str, filename: &str, bytes: &[u8]) {
+    info!("Processing file: {}", file_id);
+    let result = match extract_text(filename, bytes) {
+        Ok(text) => split_and_insert(pool, &text, CHUNK_SIZE, file_id, None)
+            .await
+            .map(|_| ())
+            .map_err(|e| format!("Failed to index file: {}", e)),
+        Err(e) => Err(e.to_string()),
+    };
+
+    let status_update = match result {
+        Ok(_) => update_file_status(pool, file_id, FILE_STATUS_PROCESSED, None).await,
+        Err(e) => {
+            error!("Failed to process file {}: {}", file_id, e);
+            update_file_status(pool, file_id, FILE_STATUS_ERROR, Some(&e)).await
+        }
+    };
+    if let Err(e) = status_update {
+        error!("Failed to update status of file {}: {}", file_id, e);
+    }
+}
+
+/// Wait until the given files are done processing or the timeout expires, and return
+/// the ids that are ready to be used. Files that failed or are still pending when the
+/// timeout expires are skipped. Files without a record (e.g. stored before status
+/// tracking existed) are considered ready.
+pub async fn wait_for_processed_files(
+    pool: &PgPool,
+    file_ids: &Vec<String>,
+    timeout: Duration,
+) -> Vec<String> {
+    let start = Instant::now();
+    loop {
+        let rows = match sqlx::query!(
+            r#"
+            SELECT id, status FROM files WHERE id = ANY($1)
+            "#,
+            file_ids,
+        )
+        .fetch_all(pool)
+        .await
+        {
+            Ok(rows) => rows,
+            Err(e) => {
+                error!("Failed to fetch file statuses: {}", e);
+                return file_ids.clone();
+            }
+        };
+
+        let pending: Vec<&String> = rows
+            .iter()
+            .filter(|row| row.status.as_deref() == Some(FILE_STATUS_UPLOADED))
+            .map(|row| &row.id)
+            .collect();
+
+        if pending.is_empty() || start.elapsed() >= timeout {
+            return file_ids
+                .iter()
+                .filter(|file_id| match rows.iter().find(|row| &row.id == *file_id) {
+                    Some(row) if row.status.as_deref() == Some(FILE_STATUS_PROCESSED) => true,
+                    Some(row) => {
+                        warn!(
+                            "Skipping file {} with status {}",
+                            file_id,
+                            row.status.as_deref().unwrap_or_default()
+                        );
+                        false
+                    }
+                    None => true,
+                })
+                .cloned()
+                .collect();
+        }
+
+        info!("Waiting for files to be processed: {:?}", pending);
+        tokio::time::sleep(Duration::from_millis(500)).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use dotenv::dotenv;
+    use sqlx::postgres::PgPoolOptions;
+    use std::env;
+
+    async fn setup() -> PgPool {
+        dotenv().ok();
+
+        let database_url = env::var("DATABASE_URL").expect("DATABASE_URL must be set");
+        PgPoolOptions::new()
+            .max_connections(5)
+            .connect(&database_url)
+            .await
+            .unwrap()
+    }
+    async fn reset_db(pool: &PgPool) {
+        sqlx::query!("TRUNCATE files, chunks RESTART IDENTITY")
+            .execute(pool)
+            .await
+            .unwrap();
+    }
+
+    #[test]
+    fn test_extract_text() {
+        assert_eq!(extract_text("a.txt", b"hello").unwrap(), "hello");
+        assert!(extract_text("a.txt", &[0xff, 0xfe, 0xfd]).is_err());
+        assert!(extract_text("a.PDF", b"not a pdf").is_err());
+    }
+
+    #[tokio::test]
+    async fn test_process_file_updates_status() {
+        let pool = setup().await;
+        reset_db(&pool).await;
+        let user_id = Uuid::default().to_string();
+
+        let file = create_file(&pool, "ok.txt", "ok.txt", 11, "assistants", &user_id)
+            .await
+            .unwrap();
+        assert_eq!(file.inner.status.as_deref(), Some(FILE_STATUS_UPLOADED));
+        process_file(&pool, "ok.txt", "ok.txt", b"hello world").await;
+        let file = get_file(&pool, "ok.txt", &user_id).await.unwrap();
+        assert_eq!(file.inner.status.as_deref(), Some(FILE_STATUS_PROCESSED));
+
+        create_file(&pool, "bad.pdf", "bad.pdf", 3, "assistants", &user_id)
+            .await
+            .unwrap();
+        process_file(&pool, "bad.pdf", "bad.pdf", b"bad").await;
+        let file = get_file(&pool, "bad.pdf", &user_id).await.unwrap();
+        assert_eq!(file.inner.status.as_deref(), Some(FILE_STATUS_ERROR));
+        assert!(file.inner.status_details.is_some());
+
+        let ready = wait_for_processed_files(
+            &pool,
+            &vec![
+                "ok.txt".to_string(),
+                "bad.pdf".to_string(),
+                "untracked.txt".to_string(),
+            ],
+            Duration::from_secs(1),
+        )
+        .await;
+        assert_eq!(ready, vec!["ok.txt".to_string(), "untracked.txt".to_string()]);
+    }
+}
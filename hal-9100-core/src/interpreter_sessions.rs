@@ -0,0 +1,412 @@
+use hal_9100_core::code_interpreter::InterpreterError;
+use hal_9100_core::sandbox::{
+    session_died, shared_sandbox, ExecutionOutput, Sandbox, SandboxFile,
+};
+use hal_9100_extra::config::{CodeInterpreterConfig, InterpreterSessionConfig};
+use log::{info, warn};
+use redis::AsyncCommands;
+use std::collections::HashMap;
+use std::sync::{Arc, Once};
+use std::time::{Duration, Instant};
+use tokio::sync::{Mutex, OnceCell, OwnedSemaphorePermit, Semaphore};
+
+// Characters of previous code kept to remind the model what a session already holds
+const MAX_HISTORY_CHARS: usize = 4000;
+// Reset requests no executor picked up are dropped after this
+const RESET_REQUEST_TTL_SECS: usize = 24 * 60 * 60;
+// Longest time between two checks for idle sessions
+const MAX_IDLE_CHECK_SECS: u64 = 60;
+
+static SHARED_SESSIONS: OnceCell<SessionManager> = OnceCell::const_new();
+static IDLE_CHECK: Once = Once::new();
+
+struct ThreadSession {
+    handle: String,
+    last_used: Instant,
+    // Held while a code runs, so the codes of a thread run one after the other
+    busy: Arc<Mutex<()>>,
+    // Codes that ran without error, oldest first
+    history: Vec<String>,
+    // One of the `max_sessions` slots, given back when the session is dropped
+    _slot: OwnedSemaphorePermit,
+}
+
+impl ThreadSession {
+    fn is_busy(&self) -> bool {
+        self.busy.try_lock().is_err()
+    }
+}
+
+/// Python sessions of the code interpreter keyed by thread id, so variables, imports and
+/// loaded data are kept between the messages of a thread. Sessions unused for
+/// `idle_timeout_secs` are stopped and at most `max_sessions` are alive at once.
+/// Sessions live in the executor process: with several executors, a thread finds its
+/// state again only when its runs are picked by the same executor.
+pub struct SessionManager {
+    sandbox: &'static dyn Sandbox,
+    config: InterpreterSessionConfig,
+    sessions: Mutex<HashMap<String, ThreadSession>>,
+    // Sessions alive or being started, at most `max_sessions`
+    slots: Arc<Semaphore>,
+    // Held while the session of a thread starts, so a thread never starts two
+    starting: Mutex<HashMap<String, Arc<Mutex<()>>>>,
+}
+
+impl SessionManager {
+    pub fn new(sandbox: &'static dyn Sandbox, config: &InterpreterSessionConfig) -> Self {
+        SessionManager {
+            sandbox,
+            config: config.clone(),
+            sessions: Mutex::new(HashMap::new()),
+            slots: Arc::new(Semaphore::new(config.max_sessions.max(1))),
+            starting: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Whether codes run in sessions, else each code runs from scratch.
+    pub fn enabled(&self) -> bool {
+        self.config.enabled && self.sandbox.supports_sessions()
+    }
+
+    /// Run Python code in the session of a thread, starting it if needed. A session whose
+    /// process died or timed out is stopped, the next code of the thread starts afresh.
+    pub async fn execute(
+        &self,
+        thread_id: &str,
+        python_code: &str,
+        files: &[SandboxFile],
+    ) -> Result<ExecutionOutput, InterpreterError> {
+        if !self.enabled() {
+            return self.sandbox.execute(python_code, files).await;
+        }
+        self.close_idle().await;
+
+        let (handle, busy) = self.session(thread_id).await?;
+        let _running = busy.lock().await;
+        let result = self
+            .sandbox
+            .execute_in_session(&handle, python_code, files)
+            .await;
+
+        let lost = match &result {
+            Ok(execution) => session_died(execution.exit_code),
+            Err(_) => true,
+        };
+        let mut sessions = self.sessions.lock().await;
+        // The session may have been reset while the code ran
+        let current = sessions
+            .get(thread_id)
+            .map_or(false, |session| session.handle == handle);
+        if current && lost {
+            warn!(
+                "Code interpreter session of thread {} was lost, its state is reset",
+                thread_id
+            );
+            // Its slot is given back once it is stopped
+            let lost_session = sessions.remove(thread_id);
+            drop(sessions);
+            self.sandbox.close_session(&handle).await;
+            drop(lost_session);
+        } else if let Some(session) = sessions.get_mut(thread_id).filter(|_| current) {
+            session.last_used = Instant::now();
+            if matches!(&result, Ok(execution) if execution.exit_code == Some(0)) {
+                session.history.push(python_code.to_string());
+                trim_history(&mut session.history);
+            }
+        }
+        result
+    }
+
+    /// Codes that already ran without error in the session of a thread, oldest first.
+    pub async fn history(&self, thread_id: &str) -> Vec<String> {
+        self.sessions
+            .lock()
+            .await
+            .get(thread_id)
+            .map(|session| session.history.clone())
+            .unwrap_or_default()
+    }
+
+    /// Stop the session of a thread once its running code is done, if it has one.
+    pub async fn reset(&self, thread_id: &str) {
+        let session = self.sessions.lock().await.remove(thread_id);
+        if let Some(session) = session {
+            info!("Resetting code interpreter session of thread {}", thread_id);
+            let _running = session.busy.lock().await;
+            self.sandbox.close_session(&session.handle).await;
+        }
+    }
+
+    /// Stop the sessions unused for longer than the idle timeout.
+    pub async fn close_idle(&self) {
+        let idle_timeout = Duration::from_secs(self.config.idle_timeout_secs);
+        let expired: Vec<(String, ThreadSession)> = {
+            let mut sessions = self.sessions.lock().await;
+            let thread_ids: Vec<String> = sessions
+                .iter()
+                .filter(|(_, session)| {
+                    session.last_used.elapsed() >= idle_timeout && !session.is_busy()
+                })
+                .map(|(thread_id, _)| thread_id.clone())
+                .collect();
+            thread_ids
+                .into_iter()
+                .filter_map(|thread_id| {
+                    sessions
+                        .remove(&thread_id)
+                        .map(|session| (thread_id, session))
+                })
+                .collect()
+        };
+        for (thread_id, session) in expired {
+            info!(
+                "Stopping idle code interpreter session of thread {}",
+                thread_id
+            );
+            self.sandbox.close_session(&session.handle).await;
+        }
+    }
+
+    // The session of a thread, started if it has none. When all sessions are taken, the
+    // least recently used one not running code is stopped to make room. Sessions are started
+    // and stopped without holding the lock of the sessions, the other threads are not blocked.
+    async fn session(&self, thread_id: &str) -> Result<(String, Arc<Mutex<()>>), InterpreterError> {
+        if let Some(session) = self.current_session(thread_id).await {
+            return Ok(session);
+        }
+
+        // Calls for the same thread wait for the first one to start the session and use it
+        let start = self
+            .starting
+            .lock()
+            .await
+            .entry(thread_id.to_string())
+            .or_default()
+            .clone();
+        let result = {
+            let _starting = start.lock().await;
+            match self.current_session(thread_id).await {
+                Some(session) => Ok(session),
+                None => self.start_session(thread_id).await,
+            }
+        };
+        let mut starting = self.starting.lock().await;
+        // Only the map and this call hold it, no other call waits on it
+        if Arc::strong_count(&start) == 2 {
+            starting.remove(thread_id);
+        }
+        result
+    }
+
+    async fn current_session(&self, thread_id: &str) -> Option<(String, Arc<Mutex<()>>)> {
+        let mut sessions = self.sessions.lock().await;
+        let session = sessions.get_mut(thread_id)?;
+        session.last_used = Instant::now();
+        Some((session.handle.clone(), session.busy.clone()))
+    }
+
+    async fn start_session(&self, thread_id: &str) -> Result<(String, Arc<Mutex<()>>), InterpreterError> {
+        let max_sessions = self.config.max_sessions.max(1);
+        // Reserve a slot, stopping an idle session if they are all taken
+        let slot = loop {
+            if let Ok(slot) = self.slots.clone().try_acquire_owned() {
+                break slot;
+            }
+            let evicted = {
+                let mut sessions = self.sessions.lock().await;
+                let evicted = sessions
+                    .iter()
+                    .filter(|(_, session)| !session.is_busy())
+                    .min_by_key(|(_, session)| session.last_used)
+                    .map(|(thread_id, _)| thread_id.clone());
+                evicted.and_then(|thread_id| sessions.remove(&thread_id).map(|s| (thread_id, s)))
+            };
+            match evicted {
+                Some((evicted_thread_id, session)) => {
+                    info!(
+                        "Stopping code interpreter session of thread {} to make room",
+                        evicted_thread_id
+                    );
+                    // Its slot is free once it is stopped
+                    self.sandbox.close_session(&session.handle).await;
+                }
+                None => {
+                    return Err(InterpreterError::Sandbox(format!(
+                        "All {} code interpreter sessions are running code",
+                        max_sessions
+                    )))
+                }
+            }
+        };
+
+        let handle = self.sandbox.start_session().await?;
+        let busy = Arc::new(Mutex::new(()));
+        self.sessions.lock().await.insert(
+            thread_id.to_string(),
+            ThreadSession {
+                handle: handle.clone(),
+                last_used: Instant::now(),
+                busy: busy.clone(),
+                history: Vec::new(),
+                _slot: slot,
+            },
+        );
+        Ok((handle, busy))
+    }
+}
+
+// Drop the oldest codes until the history fits in MAX_HISTORY_CHARS, the latest is kept
+fn trim_history(history: &mut Vec<String>) {
+    while history.len() > 1 && history.iter().map(|code| code.len()).sum::<usize>() > MAX_HISTORY_CHARS
+    {
+        history.remove(0);
+    }
+}
+
+/// The session manager shared by all code interpreter calls of the process, created on
+/// first use along with a task stopping idle sessions.
+pub async fn shared_sessions(
+    config: &CodeInterpreterConfig,
+) -> Result<&'static SessionManager, InterpreterError> {
+    let sandbox = shared_sandbox(config).await?;
+    let sessions = SHARED_SESSIONS
+        .get_or_init(|| async { SessionManager::new(sandbox, &config.session) })
+        .await;
+    IDLE_CHECK.call_once(|| {
+        let interval = config.session.idle_timeout_secs.clamp(1, MAX_IDLE_CHECK_SECS);
+        tokio::spawn(async move {
+            loop {
+                tokio::time::sleep(Duration::from_secs(interval)).await;
+                sessions.close_idle().await;
+            }
+        });
+    });
+    Ok(sessions)
+}
+
+fn reset_request_key(thread_id: &str) -> String {
+    format!("code_interpreter_reset:{}", thread_id)
+}
+
+/// Ask the executors to reset the code interpreter session of a thread before its next
+/// code runs.
+pub async fn request_session_reset(
+    con: &mut redis::aio::Connection,
+    thread_id: &str,
+) -> redis::RedisResult<()> {
+    con.set_ex(reset_request_key(thread_id), 1, RESET_REQUEST_TTL_SECS)
+        .await
+}
+
+/// Whether a reset of the session of a thread was requested, the request is consumed.
+pub async fn take_session_reset_request(
+    con: &mut redis::aio::Connection,
+    thread_id: &str,
+) -> redis::RedisResult<bool> {
+    let deleted: i64 = con.del(reset_request_key(thread_id)).await?;
+    Ok(deleted > 0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use hal_9100_core::sandbox::LocalSandbox;
+    use hal_9100_extra::config::{LocalSandboxConfig, SandboxBackend};
+
+    fn manager(max_sessions: usize, idle_timeout_secs: u64) -> SessionManager {
+        let config = CodeInterpreterConfig {
+            backend: SandboxBackend::Local,
+            local: LocalSandboxConfig {
+                bubblewrap: false,
+                ..Default::default()
+            },
+            timeout_secs: 10,
+            ..Default::default()
+        };
+        let sandbox: &'static dyn Sandbox = Box::leak(Box::new(LocalSandbox::new(&config)));
+        SessionManager::new(
+            sandbox,
+            &InterpreterSessionConfig {
+                enabled: true,
+                idle_timeout_secs,
+                max_sessions,
+            },
+        )
+    }
+
+    #[tokio::test]
+    async fn test_state_is_kept_per_thread() {
+        let sessions = manager(4, 600);
+
+        sessions.execute("thread-a", "x = 41", &[]).await.unwrap();
+        let execution = sessions.execute("thread-a", "print(x + 1)", &[]).await.unwrap();
+        assert_eq!(execution.stdout.trim(), "42");
+        assert_eq!(sessions.history("thread-a").await, vec!["x = 41", "print(x + 1)"]);
+
+        let execution = sessions.execute("thread-b", "print(x)", &[]).await.unwrap();
+        assert!(execution.stderr.contains("NameError"));
+        assert_eq!(execution.exit_code, Some(1));
+        assert!(sessions.history("thread-b").await.is_empty());
+
+        sessions.reset("thread-a").await;
+        let execution = sessions.execute("thread-a", "print(x)", &[]).await.unwrap();
+        assert!(execution.stderr.contains("NameError"));
+
+        sessions.reset("thread-a").await;
+        sessions.reset("thread-b").await;
+    }
+
+    #[tokio::test]
+    async fn test_sessions_are_limited_and_expire() {
+        let sessions = manager(1, 600);
+        sessions.execute("thread-a", "x = 1", &[]).await.unwrap();
+        // Starting the session of thread-b stops the one of thread-a
+        sessions.execute("thread-b", "y = 2", &[]).await.unwrap();
+        let execution = sessions.execute("thread-a", "print(x)", &[]).await.unwrap();
+        assert!(execution.stderr.contains("NameError"));
+        sessions.reset("thread-a").await;
+
+        let sessions = manager(4, 0);
+        sessions.execute("thread-a", "x = 1", &[]).await.unwrap();
+        sessions.close_idle().await;
+        assert!(sessions.sessions.lock().await.is_empty());
+        assert_eq!(sessions.slots.available_permits(), 4);
+    }
+
+    #[tokio::test]
+    async fn test_session_is_started_once_per_thread() {
+        let sessions = manager(4, 600);
+        let (first, second) = tokio::join!(sessions.session("thread-a"), sessions.session("thread-a"));
+        assert_eq!(first.unwrap().0, second.unwrap().0);
+        assert_eq!(sessions.sessions.lock().await.len(), 1);
+        assert_eq!(sessions.slots.available_permits(), 3);
+        assert!(sessions.starting.lock().await.is_empty());
+
+        // Another thread starts its session while the first one runs code
+        let (_, busy) = sessions.session("thread-a").await.unwrap();
+        let running = busy.lock().await;
+        let (handle, _) = sessions.session("thread-b").await.unwrap();
+        assert_eq!(sessions.sessions.lock().await["thread-b"].handle, handle);
+        drop(running);
+
+        sessions.reset("thread-a").await;
+        sessions.reset("thread-b").await;
+    }
+
+    #[tokio::test]
+    async fn test_dead_session_is_restarted() {
+        let sessions = manager(4, 600);
+        sessions.execute("thread-a", "x = 1", &[]).await.unwrap();
+
+        let execution = sessions
+            .execute("thread-a", "import os, signal\nos.kill(os.getpid(), signal.SIGKILL)", &[])
+            .await
+            .unwrap();
+        assert_eq!(execution.exit_code, Some(137));
+        assert!(sessions.history("thread-a").await.is_empty());
+
+        let execution = sessions.execute("thread-a", "print('x' in globals())", &[]).await.unwrap();
+        assert_eq!(execution.stdout.trim(), "False");
+        sessions.reset("thread-a").await;
+    }
+}
@@ -1,11 +1,18 @@
 #[allow(unused_extern_crates)]
 extern crate self as hal_9100_core;
 
+pub mod action_auth;
+pub mod action_response;
+pub mod actions;
 pub mod assistants;
 pub mod code_interpreter;
+pub mod container_pool;
+pub mod egress;
 pub mod executor;
 pub mod file_storage;
+pub mod files;
 pub mod function_calling;
+pub mod interpreter_sessions;
 pub mod messages;
 pub mod models;
 pub mod openapi;
@@ -14,5 +21,9 @@ pub mod prompts;
 pub mod retrieval;
 pub mod run_steps;
 pub mod runs;
+pub mod sandbox;
+pub mod secrets;
 pub mod test_data;
 pub mod threads;
+pub mod tools;
+pub mod truncation;
@@ -16,6 +16,8 @@ DROP TABLE IF EXISTS functions;
 DROP TABLE IF EXISTS tool_calls;
 DROP TABLE IF EXISTS chunks;
 DROP TABLE IF EXISTS run_steps;
+DROP TABLE IF EXISTS files;
+DROP TABLE IF EXISTS secrets;
 
 -- Create assistants table
 CREATE TABLE assistants (
@@ -39,7 +41,9 @@ CREATE TABLE threads (
     file_ids TEXT[],
     object TEXT,
     created_at INTEGER NOT NULL DEFAULT (EXTRACT(EPOCH FROM NOW())),
-    metadata JSONB
+    metadata JSONB,
+    summary TEXT, -- rolling summary of the oldest messages
+    summarized_messages INTEGER NOT NULL DEFAULT 0 -- number of oldest messages folded into the summary
 );
 
 -- Create messages table
@@ -77,6 +81,9 @@ CREATE TABLE runs (
     tools JSONB[],
     file_ids TEXT[],
     metadata JSONB,
+    truncation_strategy JSONB,
+    pending_actions JSONB, -- consequential action calls waiting for the user's approval
+    parallel_tool_calls BOOLEAN NOT NULL DEFAULT TRUE, -- whether the model may call several tools in a turn
     user_id UUID
 );
 
@@ -89,6 +96,7 @@ CREATE TABLE functions (
     description TEXT,
     parameters JSONB,
     metadata JSONB,
+    enabled BOOLEAN NOT NULL DEFAULT TRUE, -- disabled functions are never given to the model
     created_at INTEGER NOT NULL DEFAULT (EXTRACT(EPOCH FROM NOW()))
 );
 
@@ -114,6 +122,19 @@ CREATE TABLE chunks (
     created_at INTEGER NOT NULL DEFAULT (EXTRACT(EPOCH FROM NOW()))
 );
 
+-- Create files table
+CREATE TABLE files (
+    id TEXT PRIMARY KEY, -- file storage name in S3
+    object TEXT,
+    bytes INTEGER NOT NULL DEFAULT 0,
+    filename TEXT,
+    purpose TEXT,
+    status TEXT, -- uploaded, processed, error
+    status_details TEXT,
+    created_at INTEGER NOT NULL DEFAULT (EXTRACT(EPOCH FROM NOW())),
+    user_id UUID
+);
+
 CREATE TABLE run_steps (
     id UUID PRIMARY KEY DEFAULT uuid_generate_v4(),
     object TEXT,
@@ -133,6 +154,15 @@ CREATE TABLE run_steps (
     metadata JSONB,
     user_id UUID
 );
+-- Create secrets table, credentials of action tools encrypted with the SECRETS_KEY
+CREATE TABLE secrets (
+    id UUID PRIMARY KEY DEFAULT uuid_generate_v4(),
+    nonce BYTEA NOT NULL,
+    ciphertext BYTEA NOT NULL,
+    created_at INTEGER NOT NULL DEFAULT (EXTRACT(EPOCH FROM NOW())),
+    user_id UUID
+);
+
 -- TODO INDEXES
 -- CREATE INDEX ON chunks USING hnsw (embedding vector_l2_ops);
 -- CREATE INDEX ON chunks USING hnsw (embedding vector_ip_ops);
@@ -1,9 +1,11 @@
 use async_openai::types::{
-    AssistantObject, FunctionObject, MessageCreation, MessageObject, MessageRole, RunObject,
+    AssistantObject, FunctionObject, MessageCreation, MessageObject, MessageRole, OpenAIFile, RunObject,
     RunStatus, RunStepDetailsMessageCreationObject, RunStepObject, RunStepType, StepDetails,
     ThreadObject,
 };
 use hal_9100_extra::llm::{HalLLMClient, HalLLMRequestArgs};
+use crate::function_calling::FunctionCallWithMetadata;
+use crate::openapi::ActionPreview;
 use redis::RedisError;
 use serde::{self, Deserialize, Serialize};
 use sqlx::Error as SqlxError;
@@ -83,10 +85,28 @@ impl From<hal_9100_core::models::Message> for async_openai::types::MessageObject
     }
 }
 
+/// How the thread history is cut down before being given to the model for a run.
+/// Serialized like OpenAI's `truncation_strategy`, e.g. `{"type": "last_messages", "last_messages": 10}`.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Default)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum TruncationStrategy {
+    /// Keep as many of the most recent messages as fit in the context
+    #[default]
+    Auto,
+    /// Keep only the `last_messages` most recent messages
+    LastMessages { last_messages: usize },
+    /// Keep the `last_messages` most recent messages, older ones are compacted into a rolling
+    /// summary stored on the thread
+    Summarize { last_messages: usize },
+}
+
 #[derive(Debug, sqlx::FromRow, Serialize, Deserialize)]
 pub struct Run {
     pub inner: RunObject,
     pub user_id: String,
+    pub truncation_strategy: TruncationStrategy,
+    /// Whether the model may call several tools in the same turn
+    pub parallel_tool_calls: bool,
 }
 
 impl Default for Run {
@@ -113,6 +133,8 @@ impl Default for Run {
                 metadata: None,
             },
             user_id: String::new(),
+            truncation_strategy: TruncationStrategy::Auto,
+            parallel_tool_calls: true,
         }
     }
 }
@@ -149,6 +171,31 @@ impl Default for Assistant {
     }
 }
 
+/// An action call waiting for the user to approve it, with the request it would send.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct PendingAction {
+    pub tool_call_id: String,
+    pub step_id: String,
+    pub function: FunctionCallWithMetadata,
+    pub request: ActionPreview,
+    /// Set once the user decided. Calls that don't need approval but were made along with
+    /// ones that do are approved already and run with them.
+    #[serde(default)]
+    pub approved: Option<bool>,
+    /// Why the user rejected the action, given to the model
+    #[serde(default)]
+    pub reason: Option<String>,
+}
+
+/// The user's decision on a pending action.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct ActionApproval {
+    pub tool_call_id: String,
+    pub approve: bool,
+    #[serde(default)]
+    pub reason: Option<String>,
+}
+
 #[derive(Debug, sqlx::FromRow, Serialize, Deserialize, Clone)]
 pub struct SubmittedToolCall {
     // TODO asnyc openai models?
@@ -175,6 +222,12 @@ pub struct FunctionCallInput {
     pub request: HalLLMRequestArgs, // ! is it possible to do like in TS "omit the message propt tihng"
 }
 
+#[derive(Debug, sqlx::FromRow, Serialize, Deserialize, Clone)]
+pub struct File {
+    pub inner: OpenAIFile,
+    pub user_id: String,
+}
+
 #[derive(Debug, sqlx::FromRow, Serialize, Deserialize)]
 pub struct Chunk {
     pub id: Uuid,
@@ -186,6 +239,9 @@ pub struct Chunk {
     pub metadata: Option<HashMap<String, serde_json::Value>>,
     // pub embedding: Option<Vec<f32>>,
     pub created_at: i32,
+    // Full-text search rank, only set when the chunk comes from a search
+    #[serde(default)]
+    pub score: Option<f32>,
 }
 
 pub struct PartialChunk {
@@ -1,7 +1,7 @@
-use reqwest::Method;
+use log::error;
 use serde::{Deserialize, Serialize};
-use serde_json::Value;
-use std::collections::HashMap;
+use serde_json::{json, Map, Value};
+use std::collections::{BTreeMap, HashMap};
 
 #[derive(Debug, Serialize, Deserialize)]
 pub struct FunctionSignature {
@@ -21,17 +21,103 @@ pub struct ActionRequest {
     pub content_type: String,
     pub params: Option<Value>,
     pub headers: Option<serde_json::Value>,
+    /// Where each of `params` goes in the request, params missing from it are sent in the query
+    /// for GET and DELETE and in the body otherwise
+    #[serde(default)]
+    pub parameters: HashMap<String, ParameterLocation>,
+    /// Alternative sets of security schemes the operation accepts, an empty set means no
+    /// authentication
+    #[serde(default)]
+    pub security: Vec<Vec<SecurityRequirement>>,
 }
 
+/// How an operation authenticates, the `securitySchemes` of a spec we know how to use.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum SecurityScheme {
+    /// An API key sent in a header, query parameter or cookie
+    ApiKey {
+        name: String,
+        location: ParameterLocation,
+    },
+    HttpBearer,
+    HttpBasic,
+    /// An access token obtained with the client credentials flow
+    #[serde(rename = "oauth2_client_credentials")]
+    OAuth2ClientCredentials { token_url: String },
+}
+
+/// A security scheme of an operation, by its name in the spec, and the OAuth2 scopes it needs.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct SecurityRequirement {
+    pub name: String,
+    pub scheme: SecurityScheme,
+    #[serde(default)]
+    pub scopes: Vec<String>,
+}
+
+/// The HTTP request an action would send, shown to the user before a consequential action runs.
+/// Credentials are redacted.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct ActionPreview {
+    pub method: String,
+    pub url: String,
+    pub headers: BTreeMap<String, String>,
+    pub body: Option<String>,
+}
+
+/// Where an argument of an operation is sent in the HTTP request.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum ParameterLocation {
+    Path,
+    Query,
+    Header,
+    Cookie,
+    /// A property of an object request body
+    BodyField,
+    /// The whole request body, for bodies that are not objects
+    Body,
+}
+
+/// The arguments of an operation merged into one JSON Schema object, the schema the model
+/// generates arguments for.
+#[derive(Debug, Clone)]
+pub struct OperationArguments {
+    pub schema: Value,
+    pub locations: HashMap<String, ParameterLocation>,
+    pub content_type: String,
+}
+
+// Name of the argument holding a request body that is not an object
+const REQUEST_BODY_ARGUMENT: &str = "requestBody";
+
+// Request body content types we know how to build, by preference
+const SUPPORTED_CONTENT_TYPES: [&str; 3] = [
+    "application/json",
+    "application/x-www-form-urlencoded",
+    "multipart/form-data",
+];
+
 #[derive(Debug, Serialize, Deserialize)]
 pub struct OpenAPISpec {
     pub openapi_spec: oas3::OpenApiV3Spec,
+    /// The spec as written, `$ref`s are resolved against it
+    #[serde(skip)]
+    pub document: Value,
 }
 
 impl OpenAPISpec {
     pub fn new(spec_str: &str) -> Result<Self, Box<dyn std::error::Error>> {
         let openapi_spec = oas3::from_reader(spec_str.as_bytes())?;
-        Ok(Self { openapi_spec })
+        // YAML is a superset of JSON, this reads both kinds of specs. Going through a YAML value
+        // turns keys like unquoted status codes into strings
+        let document: serde_yaml::Value = serde_yaml::from_str(spec_str)?;
+        let document = serde_json::to_value(document)?;
+        Ok(Self {
+            openapi_spec,
+            document,
+        })
     }
     pub fn get_functions(&self) -> Result<Vec<oas3::spec::Operation>, serde_json::Error> {
         let mut operations = Vec::new();
@@ -48,16 +134,19 @@ impl OpenAPISpec {
 
         for (path, methods) in &self.openapi_spec.paths {
             for (method, spec) in methods.methods() {
+                let arguments = self.get_operation_arguments(path, &method.to_string());
                 let request = ActionRequest {
                     domain: self.openapi_spec.servers[0].url.clone(),
                     path: path.to_string(),
                     method: method.to_string(),
                     operation: spec.operation_id.as_ref().unwrap().to_string(),
                     operation_hash: None,
-                    is_consequential: false,
-                    content_type: "application/json".to_string(),
+                    is_consequential: self.is_consequential(path, &method.to_string()),
+                    content_type: arguments.content_type,
                     params: None,
                     headers: None,
+                    parameters: arguments.locations,
+                    security: self.get_operation_security(path, &method.to_string()),
                 };
                 requests.insert(request.operation.clone(), request);
             }
@@ -65,18 +154,295 @@ impl OpenAPISpec {
 
         requests
     }
+
+    /// Merge the path, query, header and cookie parameters of an operation and the properties
+    /// of its request body into a single JSON Schema object.
+    pub fn get_operation_arguments(&self, path: &str, method: &str) -> OperationArguments {
+        let path_item = &self.document["paths"][path];
+        let operation = &path_item[method.to_lowercase()];
+
+        let mut properties = Map::new();
+        let mut required = Vec::new();
+        let mut locations = HashMap::new();
+
+        // Parameters of the path item apply to all its operations, unless the operation has
+        // one of the same name and location
+        let mut parameters: Vec<Value> = Vec::new();
+        for parameter in path_item["parameters"]
+            .as_array()
+            .into_iter()
+            .chain(operation["parameters"].as_array())
+            .flatten()
+            .map(|parameter| self.resolve_refs(parameter))
+        {
+            let overridden = parameters.iter().position(|existing| {
+                existing["name"] == parameter["name"] && existing["in"] == parameter["in"]
+            });
+            match overridden {
+                Some(index) => parameters[index] = parameter,
+                None => parameters.push(parameter),
+            }
+        }
+        for parameter in parameters {
+            let name = match parameter["name"].as_str() {
+                Some(name) => name.to_string(),
+                None => continue,
+            };
+            let location = match parameter["in"].as_str() {
+                Some("path") => ParameterLocation::Path,
+                Some("query") => ParameterLocation::Query,
+                Some("header") => ParameterLocation::Header,
+                Some("cookie") => ParameterLocation::Cookie,
+                other => {
+                    error!("Skipping parameter {} in unknown location {:?}", name, other);
+                    continue;
+                }
+            };
+            // Arguments are named after their parameter, the first one keeps the name
+            if locations.contains_key(&name) {
+                error!("Skipping parameter {} in {:?}, the name is taken", name, location);
+                continue;
+            }
+            let schema = match parameter.get("schema") {
+                Some(schema) => schema.clone(),
+                None => json!({ "type": "string" }),
+            };
+            if location == ParameterLocation::Path || parameter["required"] == json!(true) {
+                required.push(name.clone());
+            }
+            properties.insert(name.clone(), schema);
+            locations.insert(name, location);
+        }
+
+        let mut content_type = "application/json".to_string();
+        let request_body = self.resolve_refs(&operation["requestBody"]);
+        if let Some(content) = request_body["content"].as_object() {
+            let chosen = SUPPORTED_CONTENT_TYPES
+                .iter()
+                .find_map(|supported| content.keys().find(|key| is_content_type(key, supported)))
+                .or_else(|| content.keys().find(|key| is_content_type(key, "+json")))
+                .or_else(|| content.keys().next());
+            if let Some(chosen) = chosen {
+                content_type = chosen.to_string();
+                let schema = &content[chosen]["schema"];
+                match schema["properties"].as_object() {
+                    Some(fields) => {
+                        for (name, field) in fields {
+                            // Parameters win over body fields of the same name
+                            if locations.contains_key(name) {
+                                error!("Skipping body field {} shadowed by a parameter", name);
+                                continue;
+                            }
+                            properties.insert(name.clone(), field.clone());
+                            locations.insert(name.clone(), ParameterLocation::BodyField);
+                        }
+                        for name in schema["required"].as_array().into_iter().flatten() {
+                            if let Some(name) = name.as_str() {
+                                if locations.get(name) == Some(&ParameterLocation::BodyField)
+                                    && !required.iter().any(|required| required == name)
+                                {
+                                    required.push(name.to_string());
+                                }
+                            }
+                        }
+                    }
+                    None => {
+                        properties.insert(REQUEST_BODY_ARGUMENT.to_string(), schema.clone());
+                        locations.insert(
+                            REQUEST_BODY_ARGUMENT.to_string(),
+                            ParameterLocation::Body,
+                        );
+                        if request_body["required"] == json!(true) {
+                            required.push(REQUEST_BODY_ARGUMENT.to_string());
+                        }
+                    }
+                }
+            }
+        }
+
+        OperationArguments {
+            schema: json!({
+                "type": "object",
+                "properties": properties,
+                "required": required,
+            }),
+            locations,
+            content_type,
+        }
+    }
+
+    /// Whether an operation needs the user's approval before running: operations flagged with
+    /// `x-openai-isConsequential`, or else the ones that write.
+    pub fn is_consequential(&self, path: &str, method: &str) -> bool {
+        self.declared_consequential(path, method)
+            .unwrap_or_else(|| writes(method))
+    }
+
+    /// The `x-openai-isConsequential` flag of an operation, if the spec sets it.
+    pub fn declared_consequential(&self, path: &str, method: &str) -> Option<bool> {
+        self.document["paths"][path][method.to_lowercase()]["x-openai-isConsequential"].as_bool()
+    }
+
+    /// The parts of the JSON responses of an operation the model is given, as JSONPath like
+    /// `$.items[*].name`, from its `x-response-fields`. Empty when the whole response is given.
+    pub fn get_response_fields(&self, path: &str, method: &str) -> Vec<String> {
+        let operation = &self.document["paths"][path][method.to_lowercase()];
+        operation["x-response-fields"]
+            .as_array()
+            .into_iter()
+            .flatten()
+            .filter_map(|field| field.as_str().map(str::to_string))
+            .collect()
+    }
+
+    /// The alternative sets of security schemes an operation accepts, from its `security` or the
+    /// spec's. Alternatives using a scheme we can't authenticate with are left out.
+    pub fn get_operation_security(&self, path: &str, method: &str) -> Vec<Vec<SecurityRequirement>> {
+        let operation = &self.document["paths"][path][method.to_lowercase()];
+        let alternatives = match operation.get("security") {
+            Some(security) => security,
+            None => &self.document["security"],
+        };
+
+        let mut security = Vec::new();
+        'alternatives: for alternative in alternatives.as_array().into_iter().flatten() {
+            let mut requirements = Vec::new();
+            for (name, scopes) in alternative.as_object().into_iter().flatten() {
+                let definition =
+                    self.resolve_refs(&self.document["components"]["securitySchemes"][name]);
+                let scheme = match security_scheme(&definition) {
+                    Some(scheme) => scheme,
+                    None => {
+                        error!("Skipping unsupported security scheme {}: {}", name, definition);
+                        continue 'alternatives;
+                    }
+                };
+                requirements.push(SecurityRequirement {
+                    name: name.to_string(),
+                    scheme,
+                    scopes: serde_json::from_value(scopes.clone()).unwrap_or_default(),
+                });
+            }
+            security.push(requirements);
+        }
+        security
+    }
+
+    /// Inline the local `$ref`s of `value`. Recursive references are replaced by an empty
+    /// schema so the result stays finite.
+    pub fn resolve_refs(&self, value: &Value) -> Value {
+        self.resolve_refs_seen(value, &mut Vec::new())
+    }
+
+    fn resolve_refs_seen(&self, value: &Value, seen: &mut Vec<String>) -> Value {
+        match value {
+            Value::Object(map) => match map.get("$ref").and_then(Value::as_str) {
+                Some(reference) => {
+                    if seen.iter().any(|r| r == reference) {
+                        return json!({});
+                    }
+                    let target = reference
+                        .strip_prefix('#')
+                        .and_then(|pointer| self.document.pointer(pointer));
+                    match target {
+                        Some(target) => {
+                            seen.push(reference.to_string());
+                            let resolved = self.resolve_refs_seen(target, seen);
+                            seen.pop();
+                            resolved
+                        }
+                        None => {
+                            error!("Could not resolve reference {}", reference);
+                            json!({})
+                        }
+                    }
+                }
+                None => Value::Object(
+                    map.iter()
+                        .map(|(key, value)| (key.clone(), self.resolve_refs_seen(value, seen)))
+                        .collect(),
+                ),
+            },
+            Value::Array(items) => Value::Array(
+                items
+                    .iter()
+                    .map(|item| self.resolve_refs_seen(item, seen))
+                    .collect(),
+            ),
+            _ => value.clone(),
+        }
+    }
+}
+
+fn security_scheme(definition: &Value) -> Option<SecurityScheme> {
+    match definition["type"].as_str()? {
+        "apiKey" => {
+            let location = match definition["in"].as_str()? {
+                "header" => ParameterLocation::Header,
+                "query" => ParameterLocation::Query,
+                "cookie" => ParameterLocation::Cookie,
+                _ => return None,
+            };
+            Some(SecurityScheme::ApiKey {
+                name: definition["name"].as_str()?.to_string(),
+                location,
+            })
+        }
+        "http" => match definition["scheme"].as_str()?.to_lowercase().as_str() {
+            "bearer" => Some(SecurityScheme::HttpBearer),
+            "basic" => Some(SecurityScheme::HttpBasic),
+            _ => None,
+        },
+        "oauth2" => Some(SecurityScheme::OAuth2ClientCredentials {
+            token_url: definition["flows"]["clientCredentials"]["tokenUrl"]
+                .as_str()?
+                .to_string(),
+        }),
+        _ => None,
+    }
+}
+
+// Whether the media type `content_type` is `expected`, or ends with it for suffixes like `+json`
+fn is_content_type(content_type: &str, expected: &str) -> bool {
+    let mime = content_type
+        .split(';')
+        .next()
+        .unwrap_or_default()
+        .trim()
+        .to_lowercase();
+    if expected.starts_with('+') {
+        mime.ends_with(expected)
+    } else {
+        mime == expected
+    }
+}
+
+fn writes(method: &str) -> bool {
+    matches!(method.to_uppercase().as_str(), "POST" | "PUT" | "PATCH" | "DELETE")
+}
+
+/// Whether the operation of a stored action function needs approval. Functions registered
+/// before the flag was computed all stored `false`, so a `false` is only trusted when the spec
+/// declared it, otherwise the operation is judged by its method.
+pub fn stored_is_consequential(metadata: &Value) -> bool {
+    match (
+        metadata["is_consequential"].as_bool(),
+        metadata["x-openai-isConsequential"].as_bool(),
+    ) {
+        (_, Some(declared)) => declared,
+        (Some(true), None) => true,
+        _ => writes(metadata["method"].as_str().unwrap_or_default()),
+    }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
     use crate::test_data::OPENAPI_SPEC;
-    use oas3;
     #[test]
     fn test_get_functions_and_requests() {
         // Read the OpenAPI spec from a file
-        let openapi_spec = oas3::from_reader(OPENAPI_SPEC.as_bytes()).unwrap();
-        let openapi = OpenAPISpec { openapi_spec };
+        let openapi = OpenAPISpec::new(OPENAPI_SPEC).unwrap();
 
         // Test get_functions
         let functions = openapi.get_functions().unwrap();
@@ -86,4 +452,268 @@ mod tests {
         let requests = openapi.get_http_requests();
         assert!(!requests.is_empty());
     }
+
+    #[test]
+    fn test_get_operation_arguments() {
+        let openapi = OpenAPISpec::new(
+            r##"
+openapi: 3.0.0
+info:
+  title: Pets
+  version: 1.0.0
+servers:
+  - url: http://localhost
+paths:
+  /pets/{petId}:
+    parameters:
+      - name: petId
+        in: path
+        schema:
+          type: integer
+    patch:
+      operationId: updatePet
+      parameters:
+        - $ref: '#/components/parameters/Trace'
+        - name: dry_run
+          in: query
+          schema:
+            type: boolean
+      requestBody:
+        required: true
+        content:
+          application/json:
+            schema:
+              $ref: '#/components/schemas/Pet'
+      responses:
+        '200':
+          description: ok
+    delete:
+      operationId: deletePet
+      parameters:
+        - name: petId
+          in: path
+          required: true
+          schema:
+            type: string
+        - name: petId
+          in: query
+          schema:
+            type: string
+      responses:
+        '204':
+          description: deleted
+  /pets/{petId}/photo:
+    put:
+      operationId: putPhoto
+      parameters:
+        - name: petId
+          in: path
+          schema:
+            type: integer
+      requestBody:
+        required: true
+        content:
+          text/plain:
+            schema:
+              type: string
+      responses:
+        '200':
+          description: ok
+components:
+  parameters:
+    Trace:
+      name: X-Trace
+      in: header
+      required: true
+      schema:
+        type: string
+  schemas:
+    Pet:
+      type: object
+      required: [name]
+      properties:
+        name:
+          type: string
+        parent:
+          $ref: '#/components/schemas/Pet'
+"##,
+        )
+        .unwrap();
+
+        let arguments = openapi.get_operation_arguments("/pets/{petId}", "PATCH");
+        assert_eq!(arguments.content_type, "application/json");
+        assert_eq!(arguments.locations["petId"], ParameterLocation::Path);
+        assert_eq!(arguments.locations["X-Trace"], ParameterLocation::Header);
+        assert_eq!(arguments.locations["dry_run"], ParameterLocation::Query);
+        assert_eq!(arguments.locations["name"], ParameterLocation::BodyField);
+        assert_eq!(arguments.schema["properties"]["petId"]["type"], "integer");
+        // The recursive reference is left open
+        assert_eq!(arguments.schema["properties"]["parent"], json!({}));
+        assert_eq!(
+            arguments.schema["required"],
+            json!(["petId", "X-Trace", "name"])
+        );
+
+        // The operation's parameter replaces the path item's, one of another location can't
+        // take its name
+        let arguments = openapi.get_operation_arguments("/pets/{petId}", "delete");
+        assert_eq!(arguments.locations.len(), 1);
+        assert_eq!(arguments.locations["petId"], ParameterLocation::Path);
+        assert_eq!(arguments.schema["properties"]["petId"]["type"], "string");
+        assert_eq!(arguments.schema["required"], json!(["petId"]));
+
+        let arguments = openapi.get_operation_arguments("/pets/{petId}/photo", "put");
+        assert_eq!(arguments.content_type, "text/plain");
+        assert_eq!(arguments.locations["requestBody"], ParameterLocation::Body);
+        assert_eq!(arguments.schema["required"], json!(["petId", "requestBody"]));
+
+        let requests = openapi.get_http_requests();
+        assert_eq!(requests["updatePet"].parameters["petId"], ParameterLocation::Path);
+    }
+
+    #[test]
+    fn test_get_operation_security() {
+        let openapi = OpenAPISpec::new(
+            r##"
+openapi: 3.0.0
+info:
+  title: Pets
+  version: 1.0.0
+servers:
+  - url: http://localhost
+security:
+  - apiKey: []
+paths:
+  /pets:
+    get:
+      operationId: listPets
+      x-response-fields: ["$[*].name"]
+      responses:
+        '200':
+          description: ok
+    post:
+      operationId: createPet
+      security:
+        - oauth: [pets:write]
+        - bearer: []
+          openId: []
+        - {}
+      responses:
+        '200':
+          description: ok
+components:
+  securitySchemes:
+    apiKey:
+      type: apiKey
+      in: query
+      name: key
+    bearer:
+      type: http
+      scheme: Bearer
+    openId:
+      type: openIdConnect
+      openIdConnectUrl: https://example.com/.well-known/openid-configuration
+    oauth:
+      type: oauth2
+      flows:
+        clientCredentials:
+          tokenUrl: https://example.com/token
+          scopes:
+            pets:write: write pets
+"##,
+        )
+        .unwrap();
+
+        assert_eq!(
+            openapi.get_operation_security("/pets", "get"),
+            vec![vec![SecurityRequirement {
+                name: "apiKey".to_string(),
+                scheme: SecurityScheme::ApiKey {
+                    name: "key".to_string(),
+                    location: ParameterLocation::Query,
+                },
+                scopes: vec![],
+            }]]
+        );
+        // The alternative needing OpenID Connect is dropped, the empty one allows anonymous calls
+        assert_eq!(
+            openapi.get_operation_security("/pets", "post"),
+            vec![
+                vec![SecurityRequirement {
+                    name: "oauth".to_string(),
+                    scheme: SecurityScheme::OAuth2ClientCredentials {
+                        token_url: "https://example.com/token".to_string(),
+                    },
+                    scopes: vec!["pets:write".to_string()],
+                }],
+                vec![],
+            ]
+        );
+    }
+
+    #[test]
+    fn test_is_consequential() {
+        let openapi = OpenAPISpec::new(
+            r##"
+openapi: 3.0.0
+info:
+  title: Pets
+  version: 1.0.0
+servers:
+  - url: http://localhost
+paths:
+  /pets:
+    get:
+      operationId: listPets
+      responses:
+        '200':
+          description: ok
+    post:
+      operationId: createPet
+      responses:
+        '200':
+          description: ok
+  /pets/search:
+    get:
+      operationId: exportPets
+      x-openai-isConsequential: true
+      responses:
+        '200':
+          description: ok
+    post:
+      operationId: searchPets
+      x-openai-isConsequential: false
+      responses:
+        '200':
+          description: ok
+"##,
+        )
+        .unwrap();
+
+        assert!(!openapi.is_consequential("/pets", "GET"));
+        assert!(openapi.is_consequential("/pets", "POST"));
+        assert!(openapi.is_consequential("/pets/search", "GET"));
+        assert_eq!(openapi.get_response_fields("/pets", "GET"), vec!["$[*].name"]);
+        assert!(openapi.get_response_fields("/pets", "POST").is_empty());
+        assert!(!openapi.is_consequential("/pets/search", "POST"));
+        assert_eq!(openapi.declared_consequential("/pets", "POST"), None);
+        assert_eq!(openapi.declared_consequential("/pets/search", "POST"), Some(false));
+    }
+
+    #[test]
+    fn test_stored_is_consequential() {
+        // Declared by the spec
+        assert!(!stored_is_consequential(&json!({
+            "method": "post", "is_consequential": false, "x-openai-isConsequential": false,
+        })));
+        assert!(stored_is_consequential(&json!({
+            "method": "get", "is_consequential": true, "x-openai-isConsequential": true,
+        })));
+        assert!(stored_is_consequential(&json!({"method": "get", "is_consequential": true})));
+        // Registered before the flag was computed, every function stored `false`
+        assert!(stored_is_consequential(&json!({"method": "post", "is_consequential": false})));
+        assert!(stored_is_consequential(&json!({"method": "DELETE", "is_consequential": false})));
+        assert!(!stored_is_consequential(&json!({"method": "get", "is_consequential": false})));
+        assert!(stored_is_consequential(&json!({"method": "put"})));
+    }
 }
@@ -1,6 +1,8 @@
+use async_openai::types::{MessageContent, MessageRole};
 use hal_9100_core::models::Message;
 
-use tiktoken_rs::cl100k_base;
+use hal_9100_extra::config::ContextBudget;
+use tiktoken_rs::{cl100k_base, CoreBPE};
 
 // This function formats the messages into a string
 pub fn format_messages(messages: &Vec<Message>) -> String {
@@ -17,32 +19,93 @@ pub fn format_messages(messages: &Vec<Message>) -> String {
     formatted_messages
 }
 
+// This function returns the text of the last user message, or an empty string if there is none
+pub fn last_user_message(messages: &Vec<Message>) -> String {
+    messages
+        .iter()
+        .rev()
+        .find(|message| matches!(message.inner.role, MessageRole::User))
+        .map(|message| {
+            message
+                .inner
+                .content
+                .iter()
+                .filter_map(|content| match content {
+                    MessageContent::Text(text) => Some(text.text.value.clone()),
+                    _ => None,
+                })
+                .collect::<Vec<String>>()
+                .join("\n")
+        })
+        .unwrap_or_default()
+}
+
+// A source of the prompt, rendered as one block per item wrapped in `tag`
+struct PromptPart<'a> {
+    tag: &'a str,
+    items: Vec<String>,
+    share: f32,
+    // Keep the end of the text when truncating (e.g. the most recent messages)
+    keep_end: bool,
+    // Items are kept whole or dropped instead of being truncated (e.g. retrieval chunks)
+    atomic: bool,
+}
+
+fn render_item(tag: &str, item: &str) -> String {
+    format!("<{}>\n{}\n</{}>\n", tag, item, tag)
+}
+
+// Truncate a text to at most `max_tokens` tokens, marking the cut with "[...]"
+fn truncate_to_tokens(bpe: &CoreBPE, text: &str, max_tokens: usize, keep_end: bool) -> String {
+    let tokens = bpe.encode_with_special_tokens(text);
+    if tokens.len() <= max_tokens {
+        return text.to_string();
+    }
+    let marker = "[...]";
+    let mut keep = max_tokens.saturating_sub(bpe.encode_with_special_tokens(marker).len());
+    // A cut in the middle of a multi-byte character does not decode, drop tokens until it does
+    while keep > 0 {
+        let kept = if keep_end {
+            tokens[tokens.len() - keep..].to_vec()
+        } else {
+            tokens[..keep].to_vec()
+        };
+        if let Ok(kept) = bpe.decode(kept) {
+            return if keep_end {
+                format!("{}{}", marker, kept)
+            } else {
+                format!("{}{}", kept, marker)
+            };
+        }
+        keep -= 1;
+    }
+    String::new()
+}
+
 /// Builds the instructions for the assistant.
 ///
 /// This function takes several arguments, constructs parts of the instructions separately, and then
-/// combines them into the final instructions string based on their priority and the context size limit.
+/// combines them into the final instructions string within the context size limit.
 ///
 /// # Arguments
 ///
 /// * `original_instructions` - The original instructions string.
-/// * `file_contents` - A vector of strings representing the file contents.
+/// * `retrieval_files` - A vector of strings representing the file contents.
 /// * `previous_messages` - A string representing the previous messages.
-/// * `tools` - A string representing the tools.
+/// * `function_calls` - A string representing the function calls outputs.
 /// * `code_output` - An optional string representing the code output.
-/// * `context_size` - The context size limit for the language model.
-/// * `retrieval_chunks` - A vector of strings representing the retrieval chunks.
+/// * `retrieval_chunks` - A vector of strings representing the retrieval chunks, most relevant first.
+/// * `context_size` - The context size of the language model.
+/// * `budget` - The share of the context given to each part.
+/// * `action_calls` - A string representing the action calls outputs.
 ///
 /// # Returns
 ///
-/// * A string representing the final instructions for the assistant.
-///
-/// # Note
-///
 /// The function uses the `tiktoken_rs` library to count the tokens in the instructions.
-/// The parts of the instructions are added to the final instructions string based on their priority.
-/// The order of priority (from highest to lowest) is: original instructions, tools, code output,
-/// previous messages, file contents, and retrieval chunks.
-/// If a part doesn't fit within the context size limit, it is not added to the final instructions.
+/// Each part gets its share of the context from the budget, the share left unused by a part is
+/// given to the parts that need more. A part that does not fit in its share is truncated
+/// (previous messages keep the most recent ones, retrieval chunks are kept whole in order of
+/// relevance) instead of being dropped.
 pub fn build_instructions(
     original_instructions: &str,
     retrieval_files: &Vec<String>,
@@ -50,66 +113,144 @@ pub fn build_instructions(
     function_calls: &str,
     code_output: Option<&str>,
     retrieval_chunks: &Vec<String>,
-    context_size: Option<usize>,
+    context_size: usize,
+    budget: &ContextBudget,
     action_calls: &str,
 ) -> String {
     let bpe = cl100k_base().unwrap();
 
-    // if context_size is None, use env var or default to x
-    let context_size = context_size.unwrap_or_else(|| {
-        std::env::var("MODEL_CONTEXT_SIZE")
-            .unwrap_or_else(|_| "4096".to_string())
-            .parse::<usize>()
-            .unwrap_or(4096)
-    });
-
-    // Build each part of the instructions
-    let instructions_part = format!(
-        "<instructions>\n{}\n</instructions>\n",
-        original_instructions
-    );
-    let retrieval_files_part = format!("<file>\n{:?}\n</file>\n", retrieval_files);
-    let retrieval_chunks_part = format!("<chunk>\n{:?}\n</chunk>\n", retrieval_chunks);
-    let function_calls_part = format!("<function_calls>\n{}\n</function_calls>\n", function_calls);
-    let code_output_part = match code_output {
-        Some(output) => format!("<math_solution>\n{}\n</math_solution>\n", output),
-        None => String::new(),
+    let non_empty = |s: &str| -> Vec<String> {
+        if s.trim().is_empty() {
+            vec![]
+        } else {
+            vec![s.to_string()]
+        }
     };
-    let previous_messages_part = format!(
-        "<previous_messages>\n{}\n</previous_messages>\n",
-        previous_messages
-    );
-    let action_calls_part = format!("<action_calls>\n{}\n</action_calls>\n", action_calls);
-
-    // Initialize the final instructions with the highest priority part
-    let mut final_instructions = instructions_part;
-
-    // List of other parts ordered by priority
-    let other_parts = [
-        function_calls_part,
-        action_calls_part,
-        previous_messages_part,
-        code_output_part,
-        retrieval_files_part.clone(),
-        retrieval_chunks_part.clone(),
+    // Parts in the order they appear in the instructions
+    let parts = vec![
+        PromptPart {
+            tag: "instructions",
+            items: non_empty(original_instructions),
+            share: budget.instructions,
+            keep_end: false,
+            atomic: false,
+        },
+        PromptPart {
+            tag: "function_calls",
+            items: non_empty(function_calls),
+            share: budget.function_calls,
+            keep_end: false,
+            atomic: false,
+        },
+        PromptPart {
+            tag: "action_calls",
+            items: non_empty(action_calls),
+            share: budget.action_calls,
+            keep_end: false,
+            atomic: false,
+        },
+        PromptPart {
+            tag: "previous_messages",
+            items: non_empty(previous_messages),
+            share: budget.previous_messages,
+            keep_end: true,
+            atomic: false,
+        },
+        PromptPart {
+            tag: "math_solution",
+            items: non_empty(code_output.unwrap_or_default()),
+            share: budget.code_output,
+            keep_end: false,
+            atomic: false,
+        },
+        PromptPart {
+            tag: "file",
+            items: retrieval_files.clone(),
+            share: budget.retrieval_files,
+            keep_end: false,
+            atomic: false,
+        },
+        PromptPart {
+            tag: "chunk",
+            items: retrieval_chunks.clone(),
+            share: budget.retrieval_chunks,
+            keep_end: false,
+            atomic: true,
+        },
     ];
-    // TODO: probably this could be made customisable if someone has a usecase where code is very important for example
-
-    // Add other parts to the final instructions if they fit in the context limit
-    for part in &other_parts {
-        let part_tokens = bpe.encode_with_special_tokens(part).len();
-        let final_instructions_tokens = bpe.encode_with_special_tokens(&final_instructions).len();
-
-        if final_instructions_tokens + part_tokens <= context_size {
-            // If file_contents_part is already in final_instructions, do not add retrieval_chunks_part
-            if part == &retrieval_chunks_part && final_instructions.contains(&retrieval_chunks_part)
-            {
-                continue;
+
+    // Shares summing to more than the whole context are scaled down
+    let total_share: f32 = parts.iter().map(|p| p.share.max(0.0)).sum();
+    let scale = if total_share > 1.0 { 1.0 / total_share } else { 1.0 };
+    let shares: Vec<f32> = parts.iter().map(|p| p.share.max(0.0) * scale).collect();
+
+    let needs: Vec<usize> = parts
+        .iter()
+        .map(|p| {
+            p.items
+                .iter()
+                .map(|i| bpe.encode_with_special_tokens(&render_item(p.tag, i)).len())
+                .sum()
+        })
+        .collect();
+    let mut allowances: Vec<usize> = shares
+        .iter()
+        .map(|share| (share * context_size as f32) as usize)
+        .collect();
+
+    // Give the space unused by small parts to the parts that need more, pro rata of their share
+    for _ in 0..parts.len() {
+        let mut surplus = 0;
+        for i in 0..parts.len() {
+            if allowances[i] > needs[i] {
+                surplus += allowances[i] - needs[i];
+                allowances[i] = needs[i];
             }
-            final_instructions += part;
-        } else {
+        }
+        let hungry: Vec<usize> = (0..parts.len())
+            .filter(|&i| needs[i] > allowances[i] && shares[i] > 0.0)
+            .collect();
+        let hungry_share: f32 = hungry.iter().map(|&i| shares[i]).sum();
+        if surplus == 0 || hungry.is_empty() || hungry_share == 0.0 {
             break;
         }
+        for &i in &hungry {
+            allowances[i] += (surplus as f32 * shares[i] / hungry_share) as usize;
+        }
+    }
+
+    let mut final_instructions = String::new();
+    for (i, part) in parts.iter().enumerate() {
+        if needs[i] <= allowances[i] {
+            for item in &part.items {
+                final_instructions += &render_item(part.tag, item);
+            }
+            continue;
+        }
+        let mut remaining = allowances[i];
+        for (n, item) in part.items.iter().enumerate() {
+            let rendered = render_item(part.tag, item);
+            let tokens = bpe.encode_with_special_tokens(&rendered).len();
+            if tokens <= remaining {
+                final_instructions += &rendered;
+                remaining -= tokens;
+            } else if part.atomic {
+                // Items are ordered by relevance, stop at the first one that does not fit
+                break;
+            } else {
+                // Split what is left evenly between this item and the next ones
+                let item_allowance = remaining / (part.items.len() - n);
+                let overhead = bpe.encode_with_special_tokens(&render_item(part.tag, "")).len();
+                if item_allowance <= overhead {
+                    continue;
+                }
+                let truncated =
+                    truncate_to_tokens(&bpe, item, item_allowance - overhead, part.keep_end);
+                let rendered = render_item(part.tag, &truncated);
+                remaining = remaining.saturating_sub(bpe.encode_with_special_tokens(&rendered).len());
+                final_instructions += &rendered;
+            }
+        }
     }
 
     final_instructions
@@ -118,19 +259,20 @@ pub fn build_instructions(
 #[cfg(test)]
 mod tests {
     use hal_9100_core::prompts::build_instructions;
+    use hal_9100_extra::config::ContextBudget;
     use tiktoken_rs::cl100k_base;
 
     #[test]
     fn test_build_instructions_context_limit() {
         let original_instructions = "Solve the quadratic equation x^2 + 5x + 6 = 0.";
         let file_contents = vec![
-            "# Python script to solve quadratic equations\nimport cmath\ndef solve_quadratic(a, b, c):\n    # calculate the discriminant\n    d = (b**2) - (4*a*c)\n    # find two solutions\n    sol1 = (-b-cmath.sqrt(d))/(2*a)\n    sol2 = (-b+cmath.sqrt(d))/(2*a)\n    return sol1, sol2\n".to_string(),
+            "# Python script to solve quadratic equations\nimport cmath\ndef solve_quadratic(a, b, c):\n    # calculate the discriminant\n    d = (b**2) - (4*a*c)\n    # find two solutions\n    sol1 = (-b-cmath.sqrt(d))/(2*a)\n    sol2 = (-b+cmath.sqrt(d))/(2*a)\n    return sol1, sol2\n".repeat(20),
             "# Another Python script\nprint('Hello, world!')\n".to_string(),
         ];
         let previous_messages = "<message>\n{\"role\": \"user\", \"content\": \"Can you solve a quadratic equation for me?\"}\n</message>\n<message>\n{\"role\": \"assistant\", \"content\": \"Sure, I can help with that. What's the equation?\"}\n</message>\n";
         let function_calls = "code_interpreter";
         let code_output = Some("The solutions are (-2+0j) and (-3+0j)");
-        let context_size = 200; // Set a realistic context size
+        let context_size = 500; // Set a realistic context size
         let retrieval_chunks = vec![
             "Here's a chunk of text retrieved from a large document...".to_string(),
             "And here's another chunk of text...".to_string(),
@@ -144,7 +286,8 @@ mod tests {
             function_calls,
             code_output,
             &retrieval_chunks,
-            Some(context_size),
+            context_size,
+            &ContextBudget::default(),
             action_calls,
         );
 
@@ -158,7 +301,7 @@ mod tests {
             "The instructions exceed the context limit"
         );
 
-        // Check that the instructions contain the most important parts
+        // Check that the instructions contain the parts that fit in their share
         assert!(
             instructions.contains(original_instructions),
             "The instructions do not contain the original instructions"
@@ -171,15 +314,55 @@ mod tests {
             instructions.contains(previous_messages),
             "The instructions do not contain the previous messages"
         );
+        assert!(
+            instructions.contains(&retrieval_chunks[0]),
+            "The instructions do not contain the retrieval chunks"
+        );
 
-        // Check that the instructions do not contain the less important parts
+        // Check that the large file is truncated instead of dropped
         assert!(
             !instructions.contains(&file_contents[0]),
-            "The instructions contain the file contents"
+            "The instructions contain the whole file contents"
+        );
+        assert!(
+            instructions.contains("# Python script to solve quadratic equations"),
+            "The instructions do not contain the beginning of the file contents"
         );
         assert!(
-            !instructions.contains(&retrieval_chunks[0]),
-            "The instructions contain the retrieval chunks"
+            instructions.contains(&file_contents[1]),
+            "The instructions do not contain the small file"
+        );
+    }
+
+    #[test]
+    fn test_build_instructions_large_output_does_not_evict_other_parts() {
+        let function_calls = "The weather is sunny. ".repeat(2000);
+        let previous_messages = (0..200)
+            .map(|i| format!("<message>\nmessage number {}\n</message>\n", i))
+            .collect::<String>();
+        let retrieval_chunks = vec!["The president of Mars is Elon Musk.".to_string()];
+        let context_size = 1000;
+
+        let instructions = build_instructions(
+            "You are a helpful assistant.",
+            &vec![],
+            &previous_messages,
+            &function_calls,
+            None,
+            &retrieval_chunks,
+            context_size,
+            &ContextBudget::default(),
+            "",
         );
+
+        let bpe = cl100k_base().unwrap();
+        assert!(bpe.encode_with_special_tokens(&instructions).len() <= context_size);
+        assert!(instructions.contains("<function_calls>\nThe weather is sunny."));
+        assert!(instructions.contains(&retrieval_chunks[0]));
+        // The most recent messages are kept
+        assert!(instructions.contains("message number 199"));
+        assert!(!instructions.contains("message number 0\n"));
+        // Empty parts are not added
+        assert!(!instructions.contains("<action_calls>"));
     }
 }
@@ -1,8 +1,12 @@
+use async_openai::types::{
+    FileCitation, MessageContentTextAnnotations, MessageContentTextAnnotationsFileCitationObject,
+};
 use hal_9100_core::models::Chunk;
 use hal_9100_extra::llm::HalLLMClient;
 use hal_9100_extra::llm::HalLLMRequestArgs;
 use log::error;
 use log::info;
+use regex::Regex;
 use serde_json::{self, Value};
 use sqlx::types::JsonValue;
 use sqlx::PgPool;
@@ -107,79 +111,115 @@ pub async fn split_and_insert(
             end_index: row.end_index,
             metadata: serde_json::from_value(row.metadata.unwrap_or_default()).unwrap(),
             created_at: row.created_at,
+            score: None,
         })
         .collect())
 }
 
+// Limits applied to the search queries proposed by the LLM
+const MAX_SEARCH_QUERIES: usize = 5;
+const MAX_SEARCH_QUERY_LENGTH: usize = 200;
+
+/// Parse and validate the search queries proposed by the LLM. The output must contain a JSON
+/// object like `{"queries": ["..."]}` (`keywords` is accepted too). Queries are trimmed,
+/// stripped from control characters, truncated, deduplicated and capped in number.
+pub fn parse_search_queries(output: &str) -> Result<Vec<String>, String> {
+    // LLMs like to wrap JSON in prose or code fences, only keep the outermost object
+    let start = output.find('{').ok_or("No JSON object in output")?;
+    let end = output.rfind('}').ok_or("No JSON object in output")?;
+    if end < start {
+        return Err("No JSON object in output".to_string());
+    }
+    let value: Value = serde_json::from_str(&output[start..=end])
+        .map_err(|e| format!("Invalid JSON in output: {}", e))?;
+
+    let mut queries: Vec<String> = Vec::new();
+    for key in ["queries", "keywords"] {
+        if let Some(items) = value.get(key).and_then(|v| v.as_array()) {
+            for item in items.iter().filter_map(|v| v.as_str()) {
+                let query: String = item
+                    .chars()
+                    .filter(|c| !c.is_control())
+                    .take(MAX_SEARCH_QUERY_LENGTH)
+                    .collect::<String>()
+                    .trim()
+                    .to_string();
+                if !query.is_empty() && !queries.contains(&query) {
+                    queries.push(query);
+                }
+            }
+        }
+    }
+    queries.truncate(MAX_SEARCH_QUERIES);
+
+    if queries.is_empty() {
+        return Err(format!("No valid queries in output: {}", output));
+    }
+    Ok(queries)
+}
+
+/// Build a search query from the raw user message, used when the LLM rewrite fails.
+/// Words are OR-ed so that a long question still matches chunks containing some of them.
+pub fn fallback_search_query(message: &str) -> String {
+    let mut words: Vec<String> = Vec::new();
+    for word in message
+        .split(|c: char| !c.is_alphanumeric())
+        .filter(|w| w.chars().count() > 2)
+        .map(|w| w.to_lowercase())
+    {
+        if !words.contains(&word) {
+            words.push(word);
+        }
+    }
+    words.join(" or ")
+}
+
 pub async fn generate_queries_and_fetch_chunks(
     pool: &PgPool,
     client: HalLLMClient,
     mut request: HalLLMRequestArgs,
+    last_user_message: &str,
 ) -> Result<Vec<Chunk>, Box<dyn Error>> {
-    // Generate full-text search queries using the llm() function
-    let p = "You are a helpful assistant that generate full-text search queries for the user.
-You must return return the best full-text search query for the given context to solve the user's problem.
-
-Your output will be used in the following code:
+    let p = "You are a helpful assistant that generates search queries to find the information needed to solve the user's problem in a set of documents.
 
-// Convert the query to tsquery and execute it on the database
-let rows = sqlx::query!(
-    r#\"
-    SELECT * FROM chunks 
-    WHERE to_tsvector(data) @@ to_tsquery($1)
-    \"#,
-    // This is where your answer will be used, so make sure to keep the right format
-    query,
-)
-.fetch_all(pool)
-.await?;
+Return a JSON object with a \"queries\" field containing a list of 1 to 5 short search queries, each made of a few keywords or a short sub-question.
+The queries are used by a full-text search engine, so favour specific words likely to appear in the documents.
+Do not add any comment, only return the JSON object.
 
-Where \"query\" is your output.
+Examples:
 
-Rules:
-- If your output is not correctly a string containing a full-text search query, the universe will be terminated.
-- If your output is not a valid full-text search query, this will be the end of the universe.
-- Do not add SPACES between words, only use the | character or & character to combine words.
-- Only return a query, NOTHING ELSE, do not comment on the query! or the big crunch will be initiated.
+1. Healthcare: {\"queries\": [\"heart disease\", \"stroke risk factors\"]}
 
-Bad examples:
+2. Finance: {\"queries\": [\"stocks\", \"bonds yield\"]}
 
-1. Math: wrong output: \"To | find | solutions | to | the | equation | `3x | +11 | = | 14`, | a | full-text | search | query ...\"
-
-2. Engineering: wrong output: \"neuromorphic computing\" instead of \"neuromorphic | computing\".
-
-3. Greentech: wrong output: \"solar panels | PDF manual | deal with | installation | maintenance\" instead of \"solar | panels | PDF | manual | deal | with | installation | maintenance\".
-
-Good examples:
-
-1. Healthcare: your output could be \"heart & disease | stroke\".
-
-2. Finance: your output could be \"stocks | bonds\".
-
-3. Education: your output could be \"mathematics | physics\".
-
-4. Automotive: your output could be \"sedan | SUV\".
-
-5. Agriculture: your output could be \"organic | conventional & farming\".
-
-Query:";
+3. Agriculture: {\"queries\": [\"organic farming\", \"conventional farming\"]}
+";
 
     request.set_system_prompt(p.to_string());
-    let query = client.create_chat_completion(request).await?;
-
-    // TODO: bad processing
-    // if the llm return two words like "dog food", just add a | between them
-    // using a regex for safety
-    // let re = regex::Regex::new(r"\s+").unwrap();
-    // let query = re.replace_all(&query, " | ").to_string();
+    let queries = match client.create_chat_completion(request).await {
+        Ok(output) => parse_search_queries(&output),
+        Err(e) => Err(format!("Failed to generate queries: {}", e)),
+    };
+    let queries = match queries {
+        Ok(queries) => queries,
+        Err(e) => {
+            error!("Query rewriting failed, falling back to the user message: {}", e);
+            vec![fallback_search_query(last_user_message)]
+        }
+    };
+    info!("Searching chunks with queries: {:?}", queries);
 
-    // Convert the query to tsquery and execute it on the database
+    // websearch_to_tsquery never fails on user input, whatever the LLM wrote
     let rows = sqlx::query!(
         r#"
-        SELECT * FROM chunks 
-        WHERE to_tsvector(data) @@ to_tsquery($1)
+        SELECT c.*, MAX(ts_rank(to_tsvector(c.data), q.tsq)) AS score
+        FROM chunks c
+        JOIN (SELECT websearch_to_tsquery(query) AS tsq FROM unnest($1::text[]) AS query) q
+        ON to_tsvector(c.data) @@ q.tsq
+        GROUP BY c.id
+        ORDER BY score DESC
         "#,
-        query,
+        &queries,
     )
     .fetch_all(pool)
     .await?;
@@ -200,12 +240,83 @@ Query:";
                 _ => None,
             },
             created_at: row.created_at,
+            score: row.score,
         })
         .collect();
 
     Ok(chunks)
 }
 
+/// Format chunks for the prompt. Each chunk gets a 1-based `source` number the LLM
+/// uses to cite it with a `【{source}†source】` marker.
+pub fn format_chunks_for_prompt(chunks: &Vec<Chunk>) -> Vec<String> {
+    chunks
+        .iter()
+        .enumerate()
+        .map(|(i, c)| {
+            serde_json::to_string(&serde_json::json!({
+                "source": i + 1,
+                "data": c.data,
+                "sequence": c.sequence,
+                "start_index": c.start_index,
+                "end_index": c.end_index,
+                "metadata": c.metadata,
+            }))
+            .unwrap()
+        })
+        .collect()
+}
+
+/// Turn the `【{source}†source】` markers of an LLM answer into file citation annotations
+/// pointing to the chunks given in the prompt. Indexes are in characters, markers
+/// referring to unknown sources are ignored.
+pub fn extract_file_citations(text: &str, chunks: &Vec<Chunk>) -> Vec<MessageContentTextAnnotations> {
+    let re = Regex::new(r"【(\d+)(?::\d+)?†[^】]*】").unwrap();
+    re.captures_iter(text)
+        .filter_map(|cap| {
+            let marker = cap.get(0)?;
+            let source = cap[1].parse::<usize>().ok()?;
+            let chunk = chunks.get(source.checked_sub(1)?)?;
+            let start_index = text[..marker.start()].chars().count() as u32;
+            let end_index = start_index + marker.as_str().chars().count() as u32;
+            Some(MessageContentTextAnnotations::FileCitation(
+                MessageContentTextAnnotationsFileCitationObject {
+                    r#type: "file_citation".to_string(),
+                    text: marker.as_str().to_string(),
+                    file_citation: FileCitation {
+                        file_id: chunk.file_id.clone(),
+                        quote: chunk.data.clone(),
+                    },
+                    start_index,
+                    end_index,
+                },
+            ))
+        })
+        .collect()
+}
+
+/// Details of a retrieval step: the chunks found and their score.
+pub fn retrieval_step_details(chunks: &Vec<Chunk>) -> HashMap<String, Value> {
+    let mut details = HashMap::new();
+    details.insert(
+        "chunks".to_string(),
+        Value::Array(
+            chunks
+                .iter()
+                .map(|c| {
+                    serde_json::json!({
+                        "id": c.id.to_string(),
+                        "file_id": c.file_id,
+                        "sequence": c.sequence,
+                        "score": c.score,
+                    })
+                })
+                .collect(),
+        ),
+    );
+    details
+}
+
 // TODO: kinda dirty function could be better
 // This function retrieves file contents given a list of file_ids
 pub async fn retrieve_file_contents(
@@ -300,6 +411,71 @@ The president of the Moon is TDB.
         }
     }
 
+    #[test]
+    fn test_extract_file_citations() {
+        let chunk = |file_id: &str, data: &str| Chunk {
+            id: sqlx::types::Uuid::new_v4(),
+            sequence: 0,
+            data: data.to_string(),
+            file_id: file_id.to_string(),
+            start_index: 0,
+            end_index: 0,
+            metadata: None,
+            created_at: 0,
+            score: Some(0.5),
+        };
+        let chunks = vec![
+            chunk("a.txt", "The president of Mars is Elon Musk."),
+            chunk("b.pdf", "The president of the Moon is TDB."),
+        ];
+        let text = "Mars: Elon Musk【1†source】. Moon: TDB【2†source】. Venus: ?【3†source】";
+
+        let annotations = extract_file_citations(text, &chunks);
+
+        assert_eq!(annotations.len(), 2, "Unknown sources should be ignored");
+        match &annotations[0] {
+            MessageContentTextAnnotations::FileCitation(citation) => {
+                assert_eq!(citation.file_citation.file_id, "a.txt");
+                assert_eq!(citation.file_citation.quote, chunks[0].data);
+                assert_eq!(citation.start_index, 15);
+                assert_eq!(citation.end_index, 25);
+                assert_eq!(citation.text, "【1†source】");
+            }
+            _ => panic!("Expected a file citation"),
+        }
+        match &annotations[1] {
+            MessageContentTextAnnotations::FileCitation(citation) => {
+                assert_eq!(citation.file_citation.file_id, "b.pdf");
+            }
+            _ => panic!("Expected a file citation"),
+        }
+    }
+
+    #[test]
+    fn test_parse_search_queries() {
+        let output = "Sure! ```json\n{\"queries\": [\" dog food \", \"dog food\", \"\", 42, \"VC\\u0000 funding\"]}\n```";
+        assert_eq!(
+            parse_search_queries(output).unwrap(),
+            vec!["dog food".to_string(), "VC funding".to_string()]
+        );
+
+        let output = "{\"keywords\": [\"a\", \"b\", \"c\", \"d\", \"e\", \"f\"]}";
+        assert_eq!(parse_search_queries(output).unwrap().len(), 5);
+
+        assert!(parse_search_queries("dog & food | (").is_err());
+        assert!(parse_search_queries("{\"queries\": []}").is_err());
+        assert!(parse_search_queries("} oops {").is_err());
+    }
+
+    #[test]
+    fn test_fallback_search_query() {
+        assert_eq!(
+            fallback_search_query("What's the BarkByte dog-food? What is it?"),
+            "what or the or barkbyte or dog or food"
+        );
+        assert_eq!(fallback_search_query("?!"), "");
+    }
+
     #[tokio::test]
     async fn test_insert_chunks_into_db() {
         dotenv().ok();
@@ -349,7 +525,7 @@ The president of the Moon is TDB.
         );
         let mut request = HalLLMRequestArgs::default();
         request.set_last_user_prompt(context.to_string());
-        let result = generate_queries_and_fetch_chunks(&pool, llm_client, request).await;
+        let result = generate_queries_and_fetch_chunks(&pool, llm_client, request, context).await;
 
         // Check the result
         assert!(
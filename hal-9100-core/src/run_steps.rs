@@ -163,6 +163,28 @@ pub async fn update_step(
     })
 }
 
+/// Replace the metadata of a step, e.g. to record how its tool calls ran.
+pub async fn update_step_metadata(
+    pool: &PgPool,
+    step_id: &str,
+    metadata: HashMap<String, serde_json::Value>,
+    user_id: &str,
+) -> Result<(), sqlx::Error> {
+    sqlx::query!(
+        r#"
+        UPDATE run_steps
+        SET metadata = $2
+        WHERE id::text = $1 AND user_id::text = $3
+        "#,
+        step_id,
+        serde_json::to_value(metadata).unwrap(),
+        user_id,
+    )
+    .execute(pool)
+    .await?;
+    Ok(())
+}
+
 pub async fn set_all_steps_status(
     pool: &PgPool,
     run_id: &str,
@@ -432,6 +454,8 @@ mod tests {
             &assistant.inner.id,
             "No",
             &user_id.to_string(),
+            None,
+            None,
         )
         .await
         .unwrap();
@@ -3,20 +3,25 @@ use async_openai::types::AssistantToolsRetrieval;
 use async_openai::types::RequiredAction;
 use async_openai::types::RunObject;
 use async_openai::types::RunStatus;
+use async_openai::types::SubmitToolOutputs;
 use log::{error, info};
 use serde::Deserialize;
 use serde::Serialize;
 use sqlx::PgPool;
 
 use futures::stream::StreamExt; // Don't forget to import StreamExt
+use hal_9100_core::models::ActionApproval;
+use hal_9100_core::models::PendingAction;
 use hal_9100_core::models::Run;
 use hal_9100_core::models::SubmittedToolCall;
+use hal_9100_core::models::TruncationStrategy;
 use redis::AsyncCommands;
 use serde_json::json;
 use sqlx::types::Uuid;
 use std::collections::HashMap;
 use std::error::Error;
 
+/// The tool calls among `tool_call_ids` whose output was submitted.
 pub async fn get_tool_calls(
     pool: &PgPool,
     tool_call_ids: Vec<&str>,
@@ -26,14 +31,19 @@ pub async fn get_tool_calls(
         tool_call_ids
     );
 
+    let ids = tool_call_ids
+        .into_iter()
+        .map(|id| {
+            Uuid::parse_str(id).map_err(|_| {
+                sqlx::Error::Configuration(format!("Invalid tool call id: {}", id).into())
+            })
+        })
+        .collect::<Result<Vec<_>, _>>()?;
     let rows = sqlx::query!(
         r#"
-        SELECT * FROM tool_calls WHERE id = ANY($1)
+        SELECT * FROM tool_calls WHERE id = ANY($1) AND output IS NOT NULL
         "#,
-        &tool_call_ids
-            .into_iter()
-            .map(|s| Uuid::parse_str(s).unwrap())
-            .collect::<Vec<_>>(),
+        &ids,
     )
     .fetch_all(pool)
     .await?;
@@ -52,6 +62,28 @@ pub async fn get_tool_calls(
     Ok(tool_calls)
 }
 
+/// The ids of the tool calls of a required action still waiting for their output.
+pub async fn get_missing_tool_outputs(
+    pool: &PgPool,
+    required_action: &RequiredAction,
+) -> Result<Vec<String>, sqlx::Error> {
+    let required_ids: Vec<&str> = required_action
+        .submit_tool_outputs
+        .tool_calls
+        .iter()
+        .map(|tool_call| tool_call.id.as_str())
+        .collect();
+    let submitted = get_tool_calls(pool, required_ids.clone()).await?;
+    Ok(required_ids
+        .into_iter()
+        .filter(|id| !submitted.iter().any(|tool_call| tool_call.id == *id))
+        .map(|id| id.to_string())
+        .collect())
+}
+
+/// Save the outputs of the tool calls a run is waiting for. The outputs may be submitted over
+/// several requests: the run is queued again once every call has its output, until then it
+/// stays in `requires_action`.
 pub async fn submit_tool_outputs(
     pool: &PgPool,
     thread_id: &str,
@@ -62,34 +94,111 @@ pub async fn submit_tool_outputs(
 ) -> Result<Run, sqlx::Error> {
     info!("Submitting tool outputs for run_id: {}", run_id);
 
-    // Fetch the updated run from the database
     let run = get_run(pool, thread_id, run_id, user_id).await?;
 
     // should throw if run is not in status requires_action
-    if run.inner.status != RunStatus::RequiresAction {
+    let required_action = match &run.inner.required_action {
+        Some(action)
+            if run.inner.status == RunStatus::RequiresAction
+                && action.r#type == "submit_tool_outputs" =>
+        {
+            action.clone()
+        }
+        _ => {
+            let err_msg = "Run is not in status requires_action";
+            error!("{}", err_msg);
+            return Err(sqlx::Error::Configuration(err_msg.into()));
+        }
+    };
+    let required_ids: Vec<&str> = required_action
+        .submit_tool_outputs
+        .tool_calls
+        .iter()
+        .map(|tool_call| tool_call.id.as_str())
+        .collect();
+
+    if tool_outputs.is_empty() {
+        let err_msg = format!(
+            "No tool outputs submitted, the run is waiting for: {}",
+            get_missing_tool_outputs(pool, &required_action).await?.join(", ")
+        );
+        error!("{}", err_msg);
+        return Err(sqlx::Error::Configuration(err_msg.into()));
+    }
+    let unknown: Vec<&str> = tool_outputs
+        .iter()
+        .map(|tool_output| tool_output.id.as_str())
+        .filter(|id| !required_ids.contains(id))
+        .collect();
+    if !unknown.is_empty() {
+        let err_msg = format!(
+            "Tool calls {} do not belong to run {}",
+            unknown.join(", "),
+            run_id
+        );
+        error!("{}", err_msg);
+        return Err(sqlx::Error::Configuration(err_msg.into()));
+    }
+    for (i, tool_output) in tool_outputs.iter().enumerate() {
+        if tool_outputs[..i].iter().any(|other| other.id == tool_output.id) {
+            let err_msg = format!("Tool call {} is submitted more than once", tool_output.id);
+            error!("{}", err_msg);
+            return Err(sqlx::Error::Configuration(err_msg.into()));
+        }
+    }
+
+    // The run is locked so that concurrent submissions see each other's outputs and only the
+    // last one queues it
+    let mut tx = pool.begin().await?;
+    let status = sqlx::query!(
+        r#"
+        SELECT status FROM runs
+        WHERE id::text = $1 AND user_id::text = $2
+        FOR UPDATE
+        "#,
+        run_id,
+        user_id,
+    )
+    .fetch_one(&mut *tx)
+    .await?
+    .status;
+    if status.as_deref() != Some("requires_action") {
         let err_msg = "Run is not in status requires_action";
         error!("{}", err_msg);
         return Err(sqlx::Error::Configuration(err_msg.into()));
     }
-    // should throw if tool outputs length is not matching all the tool calls asked for
-    if run
-        .inner
-        .required_action
-        .unwrap()
-        .submit_tool_outputs
-        .tool_calls
-        .len()
-        != tool_outputs.len()
+
+    let submitted = sqlx::query!(
+        r#"
+        SELECT id::text AS "id!" FROM tool_calls
+        WHERE run_id::text = $1 AND user_id::text = $2 AND id::text = ANY($3) AND output IS NOT NULL
+        "#,
+        run_id,
+        user_id,
+        &required_ids
+            .iter()
+            .map(|id| id.to_string())
+            .collect::<Vec<String>>(),
+    )
+    .fetch_all(&mut *tx)
+    .await?
+    .into_iter()
+    .map(|row| row.id)
+    .collect::<Vec<String>>();
+    if let Some(tool_output) = tool_outputs
+        .iter()
+        .find(|tool_output| submitted.contains(&tool_output.id))
     {
-        let err_msg = "You must submit all tool outputs";
+        let err_msg = format!(
+            "The output of tool call {} was already submitted",
+            tool_output.id
+        );
         error!("{}", err_msg);
         return Err(sqlx::Error::Configuration(err_msg.into()));
     }
 
-    // Iterate over tool_outputs and update each tool_call in the database
-    for tool_output in tool_outputs {
+    for tool_output in tool_outputs.iter() {
         info!("Updating tool call for tool_call_id: {}", tool_output.id);
-        // TODO parallel
         sqlx::query!(
             r#"
             UPDATE tool_calls
@@ -101,10 +210,41 @@ pub async fn submit_tool_outputs(
             run_id,
             user_id,
         )
-        .execute(pool)
+        .execute(&mut *tx)
         .await?;
     }
 
+    let missing: Vec<&str> = required_ids
+        .iter()
+        .filter(|id| {
+            !submitted.iter().any(|submitted_id| submitted_id == *id)
+                && !tool_outputs.iter().any(|tool_output| tool_output.id == **id)
+        })
+        .copied()
+        .collect();
+    if !missing.is_empty() {
+        tx.commit().await?;
+        info!(
+            "Run {} still waits for the outputs of tool calls: {}",
+            run_id,
+            missing.join(", ")
+        );
+        return get_run(pool, thread_id, run_id, user_id).await;
+    }
+
+    sqlx::query!(
+        r#"
+        UPDATE runs
+        SET status = 'queued'
+        WHERE id::text = $1 AND user_id::text = $2
+        "#,
+        run_id,
+        user_id,
+    )
+    .execute(&mut *tx)
+    .await?;
+    tx.commit().await?;
+
     // Create a JSON object with run_id and thread_id
     let ids = serde_json::json!({
         "run_id": run.inner.id,
@@ -115,23 +255,174 @@ pub async fn submit_tool_outputs(
     // Convert the JSON object to a string
     let ids_string = ids.to_string();
 
-    // should queue the run and update run status to queued
+    // should queue the run
     con.lpush("run_queue", ids_string)
         .await
         .map_err(|e| sqlx::Error::Configuration(e.into()))?;
 
-    let updated_run = update_run_status(
+    get_run(pool, thread_id, run_id, user_id).await
+}
+
+/// Stop the run until the user approves or rejects `actions`.
+pub async fn request_action_approval(
+    pool: &PgPool,
+    thread_id: &str,
+    run_id: &str,
+    user_id: &str,
+    actions: Vec<PendingAction>,
+) -> Result<Run, sqlx::Error> {
+    info!("Requesting approval of {} actions for run_id: {}", actions.len(), run_id);
+    sqlx::query!(
+        r#"
+        UPDATE runs
+        SET pending_actions = $1
+        WHERE id::text = $2 AND user_id::text = $3
+        "#,
+        serde_json::to_value(&actions).unwrap(),
+        run_id,
+        user_id,
+    )
+    .execute(pool)
+    .await?;
+
+    update_run_status(
         pool,
         thread_id,
         run_id,
-        RunStatus::Queued,
+        RunStatus::RequiresAction,
         user_id,
+        Some(RequiredAction {
+            r#type: "approve_action".to_string(),
+            submit_tool_outputs: SubmitToolOutputs { tool_calls: vec![] },
+        }),
         None,
-        None,
     )
+    .await
+}
+
+pub async fn get_pending_actions(
+    pool: &PgPool,
+    run_id: &str,
+    user_id: &str,
+) -> Result<Vec<PendingAction>, sqlx::Error> {
+    let row = sqlx::query!(
+        r#"
+        SELECT pending_actions FROM runs
+        WHERE id::text = $1 AND user_id::text = $2
+        "#,
+        run_id,
+        user_id,
+    )
+    .fetch_one(pool)
     .await?;
+    Ok(row
+        .pending_actions
+        .map(|actions| serde_json::from_value(actions).unwrap_or_default())
+        .unwrap_or_default())
+}
 
-    Ok(updated_run)
+/// Forget the pending actions of a run once the executor ran or rejected them.
+pub async fn clear_pending_actions(
+    pool: &PgPool,
+    run_id: &str,
+    user_id: &str,
+) -> Result<(), sqlx::Error> {
+    sqlx::query!(
+        r#"
+        UPDATE runs
+        SET pending_actions = NULL
+        WHERE id::text = $1 AND user_id::text = $2
+        "#,
+        run_id,
+        user_id,
+    )
+    .execute(pool)
+    .await?;
+    Ok(())
+}
+
+/// Record the user's decisions on the pending actions of a run and queue it again. Every
+/// action waiting for approval must be decided on.
+pub async fn submit_action_approvals(
+    pool: &PgPool,
+    thread_id: &str,
+    run_id: &str,
+    user_id: &str,
+    approvals: Vec<ActionApproval>,
+    mut con: redis::aio::Connection,
+) -> Result<Run, sqlx::Error> {
+    info!("Submitting action approvals for run_id: {}", run_id);
+
+    // The run is locked and queued in the same transaction, so that concurrent approvals
+    // can't both queue it and get the consequential requests sent twice
+    let mut tx = pool.begin().await?;
+    let row = sqlx::query!(
+        r#"
+        SELECT status, required_action, pending_actions FROM runs
+        WHERE id::text = $1 AND thread_id::text = $2 AND user_id::text = $3
+        FOR UPDATE
+        "#,
+        run_id,
+        thread_id,
+        user_id,
+    )
+    .fetch_one(&mut *tx)
+    .await?;
+    let awaiting_approval = row.status.as_deref() == Some("requires_action")
+        && row
+            .required_action
+            .as_ref()
+            .map_or(false, |action| action["type"] == "approve_action");
+    if !awaiting_approval {
+        let err_msg = "Run is not waiting for actions to be approved";
+        error!("{}", err_msg);
+        return Err(sqlx::Error::Configuration(err_msg.into()));
+    }
+
+    let mut actions: Vec<PendingAction> = row
+        .pending_actions
+        .map(|actions| serde_json::from_value(actions).unwrap_or_default())
+        .unwrap_or_default();
+    for action in actions.iter_mut().filter(|action| action.approved.is_none()) {
+        let approval = match approvals
+            .iter()
+            .find(|approval| approval.tool_call_id == action.tool_call_id)
+        {
+            Some(approval) => approval,
+            None => {
+                let err_msg = format!("Missing approval for tool call {}", action.tool_call_id);
+                error!("{}", err_msg);
+                return Err(sqlx::Error::Configuration(err_msg.into()));
+            }
+        };
+        action.approved = Some(approval.approve);
+        action.reason = approval.reason.clone();
+    }
+
+    sqlx::query!(
+        r#"
+        UPDATE runs
+        SET pending_actions = $1, required_action = NULL, status = 'queued'
+        WHERE id::text = $2 AND user_id::text = $3
+        "#,
+        serde_json::to_value(&actions).unwrap(),
+        run_id,
+        user_id,
+    )
+    .execute(&mut *tx)
+    .await?;
+    tx.commit().await?;
+
+    let ids = serde_json::json!({
+        "run_id": run_id,
+        "thread_id": thread_id,
+        "user_id": user_id
+    });
+    con.lpush("run_queue", ids.to_string())
+        .await
+        .map_err(|e| sqlx::Error::Configuration(e.into()))?;
+
+    get_run(pool, thread_id, run_id, user_id).await
 }
 
 pub async fn create_run_and_produce_to_executor_queue(
@@ -140,6 +431,8 @@ pub async fn create_run_and_produce_to_executor_queue(
     assistant_id: &str,
     instructions: &str,
     user_id: &str,
+    truncation_strategy: Option<TruncationStrategy>,
+    parallel_tool_calls: Option<bool>,
     mut con: redis::aio::Connection,
 ) -> Result<Run, sqlx::Error> {
     info!(
@@ -147,7 +440,7 @@ pub async fn create_run_and_produce_to_executor_queue(
         assistant_id, thread_id
     );
     // Create Run in database
-    let run = match create_run(pool, thread_id, assistant_id, instructions, user_id).await {
+    let run = match create_run(pool, thread_id, assistant_id, instructions, user_id, truncation_strategy, parallel_tool_calls).await {
         Ok(run) => run,
         Err(e) => {
             eprintln!("Failed to create run in database: {}", e);
@@ -191,18 +484,22 @@ pub async fn create_run(
     assistant_id: &str,
     instructions: &str,
     user_id: &str,
+    truncation_strategy: Option<TruncationStrategy>,
+    parallel_tool_calls: Option<bool>,
 ) -> Result<Run, sqlx::Error> {
     info!("Creating run for assistant_id: {}", assistant_id);
     let row = sqlx::query!(
         r#"
-        INSERT INTO runs (thread_id, assistant_id, instructions, user_id)
-        VALUES ($1, $2, $3, $4)
+        INSERT INTO runs (thread_id, assistant_id, instructions, user_id, truncation_strategy, parallel_tool_calls)
+        VALUES ($1, $2, $3, $4, $5, $6)
         RETURNING *
         "#,
         Uuid::parse_str(thread_id).unwrap(),
         Uuid::parse_str(assistant_id).unwrap(),
         instructions,
-        Uuid::parse_str(user_id).unwrap()
+        Uuid::parse_str(user_id).unwrap(),
+        serde_json::to_value(truncation_strategy.unwrap_or_default()).unwrap(),
+        parallel_tool_calls.unwrap_or(true)
     )
     .fetch_one(pool)
     .await?;
@@ -260,6 +557,9 @@ pub async fn create_run(
             ),
         },
         user_id: row.user_id.unwrap_or_default().to_string(),
+        truncation_strategy: serde_json::from_value(row.truncation_strategy.unwrap_or_default())
+            .unwrap_or_default(),
+        parallel_tool_calls: row.parallel_tool_calls,
     })
 }
 
@@ -349,6 +649,9 @@ pub async fn get_run(
             ),
         },
         user_id: row.user_id.unwrap_or_default().to_string(),
+        truncation_strategy: serde_json::from_value(row.truncation_strategy.unwrap_or_default())
+            .unwrap_or_default(),
+        parallel_tool_calls: row.parallel_tool_calls,
     })
 }
 
@@ -438,6 +741,9 @@ pub async fn update_run(
             ),
         },
         user_id: row.user_id.unwrap_or_default().to_string(),
+        truncation_strategy: serde_json::from_value(row.truncation_strategy.unwrap_or_default())
+            .unwrap_or_default(),
+        parallel_tool_calls: row.parallel_tool_calls,
     })
 }
 
@@ -564,6 +870,9 @@ pub async fn update_run_status(
             ),
         },
         user_id: row.user_id.unwrap_or_default().to_string(),
+        truncation_strategy: serde_json::from_value(row.truncation_strategy.unwrap_or_default())
+            .unwrap_or_default(),
+        parallel_tool_calls: row.parallel_tool_calls,
     })
 }
 
@@ -688,6 +997,9 @@ pub async fn list_runs(
                 ),
             },
             user_id: row.user_id.unwrap_or_default().to_string(),
+            truncation_strategy: serde_json::from_value(row.truncation_strategy.unwrap_or_default())
+                .unwrap_or_default(),
+            parallel_tool_calls: row.parallel_tool_calls,
         })
         .collect();
 
@@ -697,15 +1009,26 @@ pub async fn list_runs(
 #[cfg(test)]
 mod tests {
     use crate::assistants::create_assistant;
+    use crate::egress::ActionClient;
     use crate::executor::try_run_executor;
+    use crate::file_storage::FileStorage;
+    use crate::function_calling::FunctionCallWithMetadata;
+    use crate::openapi::ActionPreview;
+    use crate::run_steps::{create_step, list_steps};
+    use crate::tools::{function_step_details, run_action};
+    use hal_9100_extra::config::ActionsConfig;
+    use hal_9100_extra::config::Hal9100Config;
     use crate::models::Assistant;
     use crate::threads::create_thread;
 
     use super::*;
     use async_openai::types::{
-        AssistantObject, FunctionCall, RunToolCallObject, SubmitToolOutputs, ThreadObject,
+        AssistantObject, FunctionCall, RunStepDetailsToolCalls, RunStepType, RunToolCallObject,
+        StepDetails, SubmitToolOutputs, ThreadObject,
     };
     use dotenv::dotenv;
+    use httpmock::prelude::*;
+    use std::collections::BTreeMap;
     use hal_9100_core::models::Thread;
     use hal_9100_extra::llm::HalLLMClient;
     use sqlx::postgres::PgPoolOptions;
@@ -790,6 +1113,8 @@ mod tests {
             &assistant.inner.id,
             "Please address the user as Jane Doe. The user has a premium account.",
             &assistant.user_id,
+            None,
+            None,
             con,
         )
         .await; // Use the id of the new thread
@@ -866,6 +1191,8 @@ mod tests {
             &assistant.inner.id, // assistant_id
             "Please address the user as Jane Doe. The user has a premium account.",
             &Uuid::default().to_string(), // user_id
+            None,
+            None,
         )
         .await
         .unwrap();
@@ -941,6 +1268,8 @@ mod tests {
             &assistant.inner.id, // assistant_id
             "Please address the user as Jane Doe. The user has a premium account.",
             &Uuid::default().to_string(),
+            None,
+            None,
         )
         .await
         .unwrap();
@@ -975,6 +1304,354 @@ mod tests {
         assert!(!result.is_ok(), "should be Err");
     }
 
+    #[tokio::test]
+    async fn test_submit_tool_outputs_partially() {
+        let pool = setup().await;
+        reset_db(&pool).await;
+        let user_id = Uuid::default().to_string();
+        let thread = create_thread(
+            &pool,
+            &Thread {
+                inner: ThreadObject {
+                    id: "".to_string(),
+                    object: "".to_string(),
+                    created_at: 0,
+                    metadata: None,
+                },
+                user_id: user_id.clone(),
+            },
+        )
+        .await
+        .unwrap();
+        let assistant = create_assistant(
+            &pool,
+            &Assistant {
+                inner: AssistantObject {
+                    id: "".to_string(),
+                    object: "".to_string(),
+                    created_at: 0,
+                    name: Some("Weather".to_string()),
+                    description: None,
+                    model: "claude-2.1".to_string(),
+                    instructions: None,
+                    tools: vec![],
+                    file_ids: vec![],
+                    metadata: None,
+                },
+                user_id: user_id.clone(),
+            },
+        )
+        .await
+        .unwrap();
+        let run = create_run(&pool, &thread.inner.id, &assistant.inner.id, "", &user_id, None, None)
+            .await
+            .unwrap();
+        assert!(run.parallel_tool_calls);
+
+        // The model called the same function for two cities
+        let tool_call_ids: Vec<String> = (0..2).map(|_| Uuid::new_v4().to_string()).collect();
+        let tool_calls = tool_call_ids
+            .iter()
+            .map(|id| RunToolCallObject {
+                id: id.clone(),
+                r#type: "function".to_string(),
+                function: FunctionCall {
+                    name: "weather".to_string(),
+                    arguments: "{}".to_string(),
+                },
+            })
+            .collect();
+        let required_action = RequiredAction {
+            r#type: "submit_tool_outputs".to_string(),
+            submit_tool_outputs: SubmitToolOutputs { tool_calls },
+        };
+        update_run_status(
+            &pool,
+            &thread.inner.id,
+            &run.inner.id,
+            RunStatus::RequiresAction,
+            &user_id,
+            Some(required_action.clone()),
+            None,
+        )
+        .await
+        .unwrap();
+
+        let output = |id: &str| SubmittedToolCall {
+            id: id.to_string(),
+            output: "sunny".to_string(),
+            run_id: run.inner.id.clone(),
+            created_at: 0,
+            user_id: user_id.clone(),
+        };
+        let redis_url = std::env::var("REDIS_URL").expect("REDIS_URL must be set");
+        let client = redis::Client::open(redis_url).unwrap();
+
+        // Calls of another run, bad ids and duplicates are refused
+        for tool_outputs in [
+            vec![output(&Uuid::new_v4().to_string())],
+            vec![output("call_abc123")],
+            vec![output(&tool_call_ids[0]), output(&tool_call_ids[0])],
+            vec![],
+        ] {
+            let con = client.get_async_connection().await.unwrap();
+            let result = submit_tool_outputs(
+                &pool,
+                &thread.inner.id,
+                &run.inner.id,
+                &user_id,
+                tool_outputs,
+                con,
+            )
+            .await;
+            assert!(result.is_err(), "should be Err");
+        }
+
+        // The run keeps waiting for the second output
+        let con = client.get_async_connection().await.unwrap();
+        let run = submit_tool_outputs(
+            &pool,
+            &thread.inner.id,
+            &run.inner.id,
+            &user_id,
+            vec![output(&tool_call_ids[0])],
+            con,
+        )
+        .await
+        .unwrap();
+        assert_eq!(run.inner.status, RunStatus::RequiresAction);
+        assert_eq!(
+            get_missing_tool_outputs(&pool, &required_action).await.unwrap(),
+            vec![tool_call_ids[1].clone()]
+        );
+
+        // An output is only submitted once
+        let con = client.get_async_connection().await.unwrap();
+        let result = submit_tool_outputs(
+            &pool,
+            &thread.inner.id,
+            &run.inner.id,
+            &user_id,
+            vec![output(&tool_call_ids[0])],
+            con,
+        )
+        .await;
+        assert!(result.is_err(), "should be Err");
+
+        let con = client.get_async_connection().await.unwrap();
+        let run = submit_tool_outputs(
+            &pool,
+            &thread.inner.id,
+            &run.inner.id,
+            &user_id,
+            vec![output(&tool_call_ids[1])],
+            con,
+        )
+        .await
+        .unwrap();
+        assert_eq!(run.inner.status, RunStatus::Queued);
+        assert!(get_missing_tool_outputs(&pool, &required_action)
+            .await
+            .unwrap()
+            .is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_submit_action_approvals() {
+        let pool = setup().await;
+        reset_db(&pool).await;
+        let user_id = Uuid::default().to_string();
+        let thread = create_thread(
+            &pool,
+            &Thread {
+                inner: ThreadObject {
+                    id: "".to_string(),
+                    object: "".to_string(),
+                    created_at: 0,
+                    metadata: None,
+                },
+                user_id: user_id.clone(),
+            },
+        )
+        .await
+        .unwrap();
+        let assistant = create_assistant(
+            &pool,
+            &Assistant {
+                inner: AssistantObject {
+                    id: "".to_string(),
+                    object: "".to_string(),
+                    created_at: 0,
+                    name: Some("Todos".to_string()),
+                    description: None,
+                    model: "claude-2.1".to_string(),
+                    instructions: None,
+                    tools: vec![],
+                    file_ids: vec![],
+                    metadata: None,
+                },
+                user_id: user_id.clone(),
+            },
+        )
+        .await
+        .unwrap();
+        let run = create_run(&pool, &thread.inner.id, &assistant.inner.id, "", &user_id, None, None)
+            .await
+            .unwrap();
+
+        // The model wants to create a todo and to delete another one
+        let server = MockServer::start();
+        let create_todo = server.mock(|when, then| {
+            when.method(POST).path("/v1/todos").json_body(json!({"title": "Buy milk"}));
+            then.status(201).json_body(json!({"id": 2, "title": "Buy milk"}));
+        });
+        let delete_todo = server.mock(|when, then| {
+            when.method(DELETE).path("/v1/todos/1");
+            then.status(204);
+        });
+        let mut actions = vec![];
+        for (method, path, operation, arguments) in [
+            ("post", "/todos", "createTodo", json!({"title": "Buy milk"})),
+            ("delete", "/todos/{id}", "deleteTodo", json!({"id": 1})),
+        ] {
+            let tool_call_id = Uuid::new_v4().to_string();
+            let step = create_step(
+                &pool,
+                &run.inner.id,
+                &assistant.inner.id,
+                &thread.inner.id,
+                RunStepType::ToolCalls,
+                RunStatus::InProgress,
+                function_step_details(&tool_call_id, operation, &arguments.to_string(), None),
+                &user_id,
+            )
+            .await
+            .unwrap();
+            actions.push(PendingAction {
+                tool_call_id,
+                step_id: step.inner.id,
+                function: FunctionCallWithMetadata {
+                    name: operation.to_string(),
+                    arguments: arguments.to_string(),
+                    metadata: Some(json!({
+                        "domain": server.url("/v1"),
+                        "path": path,
+                        "method": method,
+                        "operation": operation,
+                        "is_consequential": true,
+                        "content_type": "application/json",
+                        "parameters": {"title": "body_field", "id": "path"},
+                    })),
+                },
+                request: ActionPreview {
+                    method: method.to_uppercase(),
+                    url: server.url(format!("/v1{}", path)),
+                    headers: BTreeMap::new(),
+                    body: None,
+                },
+                approved: None,
+                reason: None,
+            });
+        }
+        let (create_id, delete_id) = (actions[0].tool_call_id.clone(), actions[1].tool_call_id.clone());
+        let run = request_action_approval(&pool, &thread.inner.id, &run.inner.id, &user_id, actions)
+            .await
+            .unwrap();
+        assert_eq!(run.inner.status, RunStatus::RequiresAction);
+
+        let approval = |id: &str, approve: bool, reason: Option<&str>| ActionApproval {
+            tool_call_id: id.to_string(),
+            approve,
+            reason: reason.map(|reason| reason.to_string()),
+        };
+        let redis_url = std::env::var("REDIS_URL").expect("REDIS_URL must be set");
+        let client = redis::Client::open(redis_url).unwrap();
+
+        // Every action waiting for approval must be decided at once
+        let con = client.get_async_connection().await.unwrap();
+        let result = submit_action_approvals(
+            &pool,
+            &thread.inner.id,
+            &run.inner.id,
+            &user_id,
+            vec![approval(&create_id, true, None)],
+            con,
+        )
+        .await;
+        assert!(matches!(result, Err(sqlx::Error::Configuration(_))));
+        assert!(get_pending_actions(&pool, &run.inner.id, &user_id)
+            .await
+            .unwrap()
+            .iter()
+            .all(|action| action.approved.is_none()));
+
+        let con = client.get_async_connection().await.unwrap();
+        let run = submit_action_approvals(
+            &pool,
+            &thread.inner.id,
+            &run.inner.id,
+            &user_id,
+            vec![
+                approval(&create_id, true, None),
+                approval(&delete_id, false, Some("I still need it")),
+            ],
+            con,
+        )
+        .await
+        .unwrap();
+        assert_eq!(run.inner.status, RunStatus::Queued);
+        assert!(run.inner.required_action.is_none());
+
+        // The run is not waiting anymore
+        let con = client.get_async_connection().await.unwrap();
+        let result = submit_action_approvals(
+            &pool,
+            &thread.inner.id,
+            &run.inner.id,
+            &user_id,
+            vec![approval(&create_id, true, None), approval(&delete_id, true, None)],
+            con,
+        )
+        .await;
+        assert!(matches!(result, Err(sqlx::Error::Configuration(_))));
+
+        // The executor sends the approved request and gives the model the reason of the rejection
+        let action_client = ActionClient::new(&ActionsConfig {
+            allow_private: true,
+            ..Default::default()
+        })
+        .unwrap();
+        let file_storage = FileStorage::new().await;
+        let mut outputs = vec![];
+        for action in get_pending_actions(&pool, &run.inner.id, &user_id).await.unwrap() {
+            let output = run_action(&pool, &action_client, &file_storage, 1000, &run, action)
+                .await
+                .unwrap();
+            outputs.push(output);
+        }
+        create_todo.assert();
+        delete_todo.assert_hits(0);
+        assert!(outputs[0].contains("Buy milk"));
+        assert!(outputs[1].contains("The user rejected this action: I still need it"));
+        let steps = list_steps(&pool, &thread.inner.id, &run.inner.id, &user_id)
+            .await
+            .unwrap();
+        let status = |id: &str| {
+            steps
+                .iter()
+                .find(|step| match &step.inner.step_details {
+                    StepDetails::ToolCalls(details) => matches!(
+                        &details.tool_calls[0],
+                        RunStepDetailsToolCalls::Function(call) if call.id == id
+                    ),
+                    _ => false,
+                })
+                .map(|step| step.inner.status.clone())
+        };
+        assert_eq!(status(&create_id), Some(RunStatus::Completed));
+        assert_eq!(status(&delete_id), Some(RunStatus::Cancelled));
+    }
+
     #[tokio::test]
     #[ignore] // TODO: finish this test
     async fn test_create_run_failure() {
@@ -1030,6 +1707,8 @@ mod tests {
                 .repeat(100)
                 .as_str(),
             &Uuid::default().to_string(),
+            None,
+            None,
         )
         .await;
 
@@ -1043,7 +1722,7 @@ mod tests {
             std::env::var("MODEL_URL").expect("MODEL_URL must be set"),
             std::env::var("MODEL_API_KEY").expect("MODEL_API_KEY must be set"),
         );
-        let result = try_run_executor(&pool, &mut con, llm_client).await;
+        let result = try_run_executor(&pool, &mut con, llm_client, &Hal9100Config::default()).await;
         assert!(result.is_ok());
 
         println!("result: {:?}", result);
@@ -0,0 +1,1083 @@
+use async_trait::async_trait;
+use bytes::Bytes;
+use hal_9100_core::code_interpreter::InterpreterError;
+use hal_9100_core::container_pool::ContainerPool;
+use hal_9100_extra::config::{CodeInterpreterConfig, CodeLanguage, SandboxBackend};
+use log::{info, warn};
+use serde::Deserialize;
+use serde_json::json;
+use std::collections::{HashMap, HashSet};
+use std::ffi::CString;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::PermissionsExt;
+use std::os::unix::process::ExitStatusExt;
+use std::path::{Path, PathBuf};
+use std::process::{ExitStatus, Stdio};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::UnixStream;
+use tokio::process::{Child, Command};
+use tokio::sync::OnceCell;
+use uuid::Uuid;
+
+// Writable directory the code runs in
+pub(crate) const WORKDIR: &str = "/tmp";
+// Directory the generated code is put in
+pub(crate) const CODE_DIR: &str = "/code";
+pub(crate) const SCRIPT_NAME: &str = "script.py";
+// Read-only directory the user files are put in
+pub(crate) const DATA_DIR: &str = "/mnt/data";
+// Directory the code saves the files it creates for the user in
+pub(crate) const OUTPUT_DIR: &str = "/tmp/output";
+// Files created by the code beyond this are ignored
+pub(crate) const MAX_OUTPUT_FILES: usize = 20;
+// Python program keeping the state of a session, put next to the code
+pub(crate) const KERNEL_NAME: &str = "kernel.py";
+// Socket the kernel of a session listens on, in the working directory
+pub(crate) const KERNEL_SOCKET_NAME: &str = ".kernel.sock";
+// Time a kernel has to start listening
+const KERNEL_START_SECS: u64 = 10;
+
+/// Runs the codes of a session in the same namespace so variables and imports are kept.
+/// `serve <socket>` listens for `{"code": ...}` or `{"path": ...}` requests, one per
+/// connection, and answers `{"stdout": ..., "stderr": ..., "error": ...}`. `send <socket> <path>` makes
+/// a request for a code file and prints the answer.
+pub(crate) const KERNEL_SCRIPT: &str = r#"import contextlib, io, json, socket, sys, traceback
+
+
+def read_all(connection):
+    chunks = []
+    while True:
+        chunk = connection.recv(65536)
+        if not chunk:
+            return b"".join(chunks)
+        chunks.append(chunk)
+
+
+def run_cell(code, filename, namespace):
+    stdout, stderr = io.StringIO(), io.StringIO()
+    error = False
+    with contextlib.redirect_stdout(stdout), contextlib.redirect_stderr(stderr):
+        try:
+            exec(compile(code, filename, "exec"), namespace)
+        except SystemExit as e:
+            error = e.code not in (None, 0)
+        except BaseException:
+            error = True
+            etype, value, tb = sys.exc_info()
+            # Skip the frame of the kernel
+            traceback.print_exception(etype, value, tb.tb_next)
+    return {"stdout": stdout.getvalue(), "stderr": stderr.getvalue(), "error": error}
+
+
+def serve(socket_path):
+    namespace = {"__name__": "__main__"}
+    server = socket.socket(socket.AF_UNIX, socket.SOCK_STREAM)
+    server.bind(socket_path)
+    server.listen(1)
+    while True:
+        connection, _ = server.accept()
+        with connection:
+            request = read_all(connection)
+            # Connections without a request only check the kernel is up
+            if not request:
+                continue
+            request = json.loads(request)
+            if "path" in request:
+                with open(request["path"]) as cell:
+                    code, filename = cell.read(), request["path"]
+            else:
+                code, filename = request["code"], "<cell>"
+            response = run_cell(code, filename, namespace)
+            try:
+                connection.sendall(json.dumps(response).encode())
+            except OSError:
+                pass
+
+
+def send(socket_path, cell_path):
+    client = socket.socket(socket.AF_UNIX, socket.SOCK_STREAM)
+    client.connect(socket_path)
+    client.sendall(json.dumps({"path": cell_path}).encode())
+    client.shutdown(socket.SHUT_WR)
+    sys.stdout.write(read_all(client).decode())
+
+
+if __name__ == "__main__":
+    if sys.argv[1] == "serve":
+        serve(sys.argv[2])
+    else:
+        send(sys.argv[2], sys.argv[3])
+"#;
+
+// System directories visible read-only inside the bubblewrap jail, the Python interpreter
+// and its libraries must live there
+const BUBBLEWRAP_SYSTEM_PATHS: [&str; 10] = [
+    "/usr",
+    "/bin",
+    "/sbin",
+    "/lib",
+    "/lib32",
+    "/lib64",
+    "/etc/alternatives",
+    "/etc/ld.so.cache",
+    "/etc/localtime",
+    "/etc/ssl",
+];
+
+// Extra time given to the remote runner to answer after the code timeout
+const HTTP_GRACE_SECS: u64 = 10;
+
+static SHARED_SANDBOX: OnceCell<Box<dyn Sandbox>> = OnceCell::const_new();
+
+/// A file given to the code, `name` is its file name in the data directory.
+#[derive(Debug, Clone)]
+pub struct SandboxFile {
+    pub name: String,
+    pub bytes: Bytes,
+}
+
+#[derive(Debug, Clone)]
+pub struct ExecutionOutput {
+    pub stdout: String,
+    pub stderr: String,
+    pub exit_code: Option<i64>,
+    /// Files the code saved in the output directory, named by their path relative to it
+    pub files: Vec<SandboxFile>,
+}
+
+/// Somewhere the code interpreter can run generated code.
+#[async_trait]
+pub trait Sandbox: Send + Sync {
+    /// Prepare the backend ahead of the first run, e.g. pull images or start containers.
+    async fn warm(&self) -> Result<(), InterpreterError> {
+        Ok(())
+    }
+
+    /// Directory the code finds the files given to `execute` in.
+    fn data_dir(&self) -> String {
+        DATA_DIR.to_string()
+    }
+
+    /// Directory the code saves the files it creates in, they are returned by `execute`.
+    fn output_dir(&self) -> String {
+        OUTPUT_DIR.to_string()
+    }
+
+    /// Run Python code with read-only access to `files` and return its output and exit
+    /// code. Fails with `InterpreterError::Timeout` when the code runs longer than allowed.
+    async fn execute(
+        &self,
+        python_code: &str,
+        files: &[SandboxFile],
+    ) -> Result<ExecutionOutput, InterpreterError>;
+
+    /// Whether the backend can keep a Python process alive between codes.
+    fn supports_sessions(&self) -> bool {
+        false
+    }
+
+    /// Start a Python process that keeps variables, imports and loaded data between the
+    /// codes run in it with `execute_in_session`, and return its handle.
+    async fn start_session(&self) -> Result<String, InterpreterError> {
+        Err(InterpreterError::Sandbox(
+            "This sandbox does not support sessions".to_string(),
+        ))
+    }
+
+    /// Run Python code in a session, `files` already given to it are not copied again.
+    /// The exit code is 0 or 1 depending on whether the code raised, anything else means
+    /// the session process itself died and its state is lost.
+    async fn execute_in_session(
+        &self,
+        _session: &str,
+        _python_code: &str,
+        _files: &[SandboxFile],
+    ) -> Result<ExecutionOutput, InterpreterError> {
+        Err(InterpreterError::Sandbox(
+            "This sandbox does not support sessions".to_string(),
+        ))
+    }
+
+    /// Stop a session and free what it holds.
+    async fn close_session(&self, _session: &str) {}
+}
+
+/// Whether the exit code of `execute_in_session` means the session process died.
+pub fn session_died(exit_code: Option<i64>) -> bool {
+    !matches!(exit_code, Some(0) | Some(1))
+}
+
+#[derive(Deserialize, Debug)]
+pub(crate) struct KernelResponse {
+    pub stdout: String,
+    pub stderr: String,
+    pub error: bool,
+}
+
+impl KernelResponse {
+    pub(crate) fn exit_code(&self) -> Option<i64> {
+        Some(if self.error { 1 } else { 0 })
+    }
+}
+
+/// Create the sandbox backend chosen in the config.
+pub fn new_sandbox(config: &CodeInterpreterConfig) -> Result<Box<dyn Sandbox>, InterpreterError> {
+    info!("Using {:?} code interpreter sandbox", config.backend);
+    Ok(match config.backend {
+        SandboxBackend::Docker => Box::new(ContainerPool::new(config)?),
+        SandboxBackend::Local => Box::new(LocalSandbox::new(config)),
+        SandboxBackend::Http => Box::new(HttpSandbox::new(config)?),
+    })
+}
+
+/// The sandbox shared by all code interpreter calls of the process, created on first use.
+pub async fn shared_sandbox(
+    config: &CodeInterpreterConfig,
+) -> Result<&'static dyn Sandbox, InterpreterError> {
+    SHARED_SANDBOX
+        .get_or_try_init(|| async { new_sandbox(config) })
+        .await
+        .map(|sandbox| sandbox.as_ref())
+}
+
+/// Runs the code as a subprocess of the executor, for machines without Docker.
+/// The process gets memory, CPU time and file size limits, a process limit when a cgroup is
+/// configured (see `LocalSandboxConfig::cgroup`), and unless disabled
+/// runs in a bubblewrap jail with no network, its own PID namespace and a read-only view
+/// of the system directories. Without the jail, files are put in a `data` directory and
+/// created files are read from an `output` directory next to the code, instead of
+/// `/mnt/data` and `/tmp/output`. A session is a kernel process with the same limits,
+/// except CPU time, which is only bounded per code by the timeout.
+pub struct LocalSandbox {
+    config: CodeInterpreterConfig,
+    sessions: Mutex<HashMap<String, Arc<tokio::sync::Mutex<LocalSession>>>>,
+}
+
+// A kernel process and the directories it was given
+struct LocalSession {
+    child: Child,
+    run_dir: PathBuf,
+    work_dir: PathBuf,
+    data_dir: PathBuf,
+    socket_path: PathBuf,
+    // Limits the processes of the kernel, see `LocalSandboxConfig::cgroup`
+    cgroup: Option<PathBuf>,
+    // Names of the files already in the data directory
+    files: HashSet<String>,
+}
+
+impl LocalSandbox {
+    pub fn new(config: &CodeInterpreterConfig) -> Self {
+        LocalSandbox {
+            config: config.clone(),
+            sessions: Mutex::new(HashMap::new()),
+        }
+    }
+
+    // Python running `script_path` with `args`. `cpu_secs` limits the CPU time of the
+    // process, it is not set for sessions which live across many codes. The process is moved
+    // to `cgroup` before it starts.
+    fn command(
+        &self,
+        script_path: &Path,
+        args: &[String],
+        work_dir: &Path,
+        data_dir: &Path,
+        cpu_secs: Option<u64>,
+        cgroup: Option<&Path>,
+    ) -> Command {
+        let local = &self.config.local;
+        let script_name = script_path
+            .file_name()
+            .and_then(|name| name.to_str())
+            .unwrap_or(SCRIPT_NAME);
+        let (mut command, home) = if local.bubblewrap {
+            let mut command = Command::new(&local.bubblewrap_path);
+            for path in BUBBLEWRAP_SYSTEM_PATHS {
+                command.args(["--ro-bind-try", path, path]);
+            }
+            command
+                .args(["--dev", "/dev", "--proc", "/proc"])
+                .args(["--unshare-all", "--die-with-parent", "--new-session"])
+                .arg("--bind")
+                .arg(work_dir)
+                .arg(WORKDIR)
+                .arg("--ro-bind")
+                .arg(script_path)
+                .arg(format!("{}/{}", CODE_DIR, script_name))
+                .arg("--ro-bind")
+                .arg(data_dir)
+                .arg(DATA_DIR)
+                .args(["--chdir", WORKDIR, "--"])
+                .arg(&local.python)
+                .arg(format!("{}/{}", CODE_DIR, script_name))
+                .args(args);
+            (command, Path::new(WORKDIR).to_path_buf())
+        } else {
+            let mut command = Command::new(&local.python);
+            command.arg(script_path).args(args).current_dir(work_dir);
+            (command, work_dir.to_path_buf())
+        };
+
+        command
+            .env_clear()
+            .env("PATH", "/usr/local/bin:/usr/bin:/bin")
+            .env("HOME", &home)
+            .env("MPLCONFIGDIR", &home)
+            // Math libraries start a thread per core otherwise
+            .env("OPENBLAS_NUM_THREADS", "1")
+            .env("OMP_NUM_THREADS", "1")
+            .stdin(Stdio::null())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .kill_on_drop(true);
+
+        // RLIMIT_NPROC is not used for the processes, it counts all the processes of the user
+        // and root ignores it
+        let mut limits = vec![
+            (libc::RLIMIT_AS, self.config.memory_bytes as u64),
+            (libc::RLIMIT_FSIZE, self.config.tmpfs_size_bytes as u64),
+        ];
+        if let Some(cpu_secs) = cpu_secs {
+            limits.push((libc::RLIMIT_CPU, cpu_secs));
+        }
+        let cgroup_procs = cgroup
+            .and_then(|cgroup| CString::new(cgroup.join("cgroup.procs").as_os_str().as_bytes()).ok());
+        // SAFETY: only async-signal-safe calls between fork and exec
+        unsafe {
+            command.pre_exec(move || {
+                // Writing 0 moves the writing process
+                if let Some(cgroup_procs) = &cgroup_procs {
+                    let fd = libc::open(cgroup_procs.as_ptr(), libc::O_WRONLY);
+                    if fd < 0 || libc::write(fd, b"0".as_ptr().cast(), 1) != 1 {
+                        return Err(std::io::Error::last_os_error());
+                    }
+                    libc::close(fd);
+                }
+                for &(resource, limit) in &limits {
+                    let rlimit = libc::rlimit {
+                        rlim_cur: limit as libc::rlim_t,
+                        rlim_max: limit as libc::rlim_t,
+                    };
+                    if libc::setrlimit(resource, &rlimit) != 0 {
+                        return Err(std::io::Error::last_os_error());
+                    }
+                }
+                Ok(())
+            });
+        }
+        command
+    }
+
+    async fn run(
+        &self,
+        script_path: &Path,
+        work_dir: &Path,
+        data_dir: &Path,
+        cgroup: Option<&Path>,
+        python_code: &str,
+    ) -> Result<ExecutionOutput, InterpreterError> {
+        let child = self
+            .command(
+                script_path,
+                &[],
+                work_dir,
+                data_dir,
+                Some(self.config.timeout_secs),
+                cgroup,
+            )
+            .spawn()
+            .map_err(|e| InterpreterError::Sandbox(format!("Failed to start Python: {}", e)))?;
+        // On timeout the child is dropped, which kills it
+        let output = match tokio::time::timeout(
+            Duration::from_secs(self.config.timeout_secs),
+            child.wait_with_output(),
+        )
+        .await
+        {
+            Ok(output) => output.map_err(|e| InterpreterError::Sandbox(e.to_string()))?,
+            Err(_) => {
+                return Err(InterpreterError::Timeout {
+                    timeout_secs: self.config.timeout_secs,
+                    language: CodeLanguage::Python,
+                    python_code: python_code.to_string(),
+                })
+            }
+        };
+
+        let files = read_output_files(&work_dir.join("output"))
+            .await
+            .map_err(|e| InterpreterError::Sandbox(format!("Failed to read created files: {}", e)))?;
+        Ok(ExecutionOutput {
+            stdout: String::from_utf8_lossy(&output.stdout).to_string(),
+            stderr: String::from_utf8_lossy(&output.stderr).to_string(),
+            exit_code: exit_code(&output.status),
+            files,
+        })
+    }
+}
+
+impl LocalSandbox {
+    // A cgroup of its own for a code or session, limiting its processes and threads to
+    // `pids_limit`. None when no cgroup is configured.
+    async fn create_cgroup(&self, id: &str) -> Result<Option<PathBuf>, InterpreterError> {
+        let parent = match &self.config.local.cgroup {
+            Some(parent) => parent,
+            None => return Ok(None),
+        };
+        let cgroup = Path::new(parent).join(format!("hal-9100-{}", id));
+        let created = async {
+            tokio::fs::create_dir(&cgroup).await?;
+            tokio::fs::write(cgroup.join("pids.max"), self.config.pids_limit.to_string()).await
+        }
+        .await;
+        match created {
+            Ok(()) => Ok(Some(cgroup)),
+            Err(e) => {
+                let _ = tokio::fs::remove_dir(&cgroup).await;
+                Err(InterpreterError::Sandbox(format!(
+                    "Failed to create cgroup {}: {}",
+                    cgroup.display(),
+                    e
+                )))
+            }
+        }
+    }
+
+    // Where the files of a run or session are put on the host, see `data_dir`
+    fn data_path(&self, run_dir: &Path, work_dir: &Path) -> PathBuf {
+        if self.config.local.bubblewrap {
+            run_dir.join("data")
+        } else {
+            work_dir.join("data")
+        }
+    }
+
+    fn session(
+        &self,
+        session: &str,
+    ) -> Result<Arc<tokio::sync::Mutex<LocalSession>>, InterpreterError> {
+        self.sessions
+            .lock()
+            .unwrap()
+            .get(session)
+            .cloned()
+            .ok_or_else(|| InterpreterError::Sandbox(format!("Unknown session {}", session)))
+    }
+
+    async fn spawn_kernel(
+        &self,
+        run_dir: &Path,
+        cgroup: Option<PathBuf>,
+    ) -> Result<LocalSession, InterpreterError> {
+        let work_dir = run_dir.join("work");
+        let kernel_path = run_dir.join(KERNEL_NAME);
+        let data_dir = self.data_path(run_dir, &work_dir);
+        let socket_path = work_dir.join(KERNEL_SOCKET_NAME);
+        // The jail sees the working directory as /tmp
+        let socket_arg = if self.config.local.bubblewrap {
+            format!("{}/{}", WORKDIR, KERNEL_SOCKET_NAME)
+        } else {
+            socket_path.to_string_lossy().to_string()
+        };
+
+        write_run_files(&kernel_path, KERNEL_SCRIPT, &work_dir, &data_dir, &[])
+            .await
+            .map_err(|e| InterpreterError::Sandbox(format!("Failed to write the kernel: {}", e)))?;
+        // The kernel prints the output of the codes in its answers, anything else is noise
+        let mut child = self
+            .command(
+                &kernel_path,
+                &["serve".to_string(), socket_arg],
+                &work_dir,
+                &data_dir,
+                None,
+                cgroup.as_deref(),
+            )
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .spawn()
+            .map_err(|e| InterpreterError::Sandbox(format!("Failed to start Python: {}", e)))?;
+        wait_for_kernel(&mut child, &socket_path).await?;
+
+        Ok(LocalSession {
+            child,
+            run_dir: run_dir.to_path_buf(),
+            work_dir,
+            data_dir,
+            socket_path,
+            cgroup,
+            files: HashSet::new(),
+        })
+    }
+}
+
+// Kill the processes left in a cgroup and remove it
+async fn remove_cgroup(cgroup: &Path) {
+    // cgroup.kill needs Linux 5.14, the processes left are only those the code detached
+    let _ = tokio::fs::write(cgroup.join("cgroup.kill"), "1").await;
+    // The cgroup can be removed once the killed processes are reaped
+    for _ in 0..20 {
+        if tokio::fs::remove_dir(cgroup).await.is_ok() {
+            return;
+        }
+        tokio::time::sleep(Duration::from_millis(50)).await;
+    }
+    warn!("Failed to remove cgroup {}", cgroup.display());
+}
+
+// Wait until the kernel accepts connections on its socket
+async fn wait_for_kernel(child: &mut Child, socket_path: &Path) -> Result<(), InterpreterError> {
+    let deadline = tokio::time::Instant::now() + Duration::from_secs(KERNEL_START_SECS);
+    while tokio::time::Instant::now() < deadline {
+        if let Ok(Some(status)) = child.try_wait() {
+            return Err(InterpreterError::Sandbox(format!(
+                "The session kernel exited with {}",
+                status
+            )));
+        }
+        if UnixStream::connect(socket_path).await.is_ok() {
+            return Ok(());
+        }
+        tokio::time::sleep(Duration::from_millis(50)).await;
+    }
+    Err(InterpreterError::Sandbox(format!(
+        "The session kernel did not start within {} seconds",
+        KERNEL_START_SECS
+    )))
+}
+
+// Send one request to a kernel and read its answer
+async fn kernel_request(socket_path: &Path, request: &serde_json::Value) -> std::io::Result<Vec<u8>> {
+    let mut stream = UnixStream::connect(socket_path).await?;
+    stream.write_all(request.to_string().as_bytes()).await?;
+    stream.shutdown().await?;
+    let mut response = Vec::new();
+    stream.read_to_end(&mut response).await?;
+    Ok(response)
+}
+
+// Write the script and the files of a run, the files are made read-only
+async fn write_run_files(
+    script_path: &Path,
+    python_code: &str,
+    work_dir: &Path,
+    data_dir: &Path,
+    files: &[SandboxFile],
+) -> std::io::Result<()> {
+    tokio::fs::create_dir_all(work_dir.join("output")).await?;
+    tokio::fs::write(script_path, python_code).await?;
+    write_data_files(data_dir, files).await
+}
+
+async fn write_data_files(data_dir: &Path, files: &[SandboxFile]) -> std::io::Result<()> {
+    tokio::fs::create_dir_all(data_dir).await?;
+    for file in files {
+        let path = data_dir.join(&file.name);
+        tokio::fs::write(&path, &file.bytes).await?;
+        tokio::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o444)).await?;
+    }
+    Ok(())
+}
+
+async fn clear_dir(dir: &Path) -> std::io::Result<()> {
+    tokio::fs::remove_dir_all(dir).await?;
+    tokio::fs::create_dir_all(dir).await
+}
+
+// Regular files of a directory and its subdirectories, symbolic links are not followed
+async fn read_output_files(output_dir: &Path) -> std::io::Result<Vec<SandboxFile>> {
+    let mut files = Vec::new();
+    let mut directories = vec![output_dir.to_path_buf()];
+    while let Some(directory) = directories.pop() {
+        let mut entries = tokio::fs::read_dir(&directory).await?;
+        while let Some(entry) = entries.next_entry().await? {
+            let file_type = entry.file_type().await?;
+            if file_type.is_dir() {
+                directories.push(entry.path());
+            } else if file_type.is_file() {
+                if files.len() >= MAX_OUTPUT_FILES {
+                    warn!("Ignoring created files beyond {}", MAX_OUTPUT_FILES);
+                    return Ok(files);
+                }
+                let path = entry.path();
+                let name = path
+                    .strip_prefix(output_dir)
+                    .unwrap_or(&path)
+                    .to_string_lossy()
+                    .to_string();
+                let bytes = tokio::fs::read(&path).await?;
+                files.push(SandboxFile {
+                    name,
+                    bytes: bytes.into(),
+                });
+            }
+        }
+    }
+    Ok(files)
+}
+
+#[async_trait]
+impl Sandbox for LocalSandbox {
+    /// Nothing to prepare, the operator is told when the code can start as many processes as
+    /// it likes.
+    async fn warm(&self) -> Result<(), InterpreterError> {
+        if self.config.local.cgroup.is_none() {
+            warn!(
+                "The local code interpreter sandbox has no cgroup, pids_limit = {} is not \
+                 enforced and the code can start any number of processes. Set \
+                 code_interpreter.local.cgroup to a delegated cgroup or use the docker backend.",
+                self.config.pids_limit
+            );
+        }
+        Ok(())
+    }
+
+    fn data_dir(&self) -> String {
+        if self.config.local.bubblewrap {
+            DATA_DIR.to_string()
+        } else {
+            "data".to_string()
+        }
+    }
+
+    fn output_dir(&self) -> String {
+        if self.config.local.bubblewrap {
+            OUTPUT_DIR.to_string()
+        } else {
+            "output".to_string()
+        }
+    }
+
+    async fn execute(
+        &self,
+        python_code: &str,
+        files: &[SandboxFile],
+    ) -> Result<ExecutionOutput, InterpreterError> {
+        let id = Uuid::new_v4().to_string();
+        let run_dir = std::env::temp_dir().join(format!("hal-9100-code-interpreter-{}", id));
+        let work_dir = run_dir.join("work");
+        let script_path = run_dir.join(SCRIPT_NAME);
+        let data_dir = self.data_path(&run_dir, &work_dir);
+
+        let written =
+            write_run_files(&script_path, python_code, &work_dir, &data_dir, files).await;
+        let result = match written {
+            Ok(_) => match self.create_cgroup(&id).await {
+                Ok(cgroup) => {
+                    let result = self
+                        .run(&script_path, &work_dir, &data_dir, cgroup.as_deref(), python_code)
+                        .await;
+                    if let Some(cgroup) = &cgroup {
+                        remove_cgroup(cgroup).await;
+                    }
+                    result
+                }
+                Err(e) => Err(e),
+            },
+            Err(e) => Err(InterpreterError::Sandbox(format!(
+                "Failed to write the code and files: {}",
+                e
+            ))),
+        };
+
+        if let Err(e) = tokio::fs::remove_dir_all(&run_dir).await {
+            warn!("Failed to remove {}: {}", run_dir.display(), e);
+        }
+        result
+    }
+
+    fn supports_sessions(&self) -> bool {
+        true
+    }
+
+    async fn start_session(&self) -> Result<String, InterpreterError> {
+        let id = Uuid::new_v4().to_string();
+        let run_dir = std::env::temp_dir().join(format!("hal-9100-code-interpreter-{}", id));
+        let cgroup = self.create_cgroup(&id).await?;
+        match self.spawn_kernel(&run_dir, cgroup.clone()).await {
+            Ok(session) => {
+                info!("Started code interpreter session {}", id);
+                self.sessions
+                    .lock()
+                    .unwrap()
+                    .insert(id.clone(), Arc::new(tokio::sync::Mutex::new(session)));
+                Ok(id)
+            }
+            Err(e) => {
+                let _ = tokio::fs::remove_dir_all(&run_dir).await;
+                if let Some(cgroup) = &cgroup {
+                    remove_cgroup(cgroup).await;
+                }
+                Err(e)
+            }
+        }
+    }
+
+    async fn execute_in_session(
+        &self,
+        session: &str,
+        python_code: &str,
+        files: &[SandboxFile],
+    ) -> Result<ExecutionOutput, InterpreterError> {
+        let session = self.session(session)?;
+        let mut session = session.lock().await;
+
+        let new_files: Vec<SandboxFile> = files
+            .iter()
+            .filter(|file| !session.files.contains(&file.name))
+            .cloned()
+            .collect();
+        write_data_files(&session.data_dir, &new_files)
+            .await
+            .map_err(|e| InterpreterError::Sandbox(format!("Failed to write the files: {}", e)))?;
+        session
+            .files
+            .extend(new_files.into_iter().map(|file| file.name));
+
+        let response = tokio::time::timeout(
+            Duration::from_secs(self.config.timeout_secs),
+            kernel_request(&session.socket_path, &json!({ "code": python_code })),
+        )
+        .await
+        .map_err(|_| InterpreterError::Timeout {
+            timeout_secs: self.config.timeout_secs,
+            language: CodeLanguage::Python,
+            python_code: python_code.to_string(),
+        })?;
+        let response = response
+            .ok()
+            .and_then(|response| serde_json::from_slice::<KernelResponse>(&response).ok());
+        let (stdout, stderr, exit_code) = match response {
+            Some(response) => {
+                let exit_code = response.exit_code();
+                (response.stdout, response.stderr, exit_code)
+            }
+            // The kernel died while running the code, e.g. it went over the memory limit
+            None => match tokio::time::timeout(Duration::from_secs(1), session.child.wait()).await {
+                Ok(Ok(status)) => (String::new(), String::new(), exit_code(&status)),
+                _ => {
+                    return Err(InterpreterError::Sandbox(
+                        "The session kernel did not answer".to_string(),
+                    ))
+                }
+            },
+        };
+
+        // Files are returned once, the next codes only report the files they create
+        let output_dir = session.work_dir.join("output");
+        let files = read_output_files(&output_dir)
+            .await
+            .map_err(|e| InterpreterError::Sandbox(format!("Failed to read created files: {}", e)))?;
+        if !files.is_empty() {
+            clear_dir(&output_dir).await.map_err(|e| {
+                InterpreterError::Sandbox(format!("Failed to clean created files: {}", e))
+            })?;
+        }
+        Ok(ExecutionOutput {
+            stdout,
+            stderr,
+            exit_code,
+            files,
+        })
+    }
+
+    async fn close_session(&self, session: &str) {
+        let session = match self.sessions.lock().unwrap().remove(session) {
+            Some(session) => session,
+            None => return,
+        };
+        let mut session = session.lock().await;
+        if let Err(e) = session.child.kill().await {
+            warn!("Failed to stop the session kernel: {}", e);
+        }
+        if let Err(e) = tokio::fs::remove_dir_all(&session.run_dir).await {
+            warn!("Failed to remove {}: {}", session.run_dir.display(), e);
+        }
+        if let Some(cgroup) = &session.cgroup {
+            remove_cgroup(cgroup).await;
+        }
+    }
+}
+
+// Exit code like a shell reports it, 128 + signal for killed processes
+fn exit_code(status: &ExitStatus) -> Option<i64> {
+    status
+        .code()
+        .map(i64::from)
+        .or_else(|| status.signal().map(|signal| 128 + i64::from(signal)))
+}
+
+#[derive(Deserialize, Debug)]
+struct RunnerFile {
+    name: String,
+    content_base64: String,
+}
+
+#[derive(Deserialize, Debug)]
+struct RunnerResponse {
+    // Runners written before stdout and stderr were split answer a single output
+    #[serde(default, alias = "output")]
+    stdout: String,
+    #[serde(default)]
+    stderr: String,
+    exit_code: Option<i64>,
+    #[serde(default)]
+    files: Vec<RunnerFile>,
+}
+
+/// Posts the code to a remote runner service.
+pub struct HttpSandbox {
+    client: reqwest::Client,
+    config: CodeInterpreterConfig,
+}
+
+impl HttpSandbox {
+    pub fn new(config: &CodeInterpreterConfig) -> Result<Self, InterpreterError> {
+        if config.http.url.is_empty() {
+            return Err(InterpreterError::Sandbox(
+                "code_interpreter.http.url must be set to use the http backend".to_string(),
+            ));
+        }
+        Ok(HttpSandbox {
+            client: reqwest::Client::new(),
+            config: config.clone(),
+        })
+    }
+}
+
+#[async_trait]
+impl Sandbox for HttpSandbox {
+    async fn execute(
+        &self,
+        python_code: &str,
+        files: &[SandboxFile],
+    ) -> Result<ExecutionOutput, InterpreterError> {
+        let files: Vec<_> = files
+            .iter()
+            .map(|file| json!({"name": file.name, "content_base64": base64::encode(&file.bytes)}))
+            .collect();
+        let mut request = self
+            .client
+            .post(&self.config.http.url)
+            .timeout(Duration::from_secs(
+                self.config.timeout_secs + HTTP_GRACE_SECS,
+            ))
+            .json(&json!({
+                "code": python_code,
+                "timeout_secs": self.config.timeout_secs,
+                "files": files,
+            }));
+        if let Some(api_key) = &self.config.http.api_key {
+            request = request.bearer_auth(api_key);
+        }
+
+        let response = request.send().await.map_err(|e| {
+            if e.is_timeout() {
+                InterpreterError::Timeout {
+                    timeout_secs: self.config.timeout_secs,
+                    language: CodeLanguage::Python,
+                    python_code: python_code.to_string(),
+                }
+            } else {
+                InterpreterError::Sandbox(format!("Failed to reach the code runner: {}", e))
+            }
+        })?;
+
+        let status = response.status();
+        if !status.is_success() {
+            let body = response.text().await.unwrap_or_default();
+            return Err(InterpreterError::Sandbox(format!(
+                "Code runner answered {}: {}",
+                status, body
+            )));
+        }
+        let response: RunnerResponse = response.json().await.map_err(|e| {
+            InterpreterError::Sandbox(format!("Invalid code runner response: {}", e))
+        })?;
+        let files = response
+            .files
+            .into_iter()
+            .take(MAX_OUTPUT_FILES)
+            .map(|file| {
+                let bytes = base64::decode(&file.content_base64).map_err(|e| {
+                    InterpreterError::Sandbox(format!("Invalid file {}: {}", file.name, e))
+                })?;
+                Ok(SandboxFile {
+                    name: file.name,
+                    bytes: bytes.into(),
+                })
+            })
+            .collect::<Result<Vec<_>, InterpreterError>>()?;
+        Ok(ExecutionOutput {
+            stdout: response.stdout,
+            stderr: response.stderr,
+            exit_code: response.exit_code,
+            files,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use hal_9100_extra::config::{HttpSandboxConfig, LocalSandboxConfig};
+    use httpmock::prelude::*;
+
+    fn local_config(timeout_secs: u64) -> CodeInterpreterConfig {
+        CodeInterpreterConfig {
+            backend: SandboxBackend::Local,
+            // Not every CI machine has bubblewrap
+            local: LocalSandboxConfig {
+                bubblewrap: false,
+                ..Default::default()
+            },
+            timeout_secs,
+            ..Default::default()
+        }
+    }
+
+    #[tokio::test]
+    async fn test_local_sandbox() {
+        let sandbox = LocalSandbox::new(&local_config(10));
+
+        let execution = sandbox
+            .execute("open('out.txt', 'w').write('x')\nprint(\"it's $(fine)\")", &[])
+            .await
+            .unwrap();
+        assert_eq!(execution.stdout.trim(), "it's $(fine)");
+        assert_eq!(execution.stderr, "");
+        assert_eq!(execution.exit_code, Some(0));
+
+        let execution = sandbox
+            .execute("raise ValueError('boom')", &[])
+            .await
+            .unwrap();
+        assert!(execution.stderr.contains("ValueError: boom"));
+        assert_eq!(execution.stdout, "");
+        assert_eq!(execution.exit_code, Some(1));
+    }
+
+    #[tokio::test]
+    async fn test_local_sandbox_timeout() {
+        let sandbox = LocalSandbox::new(&local_config(1));
+        let result = sandbox.execute("while True:\n    pass", &[]).await;
+        assert!(matches!(result, Err(InterpreterError::Timeout { .. })));
+    }
+
+    #[tokio::test]
+    async fn test_local_sandbox_files() {
+        let sandbox = LocalSandbox::new(&local_config(10));
+        let files = vec![SandboxFile {
+            name: "startups.csv".to_string(),
+            bytes: Bytes::from("name,revenue\nA,1\n"),
+        }];
+        let code = format!(
+            "import os\npath = os.path.join('{}', 'startups.csv')\nprint(open(path).read())\nprint(os.access(path, os.W_OK))",
+            sandbox.data_dir()
+        );
+
+        let execution = sandbox.execute(&code, &files).await.unwrap();
+        assert!(execution.stdout.contains("name,revenue"));
+        assert!(execution.stdout.contains("False"));
+    }
+
+    #[tokio::test]
+    async fn test_local_sandbox_output_files() {
+        let sandbox = LocalSandbox::new(&local_config(10));
+        let code = format!(
+            "import os\nos.makedirs('{0}/charts')\nopen('{0}/result.csv', 'w').write('a,b')\nopen('{0}/charts/c.txt', 'w').write('c')",
+            sandbox.output_dir()
+        );
+
+        let execution = sandbox.execute(&code, &[]).await.unwrap();
+        let mut names: Vec<_> = execution.files.iter().map(|f| f.name.clone()).collect();
+        names.sort();
+        assert_eq!(names, vec!["charts/c.txt", "result.csv"]);
+        let result = execution.files.iter().find(|f| f.name == "result.csv").unwrap();
+        assert_eq!(result.bytes, Bytes::from("a,b"));
+    }
+
+    #[tokio::test]
+    async fn test_local_sandbox_session() {
+        let sandbox = LocalSandbox::new(&local_config(10));
+        let session = sandbox.start_session().await.unwrap();
+        let files = vec![SandboxFile {
+            name: "data.csv".to_string(),
+            bytes: Bytes::from("a,b\n1,2\n"),
+        }];
+
+        let code = format!(
+            "rows = open('{}/data.csv').read().splitlines()\nopen('{}/r.txt', 'w').write('r')",
+            sandbox.data_dir(),
+            sandbox.output_dir()
+        );
+        let execution = sandbox
+            .execute_in_session(&session, &code, &files)
+            .await
+            .unwrap();
+        assert_eq!(execution.exit_code, Some(0));
+        assert_eq!(execution.files.len(), 1);
+
+        // Variables are kept, the files are not given again and created files are reported once
+        let execution = sandbox
+            .execute_in_session(&session, "print(len(rows))\nraise ValueError('boom')", &files)
+            .await
+            .unwrap();
+        assert_eq!(execution.stdout, "2\n");
+        assert!(execution.stderr.contains("ValueError: boom"));
+        assert_eq!(execution.exit_code, Some(1));
+        assert!(execution.files.is_empty());
+
+        sandbox.close_session(&session).await;
+        assert!(sandbox
+            .execute_in_session(&session, "print(1)", &[])
+            .await
+            .is_err());
+    }
+
+    #[tokio::test]
+    async fn test_http_sandbox() {
+        let server = MockServer::start();
+        let mock = server.mock(|when, then| {
+            when.method(POST)
+                .path("/execute")
+                .header("authorization", "Bearer secret")
+                .json_body(json!({
+                    "code": "print(2)",
+                    "timeout_secs": 30,
+                    "files": [{"name": "a.txt", "content_base64": "aGk="}],
+                }));
+            then.status(200).json_body(json!({
+                "stdout": "2\n",
+                "stderr": "warning\n",
+                "exit_code": 0,
+                "files": [{"name": "out.txt", "content_base64": "aGk="}],
+            }));
+        });
+
+        let sandbox = HttpSandbox::new(&CodeInterpreterConfig {
+            backend: SandboxBackend::Http,
+            http: HttpSandboxConfig {
+                url: server.url("/execute"),
+                api_key: Some("secret".to_string()),
+            },
+            ..Default::default()
+        })
+        .unwrap();
+        let files = vec![SandboxFile {
+            name: "a.txt".to_string(),
+            bytes: Bytes::from("hi"),
+        }];
+        let execution = sandbox.execute("print(2)", &files).await.unwrap();
+
+        mock.assert();
+        assert_eq!(execution.stdout, "2\n");
+        assert_eq!(execution.stderr, "warning\n");
+        assert_eq!(execution.exit_code, Some(0));
+        assert_eq!(execution.files[0].name, "out.txt");
+        assert_eq!(execution.files[0].bytes, Bytes::from("hi"));
+    }
+}
@@ -0,0 +1,310 @@
+use aes_gcm::aead::{Aead, AeadCore, KeyInit, OsRng};
+use aes_gcm::{Aes256Gcm, Nonce};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use sqlx::types::Uuid;
+use sqlx::PgExecutor;
+use std::collections::HashMap;
+use std::fmt;
+
+/// Environment variable holding the key secrets are encrypted with, 32 bytes in base64. Generate
+/// one with `openssl rand -base64 32`.
+pub const SECRETS_KEY_ENV: &str = "SECRETS_KEY";
+
+/// What secret values are replaced with in logs and run steps.
+pub const REDACTED: &str = "[REDACTED]";
+
+// Shorter values would redact unrelated text
+const MIN_REDACTED_LEN: usize = 4;
+
+#[derive(Debug)]
+pub enum SecretError {
+    MissingKey,
+    InvalidKey,
+    /// The secret could not be decrypted, it was encrypted with another key or tampered with
+    Decryption,
+    NotFound,
+    SqlxError(sqlx::Error),
+    JsonError(serde_json::Error),
+}
+
+impl fmt::Display for SecretError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            SecretError::MissingKey => {
+                write!(f, "{} must be set to store secrets", SECRETS_KEY_ENV)
+            }
+            SecretError::InvalidKey => {
+                write!(f, "{} must be 32 bytes encoded in base64", SECRETS_KEY_ENV)
+            }
+            SecretError::Decryption => write!(f, "Failed to decrypt secret"),
+            SecretError::NotFound => write!(f, "Secret not found"),
+            SecretError::SqlxError(e) => write!(f, "SQLx error: {}", e),
+            SecretError::JsonError(e) => write!(f, "JSON error: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for SecretError {}
+
+impl From<sqlx::Error> for SecretError {
+    fn from(err: sqlx::Error) -> SecretError {
+        match err {
+            sqlx::Error::RowNotFound => SecretError::NotFound,
+            err => SecretError::SqlxError(err),
+        }
+    }
+}
+
+impl From<serde_json::Error> for SecretError {
+    fn from(err: serde_json::Error) -> SecretError {
+        SecretError::JsonError(err)
+    }
+}
+
+/// A credential for one of the security schemes of an action.
+#[derive(Clone, Serialize, Deserialize, PartialEq)]
+#[serde(untagged)]
+pub enum Credential {
+    /// An API key or a bearer token
+    Token(String),
+    Basic {
+        username: String,
+        password: String,
+    },
+    /// OAuth2 client credentials, the scopes of the operation are requested when not set
+    ClientCredentials {
+        client_id: String,
+        client_secret: String,
+        #[serde(default)]
+        scopes: Option<Vec<String>>,
+    },
+}
+
+/// Credentials of an action tool, only stored encrypted and never printed.
+#[derive(Clone, Default, Serialize, Deserialize, PartialEq)]
+pub struct ActionSecret {
+    /// Sent with every request of the tool
+    #[serde(default)]
+    pub headers: HashMap<String, String>,
+    /// By name of the security scheme in the spec
+    #[serde(default)]
+    pub credentials: HashMap<String, Credential>,
+}
+
+impl fmt::Debug for ActionSecret {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("ActionSecret")
+            .field("headers", &self.headers.keys().collect::<Vec<_>>())
+            .field("credentials", &self.credentials.keys().collect::<Vec<_>>())
+            .finish()
+    }
+}
+
+impl ActionSecret {
+    fn values(&self) -> Vec<String> {
+        let mut values: Vec<String> = self.headers.values().cloned().collect();
+        for credential in self.credentials.values() {
+            match credential {
+                Credential::Token(token) => values.push(token.clone()),
+                Credential::Basic { username, password } => {
+                    values.push(password.clone());
+                    values.push(base64::encode(format!("{}:{}", username, password)));
+                }
+                Credential::ClientCredentials { client_secret, .. } => {
+                    values.push(client_secret.clone())
+                }
+            }
+        }
+        values.retain(|value| value.len() >= MIN_REDACTED_LEN);
+        // Longest first so a secret containing another is redacted whole
+        values.sort_by(|a, b| b.len().cmp(&a.len()));
+        values
+    }
+
+    /// Replace the secret values appearing in `text`.
+    pub fn redact(&self, text: &str) -> String {
+        self.redact_with(text, &[])
+    }
+
+    /// Replace the secret values and `access_tokens`, fetched with the secret, appearing in
+    /// `text`.
+    pub fn redact_with(&self, text: &str, access_tokens: &[String]) -> String {
+        let mut values = self.values();
+        values.extend(
+            access_tokens
+                .iter()
+                .filter(|token| token.len() >= MIN_REDACTED_LEN)
+                .cloned(),
+        );
+        values.sort_by(|a, b| b.len().cmp(&a.len()));
+        values.iter().fold(text.to_string(), |text, value| {
+            text.replace(value, REDACTED)
+        })
+    }
+}
+
+fn cipher() -> Result<Aes256Gcm, SecretError> {
+    let key = std::env::var(SECRETS_KEY_ENV).map_err(|_| SecretError::MissingKey)?;
+    let key = base64::decode(key.trim()).map_err(|_| SecretError::InvalidKey)?;
+    Aes256Gcm::new_from_slice(&key).map_err(|_| SecretError::InvalidKey)
+}
+
+fn encrypt(cipher: &Aes256Gcm, secret: &ActionSecret) -> Result<(Vec<u8>, Vec<u8>), SecretError> {
+    let nonce = Aes256Gcm::generate_nonce(&mut OsRng);
+    let ciphertext = cipher
+        .encrypt(&nonce, serde_json::to_vec(secret)?.as_ref())
+        .map_err(|_| SecretError::InvalidKey)?;
+    Ok((nonce.to_vec(), ciphertext))
+}
+
+fn decrypt(
+    cipher: &Aes256Gcm,
+    nonce: &[u8],
+    ciphertext: &[u8],
+) -> Result<ActionSecret, SecretError> {
+    if nonce.len() != 12 {
+        return Err(SecretError::Decryption);
+    }
+    let plaintext = cipher
+        .decrypt(Nonce::from_slice(nonce), ciphertext)
+        .map_err(|_| SecretError::Decryption)?;
+    Ok(serde_json::from_slice(&plaintext)?)
+}
+
+pub async fn create_secret(
+    executor: impl PgExecutor<'_>,
+    user_id: &str,
+    secret: &ActionSecret,
+) -> Result<String, SecretError> {
+    let (nonce, ciphertext) = encrypt(&cipher()?, secret)?;
+    let row = sqlx::query!(
+        r#"
+        INSERT INTO secrets (nonce, ciphertext, user_id)
+        VALUES ($1, $2, $3)
+        RETURNING id
+        "#,
+        nonce,
+        ciphertext,
+        Uuid::parse_str(user_id).unwrap_or_default(),
+    )
+    .fetch_one(executor)
+    .await?;
+    Ok(row.id.to_string())
+}
+
+pub async fn delete_secret(
+    executor: impl PgExecutor<'_>,
+    secret_id: &str,
+    user_id: &str,
+) -> Result<(), SecretError> {
+    sqlx::query!(
+        r#"
+        DELETE FROM secrets WHERE id::text = $1 AND user_id::text = $2
+        "#,
+        secret_id,
+        user_id
+    )
+    .execute(executor)
+    .await?;
+    Ok(())
+}
+
+pub async fn get_secret(
+    executor: impl PgExecutor<'_>,
+    secret_id: &str,
+    user_id: &str,
+) -> Result<ActionSecret, SecretError> {
+    let row = sqlx::query!(
+        r#"
+        SELECT nonce, ciphertext FROM secrets
+        WHERE id::text = $1 AND user_id::text = $2
+        "#,
+        secret_id,
+        user_id
+    )
+    .fetch_one(executor)
+    .await?;
+    decrypt(&cipher()?, &row.nonce, &row.ciphertext)
+}
+
+/// Move the `headers` and `credentials` of an action tool into an encrypted secret, leaving its
+/// `secret_id` in their place. Tools without any are left as is.
+pub async fn store_tool_secret(
+    executor: impl PgExecutor<'_>,
+    user_id: &str,
+    tool: &mut Value,
+) -> Result<(), SecretError> {
+    let data = match tool.get_mut("data").and_then(Value::as_object_mut) {
+        Some(data) => data,
+        None => return Ok(()),
+    };
+    let headers = data.remove("headers").unwrap_or(Value::Null);
+    let credentials = data.remove("credentials").unwrap_or(Value::Null);
+    if headers.is_null() && credentials.is_null() {
+        return Ok(());
+    }
+    let secret: ActionSecret = serde_json::from_value(json!({
+        "headers": if headers.is_null() { json!({}) } else { headers },
+        "credentials": if credentials.is_null() { json!({}) } else { credentials },
+    }))?;
+    let secret_id = create_secret(executor, user_id, &secret).await?;
+    data.insert("secret_id".to_string(), json!(secret_id));
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_encrypt_decrypt() {
+        let cipher = Aes256Gcm::new_from_slice(&[7u8; 32]).unwrap();
+        let secret: ActionSecret = serde_json::from_value(json!({
+            "headers": {"x-api-key": "abcdef"},
+            "credentials": {
+                "bearer": "token-123",
+                "basic": {"username": "user", "password": "hunter22"},
+                "oauth": {"client_id": "id", "client_secret": "s3cr3t"},
+            }
+        }))
+        .unwrap();
+
+        let (nonce, ciphertext) = encrypt(&cipher, &secret).unwrap();
+        assert!(!String::from_utf8_lossy(&ciphertext).contains("token-123"));
+        assert_eq!(decrypt(&cipher, &nonce, &ciphertext).unwrap(), secret);
+
+        let other = Aes256Gcm::new_from_slice(&[8u8; 32]).unwrap();
+        assert!(matches!(
+            decrypt(&other, &nonce, &ciphertext),
+            Err(SecretError::Decryption)
+        ));
+    }
+
+    #[test]
+    fn test_redact() {
+        let secret: ActionSecret = serde_json::from_value(json!({
+            "headers": {"x-api-key": "abcdef"},
+            "credentials": {
+                "bearer": "token-123",
+                "basic": {"username": "user", "password": "hunter22"},
+                "short": "ab",
+            }
+        }))
+        .unwrap();
+
+        assert_eq!(
+            secret.redact("key abcdef, token token-123, basic dXNlcjpodW50ZXIyMg==, ab"),
+            "key [REDACTED], token [REDACTED], basic [REDACTED], ab"
+        );
+        assert_eq!(
+            secret.redact_with(
+                "token token-123, access acc3ss-t0k3n",
+                &["acc3ss-t0k3n".to_string()]
+            ),
+            "token [REDACTED], access [REDACTED]"
+        );
+        let printed = format!("{:?}", secret);
+        assert!(!printed.contains("abcdef") && !printed.contains("hunter22"));
+    }
+}
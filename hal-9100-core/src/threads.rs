@@ -159,6 +159,53 @@ pub async fn delete_thread(
     Ok(())
 }
 
+/// Returns the rolling summary of the thread and the number of oldest messages folded into it.
+pub async fn get_thread_summary(
+    pool: &PgPool,
+    thread_id: &str,
+    user_id: &str,
+) -> Result<(Option<String>, i32), sqlx::Error> {
+    let row = sqlx::query!(
+        r#"
+        SELECT summary, summarized_messages FROM threads WHERE id::text = $1 AND user_id::text = $2
+        "#,
+        thread_id,
+        user_id,
+    )
+    .fetch_one(pool)
+    .await?;
+
+    Ok((row.summary, row.summarized_messages))
+}
+
+pub async fn update_thread_summary(
+    pool: &PgPool,
+    thread_id: &str,
+    user_id: &str,
+    summary: &str,
+    summarized_messages: i32,
+) -> Result<(), sqlx::Error> {
+    info!(
+        "Updating summary of thread {} up to message {}",
+        thread_id, summarized_messages
+    );
+    sqlx::query!(
+        r#"
+        UPDATE threads
+        SET summary = $1, summarized_messages = $2
+        WHERE id::text = $3 AND user_id::text = $4
+        "#,
+        summary,
+        summarized_messages,
+        thread_id,
+        user_id,
+    )
+    .execute(pool)
+    .await?;
+
+    Ok(())
+}
+
 #[cfg(test)]
 mod tests {
     use hal_9100_core::runs::{create_run_and_produce_to_executor_queue, get_run};
@@ -0,0 +1,1089 @@
+use async_openai::types::{
+    AssistantTools, CodeInterpreter, CodeInterpreterOutput, FunctionCall, FunctionObject,
+    ImageFile, RequiredAction, RunStatus, RunStepDetailsToolCalls,
+    RunStepDetailsToolCallsCodeObject, RunStepDetailsToolCallsCodeOutputImageObject,
+    RunStepDetailsToolCallsCodeOutputLogsObject, RunStepDetailsToolCallsFunctionObject,
+    RunStepDetailsToolCallsObject, RunStepDetailsToolCallsRetrievalObject, RunStepFunctionObject,
+    RunStepType, RunToolCallObject, StepDetails, SubmitToolOutputs,
+};
+use async_trait::async_trait;
+use bytes::Bytes;
+use futures::future::try_join_all;
+use hal_9100_extra::config::Hal9100Config;
+use hal_9100_extra::llm::{HalLLMClient, HalLLMRequestArgs};
+use log::{error, info};
+use serde_json::{json, Value};
+use sqlx::PgPool;
+use std::collections::HashMap;
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+
+use crate::action_response::{extension, ResponseBody};
+use crate::code_interpreter::{
+    fetch_sandbox_files, is_image, safe_interpreter, CodeAttempt, InterpreterEvent,
+};
+use crate::egress::{shared_action_client, ActionClient};
+use crate::executor::RunError;
+use crate::file_storage::FileStorage;
+use crate::files::{create_output_file, wait_for_processed_files};
+use crate::function_calling::{
+    create_function_call, execute_request, generate_function_call, preview_request,
+    FunctionCallWithMetadata,
+};
+use crate::interpreter_sessions::{shared_sessions, take_session_reset_request};
+use crate::models::{Assistant, Chunk, Function, FunctionCallInput, PendingAction, Run};
+use crate::openapi::{stored_is_consequential, ActionRequest};
+use crate::retrieval::{
+    generate_queries_and_fetch_chunks, retrieval_step_details, retrieve_file_contents,
+};
+use crate::run_steps::{create_step, update_step, update_step_metadata};
+use crate::runs::{request_action_approval, update_run_status};
+use crate::secrets::{get_secret, ActionSecret};
+
+// How long a run waits for its files to finish processing before skipping them
+const FILE_PROCESSING_TIMEOUT: Duration = Duration::from_secs(30);
+
+// Tools registered on top of the built-in ones, see `register_tool`
+static REGISTERED_TOOLS: RwLock<Vec<Arc<dyn Tool>>> = RwLock::new(Vec::new());
+
+/// A tool the executor runs on the server when the model decides to use it, e.g. retrieval or
+/// a web search. Assistants use it with `{"type": <name>, "data": ...}` in their tools.
+#[async_trait(?Send)]
+pub trait Tool: Send + Sync {
+    /// The type of the tool in the assistant's tools, which is also what the model answers
+    /// to use it.
+    fn name(&self) -> &str;
+
+    /// Tells the model deciding which tools to use what the tool is useful for.
+    fn description(&self) -> &str;
+
+    /// JSON schema of the input the model generates to call the tool, see
+    /// `ToolContext::generate_input`. Tools without one need no input.
+    fn input_schema(&self) -> Option<Value> {
+        None
+    }
+
+    /// How a tool of the assistant is shown to the model deciding which tools to use.
+    fn describe(&self, _tool: &AssistantTools) -> Value {
+        let mut description = json!({
+            "name": self.name(),
+            "description": self.description(),
+        });
+        if let Some(input_schema) = self.input_schema() {
+            description["input_schema"] = input_schema;
+        }
+        description
+    }
+
+    /// Read the tool from the tools of an assistant, as sent to the API and stored.
+    fn parse(&self, tool: &Value) -> Result<AssistantTools, serde_json::Error> {
+        Ok(AssistantTools::Extra(serde_json::from_value(tool.clone())?))
+    }
+
+    /// Run the tool for the run of the context and add what it found to the context of the
+    /// answer. Returns the updated run when it has to wait for the user, e.g. for function
+    /// outputs or an action approval.
+    async fn execute(&self, context: &mut ToolContext<'_>) -> Result<Option<Run>, RunError>;
+
+    /// The details of the run step of a call of the tool, `output` is `None` while it runs.
+    fn step_details(&self, tool_call_id: &str, input: &str, output: Option<String>) -> StepDetails {
+        function_step_details(tool_call_id, self.name(), input, output)
+    }
+}
+
+/// The run a tool is executed for, and the context of the answer the tools fill.
+pub struct ToolContext<'a> {
+    pub pool: &'a PgPool,
+    pub con: &'a mut redis::aio::Connection,
+    pub config: &'a Hal9100Config,
+    pub file_storage: &'a FileStorage,
+    /// Client set to the model of the assistant
+    pub client: HalLLMClient,
+    /// Request with the instructions as system prompt and the formatted messages as user prompt
+    pub request: HalLLMRequestArgs,
+    pub run: &'a Run,
+    pub assistant: &'a Assistant,
+    pub formatted_messages: &'a str,
+    pub last_user_message: &'a str,
+    /// Calls of the user's functions with the outputs they submitted
+    pub function_calls: String,
+    /// Calls run on the server, of actions and registered tools, with their outputs
+    pub action_calls: String,
+    pub retrieval_files: Vec<String>,
+    pub retrieval_chunks: Vec<Chunk>,
+    pub code_output: Option<String>,
+    /// Files created by the code interpreter, as (path in the sandbox, file id)
+    pub output_files: Vec<(String, String)>,
+}
+
+impl ToolContext<'_> {
+    /// An error failing the run.
+    pub fn error(&self, message: String) -> RunError {
+        run_error(self.run, message)
+    }
+
+    /// Generate the input of a tool from its input schema and the conversation.
+    pub async fn generate_input(&self, tool: &dyn Tool) -> Result<String, RunError> {
+        let parameters = match tool.input_schema() {
+            Some(input_schema) => input_schema,
+            None => return Ok("{}".to_string()),
+        };
+        let function_call_input = FunctionCallInput {
+            function: Function {
+                metadata: None,
+                assistant_id: self.assistant.inner.id.clone(),
+                user_id: self.run.user_id.clone(),
+                inner: FunctionObject {
+                    name: tool.name().to_string(),
+                    description: Some(tool.description().to_string()),
+                    parameters: Some(parameters),
+                },
+            },
+            client: self.client.clone(),
+            request: self.request.clone().temperature(0.0),
+        };
+        generate_function_call(function_call_input)
+            .await
+            .map(|function| function.arguments)
+            .map_err(|e| {
+                self.error(format!(
+                    "Failed to generate the input of {}: {}",
+                    tool.name(),
+                    e
+                ))
+            })
+    }
+
+    /// Save a call of a tool as a completed run step and give its output to the model.
+    pub async fn record_call(
+        &mut self,
+        tool: &dyn Tool,
+        input: &str,
+        output: &str,
+    ) -> Result<(), RunError> {
+        let tool_call_id = uuid::Uuid::new_v4().to_string();
+        self.create_step(
+            RunStatus::Completed,
+            tool.step_details(&tool_call_id, input, Some(output.to_string())),
+        )
+        .await?;
+        self.add_action_call(format_action_call(tool.name(), input, output));
+        Ok(())
+    }
+
+    /// Add the call of an action or a registered tool to the prompt.
+    pub fn add_action_call(&mut self, action_call: String) {
+        if !self.action_calls.is_empty() {
+            self.action_calls.push('\n');
+        }
+        self.action_calls.push_str(&action_call);
+    }
+
+    async fn create_step(
+        &self,
+        status: RunStatus,
+        step_details: StepDetails,
+    ) -> Result<String, RunError> {
+        let step = create_step(
+            self.pool,
+            &self.run.inner.id,
+            &self.assistant.inner.id,
+            &self.run.inner.thread_id,
+            RunStepType::ToolCalls,
+            status,
+            step_details,
+            &self.run.user_id,
+        )
+        .await
+        .map_err(|e| self.error(format!("Failed to create step: {}", e)))?;
+        Ok(step.inner.id)
+    }
+
+    // The files of the run and of the assistant, skipping those that failed processing and
+    // waiting a bit for the ones still being processed
+    async fn file_ids(&self) -> Vec<String> {
+        let mut all_file_ids = Vec::new();
+        all_file_ids.extend(self.run.inner.file_ids.iter().cloned());
+        all_file_ids.extend(self.assistant.inner.file_ids.iter().cloned());
+        wait_for_processed_files(self.pool, &all_file_ids, FILE_PROCESSING_TIMEOUT).await
+    }
+
+    // Add the contents of the files and the chunks matching the conversation to the context
+    async fn retrieve(&mut self, file_ids: Vec<String>) {
+        info!("Retrieving file contents for file_ids: {:?}", file_ids);
+        let (retrieval_files, retrieval_chunks) = tokio::join!(
+            retrieve_file_contents(&file_ids, self.file_storage),
+            generate_queries_and_fetch_chunks(
+                self.pool,
+                self.client.clone(),
+                self.request.clone().temperature(0.0),
+                self.last_user_message,
+            )
+        );
+        self.retrieval_files = retrieval_files;
+        self.retrieval_chunks = retrieval_chunks.unwrap_or_else(|e| {
+            // ! sometimes LLM generates stupid SQL queries. for now we dont crash the run
+            error!("Failed to retrieve chunks: {}", e);
+            vec![]
+        });
+    }
+}
+
+/// The tools assistants can use, by name.
+#[derive(Clone)]
+pub struct ToolRegistry {
+    tools: Vec<Arc<dyn Tool>>,
+}
+
+impl ToolRegistry {
+    /// A registry with the built-in tools.
+    pub fn new() -> Self {
+        ToolRegistry {
+            tools: vec![
+                Arc::new(FunctionTool),
+                Arc::new(RetrievalTool),
+                Arc::new(CodeInterpreterTool),
+                Arc::new(ActionTool),
+            ],
+        }
+    }
+
+    /// Add a tool, replacing the one of the same name if any.
+    pub fn register(&mut self, tool: Arc<dyn Tool>) {
+        self.tools.retain(|t| t.name() != tool.name());
+        self.tools.push(tool);
+    }
+
+    pub fn get(&self, name: &str) -> Option<Arc<dyn Tool>> {
+        self.tools.iter().find(|tool| tool.name() == name).cloned()
+    }
+
+    pub fn names(&self) -> Vec<String> {
+        self.tools.iter().map(|tool| tool.name().to_string()).collect()
+    }
+}
+
+impl Default for ToolRegistry {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Make a tool available to assistants. The API and the executor both need it registered
+/// before they start, to accept the assistants using it and to run it.
+pub fn register_tool(tool: Arc<dyn Tool>) {
+    let mut tools = REGISTERED_TOOLS.write().unwrap();
+    tools.retain(|t| t.name() != tool.name());
+    tools.push(tool);
+}
+
+/// The built-in tools along with the registered ones.
+pub fn tool_registry() -> ToolRegistry {
+    let mut registry = ToolRegistry::new();
+    for tool in REGISTERED_TOOLS.read().unwrap().iter() {
+        registry.register(tool.clone());
+    }
+    registry
+}
+
+/// The name of the tool an assistant tool is used through.
+pub fn tool_type(tool: &AssistantTools) -> &str {
+    match tool {
+        AssistantTools::Code(_) => "code_interpreter",
+        AssistantTools::Retrieval(_) => "retrieval",
+        AssistantTools::Function(_) => "function",
+        AssistantTools::Extra(e) => e.r#type.as_str(),
+    }
+}
+
+/// Details of a run step calling a function, also used for actions and registered tools.
+pub fn function_step_details(
+    tool_call_id: &str,
+    name: &str,
+    arguments: &str,
+    output: Option<String>,
+) -> StepDetails {
+    StepDetails::ToolCalls(RunStepDetailsToolCallsObject {
+        r#type: "function".to_string(), // TODO not sure it should be function or action
+        tool_calls: vec![RunStepDetailsToolCalls::Function(RunStepDetailsToolCallsFunctionObject {
+            id: tool_call_id.to_string(),
+            r#type: "function".to_string(),
+            function: RunStepFunctionObject {
+                name: name.to_string(),
+                arguments: arguments.to_string(),
+                output,
+            },
+        })],
+    })
+}
+
+fn run_error(run: &Run, message: String) -> RunError {
+    RunError {
+        message,
+        run_id: run.inner.id.clone(),
+        thread_id: run.inner.thread_id.clone(),
+        user_id: run.user_id.clone(),
+    }
+}
+
+/// Calls the functions of the user, the run waits for the user to submit their outputs.
+pub struct FunctionTool;
+
+#[async_trait(?Send)]
+impl Tool for FunctionTool {
+    fn name(&self) -> &str {
+        "function"
+    }
+
+    fn description(&self) -> &str {
+        "Useful to call functions in the user's product, which would provide you later some additional context about the user's problem. You can also use this to perform actions in the user's product."
+    }
+
+    fn describe(&self, tool: &AssistantTools) -> Value {
+        match tool {
+            AssistantTools::Function(e) => json!({
+                "name": self.name(),
+                "description": self.description(),
+                "function": {
+                    "name": e.function.name,
+                    "description": e.function.description,
+                    "arguments": e.function.parameters,
+                }
+            }),
+            _ => json!({"name": self.name(), "description": self.description()}),
+        }
+    }
+
+    fn parse(&self, tool: &Value) -> Result<AssistantTools, serde_json::Error> {
+        Ok(AssistantTools::Function(serde_json::from_value(tool.clone())?))
+    }
+
+    async fn execute(&self, context: &mut ToolContext<'_>) -> Result<Option<Run>, RunError> {
+        let context = &*context;
+        let run = context.run;
+        // skip this if tools is not empty (e.g. if there are required_action (s))
+        if run.inner.required_action.is_some() {
+            info!("Skipping function call because there is a required action");
+            return Ok(None);
+        }
+
+        info!("Generating function to call");
+        let function_results = create_function_call(
+            context.pool,
+            &context.assistant.inner.id,
+            &run.user_id,
+            context.client.clone(),
+            context.request.clone().temperature(0.0),
+            &context.config.function_selection,
+            run.parallel_tool_calls,
+        )
+        .await
+        .map_err(|e| context.error(format!("Failed to create function call: {}", e)))?;
+
+        info!("Function results: {:?}", function_results);
+        if function_results.is_empty() {
+            return Ok(None);
+        }
+
+        // The function call requires user action, leave early waiting for more context
+        let tool_calls = function_results
+            .iter()
+            .map(|function| RunToolCallObject {
+                id: uuid::Uuid::new_v4().to_string(),
+                r#type: "function".to_string(), // TODO hardcoded
+                function: FunctionCall {
+                    name: function.name.clone(),
+                    arguments: function.arguments.clone(),
+                },
+            })
+            .collect::<Vec<RunToolCallObject>>();
+        let updated_run = update_run_status(
+            context.pool,
+            &run.inner.thread_id,
+            &run.inner.id,
+            RunStatus::RequiresAction,
+            &run.user_id,
+            Some(RequiredAction {
+                r#type: "submit_tool_outputs".to_string(),
+                submit_tool_outputs: SubmitToolOutputs {
+                    tool_calls: tool_calls.clone(),
+                },
+            }),
+            None,
+        )
+        .await
+        .map_err(|e| context.error(format!("Failed to update run status: {}", e)))?;
+
+        // A step with output None for each function call, completed once its output is submitted
+        try_join_all(tool_calls.iter().map(|tool_call| {
+            context.create_step(
+                RunStatus::InProgress,
+                function_step_details(
+                    &tool_call.id,
+                    &tool_call.function.name,
+                    &tool_call.function.arguments,
+                    None,
+                ),
+            )
+        }))
+        .await?;
+
+        info!(
+            "Run updated to requires_action with {:?}",
+            updated_run.inner.required_action
+        );
+        Ok(Some(updated_run))
+    }
+}
+
+/// Searches the files of the run and of the assistant.
+pub struct RetrievalTool;
+
+#[async_trait(?Send)]
+impl Tool for RetrievalTool {
+    fn name(&self) -> &str {
+        "retrieval"
+    }
+
+    fn description(&self) -> &str {
+        "useful to retrieve information from files"
+    }
+
+    fn parse(&self, tool: &Value) -> Result<AssistantTools, serde_json::Error> {
+        Ok(AssistantTools::Retrieval(serde_json::from_value(tool.clone())?))
+    }
+
+    async fn execute(&self, context: &mut ToolContext<'_>) -> Result<Option<Run>, RunError> {
+        let file_ids = context.file_ids().await;
+        if file_ids.is_empty() {
+            return Ok(None);
+        }
+        context.retrieve(file_ids).await;
+
+        context
+            .create_step(
+                RunStatus::InProgress,
+                StepDetails::ToolCalls(RunStepDetailsToolCallsObject {
+                    r#type: "retrieval".to_string(),
+                    tool_calls: vec![RunStepDetailsToolCalls::Retrieval(
+                        RunStepDetailsToolCallsRetrievalObject {
+                            id: uuid::Uuid::new_v4().to_string(),
+                            r#type: "retrieval".to_string(),
+                            retrieval: retrieval_step_details(&context.retrieval_chunks),
+                        },
+                    )],
+                }),
+            )
+            .await?;
+        Ok(None)
+    }
+}
+
+// A code of the code interpreter as it is being run, `attempt` is set once it ran
+struct CodeToolCall {
+    id: String,
+    code: String,
+    attempt: Option<CodeAttempt>,
+}
+
+// Follows the code interpreter step of a run, one tool call per code. The step is updated
+// every time a code starts or finishes so the run steps show what is running.
+struct CodeInterpreterStep {
+    step_id: String,
+    user_id: String,
+    tool_calls: Vec<CodeToolCall>,
+}
+
+impl CodeInterpreterStep {
+    async fn record(&mut self, pool: &PgPool, event: InterpreterEvent) {
+        match event {
+            InterpreterEvent::AttemptStarted { code, .. } => self.tool_calls.push(CodeToolCall {
+                id: uuid::Uuid::new_v4().to_string(),
+                code,
+                attempt: None,
+            }),
+            InterpreterEvent::AttemptFinished(attempt) => match self.tool_calls.last_mut() {
+                Some(tool_call) if tool_call.attempt.is_none() => tool_call.attempt = Some(attempt),
+                _ => self.tool_calls.push(CodeToolCall {
+                    id: uuid::Uuid::new_v4().to_string(),
+                    code: attempt.code.clone(),
+                    attempt: Some(attempt),
+                }),
+            },
+        }
+        self.save(pool, RunStatus::InProgress, &[]).await;
+    }
+
+    // Write the tool calls and how each code ran to the step. The images created by the
+    // code are shown with the code that succeeded.
+    async fn save(&self, pool: &PgPool, status: RunStatus, image_file_ids: &[String]) {
+        let tool_calls = self
+            .tool_calls
+            .iter()
+            .map(|tool_call| code_interpreter_tool_call(tool_call, image_file_ids))
+            .collect();
+        if let Err(e) = update_step(
+            pool,
+            &self.step_id,
+            status,
+            StepDetails::ToolCalls(RunStepDetailsToolCallsObject {
+                r#type: "code_interpreter".to_string(),
+                tool_calls,
+            }),
+            &self.user_id,
+        )
+        .await
+        {
+            error!("Failed to update step: {}", e);
+            return;
+        }
+
+        let attempts = self
+            .tool_calls
+            .iter()
+            .map(|tool_call| match &tool_call.attempt {
+                Some(attempt) => json!({
+                    "tool_call_id": tool_call.id,
+                    "language": attempt.language.name(),
+                    "status": if attempt.error.is_none() { "completed" } else { "failed" },
+                    "exit_code": attempt.exit_code,
+                    "error": attempt.error,
+                    "duration_ms": attempt.duration_ms,
+                }),
+                None => json!({
+                    "tool_call_id": tool_call.id,
+                    "status": "in_progress",
+                }),
+            })
+            .collect::<Vec<_>>();
+        let metadata = HashMap::from([("code_interpreter_attempts".to_string(), json!(attempts))]);
+        if let Err(e) = update_step_metadata(pool, &self.step_id, metadata, &self.user_id).await {
+            error!("Failed to update step metadata: {}", e);
+        }
+    }
+}
+
+fn code_interpreter_tool_call(
+    tool_call: &CodeToolCall,
+    image_file_ids: &[String],
+) -> RunStepDetailsToolCalls {
+    let mut outputs = Vec::new();
+    if let Some(attempt) = &tool_call.attempt {
+        let mut logs = format!("{}{}", attempt.stdout, attempt.stderr);
+        if let Some(error) = &attempt.error {
+            logs.push_str(&format!("\nError: the code {}", error));
+        }
+        outputs.push(CodeInterpreterOutput::Log(RunStepDetailsToolCallsCodeOutputLogsObject {
+            r#type: "log".to_string(),
+            logs,
+        }));
+        if attempt.error.is_none() {
+            outputs.extend(image_file_ids.iter().map(|file_id| {
+                CodeInterpreterOutput::Image(RunStepDetailsToolCallsCodeOutputImageObject {
+                    r#type: "image".to_string(),
+                    image: ImageFile {
+                        file_id: file_id.clone(),
+                    },
+                })
+            }));
+        }
+    }
+    RunStepDetailsToolCalls::Code(RunStepDetailsToolCallsCodeObject {
+        id: tool_call.id.clone(),
+        r#type: "code_interpreter".to_string(),
+        code_interpreter: CodeInterpreter {
+            input: tool_call.code.clone(),
+            outputs,
+        },
+    })
+}
+
+/// Writes and runs code in the sandbox, in the Python session of the thread.
+pub struct CodeInterpreterTool;
+
+#[async_trait(?Send)]
+impl Tool for CodeInterpreterTool {
+    fn name(&self) -> &str {
+        "code_interpreter"
+    }
+
+    fn description(&self) -> &str {
+        "useful for performing complex math problems which LLMs are bad at by default. Do not use code_interpreter if it's simple math that you believe a LLM can do (e.g. 1 + 1, 9 * 7, etc.) - Make sure to use code interpreter for more complex math problems"
+    }
+
+    fn parse(&self, tool: &Value) -> Result<AssistantTools, serde_json::Error> {
+        Ok(AssistantTools::Code(serde_json::from_value(tool.clone())?))
+    }
+
+    async fn execute(&self, context: &mut ToolContext<'_>) -> Result<Option<Run>, RunError> {
+        let pool = context.pool;
+        let run = context.run;
+        let file_ids = context.file_ids().await;
+
+        // The code can read the files under the sandbox data directory
+        let sandbox_files =
+            fetch_sandbox_files(pool, context.file_storage, &file_ids, &run.user_id).await;
+
+        // Start the Python session of the thread from scratch if it was asked through the API
+        match take_session_reset_request(context.con, &run.inner.thread_id).await {
+            Ok(true) => match shared_sessions(&context.config.code_interpreter).await {
+                Ok(sessions) => sessions.reset(&run.inner.thread_id).await,
+                Err(e) => error!("Failed to reset the code interpreter session: {}", e),
+            },
+            Ok(false) => (),
+            Err(e) => error!("Failed to check for a code interpreter session reset: {}", e),
+        }
+
+        // The step is created before the code runs so the run steps show it running
+        let step_id = context
+            .create_step(
+                RunStatus::InProgress,
+                StepDetails::ToolCalls(RunStepDetailsToolCallsObject {
+                    r#type: "code_interpreter".to_string(),
+                    tool_calls: vec![],
+                }),
+            )
+            .await?;
+        let mut code_step = CodeInterpreterStep {
+            step_id,
+            user_id: run.user_id.clone(),
+            tool_calls: vec![],
+        };
+
+        // The request holds the formatted messages as user prompt, the code answers them.
+        // Every code is recorded in the step as it starts and finishes.
+        let (events, mut received_events) = tokio::sync::mpsc::unbounded_channel();
+        let (interpreter_results, _) = tokio::join!(
+            safe_interpreter(
+                context.client.clone(),
+                context.request.clone().temperature(0.0),
+                &context.config.code_interpreter,
+                &sandbox_files,
+                Some(run.inner.thread_id.as_str()),
+                Some(events),
+            ),
+            async {
+                while let Some(event) = received_events.recv().await {
+                    code_step.record(pool, event).await;
+                }
+            }
+        );
+        let interpreter_results = match interpreter_results {
+            Ok(interpreter_output) => interpreter_output,
+            Err(e) => {
+                // Keep the codes that failed visible in the run steps
+                code_step.save(pool, RunStatus::Failed, &[]).await;
+                return Err(context.error(format!("Failed to run code: {}", e)));
+            }
+        };
+        info!("Code interpreter results: {:?}", interpreter_results.output);
+
+        // Store the files created by the code so they can be downloaded and shown
+        for file in &interpreter_results.files {
+            let path = format!("{}/{}", interpreter_results.output_dir, file.name);
+            match create_output_file(
+                pool,
+                context.file_storage,
+                &file.name,
+                file.bytes.clone(),
+                &run.user_id,
+            )
+            .await
+            {
+                Ok(stored) => context.output_files.push((path, stored.inner.id)),
+                Err(e) => error!("Failed to store file {} created by the code: {}", path, e),
+            }
+        }
+
+        code_step
+            .save(
+                pool,
+                RunStatus::Completed,
+                &context
+                    .output_files
+                    .iter()
+                    .filter(|(path, _)| is_image(path))
+                    .map(|(_, file_id)| file_id.clone())
+                    .collect::<Vec<_>>(),
+            )
+            .await;
+
+        // Let the model know which files the code produced so it can link to them
+        let mut code_output = interpreter_results.output;
+        if !context.output_files.is_empty() {
+            let files_list = context
+                .output_files
+                .iter()
+                .map(|(path, _)| format!("- sandbox:{}", path))
+                .collect::<Vec<_>>()
+                .join("\n");
+            code_output = format!(
+                "{}\n\nFiles created by the code, link them in your answer as sandbox:<path>:\n{}",
+                code_output, files_list
+            );
+        }
+        context.code_output = Some(code_output);
+
+        if !file_ids.is_empty() {
+            context.retrieve(file_ids).await;
+        }
+        Ok(None)
+    }
+}
+
+// The request of an action call, from the metadata saved when its operation was registered
+fn action_request(function: &FunctionCallWithMetadata) -> Result<ActionRequest, serde_json::Error> {
+    let metadata = function.metadata.clone().unwrap_or_default();
+    let field = |name: &str| metadata[name].as_str().unwrap_or_default().to_string();
+    Ok(ActionRequest {
+        domain: field("domain"),
+        path: field("path"),
+        method: field("method"),
+        operation: field("operation"),
+        operation_hash: None,
+        is_consequential: stored_is_consequential(&metadata),
+        content_type: field("content_type"),
+        params: Some(serde_json::from_str(&function.arguments)?),
+        headers: metadata.get("headers").cloned(),
+        parameters: serde_json::from_value(metadata["parameters"].clone()).unwrap_or_default(),
+        security: serde_json::from_value(metadata["security"].clone()).unwrap_or_default(),
+    })
+}
+
+async fn action_secret(
+    pool: &PgPool,
+    run: &Run,
+    function: &FunctionCallWithMetadata,
+) -> Result<Option<ActionSecret>, RunError> {
+    let secret_id = function
+        .metadata
+        .as_ref()
+        .and_then(|metadata| metadata["secret_id"].as_str());
+    match secret_id {
+        Some(secret_id) => get_secret(pool, secret_id, &run.user_id)
+            .await
+            .map(Some)
+            .map_err(|e| run_error(run, format!("Failed to get action credentials: {}", e))),
+        None => Ok(None),
+    }
+}
+
+fn format_action_call(name: &str, arguments: &str, output: &str) -> String {
+    let stringified_function = serde_json::to_string(&json!({
+        "name": name,
+        "arguments": arguments,
+    })).unwrap().replace("\\", "");
+    format!(
+        "<input>{:?}</input>\n\n<output>{:?}</output>",
+        stringified_function,
+        output
+    ).replace("\\\\", "").replace("\\\"", "")
+}
+
+/// Create the step of an action call and check whether it needs the user's approval, in which
+/// case the request it would send is previewed.
+async fn prepare_action(
+    pool: &PgPool,
+    run: &Run,
+    function: FunctionCallWithMetadata,
+) -> Result<PendingAction, RunError> {
+    let secret = action_secret(pool, run, &function).await?;
+    let request = action_request(&function)
+        .map_err(|e| run_error(run, format!("Invalid action arguments: {}", e)))?;
+    let preview = preview_request(&request, secret.as_ref())
+        .map_err(|e| run_error(run, format!("Failed to preview request: {}", e)))?;
+    let tool_call_id = uuid::Uuid::new_v4().to_string();
+    // Credentials never reach the run steps, the prompt or the logs
+    let arguments = match &secret {
+        Some(secret) => secret.redact(&function.arguments),
+        None => function.arguments.clone(),
+    };
+    let step = create_step(
+        pool,
+        &run.inner.id,
+        run.inner.assistant_id.as_deref().unwrap_or_default(),
+        &run.inner.thread_id,
+        RunStepType::ToolCalls,
+        RunStatus::InProgress,
+        function_step_details(&tool_call_id, &function.name, &arguments, None),
+        &run.user_id,
+    ).await.map_err(|e| run_error(run, format!("Failed to create step: {}", e)))?;
+    Ok(PendingAction {
+        tool_call_id,
+        step_id: step.inner.id,
+        approved: if request.is_consequential { None } else { Some(true) },
+        function,
+        request: preview,
+        reason: None,
+    })
+}
+
+/// Run an approved action, or tell the model the user rejected it, and complete its step.
+/// Returns the call and its output formatted for the prompt.
+pub(crate) async fn run_action(
+    pool: &PgPool,
+    client: &ActionClient,
+    file_storage: &FileStorage,
+    max_output_chars: usize,
+    run: &Run,
+    action: PendingAction,
+) -> Result<String, RunError> {
+    let function = &action.function;
+    let secret = action_secret(pool, run, function).await?;
+    let redact = |text: &str| match &secret {
+        Some(secret) => secret.redact(text),
+        None => text.to_string(),
+    };
+    let arguments = redact(&function.arguments);
+
+    let mut response_metadata = None;
+    let (status, output) = if action.approved == Some(true) {
+        let request = action_request(function)
+            .map_err(|e| run_error(run, format!("Invalid action arguments: {}", e)))?;
+        let operation = request.operation.clone();
+        let (response, access_tokens) = execute_request(client, request, secret.as_ref(), &run.user_id).await.map_err(|e| {
+            run_error(run, redact(&format!("Failed to execute request: {}", e)))
+        })?;
+        // The API may echo the access tokens it was sent
+        let redact = |text: &str| match &secret {
+            Some(secret) => secret.redact_with(text, &access_tokens),
+            None => text.to_string(),
+        };
+        // Binary responses are stored as files instead of being shown to the model
+        let file_id = match &response.body {
+            ResponseBody::Binary { content_type, bytes } if !response.is_error() => {
+                let filename = format!("{}.{}", operation, extension(content_type));
+                match create_output_file(pool, file_storage, &filename, Bytes::from(bytes.clone()), &run.user_id).await {
+                    Ok(file) => Some(file.inner.id),
+                    Err(e) => {
+                        error!("Failed to store the response of {}: {}", operation, e);
+                        None
+                    }
+                }
+            }
+            _ => None,
+        };
+        let fields: Vec<String> = function
+            .metadata
+            .as_ref()
+            .and_then(|metadata| serde_json::from_value(metadata["response_fields"].clone()).ok())
+            .unwrap_or_default();
+        response_metadata = Some(redact(&response.step_metadata(file_id.as_deref()).to_string()));
+        (
+            RunStatus::Completed,
+            redact(&response.prompt_output(&fields, max_output_chars, file_id.as_deref())),
+        )
+    } else {
+        let reason = action.reason.as_deref().unwrap_or("no reason given");
+        (RunStatus::Cancelled, format!("The user rejected this action: {}", reason))
+    };
+    update_step(
+        pool,
+        &action.step_id,
+        status,
+        function_step_details(&action.tool_call_id, &function.name, &arguments, Some(output.clone())),
+        &run.user_id,
+    ).await.map_err(|e| run_error(run, format!("Failed to update step: {}", e)))?;
+    // The status and headers of the response
+    if let Some(response_metadata) = response_metadata {
+        let metadata = HashMap::from([(
+            "action_response".to_string(),
+            serde_json::from_str(&response_metadata).unwrap_or_default(),
+        )]);
+        if let Err(e) = update_step_metadata(pool, &action.step_id, metadata, &run.user_id).await {
+            error!("Failed to update step metadata: {}", e);
+        }
+    }
+
+    info!("Action results: {}", output);
+    Ok(format_action_call(&function.name, &arguments, &output))
+}
+
+/// Sends requests to the APIs of the OpenAPI specs of the assistant. Consequential requests
+/// wait for the user's approval.
+pub struct ActionTool;
+
+#[async_trait(?Send)]
+impl Tool for ActionTool {
+    fn name(&self) -> &str {
+        "action"
+    }
+
+    fn description(&self) -> &str {
+        "Useful to make HTTP requests to the user's APIs, which would provide you later some additional context about the user's problem. You can also use this to perform actions to help the user."
+    }
+
+    fn describe(&self, tool: &AssistantTools) -> Value {
+        let data = match tool {
+            AssistantTools::Extra(e) => e.data.clone().unwrap_or_default(),
+            _ => Value::Null,
+        };
+        json!({
+            "name": self.name(),
+            "description": self.description(),
+            "data": {
+                "info": {
+                    "description": data.get("info").unwrap_or(&json!({})).get("description").unwrap_or(&json!("")).to_string().replace("\"", ""),
+                    "title": data.get("info").unwrap_or(&json!({})).get("title").unwrap_or(&json!("")).to_string().replace("\"", ""),
+                },
+                "paths": data.get("paths"),
+            }
+        })
+    }
+
+    async fn execute(&self, context: &mut ToolContext<'_>) -> Result<Option<Run>, RunError> {
+        let run = context.run;
+        info!("Generating function to call");
+        let function_results = create_function_call(
+            context.pool,
+            &context.assistant.inner.id,
+            &run.user_id,
+            context.client.clone(),
+            context.request.clone().temperature(0.0),
+            &context.config.function_selection,
+            run.parallel_tool_calls,
+        )
+        .await
+        .map_err(|e| context.error(format!("Failed to create function call: {}", e)))?;
+
+        info!("Function results: {:?}", function_results);
+
+        let actions = try_join_all(
+            function_results
+                .into_iter()
+                .map(|function| prepare_action(context.pool, run, function)),
+        )
+        .await?;
+
+        // Consequential actions wait for the user's approval, along with the other
+        // calls of the same turn so the model gets all their outputs at once
+        if actions.iter().any(|action| action.approved.is_none()) {
+            info!("Waiting for the user to approve actions");
+            let updated_run = request_action_approval(
+                context.pool,
+                &run.inner.thread_id,
+                &run.inner.id,
+                &run.user_id,
+                actions,
+            )
+            .await
+            .map_err(|e| context.error(format!("Failed to request action approval: {}", e)))?;
+            return Ok(Some(updated_run));
+        }
+
+        let action_client = shared_action_client(&context.config.actions)
+            .await
+            .map_err(|e| context.error(format!("Failed to create action client: {}", e)))?;
+        let max_output_chars = context.config.actions.max_output_chars;
+        let outputs = try_join_all(actions.into_iter().map(|action| {
+            run_action(
+                context.pool,
+                action_client,
+                context.file_storage,
+                max_output_chars,
+                run,
+                action,
+            )
+        }))
+        .await?;
+        for output in outputs {
+            context.add_action_call(output);
+        }
+        Ok(None)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    struct Calculator;
+
+    #[async_trait(?Send)]
+    impl Tool for Calculator {
+        fn name(&self) -> &str {
+            "calculator"
+        }
+
+        fn description(&self) -> &str {
+            "useful to compute arithmetic expressions"
+        }
+
+        fn input_schema(&self) -> Option<Value> {
+            Some(json!({
+                "type": "object",
+                "properties": {"expression": {"type": "string"}},
+                "required": ["expression"],
+            }))
+        }
+
+        async fn execute(&self, _context: &mut ToolContext<'_>) -> Result<Option<Run>, RunError> {
+            Ok(None)
+        }
+    }
+
+    #[test]
+    fn test_tool_registry() {
+        let mut registry = ToolRegistry::new();
+        assert_eq!(
+            registry.names(),
+            vec!["function", "retrieval", "code_interpreter", "action"]
+        );
+        assert!(registry.get("calculator").is_none());
+
+        registry.register(Arc::new(Calculator));
+        let calculator = registry.get("calculator").unwrap();
+        let tool = calculator
+            .parse(&json!({"type": "calculator", "data": {"precision": 2}}))
+            .unwrap();
+        assert_eq!(tool_type(&tool), "calculator");
+        let description = calculator.describe(&tool);
+        assert_eq!(description["name"], "calculator");
+        assert_eq!(description["input_schema"]["required"], json!(["expression"]));
+
+        // Registering a tool of the same name replaces it
+        registry.register(Arc::new(Calculator));
+        assert_eq!(registry.names().len(), 5);
+    }
+
+    #[test]
+    fn test_action_request() {
+        let function = FunctionCallWithMetadata {
+            name: "createTodo".to_string(),
+            arguments: r#"{"title": "Buy milk"}"#.to_string(),
+            metadata: Some(json!({
+                "domain": "https://todo.example.com",
+                "path": "/todos",
+                "method": "post",
+                "operation": "createTodo",
+                "content_type": "application/json",
+                "is_consequential": true,
+                "parameters": {"title": "body_field"},
+            })),
+        };
+        let request = action_request(&function).unwrap();
+        assert_eq!(request.domain, "https://todo.example.com");
+        assert_eq!(request.method, "post");
+        assert!(request.is_consequential);
+        assert_eq!(request.params, Some(json!({"title": "Buy milk"})));
+
+        // Operations registered before the flag was computed stored `false` for every method
+        let legacy = FunctionCallWithMetadata {
+            metadata: Some(json!({
+                "domain": "https://todo.example.com",
+                "path": "/todos",
+                "method": "post",
+                "operation": "createTodo",
+                "is_consequential": false,
+            })),
+            ..function.clone()
+        };
+        assert!(action_request(&legacy).unwrap().is_consequential);
+
+        let invalid = FunctionCallWithMetadata {
+            arguments: "not json".to_string(),
+            ..function
+        };
+        assert!(action_request(&invalid).is_err());
+    }
+}
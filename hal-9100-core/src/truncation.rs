@@ -0,0 +1,174 @@
+use hal_9100_core::models::{Message, TruncationStrategy};
+use hal_9100_core::prompts::format_messages;
+use hal_9100_core::threads::{get_thread_summary, update_thread_summary};
+use hal_9100_extra::llm::{HalLLMClient, HalLLMRequestArgs};
+use log::{error, info};
+use sqlx::PgPool;
+
+/// Split the thread messages according to the truncation strategy.
+/// Returns the messages to fold into the summary (only for `Summarize`) and the messages to
+/// give to the model as is. `summarized_messages` is the number of oldest messages already
+/// in the thread summary.
+pub fn split_messages<'a>(
+    messages: &'a [Message],
+    strategy: &TruncationStrategy,
+    summarized_messages: usize,
+) -> (&'a [Message], &'a [Message]) {
+    match strategy {
+        TruncationStrategy::Auto => (&[], messages),
+        TruncationStrategy::LastMessages { last_messages } => {
+            let start = messages.len().saturating_sub(*last_messages);
+            (&[], &messages[start..])
+        }
+        TruncationStrategy::Summarize { last_messages } => {
+            let recent_start = messages.len().saturating_sub(*last_messages);
+            let summary_start = summarized_messages.min(recent_start);
+            (
+                &messages[summary_start..recent_start],
+                &messages[recent_start..],
+            )
+        }
+    }
+}
+
+async fn summarize(
+    client: HalLLMClient,
+    previous_summary: &str,
+    messages: &[Message],
+) -> Result<String, Box<dyn std::error::Error>> {
+    let mut request = HalLLMRequestArgs::default().temperature(0.0);
+    request
+        .set_system_prompt("You maintain a summary of a conversation between a user and an assistant.
+Given the current summary and the next messages of the conversation, write the updated summary.
+Keep the facts, decisions, open questions and user preferences needed to continue the conversation, drop small talk.
+Only return the updated summary, nothing else.".to_string())
+        .set_last_user_prompt(format!(
+            "<summary>\n{}\n</summary>\n{}",
+            previous_summary,
+            format_messages(&messages.to_vec())
+        ));
+    let summary = client.create_chat_completion(request).await?;
+    Ok(summary.trim().to_string())
+}
+
+/// Format the previous messages of a run following its truncation strategy.
+/// With `Summarize`, messages older than the kept ones are folded into the rolling summary
+/// stored on the thread, which is prepended to the recent messages. If summarizing fails the
+/// previous summary is used.
+pub async fn format_previous_messages(
+    pool: &PgPool,
+    client: HalLLMClient,
+    thread_id: &str,
+    user_id: &str,
+    messages: &Vec<Message>,
+    strategy: &TruncationStrategy,
+) -> String {
+    if !matches!(strategy, TruncationStrategy::Summarize { .. }) {
+        let (_, recent) = split_messages(messages, strategy, 0);
+        return format_messages(&recent.to_vec());
+    }
+
+    let (mut summary, summarized_messages) = match get_thread_summary(pool, thread_id, user_id).await
+    {
+        Ok((summary, summarized_messages)) => {
+            (summary.unwrap_or_default(), summarized_messages.max(0) as usize)
+        }
+        Err(e) => {
+            error!("Failed to get thread summary: {}", e);
+            (String::new(), 0)
+        }
+    };
+    let (to_summarize, recent) = split_messages(messages, strategy, summarized_messages);
+
+    if !to_summarize.is_empty() {
+        info!(
+            "Summarizing {} messages of thread {}",
+            to_summarize.len(),
+            thread_id
+        );
+        match summarize(client, &summary, to_summarize).await {
+            Ok(new_summary) => {
+                let summarized_messages = (summarized_messages + to_summarize.len()) as i32;
+                match update_thread_summary(
+                    pool,
+                    thread_id,
+                    user_id,
+                    &new_summary,
+                    summarized_messages,
+                )
+                .await
+                {
+                    Ok(_) => summary = new_summary,
+                    Err(e) => error!("Failed to update thread summary: {}", e),
+                }
+            }
+            Err(e) => error!("Failed to summarize thread {}: {}", thread_id, e),
+        }
+    }
+
+    if summary.is_empty() {
+        format_messages(&recent.to_vec())
+    } else {
+        format!(
+            "<conversation_summary>\n{}\n</conversation_summary>\n{}",
+            summary,
+            format_messages(&recent.to_vec())
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn messages(n: usize) -> Vec<Message> {
+        (0..n)
+            .map(|i| {
+                let mut message = Message::default();
+                message.inner.id = i.to_string();
+                message
+            })
+            .collect()
+    }
+
+    fn ids(messages: &[Message]) -> Vec<String> {
+        messages.iter().map(|m| m.inner.id.clone()).collect()
+    }
+
+    #[test]
+    fn test_split_messages() {
+        let messages = messages(5);
+
+        let (old, recent) = split_messages(&messages, &TruncationStrategy::Auto, 0);
+        assert!(old.is_empty());
+        assert_eq!(recent.len(), 5);
+
+        let strategy = TruncationStrategy::LastMessages { last_messages: 2 };
+        let (old, recent) = split_messages(&messages, &strategy, 0);
+        assert!(old.is_empty());
+        assert_eq!(ids(recent), vec!["3", "4"]);
+
+        let strategy = TruncationStrategy::LastMessages { last_messages: 10 };
+        let (_, recent) = split_messages(&messages, &strategy, 0);
+        assert_eq!(recent.len(), 5);
+
+        let strategy = TruncationStrategy::Summarize { last_messages: 2 };
+        let (old, recent) = split_messages(&messages, &strategy, 1);
+        assert_eq!(ids(old), vec!["1", "2"]);
+        assert_eq!(ids(recent), vec!["3", "4"]);
+
+        // Everything old is already summarized
+        let (old, recent) = split_messages(&messages, &strategy, 4);
+        assert!(old.is_empty());
+        assert_eq!(ids(recent), vec!["3", "4"]);
+    }
+
+    #[test]
+    fn test_truncation_strategy_format() {
+        let strategy: TruncationStrategy =
+            serde_json::from_str(r#"{"type": "last_messages", "last_messages": 10}"#).unwrap();
+        assert_eq!(strategy, TruncationStrategy::LastMessages { last_messages: 10 });
+        let strategy: TruncationStrategy = serde_json::from_str(r#"{"type": "auto"}"#).unwrap();
+        assert_eq!(strategy, TruncationStrategy::Auto);
+    }
+}
@@ -1,5 +1,296 @@
 use serde::Deserialize;
 
+use hal_9100_extra::model_registry::{ModelInfo, ModelRegistry};
+
+/// Share of the model context given to each source of the prompt. Shares should sum to at
+/// most 1, the rest of the context is left for the answer. Space a source does not use is
+/// given to the others.
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(default)]
+pub struct ContextBudget {
+    pub instructions: f32,
+    pub previous_messages: f32,
+    pub function_calls: f32,
+    pub action_calls: f32,
+    pub code_output: f32,
+    pub retrieval_files: f32,
+    pub retrieval_chunks: f32,
+}
+
+impl Default for ContextBudget {
+    fn default() -> Self {
+        ContextBudget {
+            instructions: 0.1,
+            previous_messages: 0.2,
+            function_calls: 0.05,
+            action_calls: 0.1,
+            code_output: 0.05,
+            retrieval_files: 0.1,
+            retrieval_chunks: 0.15,
+        }
+    }
+}
+
+/// How the LLM server is asked to only generate JSON matching a schema, used for function
+/// arguments. Generated arguments are validated against the schema whatever the choice.
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum ConstrainedDecoding {
+    /// JSON mode for the OpenAI models having it (`gpt-4-turbo`, `gpt-4o`, `-1106` snapshots
+    /// and later), nothing for the others
+    Auto,
+    None,
+    /// vLLM `guided_json`
+    Vllm,
+    /// llama.cpp server grammar generated from the schema
+    LlamaCpp,
+}
+
+impl Default for ConstrainedDecoding {
+    fn default() -> Self {
+        ConstrainedDecoding::Auto
+    }
+}
+
+/// How the functions and actions of an assistant to call are chosen. The model first picks
+/// the relevant ones from their names and descriptions, then only those get arguments
+/// generated.
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(default)]
+pub struct FunctionSelectionConfig {
+    /// Functions called at most per run, the model's first picks are kept
+    pub max_selected: usize,
+    /// Calls of the same function generated at most in one turn, when the run allows
+    /// parallel tool calls
+    pub max_calls_per_function: usize,
+    /// OpenAI compatible embeddings endpoint, e.g. "https://api.openai.com/v1/embeddings".
+    /// When set, assistants with more than `prefilter_top_k` functions only show the model
+    /// the ones whose description is the most similar to the conversation.
+    pub embedding_url: Option<String>,
+    pub embedding_model: String,
+    /// Sent as a bearer token
+    pub embedding_api_key: Option<String>,
+    pub prefilter_top_k: usize,
+}
+
+impl Default for FunctionSelectionConfig {
+    fn default() -> Self {
+        FunctionSelectionConfig {
+            max_selected: 5,
+            max_calls_per_function: 5,
+            embedding_url: None,
+            embedding_model: "text-embedding-ada-002".to_string(),
+            embedding_api_key: None,
+            prefilter_top_k: 10,
+        }
+    }
+}
+
+/// What outbound requests of actions may reach. Hosts are names, `*.` wildcards for their
+/// subdomains, IP addresses or CIDR ranges.
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(default)]
+pub struct ActionsConfig {
+    /// When not empty, only these hosts are reached. Allowed ranges may be private.
+    pub allow_hosts: Vec<String>,
+    /// Never reached, even when allowed
+    pub deny_hosts: Vec<String>,
+    /// Reach loopback, private, link-local and other internal addresses not allowed explicitly
+    pub allow_private: bool,
+    pub timeout_secs: u64,
+    pub connect_timeout_secs: u64,
+    pub max_redirects: usize,
+    /// Larger responses are rejected
+    pub max_response_bytes: usize,
+    /// Responses are cut to this many characters in the prompt
+    pub max_output_chars: usize,
+}
+
+impl Default for ActionsConfig {
+    fn default() -> Self {
+        ActionsConfig {
+            allow_hosts: vec![],
+            deny_hosts: vec![],
+            allow_private: false,
+            timeout_secs: 30,
+            connect_timeout_secs: 10,
+            max_redirects: 5,
+            max_response_bytes: 5 * 1024 * 1024,
+            max_output_chars: 4000,
+        }
+    }
+}
+
+/// Where the code interpreter runs the generated code.
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum SandboxBackend {
+    /// A pool of locked down Docker containers
+    Docker,
+    /// A subprocess of the executor with resource limits, jailed with bubblewrap
+    Local,
+    /// A remote runner service the code is posted to
+    Http,
+}
+
+impl Default for SandboxBackend {
+    fn default() -> Self {
+        SandboxBackend::Docker
+    }
+}
+
+/// Languages the code interpreter can write code in.
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[serde(rename_all = "snake_case")]
+pub enum CodeLanguage {
+    Python,
+    /// Run with Node.js
+    Javascript,
+    /// Run with Rscript
+    R,
+    /// DuckDB SQL, the uploaded CSV, TSV, Parquet and JSON files are queryable as views
+    Sql,
+}
+
+impl CodeLanguage {
+    pub fn name(&self) -> &'static str {
+        match self {
+            CodeLanguage::Python => "python",
+            CodeLanguage::Javascript => "javascript",
+            CodeLanguage::R => "r",
+            CodeLanguage::Sql => "sql",
+        }
+    }
+}
+
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(default)]
+pub struct LocalSandboxConfig {
+    /// Python interpreter the code is run with, SQL runs through its duckdb module
+    pub python: String,
+    /// Node.js binary JavaScript code is run with
+    pub node: String,
+    /// Rscript binary R code is run with
+    pub rscript: String,
+    /// Run the code in a bubblewrap jail without network and with a read-only view of the
+    /// system directories. Only disable on machines where the code is trusted.
+    pub bubblewrap: bool,
+    pub bubblewrap_path: String,
+    /// A cgroup v2 directory delegated to the executor, e.g. with systemd `Delegate=yes`.
+    /// Each code and session runs in its own child cgroup whose `pids.max` is `pids_limit`.
+    /// Without it the number of processes and threads of the code is not limited, run the
+    /// executor as a dedicated user with its own limits or use the docker backend.
+    pub cgroup: Option<String>,
+}
+
+impl Default for LocalSandboxConfig {
+    fn default() -> Self {
+        LocalSandboxConfig {
+            python: "python3".to_string(),
+            node: "node".to_string(),
+            rscript: "Rscript".to_string(),
+            bubblewrap: true,
+            bubblewrap_path: "bwrap".to_string(),
+            cgroup: None,
+        }
+    }
+}
+
+#[derive(Deserialize, Debug, Clone, PartialEq, Default)]
+#[serde(default)]
+pub struct HttpSandboxConfig {
+    /// Endpoint of the runner service, it receives `{"code", "timeout_secs", "files"}` and
+    /// answers `{"stdout", "stderr", "exit_code", "files"}`, files being
+    /// `{"name", "content_base64"}`
+    pub url: String,
+    /// Sent as a bearer token
+    pub api_key: Option<String>,
+}
+
+/// Python sessions kept per thread so variables and loaded data survive between messages.
+/// Ignored by the http backend, which runs every code from scratch.
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(default)]
+pub struct InterpreterSessionConfig {
+    pub enabled: bool,
+    /// Sessions unused for this long are stopped
+    pub idle_timeout_secs: u64,
+    /// Sessions alive at the same time, the least recently used idle one is stopped to
+    /// make room. With the Docker backend each session is a container.
+    pub max_sessions: usize,
+}
+
+impl Default for InterpreterSessionConfig {
+    fn default() -> Self {
+        InterpreterSessionConfig {
+            enabled: true,
+            idle_timeout_secs: 600,
+            max_sessions: 8,
+        }
+    }
+}
+
+/// Limits of the sandbox running the code interpreter. Memory, process, time and file size
+/// limits apply to the Docker and local backends, the remote runner enforces its own.
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(default)]
+pub struct CodeInterpreterConfig {
+    pub backend: SandboxBackend,
+    pub local: LocalSandboxConfig,
+    pub http: HttpSandboxConfig,
+    pub session: InterpreterSessionConfig,
+    /// Languages the generated code may be written in, the model picks one per request
+    pub languages: Vec<CodeLanguage>,
+    /// Image with the runtimes of all the enabled languages
+    pub image: String,
+    /// Memory limit in bytes, swap is disabled
+    pub memory_bytes: i64,
+    /// CPU quota in units of 10^-9 CPUs
+    pub nano_cpus: i64,
+    /// Maximum number of processes and threads. The local backend only enforces it with a
+    /// `cgroup`, see `LocalSandboxConfig`.
+    pub pids_limit: i64,
+    /// Wall-clock limit of one code execution
+    pub timeout_secs: u64,
+    /// Docker network mode, "none" disables network access
+    pub network_mode: String,
+    pub read_only_rootfs: bool,
+    /// Size of the writable tmpfs mounted on the working directory
+    pub tmpfs_size_bytes: i64,
+    /// User (name or uid:gid) the code runs as
+    pub user: String,
+    /// Number of containers kept started, which is also the number of codes run concurrently
+    pub pool_size: usize,
+    /// Runs after which a container is replaced by a fresh one
+    pub max_uses_per_container: usize,
+    /// Codes generated for one request, each failed one is shown to the model to fix it
+    pub max_attempts: usize,
+}
+
+impl Default for CodeInterpreterConfig {
+    fn default() -> Self {
+        CodeInterpreterConfig {
+            backend: SandboxBackend::default(),
+            local: LocalSandboxConfig::default(),
+            http: HttpSandboxConfig::default(),
+            session: InterpreterSessionConfig::default(),
+            languages: vec![CodeLanguage::Python],
+            image: "louis030195/hal-9100-code-interpreter:latest".to_string(),
+            memory_bytes: 512 * 1024 * 1024,
+            nano_cpus: 1_000_000_000,
+            pids_limit: 64,
+            timeout_secs: 30,
+            network_mode: "none".to_string(),
+            read_only_rootfs: true,
+            tmpfs_size_bytes: 64 * 1024 * 1024,
+            user: "65534:65534".to_string(),
+            pool_size: 2,
+            max_uses_per_container: 20,
+            max_attempts: 3,
+        }
+    }
+}
+
 #[derive(Deserialize, Debug, Clone)]
 pub struct Hal9100Config {
     pub anthropic_api_key: Option<String>,
@@ -12,6 +303,18 @@ pub struct Hal9100Config {
     pub s3_access_key: String,
     pub s3_secret_key: String,
     pub s3_bucket_name: String,
+    #[serde(default)]
+    pub models: Vec<ModelInfo>,
+    #[serde(default)]
+    pub context_budget: ContextBudget,
+    #[serde(default)]
+    pub code_interpreter: CodeInterpreterConfig,
+    #[serde(default)]
+    pub function_selection: FunctionSelectionConfig,
+    #[serde(default)]
+    pub constrained_decoding: ConstrainedDecoding,
+    #[serde(default)]
+    pub actions: ActionsConfig,
 }
 
 impl Default for Hal9100Config {
@@ -30,6 +333,18 @@ impl Default for Hal9100Config {
             s3_access_key: std::env::var("S3_ACCESS_KEY").unwrap_or("minioadmin".to_string()),
             s3_secret_key: std::env::var("S3_SECRET_KEY").unwrap_or("minioadmin".to_string()),
             s3_bucket_name: std::env::var("S3_BUCKET_NAME").unwrap_or("mybucket".to_string()),
+            models: vec![],
+            context_budget: ContextBudget::default(),
+            code_interpreter: CodeInterpreterConfig::default(),
+            function_selection: FunctionSelectionConfig::default(),
+            constrained_decoding: ConstrainedDecoding::default(),
+            actions: ActionsConfig::default(),
         }
     }
 }
+
+impl Hal9100Config {
+    pub fn model_registry(&self) -> ModelRegistry {
+        ModelRegistry::new(&self.models)
+    }
+}
@@ -4,4 +4,5 @@ extern crate self as hal_9100_extra;
 pub mod anthropic;
 pub mod config;
 pub mod llm;
+pub mod model_registry;
 pub mod openai;
@@ -1,4 +1,5 @@
 use hal_9100_extra::anthropic::call_anthropic_api;
+use hal_9100_extra::config::ConstrainedDecoding;
 use hal_9100_extra::openai::{
     call_open_source_openai_api_with_messages, call_openai_api_with_messages, Message,
 };
@@ -8,6 +9,16 @@ use std::error::Error;
 use std::ops::Deref;
 use tiktoken_rs::cl100k_base;
 
+// OpenAI models taking `response_format`, older snapshots like `gpt-4-0613` reject it
+const JSON_MODE_MODELS: &[&str] = &[
+    "gpt-4-turbo",
+    "gpt-4-1106",
+    "gpt-4-0125",
+    "gpt-4o",
+    "gpt-3.5-turbo-1106",
+    "gpt-3.5-turbo-0125",
+];
+
 #[derive(Clone, Debug)]
 pub struct HalLLMRequestArgs {
     pub messages: Vec<Message>,
@@ -18,6 +29,9 @@ pub struct HalLLMRequestArgs {
     pub top_k: Option<i32>,
     pub metadata: Option<HashMap<String, String>>,
     pub context_size: Option<i32>,
+    /// JSON Schema the answer should match, sent to the servers supporting constrained
+    /// decoding
+    pub json_schema: Option<serde_json::Value>,
 }
 
 impl Default for HalLLMRequestArgs {
@@ -31,6 +45,7 @@ impl Default for HalLLMRequestArgs {
             top_k: None,
             metadata: None,
             context_size: None,
+            json_schema: None,
         }
     }
 }
@@ -138,6 +153,11 @@ impl HalLLMRequestArgs {
         self
     }
 
+    pub fn json_schema(mut self, json_schema: serde_json::Value) -> Self {
+        self.json_schema = Some(json_schema);
+        self
+    }
+
     pub fn build(self) -> Result<Self, Box<dyn std::error::Error>> {
         // Here you can add validation logic and return Err if something is not right
         // For simplicity, we'll assume everything is fine
@@ -151,6 +171,7 @@ pub struct HalLLMClient {
     pub model_name: String,
     pub model_url: String,
     pub api_key: String, // Assuming an API key is needed
+    pub constrained_decoding: ConstrainedDecoding,
 }
 
 impl HalLLMClient {
@@ -160,6 +181,7 @@ impl HalLLMClient {
             model_name,
             model_url,
             api_key,
+            constrained_decoding: ConstrainedDecoding::default(),
         }
     }
 
@@ -172,6 +194,42 @@ impl HalLLMClient {
     pub fn set_api_key(&mut self, api_key: String) {
         self.api_key = api_key;
     }
+    pub fn set_constrained_decoding(&mut self, constrained_decoding: ConstrainedDecoding) {
+        self.constrained_decoding = constrained_decoding;
+    }
+
+    // Fields of the request body asking the server to only generate JSON matching `schema`
+    fn constrained_decoding_body(
+        &self,
+        schema: &Option<serde_json::Value>,
+    ) -> HashMap<&'static str, serde_json::Value> {
+        let mut body = HashMap::new();
+        let schema = match schema {
+            Some(schema) => schema.clone(),
+            None => return body,
+        };
+        let model_name = self.model_name.to_lowercase();
+        let json_mode = JSON_MODE_MODELS
+            .iter()
+            .any(|model| model_name.contains(model));
+        match self.constrained_decoding {
+            // The JSON mode of OpenAI does not take a schema, the prompt describes it
+            ConstrainedDecoding::Auto if json_mode => {
+                body.insert("response_format", serde_json::json!({"type": "json_object"}));
+            }
+            ConstrainedDecoding::Vllm => {
+                body.insert("guided_json", schema);
+            }
+            ConstrainedDecoding::LlamaCpp => {
+                body.insert(
+                    "response_format",
+                    serde_json::json!({"type": "json_object", "schema": schema}),
+                );
+            }
+            ConstrainedDecoding::Auto | ConstrainedDecoding::None => (),
+        }
+        body
+    }
 
     // TODO async backoff
     pub async fn create_chat_completion(
@@ -179,6 +237,7 @@ impl HalLLMClient {
         request: HalLLMRequestArgs,
     ) -> Result<String, Box<dyn Error>> {
         let mut max_tokens_to_sample = request.max_tokens_to_sample.unwrap_or(-1);
+        let extra_body = self.constrained_decoding_body(&request.json_schema);
 
         if self.model_name.contains("claude") {
             // ! disgusting but who care about anthropic? raise your hand
@@ -221,6 +280,7 @@ impl HalLLMClient {
                 request.temperature,
                 request.stop_sequences,
                 request.top_p,
+                extra_body,
             )
             .await
             .map(|res| res.choices[0].message.content.clone())
@@ -251,6 +311,7 @@ impl HalLLMClient {
                 request.stop_sequences,
                 request.top_p,
                 self.model_url.clone(),
+                extra_body,
             )
             .await
             .map(|res| res.choices[0].message.content.clone())
@@ -271,6 +332,40 @@ mod tests {
     use dotenv;
     use std::collections::HashMap;
 
+    #[test]
+    fn test_constrained_decoding_body() {
+        let schema = Some(serde_json::json!({"type": "object"}));
+        let mut client = HalLLMClient::new(
+            "gpt-4-1106-preview".to_string(),
+            "".to_string(),
+            "".to_string(),
+        );
+        assert_eq!(
+            client.constrained_decoding_body(&schema)["response_format"],
+            serde_json::json!({"type": "json_object"})
+        );
+        assert!(client.constrained_decoding_body(&None).is_empty());
+
+        // Older OpenAI snapshots don't have the JSON mode
+        client.set_model_name("gpt-4".to_string());
+        assert!(client.constrained_decoding_body(&schema).is_empty());
+        client.set_model_name("gpt-3.5-turbo-0613".to_string());
+        assert!(client.constrained_decoding_body(&schema).is_empty());
+
+        client.set_model_name("mistralai/mixtral-8x7b-instruct".to_string());
+        assert!(client.constrained_decoding_body(&schema).is_empty());
+        client.set_constrained_decoding(ConstrainedDecoding::Vllm);
+        assert_eq!(
+            client.constrained_decoding_body(&schema)["guided_json"],
+            serde_json::json!({"type": "object"})
+        );
+        client.set_constrained_decoding(ConstrainedDecoding::LlamaCpp);
+        assert_eq!(
+            client.constrained_decoding_body(&schema)["response_format"]["schema"],
+            serde_json::json!({"type": "object"})
+        );
+    }
+
     #[tokio::test]
     async fn test_llm_new() {
         let client = HalLLMClient::new(
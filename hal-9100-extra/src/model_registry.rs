@@ -0,0 +1,99 @@
+use serde::Deserialize;
+
+/// Context size used for models that are not in the registry.
+pub const DEFAULT_CONTEXT_SIZE: usize = 4096;
+
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+pub struct ModelInfo {
+    pub name: String,
+    pub context_size: usize,
+}
+
+// Models known out of the box, entries from the config take precedence
+fn builtin_models() -> Vec<ModelInfo> {
+    [
+        ("claude-2.1", 200_000),
+        ("claude-2", 100_000),
+        ("claude-instant-1", 100_000),
+        ("gpt-4-1106-preview", 128_000),
+        ("gpt-4-32k", 32_768),
+        ("gpt-4", 8_192),
+        ("gpt-3.5-turbo-1106", 16_385),
+        ("gpt-3.5-turbo-16k", 16_385),
+        ("gpt-3.5-turbo", 4_096),
+        ("mistralai/mixtral-8x7b-instruct", 32_768),
+        ("mistralai/mistral-7b-instruct", 8_192),
+        ("meta-llama/llama-2-7b-chat-hf", 4_096),
+        ("meta-llama/llama-2-13b-chat-hf", 4_096),
+        ("meta-llama/llama-2-70b-chat-hf", 4_096),
+    ]
+    .into_iter()
+    .map(|(name, context_size)| ModelInfo {
+        name: name.to_string(),
+        context_size,
+    })
+    .collect()
+}
+
+#[derive(Debug, Clone)]
+pub struct ModelRegistry {
+    models: Vec<ModelInfo>,
+}
+
+impl ModelRegistry {
+    pub fn new(models: &Vec<ModelInfo>) -> Self {
+        let mut all_models = models.clone();
+        all_models.extend(
+            builtin_models()
+                .into_iter()
+                .filter(|m| !models.iter().any(|o| o.name.eq_ignore_ascii_case(&m.name))),
+        );
+        Self { models: all_models }
+    }
+
+    /// Find a model by exact name, or else by the longest registered name it starts with
+    /// (e.g. `gpt-4-0613` matches `gpt-4`). Names are compared case-insensitively.
+    pub fn get(&self, model_name: &str) -> Option<&ModelInfo> {
+        let model_name = model_name.to_lowercase();
+        self.models
+            .iter()
+            .find(|m| m.name.to_lowercase() == model_name)
+            .or_else(|| {
+                self.models
+                    .iter()
+                    .filter(|m| model_name.starts_with(&m.name.to_lowercase()))
+                    .max_by_key(|m| m.name.len())
+            })
+    }
+
+    pub fn context_size(&self, model_name: &str) -> usize {
+        self.get(model_name)
+            .map(|m| m.context_size)
+            .unwrap_or(DEFAULT_CONTEXT_SIZE)
+    }
+}
+
+impl Default for ModelRegistry {
+    fn default() -> Self {
+        Self::new(&vec![])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_context_size() {
+        let registry = ModelRegistry::new(&vec![ModelInfo {
+            name: "gpt-4".to_string(),
+            context_size: 1000,
+        }]);
+
+        assert_eq!(registry.context_size("gpt-4"), 1000);
+        assert_eq!(registry.context_size("gpt-4-0613"), 1000);
+        assert_eq!(registry.context_size("gpt-4-32k-0613"), 32_768);
+        assert_eq!(registry.context_size("Claude-2.1"), 200_000);
+        assert_eq!(registry.context_size("unknown"), DEFAULT_CONTEXT_SIZE);
+    }
+}
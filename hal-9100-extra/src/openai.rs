@@ -247,6 +247,7 @@ pub async fn call_openai_api_with_messages(
     temperature: Option<f32>,
     stop_sequences: Option<Vec<String>>,
     top_p: Option<f32>,
+    extra_body: HashMap<&'static str, serde_json::Value>,
 ) -> Result<ChatCompletion, OpenAIApiError> {
     let url = "https://api.openai.com/v1/chat/completions";
     let default_model = "gpt-3.5-turbo".to_string();
@@ -277,6 +278,8 @@ pub async fn call_openai_api_with_messages(
     if let Some(top_p) = top_p {
         body.insert("top_p", serde_json::json!(top_p));
     }
+    // Fields only some models understand, e.g. for constrained decoding
+    body.extend(extra_body);
 
     let client = reqwest::Client::new();
     let res = client.post(url).headers(headers).json(&body).send().await?;
@@ -297,6 +300,7 @@ pub async fn call_open_source_openai_api_with_messages(
     stop_sequences: Option<Vec<String>>,
     top_p: Option<f32>,
     url: String, // url is required for open-source API
+    extra_body: HashMap<&'static str, serde_json::Value>,
 ) -> Result<ChatCompletion, OpenAIApiError> {
     let mut headers = HeaderMap::new();
     headers.insert(CONTENT_TYPE, HeaderValue::from_static("application/json"));
@@ -327,6 +331,8 @@ pub async fn call_open_source_openai_api_with_messages(
     if let Some(top_p) = top_p {
         body.insert("top_p", serde_json::json!(top_p));
     }
+    // Fields only some servers understand, e.g. for constrained decoding
+    body.extend(extra_body);
 
     let client = reqwest::Client::new();
     let res = client.post(url).headers(headers).json(&body).send().await?;
@@ -352,6 +358,66 @@ pub async fn call_open_source_openai_api_with_messages(
     }
 }
 
+#[derive(Debug, Deserialize)]
+struct Embedding {
+    index: usize,
+    embedding: Vec<f32>,
+}
+
+#[derive(Debug, Deserialize)]
+struct EmbeddingsResponse {
+    data: Vec<Embedding>,
+}
+
+/// Embed `inputs` with an OpenAI compatible embeddings endpoint, the embeddings are
+/// returned in the order of the inputs.
+pub async fn call_embeddings_api(
+    inputs: Vec<String>,
+    model: String,
+    url: String,
+    api_key: Option<String>,
+) -> Result<Vec<Vec<f32>>, OpenAIApiError> {
+    let count = inputs.len();
+    let client = reqwest::Client::new();
+    let mut request = client.post(url).json(&serde_json::json!({
+        "model": model,
+        "input": inputs,
+    }));
+    if let Some(api_key) = api_key {
+        request = request.bearer_auth(api_key);
+    }
+    let res = request.send().await?;
+    let status = res.status();
+    let raw_res = res.text().await?;
+
+    if !status.is_success() {
+        return Err(OpenAIApiError::ApiError(ApiErrorResponse {
+            error: ApiErrorDetail {
+                message: format!("API request failed with status {}: {}", status, raw_res),
+                r#type: "API Request Error".to_string(),
+                param: None,
+                code: None,
+            },
+        }));
+    }
+
+    let mut api_res: EmbeddingsResponse =
+        serde_json::from_str(&raw_res).map_err(OpenAIApiError::JSONDeserialize)?;
+    if api_res.data.len() != count {
+        return Err(OpenAIApiError::InvalidArgument(format!(
+            "Expected {} embeddings, got {}",
+            count,
+            api_res.data.len()
+        )));
+    }
+    api_res.data.sort_by_key(|embedding| embedding.index);
+    Ok(api_res
+        .data
+        .into_iter()
+        .map(|embedding| embedding.embedding)
+        .collect())
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -429,6 +495,39 @@ mod tests {
     use httpmock::Method::POST;
     use httpmock::MockServer;
 
+    #[tokio::test]
+    async fn test_call_embeddings_api() {
+        let server = MockServer::start();
+        let mock = server.mock(|when, then| {
+            when.method(POST)
+                .path("/v1/embeddings")
+                .header("authorization", "Bearer secret")
+                .json_body(serde_json::json!({
+                    "model": "text-embedding-ada-002",
+                    "input": ["a", "b"],
+                }));
+            then.status(200).json_body(serde_json::json!({
+                "object": "list",
+                "data": [
+                    {"object": "embedding", "index": 1, "embedding": [0.0, 1.0]},
+                    {"object": "embedding", "index": 0, "embedding": [1.0, 0.0]},
+                ],
+            }));
+        });
+
+        let embeddings = call_embeddings_api(
+            vec!["a".to_string(), "b".to_string()],
+            "text-embedding-ada-002".to_string(),
+            server.url("/v1/embeddings"),
+            Some("secret".to_string()),
+        )
+        .await
+        .unwrap();
+
+        mock.assert();
+        assert_eq!(embeddings, vec![vec![1.0, 0.0], vec![0.0, 1.0]]);
+    }
+
     #[tokio::test]
     async fn test_call_open_source_openai_api_error_handling() {
         // Arrange